@@ -15,6 +15,17 @@ pub fn gen_str_funcs(comps: &[Component]) -> TokenStream {
         })
         .collect();
 
+    let from_snakes: Vec<_> = comps
+        .iter()
+        .map(|comp| {
+            let name = ident(&comp.name);
+            let snake = upper_camel_to_snake(&comp.name);
+            quote! {
+                #snake => Some(ComponentType::#name)
+            }
+        })
+        .collect();
+
     let kebabs: Vec<_> = comps
         .iter()
         .map(|comp| {
@@ -34,6 +45,13 @@ pub fn gen_str_funcs(comps: &[Component]) -> TokenStream {
                 }
             }
 
+            pub fn from_snake_name(name: &str) -> Option<Self> {
+                match name {
+                    #(#from_snakes,)*
+                    _ => None,
+                }
+            }
+
             pub fn kebab_name(&self) -> &'static str {
                 match self {
                     #(#kebabs,)*
@@ -1,7 +1,7 @@
-use crate::{Component, ipc::codec::LengthDelimitedJSONCodec};
+use crate::{Component, ComponentType, ipc::codec::LengthDelimitedJSONCodec, query::DeviceInfo};
 use futures_util::{Sink, SinkExt};
 pub use model::*;
-use std::{env, io};
+use std::{env, io, path::Path};
 use tokio::net::{
     UnixStream,
     unix::{OwnedReadHalf, OwnedWriteHalf},
@@ -22,7 +22,12 @@ pub type EWriter = FramedWrite<OwnedWriteHalf, LengthDelimitedJSONCodec<Extensio
 pub type EReader = FramedRead<OwnedReadHalf, LengthDelimitedJSONCodec<IglooToExtension>>;
 
 pub async fn connect() -> io::Result<(EWriter, EReader)> {
-    let stream = UnixStream::connect("igloo.sock").await?;
+    connect_to("igloo.sock").await
+}
+
+/// [connect] to the socket at `path`
+pub async fn connect_to(path: impl AsRef<Path>) -> io::Result<(EWriter, EReader)> {
+    let stream = UnixStream::connect(path).await?;
 
     let (reader, writer) = stream.into_split();
 
@@ -49,6 +54,19 @@ pub trait AsyncWriteExtensionToIglooMut {
         name: String,
     ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
+    fn create_adopted_device(
+        &mut self,
+        name: String,
+        external_id: String,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn discovered(
+        &mut self,
+        external_id: String,
+        name: String,
+        model: Option<String>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
     fn register_entity(
         &mut self,
         device: u64,
@@ -69,6 +87,45 @@ pub trait AsyncWriteExtensionToIglooMut {
         entity: usize,
         comps: Vec<Component>,
     ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn remove_components(
+        &mut self,
+        device: u64,
+        entity: usize,
+        comps: Vec<ComponentType>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn ack(
+        &mut self,
+        id: CommandID,
+        result: Result<(), String>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn pong(&mut self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn set_device_info(
+        &mut self,
+        device: u64,
+        info: DeviceInfo,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn set_reachable(
+        &mut self,
+        device: u64,
+        reachable: bool,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn custom(
+        &mut self,
+        name: String,
+        payload: serde_json::Value,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn custom_reply(
+        &mut self,
+        id: CommandID,
+        result: Result<serde_json::Value, String>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 }
 
 pub trait AsyncWriteExtensionToIgloo {
@@ -78,6 +135,19 @@ pub trait AsyncWriteExtensionToIgloo {
 
     fn create_device(&self, name: String) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
+    fn create_adopted_device(
+        &self,
+        name: String,
+        external_id: String,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn discovered(
+        &self,
+        external_id: String,
+        name: String,
+        model: Option<String>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
     fn register_entity(
         &self,
         device: u64,
@@ -98,6 +168,45 @@ pub trait AsyncWriteExtensionToIgloo {
         entity: usize,
         comps: Vec<Component>,
     ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn remove_components(
+        &self,
+        device: u64,
+        entity: usize,
+        comps: Vec<ComponentType>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn ack(
+        &self,
+        id: CommandID,
+        result: Result<(), String>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn pong(&self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn set_device_info(
+        &self,
+        device: u64,
+        info: DeviceInfo,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn set_reachable(
+        &self,
+        device: u64,
+        reachable: bool,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn custom(
+        &self,
+        name: String,
+        payload: serde_json::Value,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn custom_reply(
+        &self,
+        id: CommandID,
+        result: Result<serde_json::Value, String>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 }
 
 impl<T> AsyncWriteExtensionToIglooMut for T
@@ -111,7 +220,35 @@ where
     }
 
     async fn create_device(&mut self, name: String) -> io::Result<()> {
-        self.feed(ExtensionToIgloo::CreateDevice { name }).await
+        self.feed(ExtensionToIgloo::CreateDevice {
+            name,
+            external_id: None,
+            info: None,
+        })
+        .await
+    }
+
+    async fn create_adopted_device(&mut self, name: String, external_id: String) -> io::Result<()> {
+        self.feed(ExtensionToIgloo::CreateDevice {
+            name,
+            external_id: Some(external_id),
+            info: None,
+        })
+        .await
+    }
+
+    async fn discovered(
+        &mut self,
+        external_id: String,
+        name: String,
+        model: Option<String>,
+    ) -> io::Result<()> {
+        self.feed(ExtensionToIgloo::Discovered {
+            external_id,
+            name,
+            model,
+        })
+        .await
     }
 
     async fn register_entity(
@@ -150,6 +287,51 @@ where
         })
         .await
     }
+
+    async fn remove_components(
+        &mut self,
+        device: u64,
+        entity: usize,
+        comps: Vec<ComponentType>,
+    ) -> io::Result<()> {
+        self.feed(ExtensionToIgloo::RemoveComponents {
+            device,
+            entity,
+            comps,
+        })
+        .await
+    }
+
+    async fn ack(&mut self, id: CommandID, result: Result<(), String>) -> io::Result<()> {
+        self.feed(ExtensionToIgloo::Ack { id, result }).await
+    }
+
+    async fn pong(&mut self) -> io::Result<()> {
+        self.feed(ExtensionToIgloo::Pong).await
+    }
+
+    async fn set_device_info(&mut self, device: u64, info: DeviceInfo) -> io::Result<()> {
+        self.feed(ExtensionToIgloo::SetDeviceInfo { device, info })
+            .await
+    }
+
+    async fn set_reachable(&mut self, device: u64, reachable: bool) -> io::Result<()> {
+        self.feed(ExtensionToIgloo::SetReachable { device, reachable })
+            .await
+    }
+
+    async fn custom(&mut self, name: String, payload: serde_json::Value) -> io::Result<()> {
+        self.feed(ExtensionToIgloo::Custom { name, payload }).await
+    }
+
+    async fn custom_reply(
+        &mut self,
+        id: CommandID,
+        result: Result<serde_json::Value, String>,
+    ) -> io::Result<()> {
+        self.feed(ExtensionToIgloo::CustomReply { id, result })
+            .await
+    }
 }
 
 #[cfg(feature = "kanal")]
@@ -161,7 +343,39 @@ impl AsyncWriteExtensionToIgloo for kanal::AsyncSender<ExtensionToIgloo> {
     }
 
     async fn create_device(&self, name: String) -> Result<(), Self::Error> {
-        self.send(ExtensionToIgloo::CreateDevice { name }).await
+        self.send(ExtensionToIgloo::CreateDevice {
+            name,
+            external_id: None,
+            info: None,
+        })
+        .await
+    }
+
+    async fn create_adopted_device(
+        &self,
+        name: String,
+        external_id: String,
+    ) -> Result<(), Self::Error> {
+        self.send(ExtensionToIgloo::CreateDevice {
+            name,
+            external_id: Some(external_id),
+            info: None,
+        })
+        .await
+    }
+
+    async fn discovered(
+        &self,
+        external_id: String,
+        name: String,
+        model: Option<String>,
+    ) -> Result<(), Self::Error> {
+        self.send(ExtensionToIgloo::Discovered {
+            external_id,
+            name,
+            model,
+        })
+        .await
     }
 
     async fn register_entity(
@@ -200,6 +414,51 @@ impl AsyncWriteExtensionToIgloo for kanal::AsyncSender<ExtensionToIgloo> {
         })
         .await
     }
+
+    async fn remove_components(
+        &self,
+        device: u64,
+        entity: usize,
+        comps: Vec<ComponentType>,
+    ) -> Result<(), Self::Error> {
+        self.send(ExtensionToIgloo::RemoveComponents {
+            device,
+            entity,
+            comps,
+        })
+        .await
+    }
+
+    async fn ack(&self, id: CommandID, result: Result<(), String>) -> Result<(), Self::Error> {
+        self.send(ExtensionToIgloo::Ack { id, result }).await
+    }
+
+    async fn pong(&self) -> Result<(), Self::Error> {
+        self.send(ExtensionToIgloo::Pong).await
+    }
+
+    async fn set_device_info(&self, device: u64, info: DeviceInfo) -> Result<(), Self::Error> {
+        self.send(ExtensionToIgloo::SetDeviceInfo { device, info })
+            .await
+    }
+
+    async fn set_reachable(&self, device: u64, reachable: bool) -> Result<(), Self::Error> {
+        self.send(ExtensionToIgloo::SetReachable { device, reachable })
+            .await
+    }
+
+    async fn custom(&self, name: String, payload: serde_json::Value) -> Result<(), Self::Error> {
+        self.send(ExtensionToIgloo::Custom { name, payload }).await
+    }
+
+    async fn custom_reply(
+        &self,
+        id: CommandID,
+        result: Result<serde_json::Value, String>,
+    ) -> Result<(), Self::Error> {
+        self.send(ExtensionToIgloo::CustomReply { id, result })
+            .await
+    }
 }
 
 pub trait WriteIglooToExtension {
@@ -211,6 +470,7 @@ pub trait WriteIglooToExtension {
 
     fn write_component(
         &mut self,
+        id: CommandID,
         device: u64,
         entity: usize,
         comp: Component,
@@ -218,6 +478,7 @@ pub trait WriteIglooToExtension {
 
     fn write_components(
         &mut self,
+        id: CommandID,
         device: u64,
         entity: usize,
         comps: Vec<Component>,
@@ -225,9 +486,21 @@ pub trait WriteIglooToExtension {
 
     fn write_custom(
         &mut self,
+        id: CommandID,
         name: String,
         payload: serde_json::Value,
     ) -> impl Future<Output = io::Result<()>> + Send;
+
+    fn write_error(&mut self, error: ExtensionError)
+    -> impl Future<Output = io::Result<()>> + Send;
+
+    fn adopt(
+        &mut self,
+        external_id: String,
+        name: String,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    fn ping(&mut self) -> impl Future<Output = io::Result<()>> + Send;
 }
 
 impl WriteIglooToExtension for IWriter {
@@ -238,20 +511,23 @@ impl WriteIglooToExtension for IWriter {
 
     async fn write_component(
         &mut self,
+        id: CommandID,
         device: u64,
         entity: usize,
         comp: Component,
     ) -> io::Result<()> {
-        self.write_components(device, entity, vec![comp]).await
+        self.write_components(id, device, entity, vec![comp]).await
     }
 
     async fn write_components(
         &mut self,
+        id: CommandID,
         device: u64,
         entity: usize,
         comps: Vec<Component>,
     ) -> io::Result<()> {
         self.feed(IglooToExtension::WriteComponents {
+            id,
             device,
             entity,
             comps,
@@ -259,7 +535,26 @@ impl WriteIglooToExtension for IWriter {
         .await
     }
 
-    async fn write_custom(&mut self, name: String, payload: serde_json::Value) -> io::Result<()> {
-        self.feed(IglooToExtension::Custom { name, payload }).await
+    async fn write_custom(
+        &mut self,
+        id: CommandID,
+        name: String,
+        payload: serde_json::Value,
+    ) -> io::Result<()> {
+        self.feed(IglooToExtension::Custom { id, name, payload })
+            .await
+    }
+
+    async fn write_error(&mut self, error: ExtensionError) -> io::Result<()> {
+        self.feed(IglooToExtension::Error(error)).await
+    }
+
+    async fn adopt(&mut self, external_id: String, name: String) -> io::Result<()> {
+        self.feed(IglooToExtension::Adopt { external_id, name })
+            .await
+    }
+
+    async fn ping(&mut self) -> io::Result<()> {
+        self.feed(IglooToExtension::Ping).await
     }
 }
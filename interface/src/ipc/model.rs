@@ -1,8 +1,11 @@
-use crate::Component;
+use crate::{Component, ComponentType, query::DeviceInfo};
 use serde::{Deserialize, Serialize};
 
 pub const DATA_PATH_ENV_VAR: &str = "DATA_PATH";
 
+/// Correlates a command sent to an Extension with its [ExtensionToIgloo::Ack]
+pub type CommandID = u64;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ExtensionToIgloo {
     /// Initiates communication
@@ -12,6 +15,21 @@ pub enum ExtensionToIgloo {
     // UpgradeTo { version: u16 }
     CreateDevice {
         name: String,
+        /// Set when adopting a device, see [IglooToExtension::Adopt]
+        #[serde(default)]
+        external_id: Option<String>,
+        /// Saves a [ExtensionToIgloo::SetDeviceInfo] right after
+        #[serde(default)]
+        info: Option<DeviceInfo>,
+    },
+
+    /// Found a device which hasn't been adopted yet
+    /// Users can accept it ([IglooToExtension::Adopt]) or ignore it
+    Discovered {
+        /// Stable identifier for the device (ex. MAC address)
+        external_id: String,
+        name: String,
+        model: Option<String>,
     },
 
     RegisterEntity {
@@ -25,6 +43,46 @@ pub enum ExtensionToIgloo {
         entity: usize,
         comps: Vec<Component>,
     },
+
+    /// The entity no longer has these components
+    /// (ex. a light switching from RGB to white-only mode)
+    RemoveComponents {
+        device: u64,
+        entity: usize,
+        comps: Vec<ComponentType>,
+    },
+
+    /// Response to [IglooToExtension::WriteComponents]
+    /// `Err` contains a human readable reason (ex. "device offline")
+    Ack {
+        id: CommandID,
+        result: Result<(), String>,
+    },
+
+    /// Response to [IglooToExtension::Ping]
+    Pong,
+
+    /// Replaces the device's info (ex. after a firmware update)
+    /// The first can also be given with [ExtensionToIgloo::CreateDevice]
+    SetDeviceInfo { device: u64, info: DeviceInfo },
+
+    /// Whether the physical device can currently be reached (ex. it's
+    /// out of range or unplugged). Devices are reachable until told otherwise.
+    SetReachable { device: u64, reachable: bool },
+
+    /// Anything that doesn't fit the component model (ex. "pairing started")
+    /// Forwarded to every client
+    Custom {
+        name: String,
+        payload: serde_json::Value,
+    },
+
+    /// Response to [IglooToExtension::Custom]
+    /// `Err` contains a human readable reason
+    CustomReply {
+        id: CommandID,
+        result: Result<serde_json::Value, String>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -34,14 +92,67 @@ pub enum IglooToExtension {
         id: u64,
     },
 
+    /// Extension should respond with [ExtensionToIgloo::Ack]
     WriteComponents {
+        id: CommandID,
         device: u64,
         entity: usize,
         comps: Vec<Component>,
     },
 
+    /// User replaced device `id` with device `by` (ex. swapping a dead plug)
+    /// `by` now goes by `id`, so it keeps its groups and metadata.
+    /// The old hardware behind `id` and the ID `by` are both gone.
+    /// Sent to the owners of both devices
+    DeviceReplaced {
+        id: u64,
+        by: u64,
+    },
+
+    /// User accepted a [ExtensionToIgloo::Discovered] device
+    /// Extension should respond with [ExtensionToIgloo::CreateDevice]
+    /// using this name and external ID
+    Adopt {
+        external_id: String,
+        name: String,
+    },
+
+    /// Call from a client (ex. "start_pairing", "get_network_map")
+    /// Extension should respond with [ExtensionToIgloo::CustomReply]
     Custom {
+        id: CommandID,
         name: String,
         payload: serde_json::Value,
     },
+
+    /// Sent periodically, Extension must respond with [ExtensionToIgloo::Pong]
+    /// or it will be detached
+    Ping,
+
+    /// Igloo rejected a message from the Extension
+    /// Repeated errors will cause the Extension to be detached
+    Error(ExtensionError),
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ExtensionError {
+    #[error("Device {0} does not exist")]
+    DeviceNotFound(u64),
+    #[error("Device {0} is not owned by this Extension")]
+    NotOwner(u64),
+    #[error("Entity {entity} does not exist on Device {device}")]
+    EntityNotFound { device: u64, entity: usize },
+    #[error(
+        "Bad entity registration on Device {device} for Entity \"{entity_id}\". Expected index={expected}, but is index={actual}."
+    )]
+    BadEntityRegistration {
+        device: u64,
+        entity_id: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Entity ID is too long. Can only be {0} at most.")]
+    EntityIDTooLong(usize),
+    #[error("Unexpected message. {0}")]
+    Unexpected(String),
 }
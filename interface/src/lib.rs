@@ -7,5 +7,8 @@ pub mod types;
 #[cfg(feature = "ipc")]
 pub mod ipc;
 
+#[cfg(feature = "sdk")]
+pub mod sdk;
+
 #[cfg(feature = "penguin")]
 pub mod penguin;
@@ -37,6 +37,18 @@ pub enum QueryError {
 
     #[error("Limit cannot be placed on an Watcher-type query.")]
     LimitOnWatcher,
+
+    #[error("History bucket size must be greater than zero.")]
+    EmptyBucket,
+
+    #[error("Statistics are only kept for Integer and Real components, not '{0:?}'.")]
+    StatisticsNotNumeric(CT),
+
+    #[error("Invalid pattern '{0}': {1}")]
+    InvalidPattern(String, String),
+
+    #[error("Query ID {0} is still waiting for acknowledgements.")]
+    DuplicateQueryID(usize),
 }
 
 use QueryError as ERR;
@@ -47,6 +59,7 @@ impl WatchQuery {
     pub fn check(&self) -> Result<WatchUpdateType, ERR> {
         match self {
             WatchQuery::Metadata => return Ok(WatchUpdateType::Metadata),
+            WatchQuery::Journal(_) => Ok(WatchUpdateType::Journal),
             WatchQuery::Component(q) => {
                 let it = q
                     .component
@@ -91,6 +104,28 @@ impl OneShotQuery {
                 E::Snapshot => R::EntitySnapshot,
                 E::Count => R::Count,
             },
+            OneShotQuery::Journal(_) => R::Journal,
+            OneShotQuery::History(q) => {
+                let it = q
+                    .component
+                    .igloo_type()
+                    .ok_or(ERR::ComponentNoValue(q.component))?;
+
+                if let Some(bucket) = q.bucket {
+                    if bucket.size_ms == 0 {
+                        return Err(ERR::EmptyBucket);
+                    }
+                    if !bucket.op.can_apply(&q.component) {
+                        return Err(ERR::InvalidAggregation(q.component, bucket.op));
+                    }
+                }
+
+                R::History(it)
+            }
+            OneShotQuery::Statistics(q) => match q.component.igloo_type() {
+                Some(IglooType::Integer | IglooType::Real) => R::Statistics,
+                _ => return Err(ERR::StatisticsNotNumeric(q.component)),
+            },
             OneShotQuery::Component(q) => {
                 match &q.action {
                     C::Count => return Ok(R::Count),
@@ -126,14 +161,20 @@ impl OneShotQuery {
                             return Err(ERR::ValueTypeMismatch(it_2, it));
                         }
 
-                        R::Count
+                        match q.await_ack {
+                            true => R::Acks,
+                            false => R::Count,
+                        }
                     }
                     C::Apply(op) => {
                         if !op.can_eval(&it) {
                             return Err(ERR::OperationNotApplicable(it));
                         }
 
-                        R::Count
+                        match q.await_ack {
+                            true => R::Acks,
+                            false => R::Count,
+                        }
                     }
                     C::Count => unreachable!(),
                 }
@@ -0,0 +1,113 @@
+use crate::{
+    Component, ComponentType,
+    id::{DeviceID, EntityID, ExtensionID, GroupID},
+};
+use serde::{Deserialize, Serialize};
+
+/// A recorded change to the device tree, see [crate::query::OneShotQuery::Journal]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Milliseconds since the Unix epoch
+    pub at: u64,
+    pub source: JournalSource,
+    pub event: JournalEvent,
+}
+
+/// Who caused a [JournalEntry]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JournalSource {
+    /// Client ID, only unique while the client is registered
+    Client(usize),
+    Extension(ExtensionID),
+    /// Igloo itself (ex. an Extension stopped responding)
+    Igloo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum JournalKind {
+    Device,
+    Entity,
+    Group,
+    Extension,
+    Discovery,
+    /// Component writes sent to Extensions
+    Set,
+    /// Component values reported by Extensions
+    Reported,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum JournalEvent {
+    DeviceCreated(DeviceID, String),
+    DeviceDeleted(DeviceID),
+    DeviceRenamed(DeviceID, String),
+    /// `by`'s hardware now lives under `device`
+    DeviceReplaced {
+        device: DeviceID,
+        by: DeviceID,
+    },
+    DeviceReachable(DeviceID, bool),
+    DeviceInfoChanged(DeviceID),
+    DeviceIconChanged(DeviceID, Option<String>),
+    DeviceNotesChanged(DeviceID),
+    DeviceLabelsChanged(DeviceID, Vec<String>),
+
+    EntityRegistered(DeviceID, EntityID),
+    EntityLabelsChanged(DeviceID, EntityID, Vec<String>),
+    ComponentsRemoved(DeviceID, EntityID, Vec<ComponentType>),
+
+    GroupCreated(GroupID, String),
+    GroupDeleted(GroupID),
+    GroupRenamed(GroupID, String),
+    GroupParentChanged(GroupID, Option<GroupID>),
+    GroupDeviceAdded(GroupID, DeviceID),
+    GroupDeviceRemoved(GroupID, DeviceID),
+
+    ExtensionAttached(ExtensionID),
+    /// With the reason (ex. "unresponsive")
+    ExtensionDetached(ExtensionID, String),
+
+    DiscoveredAccepted(ExtensionID, String),
+    DiscoveredIgnored(ExtensionID, String),
+    DiscoveredUnignored(ExtensionID, String),
+    /// Accepted, but the Extension didn't create it in time, so it's back in the inbox
+    DiscoveredAdoptTimedOut(ExtensionID, String),
+
+    /// Write sent to the owning Extension
+    Set(DeviceID, EntityID, Component),
+    /// Value reported by the owning Extension
+    Reported(DeviceID, EntityID, Component),
+}
+
+impl JournalEvent {
+    pub fn kind(&self) -> JournalKind {
+        use JournalEvent::*;
+        match self {
+            DeviceCreated(..)
+            | DeviceDeleted(_)
+            | DeviceRenamed(..)
+            | DeviceReplaced { .. }
+            | DeviceReachable(..)
+            | DeviceInfoChanged(_)
+            | DeviceIconChanged(..)
+            | DeviceNotesChanged(_)
+            | DeviceLabelsChanged(..) => JournalKind::Device,
+            EntityRegistered(..) | EntityLabelsChanged(..) | ComponentsRemoved(..) => {
+                JournalKind::Entity
+            }
+            GroupCreated(..)
+            | GroupDeleted(_)
+            | GroupRenamed(..)
+            | GroupParentChanged(..)
+            | GroupDeviceAdded(..)
+            | GroupDeviceRemoved(..) => JournalKind::Group,
+            ExtensionAttached(_) | ExtensionDetached(..) => JournalKind::Extension,
+            DiscoveredAccepted(..)
+            | DiscoveredIgnored(..)
+            | DiscoveredUnignored(..)
+            | DiscoveredAdoptTimedOut(..) => JournalKind::Discovery,
+            Set(..) => JournalKind::Set,
+            Reported(..) => JournalKind::Reported,
+        }
+    }
+}
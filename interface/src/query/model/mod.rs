@@ -3,3 +3,6 @@ pub use oneshot::*;
 
 pub mod watch;
 pub use watch::*;
+
+pub mod journal;
+pub use journal::*;
@@ -1,7 +1,9 @@
 use crate::{
     Component, ComponentType, IglooType, IglooValue,
     id::{DeviceID, EntityID, ExtensionID, GroupID},
-    query::{DeviceSnapshot, EntitySnapshot, ExtensionSnapshot, GroupSnapshot},
+    query::{
+        DeviceSnapshot, EntitySnapshot, ExtensionSnapshot, GroupSnapshot, JournalEntry, JournalKind,
+    },
     types::{agg::AggregationOp, compare::ComparisonOp, math::MathOp},
 };
 use serde::{Deserialize, Serialize};
@@ -12,12 +14,115 @@ use serde::{Deserialize, Serialize};
 //  - temporal component value queries (requires changes to device tree first)
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum OneShotQuery {
     Extension(ExtensionQuery),
     Group(GroupQuery),
     Device(DeviceQuery),
     Entity(EntityQuery),
     Component(ComponentQuery),
+    Journal(JournalQuery),
+    History(HistoryQuery),
+    Statistics(StatisticsQuery),
+}
+
+/// Recorded changes to the device tree, oldest first
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JournalQuery {
+    /// Milliseconds since the Unix epoch, inclusive
+    pub since: Option<u64>,
+    /// Milliseconds since the Unix epoch, exclusive
+    pub until: Option<u64>,
+    /// Empty matches every kind
+    pub kinds: Vec<JournalKind>,
+    /// Keeps the newest entries
+    pub limit: Option<usize>,
+}
+
+/// Recorded values of a component, see `history.toml`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    pub device_filter: DeviceFilter,
+    #[serde(default)]
+    pub entity_filter: EntityFilter,
+    pub component: ComponentType,
+    /// Milliseconds since the Unix epoch, inclusive
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// Milliseconds since the Unix epoch, exclusive
+    #[serde(default)]
+    pub until: Option<u64>,
+    /// Downsamples each series, `None` returns every recorded value
+    #[serde(default)]
+    pub bucket: Option<HistoryBucket>,
+    /// Combines every matching entity into one series
+    #[serde(default)]
+    pub merge: bool,
+}
+
+/// Buckets start at `since` (or the Unix epoch) and empty ones are skipped
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HistoryBucket {
+    pub size_ms: u64,
+    pub op: AggregationOp,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistorySeries {
+    /// `None` for a merged series
+    pub source: Option<(DeviceID, EntityID)>,
+    /// (milliseconds since the Unix epoch, value), oldest first
+    /// Bucketed points are at the start of their bucket
+    pub points: Vec<(u64, IglooValue)>,
+}
+
+/// Hourly or daily statistics of an Integer or Real component
+/// Kept for every recorded instance, even after its history is pruned
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatisticsQuery {
+    #[serde(default)]
+    pub device_filter: DeviceFilter,
+    #[serde(default)]
+    pub entity_filter: EntityFilter,
+    pub component: ComponentType,
+    pub period: StatisticsPeriod,
+    /// Milliseconds since the Unix epoch, periods starting at or after it
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// Milliseconds since the Unix epoch, periods starting before it
+    #[serde(default)]
+    pub until: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StatisticsPeriod {
+    Hour,
+    /// UTC days
+    Day,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatisticsSeries {
+    pub source: (DeviceID, EntityID),
+    /// Oldest first, periods without values are skipped
+    pub rows: Vec<StatisticsRow>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatisticsRow {
+    /// Milliseconds since the Unix epoch
+    pub start: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub sum: f64,
+    pub count: u64,
+    /// How much it went up (or down) since the last value before this period
+    /// For `SensorStateClass::TotalIncreasing`, a drop is a counter reset and
+    /// counts the new value (ex. energy used in the hour)
+    pub change: f64,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -43,6 +148,8 @@ pub enum ExtensionAction {
 pub struct GroupQuery {
     #[serde(default)]
     pub id: IDFilter<GroupID>,
+    #[serde(default)]
+    pub name: Option<Box<NameFilter>>,
     pub action: GroupAction,
     #[serde(default)]
     pub limit: Option<usize>,
@@ -106,6 +213,10 @@ pub struct ComponentQuery {
     /// R::ComponentValueWithParents instead of R::ComponentValue
     #[serde(default)]
     pub include_parents: bool,
+    /// for Set, Put, Apply: waits for Extensions to acknowledge each write
+    /// R::Acks instead of R::Count
+    #[serde(default)]
+    pub await_ack: bool,
     #[serde(default)]
     pub limit: Option<usize>,
 }
@@ -135,11 +246,32 @@ pub struct DeviceFilter {
     pub id: IDFilter<DeviceID>,
     pub owner: IDFilter<ExtensionID>,
     pub group: DeviceGroupFilter,
+    pub name: Option<Box<NameFilter>>,
 
     pub entity_count: Option<(ComparisonOp, usize)>,
 
     /// seconds
     pub last_update: Option<(ComparisonOp, usize)>,
+
+    /// See [DeviceSnapshot::available]
+    pub available: Option<bool>,
+
+    pub info: Option<Box<DeviceInfoFilter>>,
+
+    pub label: Option<Box<LabelFilter>>,
+}
+
+/// Matches against [DeviceInfo](crate::query::DeviceInfo), the same way as [NameFilter]
+/// Devices missing a field never match a filter on it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceInfoFilter {
+    pub manufacturer: Option<NameFilter>,
+    pub model: Option<NameFilter>,
+    pub hw_version: Option<NameFilter>,
+    pub sw_version: Option<NameFilter>,
+    pub serial: Option<NameFilter>,
+    pub area: Option<NameFilter>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
@@ -149,6 +281,32 @@ pub enum DeviceGroupFilter {
     In(GroupID),
     InAny(Vec<GroupID>),
     InAll(Vec<GroupID>),
+    /// In the group or any of its subgroups
+    InTree(GroupID),
+    /// In any of the groups or their subgroups
+    InAnyTree(Vec<GroupID>),
+}
+
+/// Matches device or group names
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NameFilter {
+    Is(String),
+    /// case-insensitive [NameFilter::Is]
+    IsIgnoreCase(String),
+    /// glob pattern (ex. "Kitchen *")
+    Matches(String),
+    /// case-insensitive [NameFilter::Matches]
+    MatchesIgnoreCase(String),
+    /// regular expression, prefix with `(?i)` to ignore case
+    Regex(String),
+}
+
+/// Labels are free-form tags users put on devices and entities (ex. "outdoor")
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LabelFilter {
+    Has(String),
+    HasAny(Vec<String>),
+    HasAll(Vec<String>),
 }
 
 #[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
@@ -164,6 +322,8 @@ pub struct EntityFilter {
 
     /// seconds
     pub last_update: Option<(ComparisonOp, usize)>,
+
+    pub label: Option<Box<LabelFilter>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -217,9 +377,30 @@ pub enum QueryResult {
     ComponentValue(Vec<IglooValue>),
     ComponentValueWithParents(Vec<(DeviceID, EntityID, IglooValue)>),
 
+    /// Result of each write, see [ComponentQuery::await_ack]
+    Acks(Vec<(DeviceID, EntityID, Result<(), CommandError>)>),
+
+    Journal(Vec<JournalEntry>),
+    History(Vec<HistorySeries>),
+    Statistics(Vec<StatisticsSeries>),
+
     Count(usize),
 }
 
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CommandError {
+    #[error("Extension rejected the command: {0}")]
+    Rejected(String),
+    #[error("Extension did not acknowledge the command in time")]
+    TimedOut,
+    #[error("Extension detached before acknowledging the command")]
+    Detached,
+    #[error("Extension's queue was full")]
+    ChannelFull,
+    #[error("Replaced by a newer write before it was sent")]
+    Superseded,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum QueryResultType {
     Ok,
@@ -241,5 +422,11 @@ pub enum QueryResultType {
     ComponentValue(IglooType),
     ComponentValueWithParents(IglooType),
 
+    Acks,
+
+    Journal,
+    History(IglooType),
+    Statistics,
+
     Count,
 }
@@ -1,7 +1,10 @@
 use crate::{
     ComponentType, IglooType, IglooValue,
-    id::{DeviceID, EntityIndex, ExtensionID, ExtensionIndex, GroupID},
-    query::{DeviceGroupFilter, EntityIDFilter, IDFilter, TypeFilter},
+    id::{DeviceID, EntityID, EntityIndex, ExtensionID, ExtensionIndex, GroupID},
+    query::{
+        DeviceGroupFilter, DeviceInfo, DiscoveredDevice, EntityIDFilter, IDFilter, JournalEntry,
+        JournalKind, LabelFilter, NameFilter, TypeFilter,
+    },
     types::agg::AggregationOp,
 };
 use serde::{Deserialize, Serialize};
@@ -10,6 +13,15 @@ use serde::{Deserialize, Serialize};
 pub enum WatchQuery {
     Metadata,
     Component(WatchComponentQuery),
+    /// New journal entries, see [crate::query::OneShotQuery::Journal]
+    Journal(WatchJournalQuery),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchJournalQuery {
+    /// Empty matches every kind
+    pub kinds: Vec<JournalKind>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -29,6 +41,8 @@ pub struct WatchDeviceFilter {
     pub id: IDFilter<DeviceID>,
     pub owner: IDFilter<ExtensionID>,
     pub group: DeviceGroupFilter,
+    pub name: Option<Box<NameFilter>>,
+    pub label: Option<Box<LabelFilter>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
@@ -36,6 +50,7 @@ pub struct WatchDeviceFilter {
 pub struct WatchEntityFilter {
     pub id: EntityIDFilter,
     pub type_filter: Option<TypeFilter>,
+    pub label: Option<Box<LabelFilter>>,
 }
 
 // -- Responses
@@ -45,6 +60,7 @@ pub enum WatchUpdateType {
     Metadata,
     ComponentAggregate(IglooType),
     ComponentValue(IglooType),
+    Journal,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -52,6 +68,7 @@ pub enum WatchUpdate {
     Metadata(Vec<MetadataUpdate>),
     ComponentAggregate(IglooValue),
     ComponentValue(DeviceID, EntityIndex, IglooValue),
+    Journal(JournalEntry),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -59,6 +76,8 @@ pub enum MetadataUpdate {
     /// device added or changed
     Device(DeviceID, DeviceMetadata),
     DeviceRemoved(DeviceID),
+    /// device became available or unavailable
+    DeviceAvailability(DeviceID, bool),
 
     /// group added or changed
     Group(GroupID, GroupMetadata),
@@ -67,17 +86,30 @@ pub enum MetadataUpdate {
     /// extension added or changed
     Extension(ExtensionID, ExtensionMetadata),
     ExtensionRemoved(ExtensionID),
+
+    /// discovered device added or changed
+    Discovered(DiscoveredDevice),
+    /// accepted, ignored, or its Extension detached
+    DiscoveredRemoved(ExtensionID, String),
+
+    /// entity's labels changed, sent for any entity ID even if it isn't registered
+    EntityLabels(DeviceID, EntityID, Vec<String>),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DeviceMetadata {
     pub name: String,
+    pub info: DeviceInfo,
+    pub icon: Option<String>,
+    pub notes: Option<String>,
+    pub labels: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GroupMetadata {
     pub name: String,
     pub devices: Vec<DeviceID>,
+    pub parent: Option<GroupID>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -17,6 +17,14 @@ impl OneShotQuery {
 
                 q.entity_filter.optimize();
             }
+            OneShotQuery::History(q) => {
+                TypeFilter::add_with(&mut q.entity_filter.type_filter, q.component);
+                q.entity_filter.optimize();
+            }
+            OneShotQuery::Statistics(q) => {
+                TypeFilter::add_with(&mut q.entity_filter.type_filter, q.component);
+                q.entity_filter.optimize();
+            }
             _ => {}
         }
     }
@@ -25,7 +33,7 @@ impl OneShotQuery {
 impl WatchQuery {
     pub fn optimize(&mut self) {
         match self {
-            WatchQuery::Metadata => {}
+            WatchQuery::Metadata | WatchQuery::Journal(_) => {}
             WatchQuery::Component(q) => {
                 TypeFilter::add_with(&mut q.entity_filter.type_filter, q.component);
 
@@ -560,6 +568,7 @@ mod tests {
             type_filter: None,
             value_filter: None,
             last_update: None,
+            label: None,
         };
         TypeFilter::add_with(&mut filter.type_filter, ComponentType::Light);
         assert_eq!(
@@ -572,6 +581,7 @@ mod tests {
             type_filter: Some(TypeFilter::With(ComponentType::Light)),
             value_filter: None,
             last_update: None,
+            label: None,
         };
         TypeFilter::add_with(&mut filter.type_filter, ComponentType::Dimmer);
         match filter.type_filter {
@@ -586,6 +596,7 @@ mod tests {
             )])),
             value_filter: None,
             last_update: None,
+            label: None,
         };
         TypeFilter::add_with(&mut filter.type_filter, ComponentType::Light);
         assert_eq!(
@@ -601,6 +612,7 @@ mod tests {
             type_filter: None,
             value_filter: Some(ValueFilter::If(ComparisonOp::Eq, Component::Dimmer(0.1))),
             last_update: None,
+            label: None,
         };
         filter.optimize();
         assert_eq!(
@@ -613,6 +625,7 @@ mod tests {
             type_filter: Some(TypeFilter::With(ComponentType::Light)),
             value_filter: Some(ValueFilter::If(ComparisonOp::Eq, Component::Dimmer(0.1))),
             last_update: None,
+            label: None,
         };
         filter.optimize();
         match filter.type_filter {
@@ -628,6 +641,7 @@ mod tests {
             type_filter: Some(TypeFilter::And(vec![])),
             value_filter: None,
             last_update: None,
+            label: None,
         };
         filter.optimize();
         assert_eq!(filter.type_filter, None);
@@ -642,11 +656,13 @@ mod tests {
                 type_filter: None,
                 value_filter: Some(ValueFilter::If(ComparisonOp::Eq, Component::Switch(false))),
                 last_update: None,
+                label: None,
             },
             action: ComponentAction::GetValue,
             component: ComponentType::Light,
             post_op: None,
             include_parents: false,
+            await_ack: false,
             limit: None,
         });
 
@@ -677,6 +693,7 @@ mod tests {
                 ])),
                 value_filter: None,
                 last_update: None,
+                label: None,
             },
             action: EntityAction::Snapshot,
             limit: None,
@@ -12,6 +12,18 @@ pub struct GroupSnapshot {
     pub id: GroupID,
     pub name: String,
     pub devices: FxHashSet<DeviceID>,
+    #[serde(default)]
+    pub parent: Option<GroupID>,
+}
+
+/// Reported by an Extension, waiting to be accepted or ignored
+#[derive(Debug, Clone, PartialEq, Display, Default, Serialize, Deserialize)]
+#[display("{ext}/{external_id}{{name={name},model={model:?}}}")]
+pub struct DiscoveredDevice {
+    pub ext: ExtensionID,
+    pub external_id: String,
+    pub name: String,
+    pub model: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Display, Default, Serialize, Deserialize)]
@@ -23,7 +35,9 @@ pub struct ExtensionSnapshot {
 }
 
 #[derive(Debug, Clone, PartialEq, Display, Default, Serialize, Deserialize)]
-#[display("{id}{{name={name},owner={owner},owner_ref={owner_ref:?},entities=[..],groups=[..]}}")]
+#[display(
+    "{id}{{name={name},owner={owner},owner_ref={owner_ref:?},available={available},entities=[..],groups=[..]}}"
+)]
 pub struct DeviceSnapshot {
     pub id: DeviceID,
     pub name: String,
@@ -31,13 +45,41 @@ pub struct DeviceSnapshot {
     pub owner: ExtensionID,
     pub owner_ref: Option<ExtensionIndex>,
     pub groups: FxHashSet<GroupID>,
+    /// Owner is attached and reports the device as reachable
+    pub available: bool,
+    /// Boxed to keep [IglooValue](crate::types::IglooValue) small
+    pub info: Box<DeviceInfo>,
+    pub icon: Option<String>,
+    pub notes: Option<String>,
+    pub labels: Vec<String>,
+}
+
+/// Reported by the owning Extension, see
+/// [ExtensionToIgloo::SetDeviceInfo](crate::ipc::ExtensionToIgloo::SetDeviceInfo)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceInfo {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub hw_version: Option<String>,
+    /// Firmware version
+    pub sw_version: Option<String>,
+    pub serial: Option<String>,
+    /// Where the Extension says it is (ex. the room set in the vendor's app)
+    /// Separate from the groups it's in, which are managed in Igloo
+    pub area: Option<String>,
+    /// Where users can manage the device (ex. its web UI)
+    pub configuration_url: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Display, Default, Serialize, Deserialize)]
-#[display("Entity{{id={id},index={index},components=[..]}}")]
+#[display("Entity{{id={id},index={index},stale={stale},components=[..]}}")]
 pub struct EntitySnapshot {
     pub id: EntityID,
     pub index: EntityIndex,
     pub components: Vec<Component>,
     pub parent: DeviceID,
+    /// Restored from before Igloo restarted and not yet confirmed by its owner
+    pub stale: bool,
+    pub labels: Vec<String>,
 }
@@ -0,0 +1,140 @@
+use super::Entity;
+use crate::{Component, ComponentType};
+use indexmap::IndexMap;
+use rustc_hash::FxBuildHasher;
+use std::mem;
+
+/// Pending writes, merged per entity
+#[derive(Debug, Default)]
+pub(super) struct Batch {
+    writes: IndexMap<Entity, Vec<Component>, FxBuildHasher>,
+}
+
+impl Batch {
+    pub fn push(&mut self, entity: Entity, comp: Component) {
+        merge(self.writes.entry(entity).or_default(), comp);
+    }
+
+    /// Drops a pending write of this type
+    pub fn remove(&mut self, entity: Entity, typ: ComponentType) {
+        if let Some(comps) = self.writes.get_mut(&entity) {
+            comps.retain(|c| c.get_type() != typ);
+            if comps.is_empty() {
+                self.writes.shift_remove(&entity);
+            }
+        }
+    }
+
+    /// Number of entities with pending writes
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn take(&mut self) -> IndexMap<Entity, Vec<Component>, FxBuildHasher> {
+        mem::take(&mut self.writes)
+    }
+
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+
+    /// Drops writes to device `id`
+    pub fn remove_device(&mut self, id: u64) {
+        self.writes.retain(|entity, _| entity.device != id);
+    }
+
+    /// Drops writes to device `id` and moves writes to device `by` onto it
+    pub fn replace_device(&mut self, id: u64, by: u64) {
+        self.writes = mem::take(&mut self.writes)
+            .into_iter()
+            .filter(|(entity, _)| entity.device != id)
+            .map(|(mut entity, comps)| {
+                if entity.device == by {
+                    entity.device = id;
+                }
+                (entity, comps)
+            })
+            .collect();
+    }
+}
+
+/// Replaces the component of the same type, or adds it
+pub(super) fn merge(comps: &mut Vec<Component>, comp: Component) {
+    match comps.iter_mut().find(|c| c.get_type() == comp.get_type()) {
+        Some(existing) => *existing = comp,
+        None => comps.push(comp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Entity = Entity {
+        device: 1,
+        index: 0,
+    };
+    const B: Entity = Entity {
+        device: 1,
+        index: 1,
+    };
+
+    #[test]
+    fn test_batch_keeps_newest() {
+        let mut batch = Batch::default();
+        batch.push(A, Component::Dimmer(0.1));
+        batch.push(A, Component::Switch(true));
+        batch.push(A, Component::Dimmer(0.7));
+
+        let writes = batch.take();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(
+            writes[&A],
+            vec![Component::Dimmer(0.7), Component::Switch(true)]
+        );
+        assert_eq!(batch.len(), 0);
+    }
+
+    #[test]
+    fn test_batch_separates_entities() {
+        let mut batch = Batch::default();
+        batch.push(A, Component::Switch(true));
+        batch.push(B, Component::Switch(false));
+        batch.push(A, Component::Switch(false));
+
+        let writes = batch.take();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[&A], vec![Component::Switch(false)]);
+        assert_eq!(writes[&B], vec![Component::Switch(false)]);
+    }
+
+    #[test]
+    fn test_batch_remove() {
+        let mut batch = Batch::default();
+        batch.push(A, Component::Dimmer(0.1));
+        batch.push(A, Component::Switch(true));
+        batch.push(B, Component::Switch(true));
+        batch.remove(A, ComponentType::Dimmer);
+        batch.remove(B, ComponentType::Switch);
+
+        let writes = batch.take();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[&A], vec![Component::Switch(true)]);
+    }
+
+    #[test]
+    fn test_batch_replace_device() {
+        let replacement = Entity {
+            device: 2,
+            index: 0,
+        };
+        let mut batch = Batch::default();
+        batch.push(A, Component::Switch(true));
+        batch.push(replacement, Component::Switch(false));
+        batch.replace_device(1, 2);
+
+        let writes = batch.take();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[&A], vec![Component::Switch(false)]);
+    }
+}
@@ -0,0 +1,96 @@
+use super::{ExtensionClient, SdkError};
+use crate::{id::MAX_ENTITY_ID_LENGTH, query::DeviceInfo};
+
+/// An Entity registered by this Extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Entity {
+    pub device: u64,
+    pub index: usize,
+}
+
+/// A Device created by this Extension
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub(super) id: u64,
+    pub(super) name: String,
+    /// Entity IDs, position is the index
+    pub(super) entities: Vec<String>,
+    /// Created through [super::Handler::on_adopt]
+    pub(super) adopted: bool,
+}
+
+impl Device {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn entity(&self, entity_id: &str) -> Option<Entity> {
+        let index = self.entities.iter().position(|e| e == entity_id)?;
+        Some(Entity {
+            device: self.id,
+            index,
+        })
+    }
+
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        (0..self.entities.len()).map(|index| Entity {
+            device: self.id,
+            index,
+        })
+    }
+}
+
+/// Created from [ExtensionClient::device]
+pub struct DeviceBuilder<'a> {
+    pub(super) client: &'a mut ExtensionClient,
+    pub(super) name: String,
+    pub(super) key: Option<String>,
+    pub(super) entities: Vec<String>,
+    pub(super) info: Option<DeviceInfo>,
+}
+
+impl DeviceBuilder<'_> {
+    /// Stable identifier used to find this Device again after restarting
+    /// (ex. MAC address). Defaults to the name.
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// Manufacturer, model, firmware version, etc.
+    /// Can be updated later with [ExtensionClient::set_device_info]
+    pub fn info(mut self, info: DeviceInfo) -> Self {
+        self.info = Some(info);
+        self
+    }
+
+    /// Adds an Entity. Indices are assigned in the order they are added.
+    pub fn entity(mut self, entity_id: impl Into<String>) -> Self {
+        self.entities.push(entity_id.into());
+        self
+    }
+
+    /// Creates the Device (or reuses the one from a previous run)
+    /// and registers all of its Entities
+    pub async fn build(self) -> Result<Device, SdkError> {
+        if let Some(eid) = self
+            .entities
+            .iter()
+            .find(|e| e.len() > MAX_ENTITY_ID_LENGTH)
+        {
+            return Err(SdkError::EntityIDTooLong(eid.clone()));
+        }
+
+        let key = self.key.unwrap_or_else(|| self.name.clone());
+        let device = self
+            .client
+            .create_device(key, self.name, self.entities, false)
+            .await?;
+
+        if let Some(info) = self.info {
+            self.client.set_device_info(&device, info).await?;
+        }
+
+        Ok(device)
+    }
+}
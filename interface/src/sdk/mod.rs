@@ -0,0 +1,928 @@
+//! High level client for writing Extensions
+//!
+//! ```ignore
+//! let mut client = ExtensionClient::connect().await?;
+//!
+//! let lamp = client
+//!     .device("Desk Lamp")
+//!     .key("a4:c1:38:0f:22:91")
+//!     .entity("light")
+//!     .build()
+//!     .await?;
+//!
+//! let light = lamp.entity("light").unwrap();
+//! client.set(light, Component::Switch(true));
+//! client.set(light, Component::Dimmer(0.5));
+//!
+//! client.run(MyHandler).await?;
+//! ```
+//!
+//! Devices found through discovery (ex. a Bluetooth scan) should be reported with
+//! [ExtensionClient::discovered] instead of being created right away. Once a user
+//! accepts one, [Handler::on_adopt] decides its Entities and the device is created.
+//!
+//! Writes are batched per entity and flushed every [FLUSH_INTERVAL].
+//! If the connection drops, the client reconnects and resends the last written
+//! values and reachability. Igloo keeps the Extension attached meanwhile, so its
+//! devices and Entities stay registered.
+//! Igloo's heartbeat pings are answered automatically while running.
+//!
+//! Device IDs are kept in [DEVICES_FILE] to find the same devices after restarting.
+//! If Igloo rejects one (ex. a user deleted the device), it is created again under
+//! a new ID and [Handler::on_replaced] is called. Writes to the old ID are dropped.
+
+use crate::{
+    Component, ComponentType,
+    id::MAX_ENTITY_ID_LENGTH,
+    ipc::{
+        self, AsyncWriteExtensionToIglooMut, DATA_PATH_ENV_VAR, EReader, EWriter, ExtensionError,
+        IglooToExtension,
+    },
+    query::DeviceInfo,
+};
+use batch::{Batch, merge};
+use futures_util::{SinkExt, StreamExt};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{collections::VecDeque, env, io, mem, path::PathBuf, time::Duration};
+use tokio::{
+    fs,
+    sync::mpsc,
+    time::{self, MissedTickBehavior},
+};
+
+mod batch;
+mod device;
+
+pub use device::*;
+
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(20);
+/// Flush early once this many entities have pending writes
+pub const MAX_BATCH: usize = 100;
+/// Kept well under Igloo's heartbeat timeout, which stops Extensions gone for too long
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Stored in the Extension's data path. Maps device keys to IDs.
+pub const DEVICES_FILE: &str = "sdk_devices.json";
+
+#[derive(thiserror::Error, Debug)]
+pub enum SdkError {
+    #[error("IO error: {0}")]
+    IO(#[from] io::Error),
+    #[error("Igloo closed the connection")]
+    Closed,
+    #[error("Entity ID \"{0}\" is too long")]
+    EntityIDTooLong(String),
+    #[error("Failed to save devices: {0}")]
+    Persist(#[from] serde_json::Error),
+}
+
+/// Responds to requests from Igloo
+pub trait Handler: Send {
+    /// Igloo wants these components changed
+    /// Returning `Err` tells Igloo the write was rejected (ex. "device offline")
+    fn on_write(
+        &mut self,
+        client: &ClientHandle,
+        entity: Entity,
+        comps: Vec<Component>,
+    ) -> impl Future<Output = Result<(), String>> + Send;
+
+    /// A user accepted a discovered device
+    /// Return its Entity IDs to create it, or `None` to refuse
+    fn on_adopt(
+        &mut self,
+        _client: &ClientHandle,
+        external_id: String,
+        _name: String,
+    ) -> impl Future<Output = Option<Vec<String>>> + Send {
+        async move {
+            eprintln!("Unhandled adoption of \"{external_id}\"");
+            None
+        }
+    }
+
+    /// An adopted device was created. `external_id` is its key.
+    fn on_adopted(
+        &mut self,
+        _client: &ClientHandle,
+        _external_id: String,
+        _device: Device,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// A user replaced another device with this one, see [IglooToExtension::DeviceReplaced]
+    /// Also called when Igloo no longer knew the device and it was created again
+    /// `device` now has a different ID, so update any copies of it or its Entities
+    fn on_replaced(
+        &mut self,
+        _client: &ClientHandle,
+        _key: String,
+        _device: Device,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// A client called this Extension (ex. "start_pairing")
+    /// The result is sent back to the client
+    fn on_custom(
+        &mut self,
+        _client: &ClientHandle,
+        name: String,
+        _payload: serde_json::Value,
+    ) -> impl Future<Output = Result<serde_json::Value, String>> + Send {
+        async move { Err(format!("Unhandled custom message \"{name}\"")) }
+    }
+
+    /// Igloo rejected a message from this Extension
+    fn on_error(&mut self, error: ExtensionError) -> impl Future<Output = ()> + Send {
+        async move {
+            eprintln!("Igloo rejected a message: {error}");
+        }
+    }
+}
+
+/// Cloneable handle to write components from other tasks
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    tx: mpsc::UnboundedSender<Outgoing>,
+}
+
+#[derive(Debug)]
+enum Outgoing {
+    Set(Entity, Component),
+    Remove(Entity, ComponentType),
+    Reachable(u64, bool),
+    Info(u64, DeviceInfo),
+    Custom(String, serde_json::Value),
+    Discovered {
+        external_id: String,
+        name: String,
+        model: Option<String>,
+    },
+}
+
+impl ClientHandle {
+    pub fn set(&self, entity: Entity, comp: Component) -> Result<(), SdkError> {
+        self.tx
+            .send(Outgoing::Set(entity, comp))
+            .map_err(|_| SdkError::Closed)
+    }
+
+    /// See [ExtensionClient::remove]
+    pub fn remove(&self, entity: Entity, typ: ComponentType) -> Result<(), SdkError> {
+        self.tx
+            .send(Outgoing::Remove(entity, typ))
+            .map_err(|_| SdkError::Closed)
+    }
+
+    /// See [ExtensionClient::set_reachable]
+    pub fn set_reachable(&self, device: &Device, reachable: bool) -> Result<(), SdkError> {
+        self.tx
+            .send(Outgoing::Reachable(device.id, reachable))
+            .map_err(|_| SdkError::Closed)
+    }
+
+    /// See [ExtensionClient::set_device_info]
+    pub fn set_device_info(&self, device: &Device, info: DeviceInfo) -> Result<(), SdkError> {
+        self.tx
+            .send(Outgoing::Info(device.id, info))
+            .map_err(|_| SdkError::Closed)
+    }
+
+    /// See [ExtensionClient::discovered]
+    pub fn discovered(
+        &self,
+        external_id: impl Into<String>,
+        name: impl Into<String>,
+        model: Option<String>,
+    ) -> Result<(), SdkError> {
+        self.tx
+            .send(Outgoing::Discovered {
+                external_id: external_id.into(),
+                name: name.into(),
+                model,
+            })
+            .map_err(|_| SdkError::Closed)
+    }
+
+    /// See [ExtensionClient::send_custom]
+    pub fn send_custom(
+        &self,
+        name: impl Into<String>,
+        payload: serde_json::Value,
+    ) -> Result<(), SdkError> {
+        self.tx
+            .send(Outgoing::Custom(name.into(), payload))
+            .map_err(|_| SdkError::Closed)
+    }
+
+    pub fn set_many(
+        &self,
+        entity: Entity,
+        comps: impl IntoIterator<Item = Component>,
+    ) -> Result<(), SdkError> {
+        for comp in comps {
+            self.set(entity, comp)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct ExtensionClient {
+    writer: EWriter,
+    reader: EReader,
+    /// unflushed messages have been fed to `writer`
+    dirty: bool,
+    /// messages read while waiting for `DeviceCreated`
+    pending: VecDeque<IglooToExtension>,
+    batch: Batch,
+    /// last written values, resent after reconnecting
+    state: FxHashMap<Entity, Vec<Component>>,
+    /// device ID -> reachable, sent on the next flush
+    pending_reachable: FxHashMap<u64, bool>,
+    /// devices reported unreachable, resent after reconnecting
+    unreachable: FxHashSet<u64>,
+    /// device key -> Device, for creating it again if Igloo rejects its ID
+    devices: FxHashMap<String, Device>,
+    /// IDs that were replaced or created again under a new ID
+    /// Writes to them are dropped, since Igloo would reject them
+    retired: FxHashSet<u64>,
+    /// device key -> ID, from previous runs
+    known: FxHashMap<String, u64>,
+    data_path: Option<PathBuf>,
+    /// Igloo's socket, for reconnecting
+    socket: PathBuf,
+    tx: mpsc::UnboundedSender<Outgoing>,
+    rx: mpsc::UnboundedReceiver<Outgoing>,
+}
+
+enum Event {
+    Msg(IglooToExtension),
+    Out(Outgoing),
+    Flush,
+    Closed,
+    Skip,
+}
+
+impl ExtensionClient {
+    pub async fn connect() -> Result<Self, SdkError> {
+        let socket = PathBuf::from("igloo.sock");
+        let (writer, reader) = ipc::connect_to(&socket).await?;
+        let data_path = env::var(DATA_PATH_ENV_VAR).ok().map(PathBuf::from);
+        Self::new(writer, reader, data_path, socket).await
+    }
+
+    async fn new(
+        writer: EWriter,
+        reader: EReader,
+        data_path: Option<PathBuf>,
+        socket: PathBuf,
+    ) -> Result<Self, SdkError> {
+        let known = match &data_path {
+            Some(path) => match fs::read(path.join(DEVICES_FILE)).await {
+                Ok(bytes) => serde_json::from_slice(&bytes)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => FxHashMap::default(),
+                Err(e) => return Err(e.into()),
+            },
+            None => FxHashMap::default(),
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        Ok(Self {
+            writer,
+            reader,
+            dirty: false,
+            pending: VecDeque::new(),
+            batch: Batch::default(),
+            state: FxHashMap::default(),
+            pending_reachable: FxHashMap::default(),
+            unreachable: FxHashSet::default(),
+            devices: FxHashMap::default(),
+            retired: FxHashSet::default(),
+            known,
+            data_path,
+            socket,
+            tx,
+            rx,
+        })
+    }
+
+    pub fn handle(&self) -> ClientHandle {
+        ClientHandle {
+            tx: self.tx.clone(),
+        }
+    }
+
+    pub fn device(&mut self, name: impl Into<String>) -> DeviceBuilder<'_> {
+        DeviceBuilder {
+            client: self,
+            name: name.into(),
+            key: None,
+            entities: Vec::new(),
+            info: None,
+        }
+    }
+
+    /// Queues a write, sent on the next flush
+    pub fn set(&mut self, entity: Entity, comp: Component) {
+        if self.retired.contains(&entity.device) {
+            return;
+        }
+        merge(self.state.entry(entity).or_default(), comp.clone());
+        self.batch.push(entity, comp);
+    }
+
+    /// Removes a component from the entity, sent on the next flush
+    /// (ex. a light switching from RGB to white-only mode)
+    pub async fn remove(&mut self, entity: Entity, typ: ComponentType) -> Result<(), SdkError> {
+        if self.retired.contains(&entity.device) {
+            return Ok(());
+        }
+        if let Some(comps) = self.state.get_mut(&entity) {
+            comps.retain(|c| c.get_type() != typ);
+        }
+        self.batch.remove(entity, typ);
+        self.writer
+            .remove_components(entity.device, entity.index, vec![typ])
+            .await?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Marks a device as (un)reachable, sent on the next flush
+    /// Igloo treats devices as unavailable while unreachable
+    pub fn set_reachable(&mut self, device: &Device, reachable: bool) {
+        self.mark_reachable(device.id, reachable);
+    }
+
+    fn mark_reachable(&mut self, device: u64, reachable: bool) {
+        if self.retired.contains(&device) {
+            return;
+        }
+        if reachable {
+            self.unreachable.remove(&device);
+        } else {
+            self.unreachable.insert(device);
+        }
+        self.pending_reachable.insert(device, reachable);
+    }
+
+    /// Replaces the device's info in Igloo, sent on the next flush
+    /// Igloo keeps it across restarts, so only send it when it changes
+    pub async fn set_device_info(
+        &mut self,
+        device: &Device,
+        info: DeviceInfo,
+    ) -> Result<(), SdkError> {
+        self.send_device_info(device.id, info).await
+    }
+
+    async fn send_device_info(&mut self, device: u64, info: DeviceInfo) -> Result<(), SdkError> {
+        if self.retired.contains(&device) {
+            return Ok(());
+        }
+        self.writer.set_device_info(device, info).await?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Adds a device to Igloo's discovery inbox, sent on the next flush
+    /// Reporting the same `external_id` again updates it
+    pub async fn discovered(
+        &mut self,
+        external_id: impl Into<String>,
+        name: impl Into<String>,
+        model: Option<String>,
+    ) -> Result<(), SdkError> {
+        self.writer
+            .discovered(external_id.into(), name.into(), model)
+            .await?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Sends a message to every client, sent on the next flush
+    /// Not resent after reconnecting
+    pub async fn send_custom(
+        &mut self,
+        name: impl Into<String>,
+        payload: serde_json::Value,
+    ) -> Result<(), SdkError> {
+        self.writer.custom(name.into(), payload).await?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Sends all pending writes
+    pub async fn flush(&mut self) -> Result<(), SdkError> {
+        for (device, reachable) in self.pending_reachable.drain() {
+            self.writer.set_reachable(device, reachable).await?;
+            self.dirty = true;
+        }
+
+        for (entity, comps) in self.batch.take() {
+            self.writer
+                .write_components(entity.device, entity.index, comps)
+                .await?;
+            self.dirty = true;
+        }
+
+        if self.dirty {
+            self.writer.flush().await?;
+            self.dirty = false;
+        }
+
+        Ok(())
+    }
+
+    /// Handles requests from Igloo, reconnecting whenever the connection drops
+    /// Only returns on unrecoverable errors
+    pub async fn run<H: Handler>(mut self, mut handler: H) -> Result<(), SdkError> {
+        let handle = self.handle();
+        let mut flush = time::interval(FLUSH_INTERVAL);
+        flush.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            let res = match self.pending.pop_front() {
+                Some(msg) => self.dispatch(&mut handler, &handle, msg).await,
+                None => {
+                    let event = tokio::select! {
+                        msg = self.reader.next() => match msg {
+                            Some(Ok(msg)) => Event::Msg(msg),
+                            Some(Err(e)) => {
+                                eprintln!("Error reading message from Igloo: {e}. Skipping..");
+                                Event::Skip
+                            }
+                            None => Event::Closed,
+                        },
+                        Some(out) = self.rx.recv() => Event::Out(out),
+                        _ = flush.tick() => Event::Flush,
+                    };
+
+                    match event {
+                        Event::Msg(msg) => self.dispatch(&mut handler, &handle, msg).await,
+                        Event::Out(Outgoing::Set(entity, comp)) => {
+                            self.set(entity, comp);
+                            if self.batch.len() >= MAX_BATCH {
+                                self.flush().await
+                            } else {
+                                Ok(())
+                            }
+                        }
+                        Event::Out(Outgoing::Remove(entity, typ)) => self.remove(entity, typ).await,
+                        Event::Out(Outgoing::Reachable(device, reachable)) => {
+                            self.mark_reachable(device, reachable);
+                            Ok(())
+                        }
+                        Event::Out(Outgoing::Info(device, info)) => {
+                            self.send_device_info(device, info).await
+                        }
+                        Event::Out(Outgoing::Custom(name, payload)) => {
+                            self.send_custom(name, payload).await
+                        }
+                        Event::Out(Outgoing::Discovered {
+                            external_id,
+                            name,
+                            model,
+                        }) => self.discovered(external_id, name, model).await,
+                        Event::Flush => self.flush().await,
+                        Event::Closed => Err(SdkError::Closed),
+                        Event::Skip => Ok(()),
+                    }
+                }
+            };
+
+            match res {
+                Ok(()) => {}
+                Err(SdkError::IO(_) | SdkError::Closed) => self.reconnect().await,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn dispatch<H: Handler>(
+        &mut self,
+        handler: &mut H,
+        handle: &ClientHandle,
+        msg: IglooToExtension,
+    ) -> Result<(), SdkError> {
+        use IglooToExtension::*;
+        match msg {
+            WriteComponents {
+                id,
+                device,
+                entity,
+                comps,
+            } => {
+                let entity = Entity {
+                    device,
+                    index: entity,
+                };
+                let result = handler.on_write(handle, entity, comps).await;
+                // flushed with the next batch
+                self.writer.ack(id, result).await?;
+                self.dirty = true;
+            }
+            Adopt { external_id, name } => {
+                let entities = handler
+                    .on_adopt(handle, external_id.clone(), name.clone())
+                    .await;
+                let Some(entities) = entities else {
+                    return Ok(());
+                };
+                if let Some(eid) = entities.iter().find(|e| e.len() > MAX_ENTITY_ID_LENGTH) {
+                    return Err(SdkError::EntityIDTooLong(eid.clone()));
+                }
+                let device = self
+                    .create_device(external_id.clone(), name, entities, true)
+                    .await?;
+                handler.on_adopted(handle, external_id, device).await;
+            }
+            Ping => {
+                // flushed with the next batch
+                self.writer.pong().await?;
+                self.dirty = true;
+            }
+            Custom { id, name, payload } => {
+                let result = handler.on_custom(handle, name, payload).await;
+                // flushed with the next batch
+                self.writer.custom_reply(id, result).await?;
+                self.dirty = true;
+            }
+            DeviceReplaced { id, by } => self.replace_device(handler, handle, id, by).await?,
+            // rejected writes that were already in flight
+            Error(ExtensionError::DeviceNotFound(id) | ExtensionError::NotOwner(id))
+                if self.retired.contains(&id) => {}
+            Error(ExtensionError::DeviceNotFound(id) | ExtensionError::NotOwner(id))
+                if self.devices.values().any(|device| device.id == id) =>
+            {
+                self.recreate_device(handler, handle, id).await?
+            }
+            Error(error) => handler.on_error(error).await,
+            // only expected while building a device
+            DeviceCreated { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Forgets device `id` and moves device `by` onto its ID
+    async fn replace_device<H: Handler>(
+        &mut self,
+        handler: &mut H,
+        handle: &ClientHandle,
+        id: u64,
+        by: u64,
+    ) -> Result<(), SdkError> {
+        self.known.retain(|_, known| *known != id);
+        for known in self.known.values_mut() {
+            if *known == by {
+                *known = id;
+            }
+        }
+        self.save_known().await?;
+
+        self.devices.retain(|_, device| device.id != id);
+        let mut replaced = Vec::new();
+        for (key, device) in &mut self.devices {
+            if device.id == by {
+                device.id = id;
+                replaced.push((key.clone(), device.clone()));
+            }
+        }
+        self.retired.insert(by);
+
+        self.state = mem::take(&mut self.state)
+            .into_iter()
+            .filter(|(entity, _)| entity.device != id)
+            .map(|(mut entity, comps)| {
+                if entity.device == by {
+                    entity.device = id;
+                }
+                (entity, comps)
+            })
+            .collect();
+        self.batch.replace_device(id, by);
+
+        self.unreachable.remove(&id);
+        if self.unreachable.remove(&by) {
+            self.unreachable.insert(id);
+        }
+        self.pending_reachable.remove(&id);
+        if let Some(reachable) = self.pending_reachable.remove(&by) {
+            self.pending_reachable.insert(id, reachable);
+        }
+
+        for (key, device) in replaced {
+            handler.on_replaced(handle, key, device).await;
+        }
+        Ok(())
+    }
+
+    /// Igloo rejected device `id` (ex. a user deleted it), so the cached ID is stale
+    /// Creates the device again and drops everything pending for the old ID,
+    /// the handler resends what it needs from [Handler::on_replaced]
+    async fn recreate_device<H: Handler>(
+        &mut self,
+        handler: &mut H,
+        handle: &ClientHandle,
+        id: u64,
+    ) -> Result<(), SdkError> {
+        let Some((key, old)) = self.devices.iter().find(|(_, device)| device.id == id) else {
+            return Ok(());
+        };
+        let (key, old) = (key.clone(), old.clone());
+        eprintln!("Igloo doesn't know device \"{key}\" ({id}) anymore. Creating it again..");
+
+        // only once, other rejections of it may already be on the way
+        self.retired.insert(id);
+        self.batch.remove_device(id);
+        self.state.retain(|entity, _| entity.device != id);
+        self.pending_reachable.remove(&id);
+        self.unreachable.remove(&id);
+
+        self.known.remove(&key);
+        let device = self
+            .create_device(key.clone(), old.name, old.entities, old.adopted)
+            .await?;
+
+        handler.on_replaced(handle, key, device).await;
+        Ok(())
+    }
+
+    /// `adopted` sends `key` as the external ID, see [Handler::on_adopt]
+    async fn create_device(
+        &mut self,
+        key: String,
+        name: String,
+        entities: Vec<String>,
+        adopted: bool,
+    ) -> Result<Device, SdkError> {
+        let id = match self.known.get(&key) {
+            Some(id) => *id,
+            None => {
+                match adopted {
+                    true => {
+                        self.writer
+                            .create_adopted_device(name.clone(), key.clone())
+                            .await?
+                    }
+                    false => self.writer.create_device(name.clone()).await?,
+                }
+                self.writer.flush().await?;
+                let id = self.wait_device_created(&name).await?;
+                self.known.insert(key.clone(), id);
+                self.save_known().await?;
+                id
+            }
+        };
+
+        let device = Device {
+            id,
+            name,
+            entities,
+            adopted,
+        };
+        self.register_entities(&device).await?;
+        self.writer.flush().await?;
+
+        self.devices.insert(key, device.clone());
+        Ok(device)
+    }
+
+    async fn wait_device_created(&mut self, name: &str) -> Result<u64, SdkError> {
+        loop {
+            match self.reader.next().await {
+                Some(Ok(IglooToExtension::DeviceCreated { name: n, id })) if n == name => {
+                    return Ok(id);
+                }
+                Some(Ok(msg)) => self.pending.push_back(msg),
+                Some(Err(e)) => {
+                    eprintln!("Error reading message from Igloo: {e}. Skipping..");
+                }
+                None => return Err(SdkError::Closed),
+            }
+        }
+    }
+
+    async fn register_entities(&mut self, device: &Device) -> Result<(), SdkError> {
+        for (index, entity_id) in device.entities.iter().enumerate() {
+            self.writer
+                .register_entity(device.id, entity_id.clone(), index)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn save_known(&self) -> Result<(), SdkError> {
+        let Some(path) = &self.data_path else {
+            return Ok(());
+        };
+        let bytes = serde_json::to_vec_pretty(&self.known)?;
+        fs::write(path.join(DEVICES_FILE), bytes).await?;
+        Ok(())
+    }
+
+    /// Retries with backoff until connected and resynced
+    async fn reconnect(&mut self) {
+        let mut delay = Duration::from_millis(500);
+
+        loop {
+            eprintln!("Lost connection to Igloo. Reconnecting in {delay:?}..");
+            time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+
+            let (writer, reader) = match ipc::connect_to(&self.socket).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("Failed to reconnect to Igloo: {e}");
+                    continue;
+                }
+            };
+            self.writer = writer;
+            self.reader = reader;
+
+            match self.resync().await {
+                Ok(()) => return,
+                Err(e) => eprintln!("Failed to resync with Igloo: {e}"),
+            }
+        }
+    }
+
+    /// Igloo keeps everything it already got, but messages
+    /// sent around the time the connection dropped may be lost
+    async fn resync(&mut self) -> Result<(), SdkError> {
+        // pending writes are already in `state`
+        self.batch.clear();
+        for (entity, comps) in &self.state {
+            self.writer
+                .write_components(entity.device, entity.index, comps.clone())
+                .await?;
+        }
+
+        for device in &self.unreachable {
+            self.pending_reachable.entry(*device).or_insert(false);
+        }
+
+        self.dirty = true;
+        self.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::{ExtensionToIgloo, IReader, IWriter, codec::LengthDelimitedJSONCodec};
+    use std::path::Path;
+    use tokio::net::UnixListener;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    struct Recorder(mpsc::UnboundedSender<(String, u64)>);
+
+    impl Handler for Recorder {
+        async fn on_write(
+            &mut self,
+            _client: &ClientHandle,
+            _entity: Entity,
+            _comps: Vec<Component>,
+        ) -> Result<(), String> {
+            Ok(())
+        }
+
+        async fn on_replaced(&mut self, _client: &ClientHandle, key: String, device: Device) {
+            _ = self.0.send((key, device.id));
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("igloo-sdk-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Fake Igloo's side of the next connection
+    async fn accept(listener: &UnixListener) -> (IWriter, IReader) {
+        let (stream, _addr) = listener.accept().await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut reader = FramedRead::new(reader, LengthDelimitedJSONCodec::new());
+        let hello = reader.next().await.unwrap().unwrap();
+        assert_eq!(hello, ExtensionToIgloo::WhatsUpIgloo);
+        (
+            FramedWrite::new(writer, LengthDelimitedJSONCodec::new()),
+            reader,
+        )
+    }
+
+    /// Client connected to a fake Igloo listening in `dir`
+    async fn pair(dir: &Path) -> (ExtensionClient, UnixListener, IWriter, IReader) {
+        let socket = dir.join("igloo.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let (writer, reader) = ipc::connect_to(&socket).await.unwrap();
+        let client = ExtensionClient::new(writer, reader, Some(dir.to_path_buf()), socket)
+            .await
+            .unwrap();
+        let (writer, reader) = accept(&listener).await;
+        (client, listener, writer, reader)
+    }
+
+    #[tokio::test]
+    async fn test_run_reconnects_and_resends_state() {
+        let dir = temp_dir("reconnect");
+        let (mut client, listener, writer, mut reader) = pair(&dir).await;
+        let light = Entity {
+            device: 5,
+            index: 0,
+        };
+        client.set(light, Component::Switch(true));
+        client.set(light, Component::Dimmer(0.5));
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let run = tokio::spawn(client.run(Recorder(tx)));
+
+        let written = ExtensionToIgloo::WriteComponents {
+            device: 5,
+            entity: 0,
+            comps: vec![Component::Switch(true), Component::Dimmer(0.5)],
+        };
+        assert_eq!(reader.next().await.unwrap().unwrap(), written);
+
+        drop((writer, reader));
+        let (_writer, mut reader) = time::timeout(Duration::from_secs(5), accept(&listener))
+            .await
+            .unwrap();
+        assert_eq!(reader.next().await.unwrap().unwrap(), written);
+
+        run.abort();
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_stale_device_id_is_recreated_once() {
+        let dir = temp_dir("stale");
+        std::fs::write(dir.join(DEVICES_FILE), r#"{ "Lamp": 5 }"#).unwrap();
+
+        let (mut client, _listener, mut writer, mut reader) = pair(&dir).await;
+        let lamp = client.device("Lamp").entity("light").build().await.unwrap();
+        assert_eq!(lamp.id(), 5);
+        let registered = ExtensionToIgloo::RegisterEntity {
+            device: 5,
+            entity_id: "light".to_string(),
+            entity_index: 0,
+        };
+        assert_eq!(reader.next().await.unwrap().unwrap(), registered);
+
+        let handle = client.handle();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let run = tokio::spawn(client.run(Recorder(tx)));
+
+        // a user deleted the device while the Extension was stopped,
+        // and several writes to it were in flight
+        for _ in 0..2 {
+            let err = IglooToExtension::Error(ExtensionError::DeviceNotFound(5));
+            writer.send(err).await.unwrap();
+        }
+
+        let create = ExtensionToIgloo::CreateDevice {
+            name: "Lamp".to_string(),
+            external_id: None,
+            info: None,
+        };
+        assert_eq!(reader.next().await.unwrap().unwrap(), create);
+        let created = IglooToExtension::DeviceCreated {
+            name: "Lamp".to_string(),
+            id: 9,
+        };
+        writer.send(created).await.unwrap();
+
+        let registered = ExtensionToIgloo::RegisterEntity {
+            device: 9,
+            entity_id: "light".to_string(),
+            entity_index: 0,
+        };
+        assert_eq!(reader.next().await.unwrap().unwrap(), registered);
+        assert_eq!(rx.recv().await, Some(("Lamp".to_string(), 9)));
+
+        let known = std::fs::read(dir.join(DEVICES_FILE)).unwrap();
+        let known: FxHashMap<String, u64> = serde_json::from_slice(&known).unwrap();
+        assert_eq!(known.get("Lamp"), Some(&9));
+
+        // a stale copy of the Entity is dropped instead of sent
+        let stale = lamp.entity("light").unwrap();
+        handle.set(stale, Component::Switch(true)).unwrap();
+        let light = Entity {
+            device: 9,
+            index: 0,
+        };
+        handle.set(light, Component::Switch(false)).unwrap();
+        let written = ExtensionToIgloo::WriteComponents {
+            device: 9,
+            entity: 0,
+            comps: vec![Component::Switch(false)],
+        };
+        assert_eq!(reader.next().await.unwrap().unwrap(), written);
+
+        run.abort();
+        _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,433 @@
+//! Full backups of an Igloo installation
+//!
+//! A backup holds everything in the data dir (device tree, dashboards,
+//! Extension data dirs, history, ..) plus `auth.toml` from the working dir.
+//! Transient files (`.tmp`, `.corrupt`, `.compact`, the tree's rotating backups
+//! and [LOCK_FILE]) are left out.
+//!
+//! Backups are taken while Igloo is running. The tree's files are written
+//! atomically, so each one is read whole. History and the journal are appended
+//! in place (history is flushed first), so they can end with a partial entry,
+//! which is dropped like one left by a crash. Extension data dirs are copied
+//! as they are, so an Extension writing during a backup can leave its files
+//! inconsistent.
+//!
+//! Restoring replaces the data dir, so it is only available from the CLI,
+//! and refuses while Igloo holds [LOCK_FILE].
+//!
+//! # File Format
+//!  - [MAGIC]
+//!  - file contents, back to back, in manifest order
+//!  - [Manifest] as JSON
+//!  - [u64] BE length of the manifest
+//!
+//! Putting the manifest last lets files be streamed into the archive
+//! while they are hashed.
+//!
+//! # Versioning
+//! [SCHEMA_VERSION] is the layout of the data dir. Restoring a backup
+//! from another version is refused, rather than guessing at a migration.
+
+use crate::{
+    BACKUP, DATA_DIR,
+    core::IglooRequest,
+    tree::persist::{
+        DEVICES_FILE, GROUPS_FILE, IGNORED_FILE, LOCK_FILE, LockError, MAX_BACKUPS, STATE_FILE,
+        lock_data_dir,
+    },
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::runtime::Handle;
+
+/// Bump when the data dir layout changes incompatibly
+pub const SCHEMA_VERSION: u16 = 1;
+
+pub const MAGIC: &[u8; 8] = b"IGLOOBAK";
+pub const FILE_PREFIX: &str = "igloo-";
+pub const FILE_EXT: &str = "igloo";
+/// Relative to the working dir, like the auth module
+pub const AUTH_FILE: &str = "auth.toml";
+
+/// Prefix of files from the data dir in the manifest
+const DATA_PREFIX: &str = "data/";
+const BUF_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    /// Where [run] writes backups
+    pub dir: PathBuf,
+    /// None disables scheduled backups
+    pub interval: Option<Duration>,
+    /// Backups kept in [BackupConfig::dir], 0 keeps all of them
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./backups"),
+            interval: None,
+            keep: 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub schema_version: u16,
+    /// RFC 3339
+    pub created: String,
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// `/` separated, data dir files are under `data/`
+    pub path: String,
+    pub size: u64,
+    /// Hex
+    pub sha1: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BackupError {
+    #[error("IO error: {0}")]
+    IO(#[from] io::Error),
+    #[error("Invalid manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("Not an Igloo backup")]
+    NotABackup,
+    #[error("Backup has schema version {0}, but this Igloo uses {SCHEMA_VERSION}")]
+    IncompatibleVersion(u16),
+    #[error("{0} is corrupt (checksum mismatch)")]
+    ChecksumMismatch(String),
+    #[error("Backup contains an unsafe path: {0}")]
+    UnsafePath(String),
+    #[error("{0}")]
+    Lock(#[from] LockError),
+}
+
+/// Creates a backup in the background, reporting with [IglooRequest::BackupDone]
+pub fn start(rt: &Handle, core_tx: kanal::Sender<IglooRequest>, client_id: Option<usize>) {
+    rt.spawn_blocking(move || {
+        let config = BACKUP.get().cloned().unwrap_or_default();
+        let result = run(&config).map_err(|e| e.to_string());
+        if let Err(e) = core_tx.send(IglooRequest::BackupDone { client_id, result }) {
+            eprintln!("Failed to send backup result to core: {e}");
+        }
+    });
+}
+
+/// Writes a timestamped backup into the backups dir, then prunes old ones
+pub fn run(config: &BackupConfig) -> Result<PathBuf, BackupError> {
+    fs::create_dir_all(&config.dir)?;
+    let stamp = jiff::Timestamp::now().strftime("%Y%m%dT%H%M%S%.6fZ");
+    let path = config.dir.join(format!("{FILE_PREFIX}{stamp}.{FILE_EXT}"));
+    create(DATA_DIR.get().unwrap(), &path)?;
+    prune(&config.dir, config.keep)?;
+    Ok(path)
+}
+
+/// Removes the oldest backups in `dir`, so at most `keep` remain
+pub fn prune(dir: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if name.starts_with(FILE_PREFIX) && name.ends_with(&format!(".{FILE_EXT}")) {
+            backups.push(path);
+        }
+    }
+
+    // timestamps sort lexically
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for path in &backups[..excess] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Writes a backup of `data_dir` (and [AUTH_FILE]) to `out`
+pub fn create(data_dir: &Path, out: &Path) -> Result<Manifest, BackupError> {
+    let mut sources = Vec::new();
+    collect(data_dir, DATA_PREFIX, true, &mut sources)?;
+    if fs::symlink_metadata(AUTH_FILE).is_ok_and(|meta| meta.is_file()) {
+        sources.push((AUTH_FILE.to_string(), PathBuf::from(AUTH_FILE)));
+    }
+
+    let tmp = with_suffix(out, ".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    writer.write_all(MAGIC)?;
+
+    let mut files = Vec::with_capacity(sources.len());
+    for (path, source) in sources {
+        let file = match File::open(&source) {
+            Ok(file) => file,
+            // removed since listing
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let (size, sha1) = copy_hashed(file, &mut writer, u64::MAX)?;
+        files.push(ManifestFile { path, size, sha1 });
+    }
+
+    let manifest = Manifest {
+        schema_version: SCHEMA_VERSION,
+        created: jiff::Timestamp::now().to_string(),
+        files,
+    };
+    let json = serde_json::to_vec(&manifest)?;
+    writer.write_all(&json)?;
+    writer.write_all(&(json.len() as u64).to_be_bytes())?;
+
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, out)?;
+
+    Ok(manifest)
+}
+
+/// Reads the manifest and checks every file against it, without extracting
+pub fn verify(archive: &Path) -> Result<Manifest, BackupError> {
+    let mut file = File::open(archive)?;
+    let manifest = read_manifest(&mut file)?;
+
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+    for entry in &manifest.files {
+        let (_, sha1) = copy_hashed(&mut reader, &mut io::sink(), entry.size)?;
+        if sha1 != entry.sha1 {
+            return Err(BackupError::ChecksumMismatch(entry.path.clone()));
+        }
+    }
+
+    Ok(manifest)
+}
+
+/// Replaces `data_dir` (and [AUTH_FILE]) with the contents of `archive`
+/// Refuses while Igloo is running. The archive is fully verified before anything
+/// is touched, and the previous data dir is moved aside rather than deleted.
+/// Returns where the previous data dir was moved to
+pub fn restore(archive: &Path, data_dir: &Path) -> Result<Option<PathBuf>, BackupError> {
+    // moves aside with the data dir, so it's held until the restore is done
+    let _lock = match fs::exists(data_dir)? {
+        true => Some(lock_data_dir(data_dir)?),
+        false => None,
+    };
+    let manifest = verify(archive)?;
+
+    let staging = with_suffix(data_dir, ".restoring");
+    if fs::exists(&staging)? {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    let mut reader = BufReader::new(File::open(archive)?);
+    reader.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+    let mut auth = None;
+    for entry in &manifest.files {
+        let dest = if entry.path == AUTH_FILE {
+            let dest = with_suffix(&staging, &format!("-{AUTH_FILE}"));
+            auth = Some(dest.clone());
+            dest
+        } else {
+            let Some(rel) = entry.path.strip_prefix(DATA_PREFIX) else {
+                return Err(BackupError::UnsafePath(entry.path.clone()));
+            };
+            staging.join(safe_path(rel).ok_or(BackupError::UnsafePath(entry.path.clone()))?)
+        };
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out = File::create(&dest)?;
+        let (_, sha1) = copy_hashed(&mut reader, &mut out, entry.size)?;
+        if sha1 != entry.sha1 {
+            return Err(BackupError::ChecksumMismatch(entry.path.clone()));
+        }
+        out.sync_all()?;
+    }
+
+    let suffix = format!(".before-restore-{}", jiff::Timestamp::now().as_second());
+    let previous = if fs::exists(data_dir)? {
+        let previous = with_suffix(data_dir, &suffix);
+        fs::rename(data_dir, &previous)?;
+        Some(previous)
+    } else {
+        None
+    };
+    fs::rename(&staging, data_dir)?;
+
+    if let Some(auth) = auth {
+        let auth_file = Path::new(AUTH_FILE);
+        if fs::exists(auth_file)? {
+            fs::rename(auth_file, with_suffix(auth_file, &suffix))?;
+        }
+        fs::rename(auth, auth_file)?;
+    }
+
+    Ok(previous)
+}
+
+fn read_manifest(file: &mut File) -> Result<Manifest, BackupError> {
+    let len = file.metadata()?.len();
+    let min_len = (MAGIC.len() + size_of::<u64>()) as u64;
+    if len < min_len {
+        return Err(BackupError::NotABackup);
+    }
+
+    let mut magic = [0; MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(BackupError::NotABackup);
+    }
+
+    let mut manifest_len = [0; size_of::<u64>()];
+    file.seek(SeekFrom::End(-(size_of::<u64>() as i64)))?;
+    file.read_exact(&mut manifest_len)?;
+    let manifest_len = u64::from_be_bytes(manifest_len);
+    if manifest_len > len - min_len {
+        return Err(BackupError::NotABackup);
+    }
+
+    let mut json = vec![0; manifest_len as usize];
+    file.seek(SeekFrom::Start(
+        len - size_of::<u64>() as u64 - manifest_len,
+    ))?;
+    file.read_exact(&mut json)?;
+
+    // check the version before the rest, newer versions may change the manifest
+    #[derive(Deserialize)]
+    struct Version {
+        schema_version: u16,
+    }
+    let version: Version = serde_json::from_slice(&json)?;
+    if version.schema_version != SCHEMA_VERSION {
+        return Err(BackupError::IncompatibleVersion(version.schema_version));
+    }
+    let manifest: Manifest = serde_json::from_slice(&json)?;
+
+    let contents: u64 = manifest.files.iter().map(|file| file.size).sum();
+    if contents != len - min_len - manifest_len {
+        return Err(BackupError::NotABackup);
+    }
+
+    Ok(manifest)
+}
+
+/// Adds every regular file under `dir` to `out`, sorted so backups are stable
+fn collect(
+    dir: &Path,
+    prefix: &str,
+    is_root: bool,
+    out: &mut Vec<(String, PathBuf)>,
+) -> io::Result<()> {
+    let mut entries = match fs::read_dir(dir) {
+        Ok(entries) => entries.collect::<Result<Vec<_>, _>>()?,
+        Err(e) if e.kind() == ErrorKind::NotFound && is_root => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            eprintln!(
+                "Skipping {:?} in backup, its name isn't UTF-8",
+                entry.path()
+            );
+            continue;
+        };
+        // sockets, symlinks, ..
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            collect(&entry.path(), &format!("{prefix}{name}/"), false, out)?;
+        } else if meta.is_file() && !is_transient(&name, is_root) {
+            out.push((format!("{prefix}{name}"), entry.path()));
+        }
+    }
+    Ok(())
+}
+
+fn is_transient(name: &str, is_root: bool) -> bool {
+    // `.compact` is history being compacted
+    if name.ends_with(".tmp") || name.ends_with(".corrupt") || name.ends_with(".compact") {
+        return true;
+    }
+    if is_root && name == LOCK_FILE {
+        return true;
+    }
+
+    is_root
+        && [DEVICES_FILE, GROUPS_FILE, IGNORED_FILE, STATE_FILE]
+            .iter()
+            .filter_map(|file| name.strip_prefix(file)?.strip_prefix('.'))
+            .any(|n| {
+                n.parse::<usize>()
+                    .is_ok_and(|n| (1..=MAX_BACKUPS).contains(&n))
+            })
+}
+
+/// Rejects anything that could escape the data dir
+fn safe_path(rel: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for part in rel.split('/') {
+        if part.is_empty() || part == "." || part == ".." || part.contains('\\') {
+            return None;
+        }
+        path.push(part);
+    }
+    Some(path)
+}
+
+/// Copies up to `limit` bytes, returning how many were copied and their hex SHA-1
+fn copy_hashed<R: Read, W: Write>(
+    reader: R,
+    writer: &mut W,
+    limit: u64,
+) -> io::Result<(u64, String)> {
+    let mut reader = reader.take(limit);
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0; BUF_SIZE];
+    let mut size = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+
+    if limit != u64::MAX && size != limit {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    let sha1 = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Ok((size, sha1))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
@@ -1,15 +1,41 @@
 use crate::{
-    ext::{self, ExtensionRequest},
+    BACKUP, DATA_DIR, EXT_HEARTBEAT, backup,
+    ext::{self, ExtensionHandle, ExtensionProcess, ExtensionQueue, QueueMetrics},
+    history::{
+        compact::{self, Compacted, HISTORY_COMPACT_INTERVAL},
+        export::{DeviceNames, device_names},
+        recorder::HistoryRecorder,
+    },
     query::{QueryEngine, watch::WatcherID},
-    tree::{DeviceTree, TreeIDError, mutation::TreeMutationError, persist::TreePersistError},
+    tree::{
+        DeviceTree, TreeIDError,
+        mutation::{DetachReason, TreeMutationError},
+        persist::{TreePersistError, lock_data_dir},
+    },
 };
 use igloo_interface::{
-    id::{DeviceID, EntityID, EntityIndex, ExtensionIndex, GroupID},
-    ipc::{ExtensionToIgloo, IglooToExtension},
-    query::{OneShotQuery, QueryResult, WatchQuery, WatchUpdate, check::QueryError},
+    id::{
+        DeviceID, EntityID, EntityIndex, ExtensionID, ExtensionIndex, GroupID, MAX_ENTITY_ID_LENGTH,
+    },
+    ipc::{ExtensionError, ExtensionToIgloo, IglooToExtension},
+    query::{
+        CommandError, DiscoveredDevice, JournalSource, OneShotQuery, QueryResult, WatchQuery,
+        WatchUpdate, check::QueryError,
+    },
 };
+use rustc_hash::FxHashSet;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, mem, thread::JoinHandle};
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs::File,
+    mem,
+    path::PathBuf,
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use tokio::{runtime::Handle, time::MissedTickBehavior};
 
 /// (Client | Ext) -> Igloo Core
 #[allow(clippy::large_enum_variant)]
@@ -27,10 +53,40 @@ pub enum IglooRequest {
 
     Ext {
         sender: ExtensionIndex,
+        /// [ExtensionHandle::generation] of the instance that sent it
+        generation: u64,
         content: ExtensionToIgloo,
     },
+
+    /// Sent every [TICK_INTERVAL] for time based work
+    Tick,
+
+    /// Result of [ext::rescan]
+    ExtsScanned(Vec<ExtensionID>),
+
+    /// Result of [ext::start]
+    ExtStarted {
+        id: ExtensionID,
+        result: Result<(ExtensionHandle, ExtensionQueue), IglooError>,
+    },
+
+    /// Result of [backup::start]
+    BackupDone {
+        /// None for scheduled backups
+        client_id: Option<usize>,
+        result: Result<PathBuf, String>,
+    },
+
+    /// Result of [compact::start]
+    HistoryCompacted(Vec<Compacted>),
+
+    /// Replies once every recorded entry is on disk, for `GET /history/export`
+    HistoryExport(kanal::Sender<DeviceNames>),
 }
 
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[allow(clippy::large_enum_variant)]
 #[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMsg {
@@ -56,6 +112,38 @@ pub enum ClientMsg {
         new_name: String,
     },
 
+    /// Moves `device_id`'s ID, name, groups and metadata onto `replacement`
+    /// (ex. after pairing a new plug to replace a dead one)
+    /// `replacement`'s own ID is deleted
+    ReplaceDevice {
+        device_id: DeviceID,
+        replacement: DeviceID,
+    },
+
+    /// `None` clears it
+    SetDeviceIcon {
+        device_id: DeviceID,
+        icon: Option<String>,
+    },
+    /// `None` clears it
+    SetDeviceNotes {
+        device_id: DeviceID,
+        notes: Option<String>,
+    },
+
+    /// Replaces the device's labels
+    SetDeviceLabels {
+        device_id: DeviceID,
+        labels: Vec<String>,
+    },
+
+    /// Replaces the labels of the device's entity with this ID
+    SetEntityLabels {
+        device_id: DeviceID,
+        entity_id: EntityID,
+        labels: Vec<String>,
+    },
+
     CreateGroup {
         name: String,
     },
@@ -67,11 +155,59 @@ pub enum ClientMsg {
         new_name: String,
     },
 
+    /// Moves a group into another one, or to the top if `parent` is `None`
+    SetGroupParent {
+        group_id: GroupID,
+        parent: Option<GroupID>,
+    },
+
     AddDeviceToGroup(GroupID, DeviceID),
 
     RemoveDeviceFromGroup(GroupID, DeviceID),
 
     DetachExt(ExtensionIndex),
+
+    /// Start all Extensions in the packages dir which aren't running
+    RescanExts,
+    StartExt(ExtensionID),
+    StopExt(ExtensionID),
+    /// Stop then start (ex. after updating its binary)
+    RestartExt(ExtensionID),
+
+    GetExtQueueMetrics,
+
+    /// Forwarded to the Extension as `IglooToExtension::Custom`
+    /// Its reply is sent back as [IglooResponse::ExtensionCallResult]
+    ExtensionCall {
+        call_id: usize,
+        ext: ExtensionID,
+        name: String,
+        payload: serde_json::Value,
+    },
+
+    /// List the discovery inbox, see [IglooResponse::Discovered]
+    GetDiscovered,
+    /// Tells the Extension to create the device, then adds it to `groups`
+    AcceptDiscovered {
+        ext: ExtensionID,
+        external_id: String,
+        /// Overrides the discovered name
+        name: Option<String>,
+        groups: Vec<GroupID>,
+    },
+    /// Hide it from the inbox, even after restarts
+    IgnoreDiscovered {
+        ext: ExtensionID,
+        external_id: String,
+    },
+    UnignoreDiscovered {
+        ext: ExtensionID,
+        external_id: String,
+    },
+
+    /// Write a backup into the backups dir, see [IglooResponse::BackupResult]
+    /// Restoring is only done from the CLI, since Igloo must be stopped
+    Backup,
 }
 
 /// Igloo Core -> Client
@@ -103,6 +239,22 @@ pub enum IglooResponse {
     // device tree mutation proxy
     InvalidID(TreeIDError),
     GroupCreated(GroupID),
+    ExtQueueMetrics(Vec<(ExtensionID, QueueMetrics)>),
+    Discovered(Vec<DiscoveredDevice>),
+    /// Path of the new backup
+    BackupResult(Result<PathBuf, String>),
+
+    // extension passthrough
+    ExtensionCallResult {
+        call_id: usize,
+        result: Result<serde_json::Value, CommandError>,
+    },
+    /// Sent to every client
+    ExtensionCustom {
+        ext: ExtensionID,
+        name: String,
+        payload: serde_json::Value,
+    },
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -127,13 +279,26 @@ pub enum IglooError {
     DeviceTreePersist(#[from] TreePersistError),
     #[error("IO error: {0}")]
     IO(#[from] tokio::io::Error),
+    #[error("Extension {0} failed to initialize: {1}")]
+    ExtensionInit(ExtensionID, String),
 }
 
 struct IglooCore {
     tree: DeviceTree,
     engine: QueryEngine,
     rx: kanal::Receiver<IglooRequest>,
+    /// for background tasks to respond to
+    tx: kanal::Sender<IglooRequest>,
+    rt: Handle,
     cm: ClientManager,
+    /// Extensions currently booting
+    starting: FxHashSet<ExtensionID>,
+    /// None when scheduled backups are disabled
+    next_backup: Option<Instant>,
+    /// Soon after boot, then every [HISTORY_COMPACT_INTERVAL]
+    next_compaction: Instant,
+    /// Held until shutdown, see [lock_data_dir]
+    _data_lock: File,
 }
 
 // TODO client manager needs to use generational arena
@@ -148,8 +313,9 @@ pub struct Client {
 }
 
 pub async fn spawn() -> Result<(JoinHandle<()>, kanal::Sender<IglooRequest>), Box<dyn Error>> {
+    let data_lock = lock_data_dir(DATA_DIR.get().unwrap())?;
     let mut tree = DeviceTree::load()?;
-    let mut engine = QueryEngine::default();
+    let mut engine = QueryEngine::new(HistoryRecorder::load()?);
     let (tx, rx) = kanal::bounded(100);
     let mut cm = ClientManager {
         clients: vec![None; 20],
@@ -161,21 +327,50 @@ pub async fn spawn() -> Result<(JoinHandle<()>, kanal::Sender<IglooRequest>), Bo
         tree,
         engine,
         rx,
+        tx: tx.clone(),
+        rt: Handle::current(),
         cm,
+        starting: HashSet::default(),
+        next_backup: BACKUP
+            .get()
+            .and_then(|config| config.interval)
+            .map(|interval| Instant::now() + interval),
+        next_compaction: Instant::now(),
+        _data_lock: data_lock,
     };
 
     let handle = std::thread::spawn(move || {
         core.run();
     });
 
+    let tick_tx = tx.clone_async();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            if tick_tx.send(IglooRequest::Tick).await.is_err() {
+                break;
+            }
+        }
+    });
+
     Ok((handle, tx))
 }
 
 impl IglooCore {
     fn run(mut self) {
+        // attaching Extensions spawns their tasks
+        let rt = self.rt.clone();
+        let _rt = rt.enter();
+
         while let Ok(req) = self.rx.recv() {
             if let IglooRequest::Shutdown = req {
                 println!("CORE: Shutting down");
+                if let Err(e) = self.tree.save_state() {
+                    eprintln!("CORE: Error saving state: {e}");
+                }
+                self.engine.flush_history();
                 for device in self.tree.devices().iter() {
                     println!("> device: {:?}", device.id());
                     for entity in device.entities() {
@@ -196,14 +391,90 @@ impl IglooCore {
 
     fn handle_request(&mut self, req: IglooRequest) -> Result<(), IglooError> {
         use IglooRequest::*;
+        // whatever this request mutates is journaled under its source
+        let source = match &req {
+            Client { client_id, .. } => JournalSource::Client(*client_id),
+            Ext { sender, .. } => match self.tree.ext(sender) {
+                Ok(ext) => JournalSource::Extension(ext.id().clone()),
+                Err(_) => JournalSource::Igloo,
+            },
+            _ => JournalSource::Igloo,
+        };
+        self.tree.set_journal_source(source);
+
         match req {
             Shutdown => unreachable!(),
 
             // extension proxy
             Ext {
                 sender: from,
+                generation,
                 content: msg,
-            } => self.handle_ext_msg(from, msg),
+            } => {
+                // sent before its instance detached and `from` was reused
+                if self.tree.ext(&from)?.process.generation != generation {
+                    return Ok(());
+                }
+
+                let res = self.handle_ext_msg(from, msg);
+                // errors caused by the extension are sent back to it
+                // everything else is an internal issue
+                match res.as_ref().map_err(IglooError::to_ext_error) {
+                    Err(Some(error)) => self.reject_ext_msg(from, error),
+                    _ => res,
+                }
+            }
+
+            Tick => {
+                self.tick(Instant::now());
+                Ok(())
+            }
+
+            // extension lifecycle
+            ExtsScanned(ids) => {
+                for id in ids {
+                    if self.tree.ext_index(&id).is_err() {
+                        self.start_ext(id, None);
+                    }
+                }
+                Ok(())
+            }
+            ExtStarted { id, result } => {
+                self.starting.remove(&id);
+                match result {
+                    Ok((handle, queue)) => {
+                        self.tree
+                            .attach_ext(&mut self.cm, &mut self.engine, handle, queue)?;
+                        Ok(())
+                    }
+                    Err(e) => {
+                        eprintln!("Error starting {id}: {e}");
+                        Ok(())
+                    }
+                }
+            }
+
+            BackupDone { client_id, result } => {
+                match &result {
+                    Ok(path) => println!("Backup written to {}", path.display()),
+                    Err(e) => eprintln!("Error writing backup: {e}"),
+                }
+                match client_id {
+                    Some(client_id) => self.cm.send(client_id, IglooResponse::BackupResult(result)),
+                    None => Ok(()),
+                }
+            }
+
+            HistoryCompacted(results) => {
+                self.engine.finish_history_compaction(results);
+                Ok(())
+            }
+            HistoryExport(reply) => {
+                self.engine.flush_history_buffers();
+                // the requester may have given up
+                let _ = reply.send(device_names(self.tree.devices()));
+                Ok(())
+            }
 
             // client reg
             RegisterClient(channel) => self.cm.register(channel),
@@ -227,22 +498,32 @@ impl IglooCore {
     ) -> Result<(), IglooError> {
         use ExtensionToIgloo::*;
         match msg {
-            CreateDevice { name } => {
+            CreateDevice {
+                name,
+                external_id,
+                info,
+            } => {
                 let id = self.tree.create_device(
                     &mut self.cm,
                     &mut self.engine,
                     name.clone(),
                     xindex,
+                    external_id,
+                    info.unwrap_or_default(),
                 )?;
                 let ext = self.tree.ext(&xindex)?;
-                let msg = ExtensionRequest::Msg(IglooToExtension::DeviceCreated {
+                let msg = IglooToExtension::DeviceCreated {
                     name,
                     id: *id.inner(),
-                });
-
-                if ext.channel.try_send(msg).is_err() {
-                    self.tree
-                        .detach_ext(&mut self.cm, &mut self.engine, xindex, true)?;
+                };
+
+                if ext.queue.push(msg).is_err() {
+                    self.tree.detach_ext(
+                        &mut self.cm,
+                        &mut self.engine,
+                        xindex,
+                        DetachReason::ChannelFull,
+                    )?;
                 }
                 Ok(())
             }
@@ -254,6 +535,7 @@ impl IglooCore {
             } => self.tree.register_entity(
                 &mut self.cm,
                 &mut self.engine,
+                xindex,
                 DeviceID::new(device),
                 EntityID(entity_id),
                 EntityIndex(entity_index),
@@ -266,22 +548,183 @@ impl IglooCore {
             } => self.tree.write_components(
                 &mut self.cm,
                 &mut self.engine,
+                xindex,
                 DeviceID::new(device),
                 EntityIndex(entity),
                 comps,
             ),
 
-            WhatsUpIgloo => {
-                // TODO return err
+            RemoveComponents {
+                device,
+                entity,
+                comps,
+            } => self.tree.remove_components(
+                &mut self.cm,
+                &mut self.engine,
+                xindex,
+                DeviceID::new(device),
+                EntityIndex(entity),
+                comps,
+            ),
+
+            Ack { id, result } => {
+                self.engine.on_ack(&mut self.cm, xindex, id, result);
+                Ok(())
+            }
+
+            Pong => self.tree.ext_ponged(xindex),
+
+            SetDeviceInfo { device, info } => self.tree.set_device_info(
+                &mut self.cm,
+                &mut self.engine,
+                xindex,
+                DeviceID::new(device),
+                info,
+            ),
+
+            Custom { name, payload } => {
+                let ext = self.tree.ext(&xindex)?.id().clone();
+                self.cm
+                    .broadcast(IglooResponse::ExtensionCustom { ext, name, payload });
+                Ok(())
+            }
+
+            CustomReply { id, result } => {
+                self.engine
+                    .on_custom_reply(&mut self.cm, xindex, id, result);
                 Ok(())
             }
+
+            SetReachable { device, reachable } => self.tree.set_device_reachable(
+                &mut self.cm,
+                &mut self.engine,
+                xindex,
+                DeviceID::new(device),
+                reachable,
+            ),
+
+            Discovered {
+                external_id,
+                name,
+                model,
+            } => self.tree.discover_device(
+                &mut self.cm,
+                &mut self.engine,
+                xindex,
+                external_id,
+                name,
+                model,
+            ),
+
+            WhatsUpIgloo => self.reject_ext_msg(
+                xindex,
+                ExtensionError::Unexpected("Extension is already initialized.".to_string()),
+            ),
+        }
+    }
+
+    /// Boots the Extension in the background, unless it is already booting
+    /// Attached once [IglooRequest::ExtStarted] comes back
+    fn start_ext(&mut self, id: ExtensionID, prev: Option<Arc<ExtensionProcess>>) {
+        if !self.starting.insert(id.clone()) {
+            println!("{id} is already starting");
+            return;
+        }
+        ext::start(&self.rt, id, self.tx.clone(), prev);
+    }
+
+    /// Periodic work. Every step runs even if an earlier one fails,
+    /// so one failed write (ex. disk full) doesn't hold back the rest
+    fn tick(&mut self, now: Instant) {
+        self.engine.on_tick(&mut self.cm, now);
+
+        let config = EXT_HEARTBEAT.get().copied().unwrap_or_default();
+        if let Err(e) = self
+            .tree
+            .heartbeat(&mut self.cm, &mut self.engine, &config, now)
+        {
+            eprintln!("CORE: Error checking heartbeats: {e}");
+        }
+        if let Err(e) = self
+            .tree
+            .expire_restored(&mut self.cm, &mut self.engine, now)
+        {
+            eprintln!("CORE: Error expiring restored entities: {e}");
+        }
+        if let Err(e) = self
+            .tree
+            .expire_adopting(&mut self.cm, &mut self.engine, now)
+        {
+            eprintln!("CORE: Error expiring adoptions: {e}");
+        }
+        if let Err(e) = self.tree.flush_state(now) {
+            eprintln!("CORE: Error saving state: {e}");
+        }
+
+        if let Some(at) = self.next_backup
+            && now >= at
+        {
+            let interval = BACKUP.get().and_then(|config| config.interval);
+            self.next_backup = interval.map(|interval| now + interval);
+            self.start_backup(None);
+        }
+        if now >= self.next_compaction {
+            self.next_compaction = now + HISTORY_COMPACT_INTERVAL;
+            if let Some((dir, config)) = self.engine.begin_history_compaction() {
+                compact::start(&self.rt, self.tx.clone(), dir, config);
+            }
+        }
+    }
+
+    /// Writes a backup in the background
+    /// Reported with [IglooRequest::BackupDone]
+    fn start_backup(&mut self, client_id: Option<usize>) {
+        // state is otherwise only flushed every STATE_FLUSH_INTERVAL,
+        // failing that the backup still has the last flushed one
+        if let Err(e) = self.tree.save_state() {
+            eprintln!("CORE: Error saving state for backup: {e}");
         }
+        // and history every HISTORY_FLUSH_INTERVAL
+        self.engine.flush_history_buffers();
+        backup::start(&self.rt, self.tx.clone(), client_id);
+    }
+
+    /// Sends the error back to the Extension and detaches
+    /// it if it has been rejected too many times
+    fn reject_ext_msg(
+        &mut self,
+        xindex: ExtensionIndex,
+        error: ExtensionError,
+    ) -> Result<(), IglooError> {
+        eprintln!("CORE: Rejected message from {xindex}: {error}");
+
+        let ext = self.tree.ext(&xindex)?;
+        if ext.queue.push(IglooToExtension::Error(error)).is_err() {
+            return self.tree.detach_ext(
+                &mut self.cm,
+                &mut self.engine,
+                xindex,
+                DetachReason::ChannelFull,
+            );
+        }
+
+        if self.tree.strike_ext(xindex, Instant::now())? {
+            self.tree.detach_ext(
+                &mut self.cm,
+                &mut self.engine,
+                xindex,
+                DetachReason::Misbehaving,
+            )?;
+        }
+
+        Ok(())
     }
 
     fn handle_client_msg(&mut self, client_id: usize, msg: ClientMsg) -> Result<(), IglooError> {
         use ClientMsg::*;
         match msg {
             Unregister => {
+                self.engine.on_client_unregistered(client_id);
                 let client = self.cm.unregister(client_id)?;
                 self.engine.unsub_watches(client_id, client.watchers)
             }
@@ -310,6 +753,35 @@ impl IglooCore {
             } => self
                 .tree
                 .rename_device(&mut self.cm, &mut self.engine, device_id, new_name),
+            ReplaceDevice {
+                device_id,
+                replacement,
+            } => self
+                .tree
+                .replace_device(&mut self.cm, &mut self.engine, device_id, replacement),
+            SetDeviceIcon { device_id, icon } => {
+                self.tree
+                    .set_device_icon(&mut self.cm, &mut self.engine, device_id, icon)
+            }
+            SetDeviceNotes { device_id, notes } => {
+                self.tree
+                    .set_device_notes(&mut self.cm, &mut self.engine, device_id, notes)
+            }
+            SetDeviceLabels { device_id, labels } => {
+                self.tree
+                    .set_device_labels(&mut self.cm, &mut self.engine, device_id, labels)
+            }
+            SetEntityLabels {
+                device_id,
+                entity_id,
+                labels,
+            } => self.tree.set_entity_labels(
+                &mut self.cm,
+                &mut self.engine,
+                device_id,
+                entity_id,
+                labels,
+            ),
             CreateGroup { name } => {
                 let gid = self
                     .tree
@@ -322,6 +794,10 @@ impl IglooCore {
                 self.tree
                     .rename_group(&mut self.cm, &mut self.engine, &group_id, new_name)
             }
+            SetGroupParent { group_id, parent } => {
+                self.tree
+                    .set_group_parent(&mut self.cm, &mut self.engine, group_id, parent)
+            }
             AddDeviceToGroup(gid, did) => {
                 self.tree
                     .add_device_to_group(&mut self.cm, &mut self.engine, gid, did)
@@ -330,14 +806,146 @@ impl IglooCore {
                 self.tree
                     .remove_device_from_group(&mut self.cm, &mut self.engine, gid, did)
             }
-            DetachExt(xindex) => {
+            DetachExt(xindex) => self.tree.detach_ext(
+                &mut self.cm,
+                &mut self.engine,
+                xindex,
+                DetachReason::Requested,
+            ),
+            RescanExts => {
+                ext::rescan(&self.rt, self.tx.clone());
+                Ok(())
+            }
+            Backup => {
+                self.start_backup(Some(client_id));
+                Ok(())
+            }
+            StartExt(id) => {
+                if self.tree.ext_index(&id).is_ok() {
+                    println!("{id} is already running");
+                } else {
+                    self.start_ext(id, None);
+                }
+                Ok(())
+            }
+            StopExt(id) => {
+                let xindex = *self.tree.ext_index(&id)?;
+                self.tree.detach_ext(
+                    &mut self.cm,
+                    &mut self.engine,
+                    xindex,
+                    DetachReason::Requested,
+                )
+            }
+            RestartExt(id) => {
+                let xindex = *self.tree.ext_index(&id)?;
+                let prev = self.tree.ext(&xindex)?.process.clone();
+                self.tree.detach_ext(
+                    &mut self.cm,
+                    &mut self.engine,
+                    xindex,
+                    DetachReason::Requested,
+                )?;
+                self.start_ext(id, Some(prev));
+                Ok(())
+            }
+            GetExtQueueMetrics => {
+                let metrics = self
+                    .tree
+                    .exts()
+                    .iter()
+                    .flatten()
+                    .map(|ext| (ext.id().clone(), ext.queue.metrics()))
+                    .collect();
+                self.cm
+                    .send(client_id, IglooResponse::ExtQueueMetrics(metrics))
+            }
+            ExtensionCall {
+                call_id,
+                ext,
+                name,
+                payload,
+            } => {
+                let xindex = *self.tree.ext_index(&ext)?;
+                self.engine.call_ext(
+                    &mut self.cm,
+                    &mut self.tree,
+                    (client_id, call_id),
+                    xindex,
+                    name,
+                    payload,
+                )
+            }
+            GetDiscovered => {
+                let discovered = self.tree.discovered().values().cloned().collect();
+                self.cm
+                    .send(client_id, IglooResponse::Discovered(discovered))
+            }
+            AcceptDiscovered {
+                ext,
+                external_id,
+                name,
+                groups,
+            } => self.tree.accept_discovered(
+                &mut self.cm,
+                &mut self.engine,
+                ext,
+                external_id,
+                name,
+                groups,
+            ),
+            IgnoreDiscovered { ext, external_id } => {
                 self.tree
-                    .detach_ext(&mut self.cm, &mut self.engine, xindex, false)
+                    .ignore_discovered(&mut self.cm, &mut self.engine, ext, external_id)
+            }
+            UnignoreDiscovered { ext, external_id } => {
+                self.tree
+                    .unignore_discovered(&mut self.cm, &mut self.engine, ext, external_id)
             }
         }
     }
 }
 
+impl IglooError {
+    /// Converts to an error that should be reported to the Extension
+    /// Returns `None` if this was not caused by the Extension
+    fn to_ext_error(&self) -> Option<ExtensionError> {
+        use IglooError::*;
+        Some(match self {
+            DeviceTreeID(TreeIDError::DeviceDeleted(did)) => {
+                ExtensionError::DeviceNotFound(*did.inner())
+            }
+            DeviceTreeID(TreeIDError::EntityIDTooLong) => {
+                ExtensionError::EntityIDTooLong(MAX_ENTITY_ID_LENGTH)
+            }
+            DeviceTreeMutation(TreeMutationError::ID(TreeIDError::DeviceDeleted(did))) => {
+                ExtensionError::DeviceNotFound(*did.inner())
+            }
+            DeviceTreeMutation(TreeMutationError::NotOwner(did, _)) => {
+                ExtensionError::NotOwner(*did.inner())
+            }
+            DeviceTreeMutation(TreeMutationError::EntityNotFound(did, eindex)) => {
+                ExtensionError::EntityNotFound {
+                    device: *did.inner(),
+                    entity: eindex.0,
+                }
+            }
+            DeviceTreeMutation(TreeMutationError::BadEntityRegistration(
+                did,
+                eid,
+                expected,
+                actual,
+            )) => ExtensionError::BadEntityRegistration {
+                device: *did.inner(),
+                entity_id: eid.0.clone(),
+                expected: expected.0,
+                actual: actual.0,
+            },
+            _ => return None,
+        })
+    }
+}
+
 impl ClientManager {
     fn register(&mut self, channel: kanal::Sender<IglooResponse>) -> Result<(), IglooError> {
         let client_id = if let Some(free_slot) = self.clients.iter_mut().position(|o| o.is_none()) {
@@ -383,6 +991,18 @@ impl ClientManager {
         }
     }
 
+    /// Sends to every client, skipping (and logging) any that fail
+    pub fn broadcast(&mut self, response: IglooResponse) {
+        for client_id in 0..self.clients.len() {
+            if self.clients[client_id].is_none() {
+                continue;
+            }
+            if let Err(e) = self.send(client_id, response.clone()) {
+                eprintln!("CORE: Failed to broadcast: {e}");
+            }
+        }
+    }
+
     fn get_client_mut(&mut self, client_id: usize) -> Result<&mut Client, IglooError> {
         match self.clients.get_mut(client_id) {
             Some(Some(client)) => Ok(client),
@@ -1,46 +1,57 @@
-use super::EXTS_DIR;
+use super::{EXTS_DIR, ExtensionQueue};
 use crate::core::{IglooError, IglooRequest};
-use crate::{DATA_DIR, PACKAGES_DIR};
+use crate::{DATA_DIR, EXT_QUEUE_LIMITS, PACKAGES_DIR};
 use futures_util::{SinkExt, StreamExt};
 use igloo_interface::id::{ExtensionID, ExtensionIndex};
 use igloo_interface::ipc::codec::LengthDelimitedJSONCodec;
-use igloo_interface::ipc::{
-    DATA_PATH_ENV_VAR, ExtensionToIgloo, IReader, IWriter, IglooToExtension,
-};
+use igloo_interface::ipc::{DATA_PATH_ENV_VAR, ExtensionToIgloo, IReader, IWriter};
+use std::os::unix::fs::MetadataExt;
 use std::path::{self, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 use std::{io, process::Stdio};
+use tokio::net::UnixStream;
 use tokio::process::{Child, Command};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, mpsc};
 use tokio::task::JoinHandle;
-use tokio::{fs, net::UnixListener};
+use tokio::{fs, net::UnixListener, time};
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 pub const SOCKET: &str = "igloo.sock";
 pub const EXECUTABLE: &str = "./ext";
+/// Max time for an Extension to connect (or reconnect) and send WhatsUpIgloo
+pub const INIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug)]
 pub struct ExtensionHandle {
     pub id: ExtensionID,
     pub index: ExtensionIndex,
+    /// Unique per instance, since [ExtensionIndex]es are reused after detaching
+    pub generation: u64,
     pub core_tx: kanal::AsyncSender<IglooRequest>,
-    pub ext_rx: kanal::AsyncReceiver<ExtensionRequest>,
+    pub queue: ExtensionQueue,
     pub writer: IWriter,
     pub reader: IReader,
+    /// Kept open so the Extension can reconnect while it's attached
+    pub listener: UnixListener,
     pub process: Child,
+    /// Inode of the socket this instance bound
+    pub socket_ino: u64,
 }
 
 #[derive(Debug)]
 pub struct ExtensionProcess {
     pub id: ExtensionID,
     pub index: ExtensionIndex,
+    pub generation: u64,
     pub process: RwLock<Child>,
-}
-
-#[derive(Clone)]
-pub enum ExtensionRequest {
-    Msg(IglooToExtension),
-    Flush,
+    /// Socket has been removed
+    cleaned_up: AtomicBool,
+    /// Only this socket is removed, not one a restarted instance bound since
+    socket_ino: u64,
 }
 
 fn cwd(id: &ExtensionID) -> PathBuf {
@@ -69,7 +80,7 @@ impl ExtensionHandle {
     pub async fn new(
         id: ExtensionID,
         to_core_tx: kanal::Sender<IglooRequest>,
-    ) -> Result<(Self, kanal::Sender<ExtensionRequest>), IglooError> {
+    ) -> Result<(Self, ExtensionQueue), IglooError> {
         println!("Initializing Extension {id}");
 
         let cwd = cwd(&id);
@@ -80,52 +91,60 @@ impl ExtensionHandle {
         _ = fs::remove_file(&socket_path).await;
 
         let listener = UnixListener::bind(&socket_path)?;
+        let socket_ino = fs::metadata(&socket_path).await?.ino();
 
-        // TODO need to properly keep track of this for shutdown
+        // killed if the handle is dropped before being attached (ex. IglooCore is gone)
         let mut process = Command::new(EXECUTABLE)
             .current_dir(cwd)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .env(DATA_PATH_ENV_VAR, data_path)
+            .kill_on_drop(true)
             .spawn()?;
 
         proxy_logs(&mut process, &id);
 
-        let (stream, _addr) = listener.accept().await?;
-
-        let (reader, writer) = stream.into_split();
-        let writer = FramedWrite::new(writer, LengthDelimitedJSONCodec::new());
-        let mut reader = FramedRead::new(reader, LengthDelimitedJSONCodec::new());
+        let init = async {
+            let (stream, _addr) = listener
+                .accept()
+                .await
+                .map_err(|e| format!("Failed to accept connection: {e}"))?;
+            handshake(stream).await
+        };
 
-        match reader.next().await {
-            Some(Ok(ExtensionToIgloo::WhatsUpIgloo)) => {
-                println!("{id} initialized!");
+        let (reader, writer) = match time::timeout(INIT_TIMEOUT, init).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(reason)) => {
+                _ = process.start_kill();
+                return Err(IglooError::ExtensionInit(id, reason));
             }
-            Some(Ok(msg)) => {
-                panic!("{id} didn't init. Sent '{msg:?}' instead.")
-            }
-            Some(Err(e)) => {
-                panic!("Failed to read {id}s init message: {e}")
-            }
-            None => {
-                // FIXME return error
-                panic!("{id} immediately closed the socket!")
+            Err(_) => {
+                _ = process.start_kill();
+                return Err(IglooError::ExtensionInit(
+                    id,
+                    "Timed out waiting for WhatsUpIgloo".to_string(),
+                ));
             }
         };
 
-        let (ext_tx, ext_rx) = kanal::bounded(20);
+        println!("{id} initialized!");
+
+        let queue = ExtensionQueue::new(EXT_QUEUE_LIMITS.get().copied().unwrap_or_default());
 
         Ok((
             ExtensionHandle {
                 id,
                 index: ExtensionIndex(usize::MAX),
+                generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
                 core_tx: to_core_tx.to_async(),
-                ext_rx: ext_rx.to_async(),
+                queue: queue.clone(),
                 writer,
                 reader,
+                listener,
                 process,
+                socket_ino,
             },
-            ext_tx,
+            queue,
         ))
     }
 
@@ -138,38 +157,89 @@ impl ExtensionHandle {
         let process = Arc::new(ExtensionProcess {
             id: self.id.clone(),
             index: self.index,
+            generation: self.generation,
             process: RwLock::new(self.process),
+            cleaned_up: AtomicBool::new(false),
+            socket_ino: self.socket_ino,
         });
 
-        tokio::spawn(read_task(self.id, self.index, self.reader, self.core_tx));
-
-        tokio::spawn(write_task(self.writer, self.ext_rx, process.clone()));
+        // writers of new connections
+        let (writers_tx, writers_rx) = mpsc::channel(1);
+
+        tokio::spawn(read_task(
+            self.id,
+            self.index,
+            self.generation,
+            self.reader,
+            self.listener,
+            writers_tx,
+            self.core_tx,
+        ));
+
+        tokio::spawn(write_task(
+            self.writer,
+            writers_rx,
+            self.queue,
+            process.clone(),
+        ));
 
         process
     }
 }
 
+/// Waits for WhatsUpIgloo on a new connection
+async fn handshake(stream: UnixStream) -> Result<(IReader, IWriter), String> {
+    let (reader, writer) = stream.into_split();
+    let writer = FramedWrite::new(writer, LengthDelimitedJSONCodec::new());
+    let mut reader = FramedRead::new(reader, LengthDelimitedJSONCodec::new());
+
+    match reader.next().await {
+        Some(Ok(ExtensionToIgloo::WhatsUpIgloo)) => Ok((reader, writer)),
+        Some(Ok(msg)) => Err(format!("Sent '{msg:?}' instead of WhatsUpIgloo")),
+        Some(Err(e)) => Err(format!("Failed to read init message: {e}")),
+        None => Err("Immediately closed the socket".to_string()),
+    }
+}
+
 /// Proxies requests to Extension
+/// Messages sent while it's disconnected are lost, so their writes time out
 async fn write_task(
     mut writer: IWriter,
-    ext_rx: kanal::AsyncReceiver<ExtensionRequest>,
+    mut writers: mpsc::Receiver<IWriter>,
+    queue: ExtensionQueue,
     process: Arc<ExtensionProcess>,
 ) {
-    while let Ok(msg) = ext_rx.recv().await {
-        use ExtensionRequest::*;
-        // TODO auto restarting when program ends
-        // TODO force kill (no restart) when backlogged
-        let res = match msg {
-            Msg(msg) => writer.send(msg).await,
-            Flush => writer.flush().await,
+    loop {
+        let batch = tokio::select! {
+            batch = queue.next_batch() => match batch {
+                Some(batch) => batch,
+                None => break,
+            },
+            Some(new) = writers.recv() => {
+                writer = new;
+                continue;
+            }
         };
 
-        if let Err(e) = res {
+        // TODO auto restarting when program ends
+        let count = batch.len();
+        for msg in batch {
+            if let Err(e) = writer.feed(msg).await {
+                // TODO FIXME
+                eprintln!("Error from ext: {e}");
+            }
+        }
+
+        if let Err(e) = writer.flush().await {
             // TODO FIXME
             eprintln!("Error from ext: {e}");
         }
+
+        queue.record_sent(count);
     }
 
+    // stops the read task, so it can't reconnect anymore
+    drop(writers);
     _ = process.kill().await;
 }
 
@@ -177,14 +247,27 @@ impl ExtensionProcess {
     pub async fn kill(&self) -> io::Result<()> {
         let mut proc = self.process.write().await;
         proc.kill().await?;
-        _ = fs::remove_file(&socket_path(&self.id)).await;
+        if self.cleaned_up.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let path = socket_path(&self.id);
+        if fs::metadata(&path)
+            .await
+            .is_ok_and(|meta| meta.ino() == self.socket_ino)
+        {
+            _ = fs::remove_file(&path).await;
+        }
         println!("{}/{} shutdown gracefully", self.id, self.index);
         Ok(())
         // TODO are other tasks shutdown (read, log proxy)?
     }
 
+    /// Called from IglooCore, so it can't wait on the write task
     pub fn start_kill(&self) -> io::Result<()> {
-        let mut proc = self.process.blocking_write();
+        // held by `kill`, which is already taking care of it
+        let Ok(mut proc) = self.process.try_write() else {
+            return Ok(());
+        };
         proc.start_kill()?;
         println!("{}/{} shutdown", self.id, self.index);
         Ok(())
@@ -193,31 +276,76 @@ impl ExtensionProcess {
 }
 
 /// Proxies requests to IglooCore
+/// Accepts the Extension's new connection whenever it drops, until it's detached
 async fn read_task(
     id: ExtensionID,
     index: ExtensionIndex,
+    generation: u64,
     mut reader: IReader,
+    listener: UnixListener,
+    writers: mpsc::Sender<IWriter>,
     core_tx: kanal::AsyncSender<IglooRequest>,
 ) {
     println!("{} running as {}", id, index);
 
-    while let Some(msg) = reader.next().await {
-        let msg = match msg {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("Error reading msg from {id}/{index}: {e}. Skipping..",);
-                continue;
+    loop {
+        loop {
+            let msg = tokio::select! {
+                msg = reader.next() => msg,
+                // detached, close the connection
+                _ = writers.closed() => return,
+            };
+            let msg = match msg {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    eprintln!("Error reading msg from {id}/{index}: {e}. Skipping..",);
+                    continue;
+                }
+                None => break,
+            };
+
+            let req = IglooRequest::Ext {
+                sender: index,
+                generation,
+                content: msg,
+            };
+
+            if let Err(e) = core_tx.send(req).await {
+                eprintln!("{id}/{index} failed to message to core: {e}");
             }
+        }
+
+        println!("{id}/{index} disconnected. Waiting for it to reconnect..");
+
+        // stays attached meanwhile, the heartbeat detaches it if it never does
+        let stream = tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _addr)) => stream,
+                Err(e) => {
+                    eprintln!("{id}/{index} failed to reconnect: {e}");
+                    continue;
+                }
+            },
+            _ = writers.closed() => return,
         };
 
-        let req = IglooRequest::Ext {
-            sender: index,
-            content: msg,
+        let writer;
+        (reader, writer) = match time::timeout(INIT_TIMEOUT, handshake(stream)).await {
+            Ok(Ok(conn)) => conn,
+            Ok(Err(reason)) => {
+                eprintln!("{id}/{index} failed to reconnect: {reason}");
+                continue;
+            }
+            Err(_) => {
+                eprintln!("{id}/{index} failed to reconnect: Timed out waiting for WhatsUpIgloo");
+                continue;
+            }
         };
 
-        if let Err(e) = core_tx.send(req).await {
-            eprintln!("{id}/{index} failed to message to core: {e}");
+        if writers.send(writer).await.is_err() {
+            return;
         }
+        println!("{id}/{index} reconnected");
     }
 }
 
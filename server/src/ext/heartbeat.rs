@@ -0,0 +1,24 @@
+//! Detects Extensions that are hung, but haven't exited
+//!
+//! Every [HeartbeatConfig::interval] IglooCore sends each Extension a
+//! [IglooToExtension::Ping](igloo_interface::ipc::IglooToExtension::Ping).
+//! If no Pong comes back within [HeartbeatConfig::timeout], it is detached.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    /// Max time since the last Pong
+    /// Should be a few intervals, so one slow response isn't fatal
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(20),
+        }
+    }
+}
@@ -5,11 +5,15 @@ use crate::{
     tree::DeviceTree,
 };
 use igloo_interface::id::ExtensionID;
-use std::error::Error;
-use tokio::{fs, task::JoinSet};
+use std::{error::Error, sync::Arc};
+use tokio::{fs, runtime::Handle, task::JoinSet};
 
 pub mod handle;
+pub mod heartbeat;
+pub mod queue;
 pub use handle::*;
+pub use heartbeat::*;
+pub use queue::*;
 
 pub const EXTS_DIR: &str = "extensions";
 
@@ -28,8 +32,8 @@ pub async fn spawn_all(
 
     while let Some(result) = set.join_next().await {
         match result {
-            Ok(Ok((handle, channel))) => {
-                tree.attach_ext(cm, engine, handle, channel)?;
+            Ok(Ok((handle, queue))) => {
+                tree.attach_ext(cm, engine, handle, queue)?;
             }
             Ok(Err(e)) => {
                 eprintln!("Error in extension boot task: {e}");
@@ -43,6 +47,49 @@ pub async fn spawn_all(
     Ok(())
 }
 
+/// Boots an Extension in the background, then sends
+/// [IglooRequest::ExtStarted] so IglooCore can attach it.
+/// If restarting, `prev` is waited on to exit first.
+pub fn start(
+    rt: &Handle,
+    id: ExtensionID,
+    core_tx: kanal::Sender<IglooRequest>,
+    prev: Option<Arc<ExtensionProcess>>,
+) {
+    rt.spawn(async move {
+        if let Some(prev) = prev
+            && let Err(e) = prev.kill().await
+        {
+            eprintln!("Error stopping {id} for restart: {e}");
+        }
+
+        let result = ExtensionHandle::new(id.clone(), core_tx.clone()).await;
+        let req = IglooRequest::ExtStarted { id, result };
+        if let Err(e) = core_tx.to_async().send(req).await {
+            eprintln!("Failed to send started Extension to core: {e}");
+        }
+    });
+}
+
+/// Reads the extensions directory in the background, then
+/// sends [IglooRequest::ExtsScanned]
+pub fn rescan(rt: &Handle, core_tx: kanal::Sender<IglooRequest>) {
+    rt.spawn(async move {
+        match get_all_ext_ids().await {
+            Ok(ids) => {
+                if let Err(e) = core_tx
+                    .to_async()
+                    .send(IglooRequest::ExtsScanned(ids))
+                    .await
+                {
+                    eprintln!("Failed to send scanned Extensions to core: {e}");
+                }
+            }
+            Err(e) => eprintln!("Error scanning extensions: {e}"),
+        }
+    });
+}
+
 async fn get_all_ext_ids() -> Result<Vec<ExtensionID>, IglooError> {
     let mut exts_path = PACKAGES_DIR.get().unwrap().clone();
     exts_path.push(EXTS_DIR);
@@ -0,0 +1,235 @@
+//! Outbound queue from IglooCore -> Extension
+//!
+//! Pending single component writes are coalesced by (device, entity, component),
+//! so only the newest value is sent, unless a later queued write touches the
+//! same component. This way a burst of writes (ex. dragging a dimmer slider)
+//! can't back up the queue while the Extension is busy.
+
+use igloo_interface::{
+    ComponentType,
+    ipc::{CommandID, IglooToExtension},
+};
+use rustc_hash::{FxBuildHasher, FxHashMap};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueLimits {
+    /// Max number of distinct pending writes
+    /// New writes are rejected past this
+    pub max_writes: usize,
+    /// Max number of pending messages
+    /// Reaching this means the Extension has stopped reading its socket
+    pub max_len: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            max_writes: 256,
+            max_len: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct QueueMetrics {
+    /// Messages waiting to be sent
+    pub len: usize,
+    /// Highest `len` seen
+    pub high_water: usize,
+    pub sent: u64,
+    /// Writes replaced by a newer value before being sent
+    pub coalesced: u64,
+    /// Messages dropped because the queue was full
+    pub rejected: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    /// Replaced the pending write with this ID
+    Coalesced(CommandID),
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    #[error("Too many pending writes")]
+    WritesFull,
+    #[error("Queue is full")]
+    Full,
+    #[error("Queue is closed")]
+    Closed,
+}
+
+/// (device, entity, component)
+type WriteKey = (u64, usize, ComponentType);
+
+/// Shared between IglooCore (producer) and the Extension's write task (consumer)
+#[derive(Debug, Clone)]
+pub struct ExtensionQueue {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    notify: Notify,
+    limits: QueueLimits,
+}
+
+#[derive(Debug)]
+struct State {
+    items: Vec<IglooToExtension>,
+    /// index into `items` of pending coalescable writes
+    writes: FxHashMap<WriteKey, usize>,
+    num_writes: usize,
+    closed: bool,
+    metrics: QueueMetrics,
+}
+
+fn write_key(msg: &IglooToExtension) -> Option<WriteKey> {
+    match msg {
+        IglooToExtension::WriteComponents {
+            device,
+            entity,
+            comps,
+            ..
+        } if comps.len() == 1 => Some((*device, *entity, comps[0].get_type())),
+        _ => None,
+    }
+}
+
+/// Whether `msg` is a write to the component at `key`
+fn touches(msg: &IglooToExtension, key: &WriteKey) -> bool {
+    match msg {
+        IglooToExtension::WriteComponents {
+            device,
+            entity,
+            comps,
+            ..
+        } => (*device, *entity) == (key.0, key.1) && comps.iter().any(|c| c.get_type() == key.2),
+        _ => false,
+    }
+}
+
+impl ExtensionQueue {
+    pub fn new(limits: QueueLimits) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    items: Vec::with_capacity(20),
+                    writes: HashMap::with_capacity_and_hasher(20, FxBuildHasher),
+                    num_writes: 0,
+                    closed: false,
+                    metrics: QueueMetrics::default(),
+                }),
+                notify: Notify::new(),
+                limits,
+            }),
+        }
+    }
+
+    pub fn push(&self, msg: IglooToExtension) -> Result<Pushed, QueueError> {
+        let limits = &self.shared.limits;
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.closed {
+            return Err(QueueError::Closed);
+        }
+
+        let key = write_key(&msg);
+
+        // a write queued after it (ex. a multi component write) must stay
+        // ahead of the new value, so only coalesce when nothing later touches it
+        if let Some(key) = &key
+            && let Some(&i) = state.writes.get(key)
+            && !state.items[i + 1..].iter().any(|m| touches(m, key))
+        {
+            let old = mem::replace(&mut state.items[i], msg);
+            state.metrics.coalesced += 1;
+            let IglooToExtension::WriteComponents { id, .. } = old else {
+                unreachable!()
+            };
+            return Ok(Pushed::Coalesced(id));
+        }
+
+        let is_write = matches!(msg, IglooToExtension::WriteComponents { .. });
+
+        if state.items.len() >= limits.max_len {
+            state.metrics.rejected += 1;
+            return Err(QueueError::Full);
+        }
+
+        if is_write && state.num_writes >= limits.max_writes {
+            state.metrics.rejected += 1;
+            return Err(QueueError::WritesFull);
+        }
+
+        if let Some(key) = key {
+            let i = state.items.len();
+            state.writes.insert(key, i);
+        }
+        if is_write {
+            state.num_writes += 1;
+        }
+        state.items.push(msg);
+
+        let len = state.items.len();
+        state.metrics.high_water = state.metrics.high_water.max(len);
+
+        drop(state);
+        self.shared.notify.notify_one();
+
+        Ok(Pushed::Queued)
+    }
+
+    /// Waits for and takes all pending messages
+    /// Returns `None` once closed
+    pub async fn next_batch(&self) -> Option<Vec<IglooToExtension>> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if !state.items.is_empty() {
+                    state.writes.clear();
+                    state.num_writes = 0;
+                    return Some(mem::take(&mut state.items));
+                }
+            }
+
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn record_sent(&self, count: usize) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.metrics.sent += count as u64;
+    }
+
+    /// Drops all pending messages and stops the write task
+    pub fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.items.clear();
+        state.writes.clear();
+        state.num_writes = 0;
+        drop(state);
+        self.shared.notify.notify_one();
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        let state = self.shared.state.lock().unwrap();
+        QueueMetrics {
+            len: state.items.len(),
+            ..state.metrics
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! Enforces retention, so history doesn't fill up the disk
+//!
+//! Every [HISTORY_COMPACT_INTERVAL] each primary file is rewritten into
+//! `{ID}.bin.compact` in the background, without the entries past its policy's
+//! `max_age_hours` and with old entries downsampled by its [Rollup]s.
+//!
+//! Meanwhile the recorder keeps appending to the original. Once it's done
+//! [HistoryRecorder::finish_compaction] copies those newer entries over
+//! and swaps the files. That's also when strings no entry references are
+//! garbage collected from the string table, since new entries may reference them.
+
+use super::{
+    HistoricalInstanceMetadata, HistoryConfig, HistoryError, HistoryPolicy, Rollup,
+    file::{HistoryFile, HistoryReader, RawEntry},
+    parse_primary_path,
+    recorder::{HistoryRecorder, now_ms},
+    strings::{InternID, StringTable, read_strings, strings_path, write_strings},
+    value,
+};
+use crate::core::IglooRequest;
+use igloo_interface::{
+    Aggregator, Component, ComponentType,
+    id::{DeviceID, EntityID},
+};
+use rustc_hash::FxHashSet;
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::runtime::Handle;
+
+pub const HISTORY_COMPACT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Longest retention, so entry offsets (u32 milliseconds, about 1193 hours)
+/// never overflow between compactions
+pub const MAX_AGE_HOURS: u32 = 1190;
+
+pub(super) const MS_PER_HOUR: u64 = 60 * 60 * 1000;
+
+const COMPACT_EXT: &str = "compact";
+
+/// A rewritten file, waiting to replace `path`
+#[derive(Debug)]
+pub struct Compacted {
+    pub path: PathBuf,
+    pub tmp: PathBuf,
+    pub instance: (DeviceID, EntityID, ComponentType),
+    /// Entries in `path` when it was read, the rest are copied over
+    pub count: u64,
+    pub meta: HistoricalInstanceMetadata,
+    /// Strings the compacted entries reference, for Text and Enum components
+    pub live: Option<FxHashSet<InternID>>,
+}
+
+/// Reported with [IglooRequest::HistoryCompacted]
+pub fn start(
+    rt: &Handle,
+    core_tx: kanal::Sender<IglooRequest>,
+    dir: PathBuf,
+    config: HistoryConfig,
+) {
+    rt.spawn_blocking(move || {
+        let results = run(&dir, &config, now_ms());
+        if let Err(e) = core_tx.send(IglooRequest::HistoryCompacted(results)) {
+            eprintln!("Failed to send history compaction result to core: {e}");
+        }
+    });
+}
+
+/// Compacts every primary file in `dir`, skipping ones that wouldn't change
+/// Failures are logged and leave the file alone
+pub fn run(dir: &Path, config: &HistoryConfig, now: u64) -> Vec<Compacted> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read history dir {}: {e}", dir.display());
+            return Vec::new();
+        }
+    };
+
+    let mut results = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let ext = path.extension().and_then(OsStr::to_str);
+
+        // left by a run that was interrupted
+        if ext == Some(COMPACT_EXT) {
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!("Failed to remove {}: {e}", path.display());
+            }
+            continue;
+        }
+
+        let Some(instance) = parse_primary_path(&path) else {
+            continue;
+        };
+
+        let (did, eid, typ) = &instance;
+        let policy = config
+            .policy_for(did, eid, *typ)
+            .unwrap_or_else(|| config.default_policy());
+
+        match compact_file(&path, instance.clone(), &policy, now) {
+            Ok(Some(compacted)) => results.push(compacted),
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to compact history file {}: {e}", path.display()),
+        }
+    }
+    results
+}
+
+/// Writes the compacted file, `None` if nothing would change
+fn compact_file(
+    path: &Path,
+    instance: (DeviceID, EntityID, ComponentType),
+    policy: &HistoryPolicy,
+    now: u64,
+) -> Result<Option<Compacted>, HistoryError> {
+    let typ = instance.2;
+    let mut reader = HistoryReader::open(path)?;
+    let old = reader.meta().clone();
+    let expected = value::entry_size(typ).unwrap_or(0);
+    if old.entry_size_bytes != expected {
+        return Err(HistoryError::EntrySizeMismatch {
+            expected,
+            found: old.entry_size_bytes,
+        });
+    }
+
+    let count = reader.count();
+    let entries = reader.entries(0, count)?;
+    let newest = entries.last().map(|(at, _)| *at);
+    let strings = match value::is_interned(typ) {
+        true => read_strings(&strings_path(path))?,
+        false => Vec::new(),
+    };
+
+    let max_age_hours = policy
+        .max_age_hours
+        .unwrap_or(MAX_AGE_HOURS)
+        .min(MAX_AGE_HOURS);
+    let cutoff = now.saturating_sub(max_age_hours as u64 * MS_PER_HOUR);
+    let mut kept: Vec<_> = entries
+        .iter()
+        .filter(|(at, _)| *at >= cutoff)
+        .cloned()
+        .collect();
+    kept = rollup(kept, &policy.rollups, typ, &strings, now, cutoff);
+
+    let live: Option<FxHashSet<_>> = value::is_interned(typ).then(|| {
+        kept.iter()
+            .filter_map(|(_, value)| intern_id(value))
+            .collect()
+    });
+    let garbage = live.as_ref().is_some_and(|live| {
+        (0..strings.len()).any(|id| strings[id].is_some() && !live.contains(&(id as InternID)))
+    });
+
+    let meta = HistoricalInstanceMetadata {
+        entry_size_bytes: old.entry_size_bytes,
+        // entries appended meanwhile are never older than `newest`
+        start_timestamp: kept
+            .first()
+            .map(|(at, _)| *at)
+            .or(newest)
+            .unwrap_or(old.start_timestamp),
+        max_age_hours: policy.max_age_hours,
+        min_interval_ms: policy.min_interval_ms,
+    };
+
+    let nearing_overflow = newest
+        .is_some_and(|at| at - old.start_timestamp > (MAX_AGE_HOURS / 2) as u64 * MS_PER_HOUR);
+    let policy_changed =
+        old.max_age_hours != meta.max_age_hours || old.min_interval_ms != meta.min_interval_ms;
+    if kept == entries && !nearing_overflow && !policy_changed && !garbage {
+        return Ok(None);
+    }
+
+    let tmp = tmp_path(path);
+    let _ = fs::remove_file(&tmp);
+    let mut file = HistoryFile::open(&tmp, meta.clone())?;
+    for (at, value) in &kept {
+        file.append(*at, value)?;
+    }
+    file.sync()?;
+
+    Ok(Some(Compacted {
+        path: path.to_path_buf(),
+        tmp,
+        instance,
+        count,
+        meta,
+        live,
+    }))
+}
+
+/// Downsamples entries older than each [Rollup]'s `after_hours`
+/// Only intervals that are entirely past it are rolled up, so each is done once
+/// An interval is kept at its start, but never before `cutoff`, since the file's
+/// offsets only reach [MAX_AGE_HOURS] past its oldest entry
+fn rollup(
+    entries: Vec<RawEntry>,
+    rollups: &[Rollup],
+    typ: ComponentType,
+    strings: &[Option<String>],
+    now: u64,
+    cutoff: u64,
+) -> Vec<RawEntry> {
+    if rollups.is_empty() {
+        return entries;
+    }
+
+    // coarsest first
+    let mut rollups: Vec<_> = rollups.iter().filter(|r| r.interval_ms > 0).collect();
+    rollups.sort_by_key(|r| std::cmp::Reverse(r.after_hours));
+
+    let mut out: Vec<RawEntry> = Vec::with_capacity(entries.len());
+    // (rollup, interval start, entries)
+    let mut bucket: Option<(usize, u64, Vec<RawEntry>)> = None;
+
+    for (at, value) in entries {
+        let found = rollups.iter().enumerate().find_map(|(i, r)| {
+            let interval = r.interval_ms as u64;
+            let start = at / interval * interval;
+            let cutoff = now.saturating_sub(r.after_hours as u64 * MS_PER_HOUR);
+            (start + interval <= cutoff).then_some((i, start))
+        });
+
+        if bucket.as_ref().map(|(i, start, _)| (*i, *start)) != found
+            && let Some((i, start, group)) = bucket.take()
+        {
+            let value = reduce(group, rollups[i], typ, strings);
+            push_ordered(&mut out, (start.max(cutoff), value));
+        }
+
+        match found {
+            Some((i, start)) => {
+                let (_, _, group) = bucket.get_or_insert_with(|| (i, start, Vec::new()));
+                group.push((at, value));
+            }
+            None => push_ordered(&mut out, (at, value)),
+        }
+    }
+
+    if let Some((i, start, group)) = bucket {
+        let value = reduce(group, rollups[i], typ, strings);
+        push_ordered(&mut out, (start.max(cutoff), value));
+    }
+    out
+}
+
+/// Value of an interval, `group` is never empty
+fn reduce(
+    group: Vec<RawEntry>,
+    rollup: &Rollup,
+    typ: ComponentType,
+    strings: &[Option<String>],
+) -> Vec<u8> {
+    if let Some(mut agg) = rollup.op.and_then(|op| Aggregator::new(typ, op)) {
+        for (_, bytes) in &group {
+            if let Some(comp) = value::decode(typ, bytes, strings)
+                .and_then(|value| Component::from_igloo_value(typ, value))
+            {
+                let _ = agg.push(&comp);
+            }
+        }
+
+        let mut buf = Vec::new();
+        match agg.finish() {
+            // the result is one of the values, so it's already interned
+            Some(value) if value::is_interned(typ) => {
+                let string = value::interned_str(&value);
+                if let Some(id) = strings
+                    .iter()
+                    .position(|s| s.as_deref() == string.as_deref())
+                {
+                    return (id as InternID).to_be_bytes().to_vec();
+                }
+            }
+            Some(value) if value::encode(&value, &mut buf) => return buf,
+            _ => {}
+        }
+    }
+
+    group.into_iter().next_back().unwrap().1
+}
+
+fn intern_id(bytes: &[u8]) -> Option<InternID> {
+    Some(InternID::from_be_bytes(bytes.try_into().ok()?))
+}
+
+pub(super) fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".");
+    tmp.push(COMPACT_EXT);
+    PathBuf::from(tmp)
+}
+
+/// Keeps entries in time order, since intervals start before their entries
+fn push_ordered(out: &mut Vec<RawEntry>, (at, value): RawEntry) {
+    let at = at.max(out.last().map_or(0, |(last, _)| *last));
+    out.push((at, value));
+}
+
+impl HistoryRecorder {
+    /// Copies over entries appended since [run] read each file, then swaps them in
+    pub fn finish_compaction(&mut self, results: Vec<Compacted>) {
+        self.compacting = false;
+        for compacted in results {
+            if let Err(e) = self.swap_compacted(&compacted) {
+                eprintln!(
+                    "Failed to finish compacting {}: {e}",
+                    compacted.path.display()
+                );
+                let _ = fs::remove_file(&compacted.tmp);
+                let _ = fs::remove_file(tmp_path(&strings_path(&compacted.path)));
+            }
+        }
+    }
+
+    fn swap_compacted(&mut self, compacted: &Compacted) -> Result<(), HistoryError> {
+        let (did, eid, typ) = &compacted.instance;
+        let mut open = self.open_instance(did, eid, *typ);
+        if let Some(open) = &mut open {
+            open.flush()?;
+        }
+
+        let newer = HistoryReader::open(&compacted.path)?.entries(compacted.count, u64::MAX)?;
+        let mut file = HistoryFile::open(&compacted.tmp, compacted.meta.clone())?;
+        for (at, value) in &newer {
+            file.append(*at, value)?;
+        }
+        file.sync()?;
+
+        let strings_path = strings_path(&compacted.path);
+        let strings_tmp = match &compacted.live {
+            Some(live) => {
+                // also referenced by newer entries and a held back one
+                let mut live = live.clone();
+                let held = open.as_ref().and_then(|open| open.pending.as_ref());
+                let referenced = newer.iter().chain(held).map(|(_, value)| value);
+                live.extend(referenced.filter_map(|value| intern_id(value)));
+
+                let strings: Vec<_> = read_strings(&strings_path)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, string)| string.filter(|_| live.contains(&(id as InternID))))
+                    .collect();
+                let tmp = tmp_path(&strings_path);
+                write_strings(&tmp, &strings)?;
+                Some(tmp)
+            }
+            None => None,
+        };
+
+        // primary first, the old table still has every string the new file references
+        fs::rename(&compacted.tmp, &compacted.path)?;
+        if let Some(tmp) = &strings_tmp {
+            fs::rename(tmp, &strings_path)?;
+        }
+
+        if let Some(open) = open {
+            open.file = file;
+            if strings_tmp.is_some() {
+                open.strings = Some(StringTable::open(&strings_path)?);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,655 @@
+//! Exports recorded entries as CSV or JSON Lines, and imports them back
+//!
+//! Each row is one entry, with the columns:
+//!  - `timestamp` RFC 3339 in UTC (imports also take milliseconds since the Unix epoch)
+//!  - `device` name
+//!  - `device_id` (imports find the device by name when it's empty)
+//!  - `entity` EntityID
+//!  - `component` ComponentType in snake_case
+//!  - `value` as displayed (JSON Lines uses numbers and booleans where it can)
+//!
+//! CSV starts with a header row, so imports take the columns in any order.
+//!
+//! Exports only read the files, so they're fine while Igloo is running.
+//! Imports rewrite them, so they refuse while Igloo is running (like `igloo restore`).
+//! Imported entries past the instance's retention, or in the future, are skipped,
+//! and long-term statistics are left alone.
+
+use super::{
+    HISTORY_CONFIG_FILE, HistoricalInstanceMetadata, HistoryConfig, HistoryError, HistoryPolicy,
+    compact::{MAX_AGE_HOURS, MS_PER_HOUR, tmp_path},
+    file::{HistoryFile, HistoryReader, RawEntry},
+    history_dir, instance_id, parse_primary_path,
+    recorder::now_ms,
+    strings::{StringTable, read_strings, strings_path},
+    value,
+};
+use crate::{
+    DATA_DIR,
+    tree::{
+        Device, DeviceTree,
+        arena::Arena,
+        persist::{LockError, TreePersistError, lock_data_dir},
+    },
+};
+use igloo_interface::{
+    ComponentType,
+    id::{DeviceID, DeviceIDMarker, EntityID},
+    types::IglooValue,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    mem,
+    path::Path,
+};
+
+const CSV_HEADER: &str = "timestamp,device,device_id,entity,component,value\n";
+
+/// Entries read from a file at a time
+const CHUNK_LEN: u64 = 4096;
+
+/// Name of every device in the tree
+pub type DeviceNames = FxHashMap<DeviceID, String>;
+
+type Instance = (DeviceID, EntityID, ComponentType);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExportError {
+    #[error("{0}")]
+    History(#[from] HistoryError),
+    #[error("IO error: {0}")]
+    IO(#[from] io::Error),
+    #[error("Failed to load the device tree: {0}")]
+    Tree(#[from] TreePersistError),
+    #[error("{0}")]
+    Lock(#[from] LockError),
+    #[error("Missing the `{0}` column")]
+    MissingColumn(&'static str),
+    #[error("Line {line}: {reason}")]
+    BadRow { line: usize, reason: String },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+/// Which entries are exported, empty lists match everything
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+    /// Device names or IDs
+    pub devices: Vec<String>,
+    pub entities: Vec<EntityID>,
+    pub components: Vec<ComponentType>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportSummary {
+    /// Entries that weren't recorded yet
+    pub imported: u64,
+    /// Past retention or in the future
+    pub skipped: u64,
+    /// Instances entries were imported into
+    pub files: usize,
+}
+
+/// A row as it's exported
+struct Row<'a> {
+    at: u64,
+    device: &'a str,
+    device_id: &'a DeviceID,
+    entity: &'a EntityID,
+    component: ComponentType,
+    value: IglooValue,
+}
+
+/// A row as it's imported, before it's checked
+struct Fields {
+    timestamp: String,
+    device: String,
+    device_id: String,
+    entity: String,
+    component: String,
+    value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonRow {
+    timestamp: serde_json::Value,
+    #[serde(default)]
+    device: String,
+    #[serde(default)]
+    device_id: Option<String>,
+    entity: String,
+    component: String,
+    value: serde_json::Value,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Jsonl => "application/jsonl",
+        }
+    }
+
+    /// JSON Lines for `.jsonl` files, CSV otherwise
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().is_some_and(|ext| ext == "jsonl") {
+            true => ExportFormat::Jsonl,
+            false => ExportFormat::Csv,
+        }
+    }
+}
+
+impl ExportFilter {
+    fn matches(&self, (did, eid, typ): &Instance, name: &str) -> bool {
+        (self.devices.is_empty()
+            || self
+                .devices
+                .iter()
+                .any(|d| d == name || *d == did.to_string()))
+            && (self.entities.is_empty() || self.entities.contains(eid))
+            && (self.components.is_empty() || self.components.contains(typ))
+    }
+}
+
+pub fn device_names(devices: &Arena<DeviceIDMarker, Device>) -> DeviceNames {
+    devices
+        .iter()
+        .map(|device| (*device.id(), device.name().to_string()))
+        .collect()
+}
+
+/// RFC 3339 or milliseconds since the Unix epoch
+pub fn parse_time(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    if let Ok(ms) = s.parse() {
+        return Ok(ms);
+    }
+    s.parse::<jiff::Timestamp>()
+        .ok()
+        .and_then(|at| u64::try_from(at.as_millisecond()).ok())
+        .ok_or_else(|| format!("`{s}` isn't an RFC 3339 timestamp or milliseconds"))
+}
+
+pub fn parse_component(s: &str) -> Result<ComponentType, String> {
+    ComponentType::from_snake_name(s.trim()).ok_or_else(|| format!("Unknown component `{s}`"))
+}
+
+/// Writes every entry in `dir` matching `filter`, one instance at a time
+/// Files that can't be read are logged and skipped
+pub fn export(
+    dir: &Path,
+    names: &DeviceNames,
+    filter: &ExportFilter,
+    format: ExportFormat,
+    out: &mut impl Write,
+) -> Result<u64, ExportError> {
+    let mut paths: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    paths.sort();
+
+    if format == ExportFormat::Csv {
+        out.write_all(CSV_HEADER.as_bytes())?;
+    }
+
+    let mut count = 0;
+    for path in paths {
+        let Some(instance) = parse_primary_path(&path) else {
+            continue;
+        };
+        let name = names.get(&instance.0).map_or("", String::as_str);
+        if !filter.matches(&instance, name) {
+            continue;
+        }
+
+        let res = export_file(&path, &instance, name, filter, format, out);
+        match res {
+            Ok(n) => count += n,
+            // the output went away
+            Err(e @ ExportError::IO(_)) => return Err(e),
+            Err(e) => eprintln!("Failed to export history file {}: {e}", path.display()),
+        }
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+fn export_file(
+    path: &Path,
+    (did, eid, typ): &Instance,
+    name: &str,
+    filter: &ExportFilter,
+    format: ExportFormat,
+    out: &mut impl Write,
+) -> Result<u64, ExportError> {
+    let mut reader = HistoryReader::open(path)?;
+    let expected = value::entry_size(*typ).unwrap_or(0);
+    if reader.meta().entry_size_bytes != expected {
+        return Err(HistoryError::EntrySizeMismatch {
+            expected,
+            found: reader.meta().entry_size_bytes,
+        }
+        .into());
+    }
+    let strings = match value::is_interned(*typ) {
+        true => read_strings(&strings_path(path))?,
+        false => Vec::new(),
+    };
+
+    let (first, end) = reader.bounds(filter.since, filter.until)?;
+    let mut count = 0;
+    let mut line = Vec::new();
+    for start in (first..end).step_by(CHUNK_LEN as usize) {
+        for (at, bytes) in reader.entries(start, end.min(start + CHUNK_LEN))? {
+            let Some(value) = value::decode(*typ, &bytes, &strings) else {
+                continue;
+            };
+            line.clear();
+            let row = Row {
+                at,
+                device: name,
+                device_id: did,
+                entity: eid,
+                component: *typ,
+                value,
+            };
+            match format {
+                ExportFormat::Csv => write_csv(&mut line, &row),
+                ExportFormat::Jsonl => write_jsonl(&mut line, row)?,
+            }
+            out.write_all(&line)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Merges the rows of `input` into the instances' files
+/// Every row is checked first, so a bad one doesn't import anything
+pub fn import(
+    dir: &Path,
+    names: &DeviceNames,
+    config: &HistoryConfig,
+    format: ExportFormat,
+    mut input: impl Read,
+    now: u64,
+) -> Result<ImportSummary, ExportError> {
+    let mut text = String::new();
+    input.read_to_string(&mut text)?;
+    let rows = match format {
+        ExportFormat::Csv => csv_rows(&text)?,
+        ExportFormat::Jsonl => jsonl_rows(&text)?,
+    };
+
+    let mut by_name: FxHashMap<&str, Vec<DeviceID>> = FxHashMap::default();
+    for (did, name) in names {
+        by_name.entry(name.as_str()).or_default().push(*did);
+    }
+
+    let mut instances: FxHashMap<Instance, Vec<(u64, IglooValue)>> = FxHashMap::default();
+    for (line, fields) in rows {
+        let (instance, at, value) = check_row(fields, names, &by_name)
+            .map_err(|reason| ExportError::BadRow { line, reason })?;
+        instances.entry(instance).or_default().push((at, value));
+    }
+
+    let mut summary = ImportSummary::default();
+    for (instance, values) in instances {
+        let (did, eid, typ) = &instance;
+        let policy = config
+            .policy_for(did, eid, *typ)
+            .unwrap_or_else(|| config.default_policy());
+        let max_age_hours = policy
+            .max_age_hours
+            .unwrap_or(MAX_AGE_HOURS)
+            .min(MAX_AGE_HOURS);
+        let cutoff = now.saturating_sub(max_age_hours as u64 * MS_PER_HOUR);
+
+        let (kept, skipped): (Vec<_>, Vec<_>) = values
+            .into_iter()
+            .partition(|(at, _)| (cutoff..=now).contains(at));
+        summary.skipped += skipped.len() as u64;
+        if kept.is_empty() {
+            continue;
+        }
+
+        let path = dir.join(format!("{}.bin", instance_id(did, eid, *typ)));
+        summary.imported += merge(&path, *typ, &policy, kept)?;
+        summary.files += 1;
+    }
+    Ok(summary)
+}
+
+/// `igloo history export`, device names come from the saved tree
+pub fn run_export(
+    filter: &ExportFilter,
+    format: ExportFormat,
+    out: Option<&Path>,
+) -> Result<u64, ExportError> {
+    let names = device_names(&DeviceTree::read_devices()?);
+    match out {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            export(&history_dir(), &names, filter, format, &mut out)
+        }
+        None => {
+            let mut out = BufWriter::new(io::stdout().lock());
+            export(&history_dir(), &names, filter, format, &mut out)
+        }
+    }
+}
+
+/// `igloo history import`, refuses while Igloo is running
+pub fn run_import(path: &Path, format: Option<ExportFormat>) -> Result<ImportSummary, ExportError> {
+    let data_dir = DATA_DIR.get().unwrap();
+    let _lock = lock_data_dir(data_dir)?;
+    let format = format.unwrap_or_else(|| ExportFormat::from_path(path));
+    let input = BufReader::new(File::open(path)?);
+    let names = device_names(&DeviceTree::read_devices()?);
+    let config = HistoryConfig::load(&data_dir.join(HISTORY_CONFIG_FILE))?;
+    let dir = history_dir();
+    fs::create_dir_all(&dir)?;
+    import(&dir, &names, &config, format, input, now_ms())
+}
+
+/// Rewrites `path` with `values` added, returning how many weren't in it yet
+fn merge(
+    path: &Path,
+    typ: ComponentType,
+    policy: &HistoryPolicy,
+    values: Vec<(u64, IglooValue)>,
+) -> Result<u64, ExportError> {
+    let entry_size_bytes = value::entry_size(typ).unwrap_or(0);
+    let (mut entries, old) = match HistoryReader::open(path) {
+        Ok(mut reader) => {
+            if reader.meta().entry_size_bytes != entry_size_bytes {
+                return Err(HistoryError::EntrySizeMismatch {
+                    expected: entry_size_bytes,
+                    found: reader.meta().entry_size_bytes,
+                }
+                .into());
+            }
+            let count = reader.count();
+            (reader.entries(0, count)?, Some(reader.meta().clone()))
+        }
+        Err(HistoryError::IO(e)) if e.kind() == ErrorKind::NotFound => (Vec::new(), None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut strings = match value::is_interned(typ) {
+        true => Some(StringTable::open(&strings_path(path))?),
+        false => None,
+    };
+
+    // importing the same rows twice doesn't duplicate them
+    let mut seen: FxHashSet<RawEntry> = entries.iter().cloned().collect();
+    let mut added = 0;
+    for (at, value) in values {
+        let mut buf = Vec::with_capacity(entry_size_bytes as usize);
+        match (&mut strings, value::interned_str(&value)) {
+            (Some(strings), Some(string)) => {
+                buf.extend_from_slice(&strings.intern(&string)?.to_be_bytes())
+            }
+            _ => {
+                value::encode(&value, &mut buf);
+            }
+        }
+        if seen.insert((at, buf.clone())) {
+            entries.push((at, buf));
+            added += 1;
+        }
+    }
+    if added == 0 {
+        return Ok(0);
+    }
+    entries.sort_by_key(|(at, _)| *at);
+
+    // strings first, so the new entries never reference a missing one
+    if let Some(strings) = &mut strings {
+        strings.flush()?;
+    }
+
+    let first = entries[0].0;
+    let meta = match old {
+        Some(old) => HistoricalInstanceMetadata {
+            start_timestamp: old.start_timestamp.min(first),
+            ..old
+        },
+        None => HistoricalInstanceMetadata {
+            entry_size_bytes,
+            start_timestamp: first,
+            max_age_hours: policy.max_age_hours,
+            min_interval_ms: policy.min_interval_ms,
+        },
+    };
+
+    let tmp = tmp_path(path);
+    let _ = fs::remove_file(&tmp);
+    let mut file = HistoryFile::open(&tmp, meta)?;
+    for (at, value) in &entries {
+        file.append(*at, value)?;
+    }
+    file.sync()?;
+    fs::rename(&tmp, path)?;
+    Ok(added)
+}
+
+/// Turns a row into an entry, or a reason it can't be imported
+fn check_row(
+    fields: Fields,
+    names: &DeviceNames,
+    by_name: &FxHashMap<&str, Vec<DeviceID>>,
+) -> Result<(Instance, u64, IglooValue), String> {
+    let at = parse_time(&fields.timestamp)?;
+
+    let did = match fields.device_id.trim() {
+        "" => match by_name.get(fields.device.as_str()).map(Vec::as_slice) {
+            Some([did]) => *did,
+            Some(_) => {
+                return Err(format!("More than one device is named `{}`", fields.device));
+            }
+            None => return Err(format!("Unknown device `{}`", fields.device)),
+        },
+        id => id
+            .parse()
+            .ok()
+            .filter(|did| names.contains_key(did))
+            .ok_or_else(|| format!("Unknown device ID `{id}`"))?,
+    };
+
+    if fields.entity.is_empty() {
+        return Err("Missing the entity".to_string());
+    }
+
+    let typ = parse_component(&fields.component)?;
+    let igloo_type = typ
+        .igloo_type()
+        .filter(|_| value::entry_size(typ).is_some())
+        .ok_or_else(|| format!("{typ:?} can't be recorded"))?;
+    let value = IglooValue::from_string(&igloo_type, fields.value.clone())
+        .ok_or_else(|| format!("`{}` isn't a valid {typ:?}", fields.value))?;
+
+    Ok(((did, EntityID(fields.entity), typ), at, value))
+}
+
+fn format_time(at: u64) -> String {
+    jiff::Timestamp::from_millisecond(at as i64)
+        .map(|at| at.to_string())
+        .unwrap_or_else(|_| at.to_string())
+}
+
+fn write_csv(out: &mut Vec<u8>, row: &Row) {
+    let columns = [
+        &format_time(row.at),
+        row.device,
+        &row.device_id.to_string(),
+        &row.entity.0,
+        row.component.snake_name(),
+        &row.value.to_string(),
+    ];
+    for (i, column) in columns.into_iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        if column.contains([',', '"', '\n', '\r']) {
+            out.push(b'"');
+            out.extend_from_slice(column.replace('"', "\"\"").as_bytes());
+            out.push(b'"');
+        } else {
+            out.extend_from_slice(column.as_bytes());
+        }
+    }
+    out.push(b'\n');
+}
+
+fn write_jsonl(out: &mut Vec<u8>, row: Row) -> io::Result<()> {
+    let value = match row.value {
+        IglooValue::Integer(v) => v.into(),
+        IglooValue::Real(v) => serde_json::Number::from_f64(v)
+            .map_or_else(|| v.to_string().into(), serde_json::Value::Number),
+        IglooValue::Boolean(v) => v.into(),
+        value => value.to_string().into(),
+    };
+    let row = JsonRow {
+        timestamp: format_time(row.at).into(),
+        device: row.device.to_string(),
+        device_id: Some(row.device_id.to_string()),
+        entity: row.entity.0.clone(),
+        component: row.component.snake_name().to_string(),
+        value,
+    };
+    serde_json::to_writer(&mut *out, &row)?;
+    out.push(b'\n');
+    Ok(())
+}
+
+/// Rows with the line they start on, columns are found by the header
+fn csv_rows(text: &str) -> Result<Vec<(usize, Fields)>, ExportError> {
+    let mut records = csv_records(text).into_iter();
+    let Some((_, header)) = records.next() else {
+        return Ok(Vec::new());
+    };
+    let column = |name: &str| header.iter().position(|h| h.trim() == name);
+
+    let required = |name: &'static str| column(name).ok_or(ExportError::MissingColumn(name));
+    let timestamp = required("timestamp")?;
+    let entity = required("entity")?;
+    let component = required("component")?;
+    let value = required("value")?;
+    let device = column("device");
+    let device_id = column("device_id");
+    if device.is_none() && device_id.is_none() {
+        return Err(ExportError::MissingColumn("device"));
+    }
+
+    Ok(records
+        .map(|(line, mut record)| {
+            let mut take = |i: Option<usize>| {
+                i.and_then(|i| record.get_mut(i))
+                    .map(mem::take)
+                    .unwrap_or_default()
+            };
+            let fields = Fields {
+                timestamp: take(Some(timestamp)),
+                device: take(device),
+                device_id: take(device_id),
+                entity: take(Some(entity)),
+                component: take(Some(component)),
+                value: take(Some(value)),
+            };
+            (line, fields)
+        })
+        .collect())
+}
+
+/// RFC 4180 records with the line they start on, skipping blank lines
+fn csv_records(text: &str) -> Vec<(usize, Vec<String>)> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let (mut line, mut start) = (1, 1);
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => record.push(mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                record.push(mem::take(&mut field));
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push((start, mem::take(&mut record)));
+                }
+                record.clear();
+                line += 1;
+                start = line;
+            }
+            (_, c) => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((start, record));
+    }
+    records
+}
+
+fn jsonl_rows(text: &str) -> Result<Vec<(usize, Fields)>, ExportError> {
+    let json_text = |value| match value {
+        serde_json::Value::String(s) => s,
+        value => value.to_string(),
+    };
+
+    let mut rows = Vec::new();
+    for (i, row) in text.lines().enumerate() {
+        if row.trim().is_empty() {
+            continue;
+        }
+        let row: JsonRow = serde_json::from_str(row).map_err(|e| ExportError::BadRow {
+            line: i + 1,
+            reason: e.to_string(),
+        })?;
+        let fields = Fields {
+            timestamp: json_text(row.timestamp),
+            device: row.device,
+            device_id: row.device_id.unwrap_or_default(),
+            entity: row.entity,
+            component: row.component,
+            value: json_text(row.value),
+        };
+        rows.push((i + 1, fields));
+    }
+    Ok(rows)
+}
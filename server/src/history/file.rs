@@ -0,0 +1,275 @@
+//! Reading and appending to an instance's primary file
+
+use super::{HistoricalInstanceMetadata, HistoryError, VERSION};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Bytes before the value of each entry
+pub const OFFSET_SIZE: u64 = 4;
+
+/// (milliseconds since the Unix epoch, encoded value)
+pub type RawEntry = (u64, Vec<u8>);
+
+pub struct HistoryFile {
+    meta: HistoricalInstanceMetadata,
+    writer: BufWriter<File>,
+    /// Milliseconds since the Unix epoch of the newest entry
+    last_at: Option<u64>,
+}
+
+impl HistoryFile {
+    /// Opens `path`, creating it with `meta` if it doesn't exist
+    /// A partial entry left by a crash is truncated, and a file without
+    /// a complete header is kept as `<file>.corrupt` and started over
+    pub fn open(path: &Path, meta: HistoricalInstanceMetadata) -> Result<Self, HistoryError> {
+        match Self::open_existing(path, &meta) {
+            Err(e @ (HistoryError::MissingHeader | HistoryError::Metadata(_))) => {
+                eprintln!(
+                    "History file {} is corrupt ({e}), starting over",
+                    path.display()
+                );
+                fs::rename(path, corrupt_path(path))?;
+                Self::open_existing(path, &meta)
+            }
+            res => res,
+        }
+    }
+
+    fn open_existing(path: &Path, meta: &HistoricalInstanceMetadata) -> Result<Self, HistoryError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            file.write_all(&encode_header(meta)?)?;
+            return Ok(Self {
+                meta: meta.clone(),
+                writer: BufWriter::new(file),
+                last_at: None,
+            });
+        }
+
+        let (found, header_len) = read_header(&mut file)?;
+        if found.entry_size_bytes != meta.entry_size_bytes {
+            return Err(HistoryError::EntrySizeMismatch {
+                expected: meta.entry_size_bytes,
+                found: found.entry_size_bytes,
+            });
+        }
+
+        let stride = OFFSET_SIZE + found.entry_size_bytes as u64;
+        let len = file.metadata()?.len();
+        let torn = (len - header_len) % stride;
+        if torn != 0 {
+            eprintln!(
+                "History file {} has a partial entry, truncating it",
+                path.display()
+            );
+            file.set_len(len - torn)?;
+        }
+
+        let count = (len - header_len) / stride;
+        let last_at = match count {
+            0 => None,
+            _ => {
+                file.seek(SeekFrom::Start(header_len + (count - 1) * stride))?;
+                let mut offset = [0; OFFSET_SIZE as usize];
+                file.read_exact(&mut offset)?;
+                Some(found.start_timestamp + u32::from_be_bytes(offset) as u64)
+            }
+        };
+
+        Ok(Self {
+            meta: found,
+            writer: BufWriter::new(file),
+            last_at,
+        })
+    }
+
+    pub fn last_at(&self) -> Option<u64> {
+        self.last_at
+    }
+
+    /// Buffered, see [Self::flush]
+    /// Entries stay in time order, even if the clock goes backwards
+    pub fn append(&mut self, at: u64, value: &[u8]) -> Result<(), HistoryError> {
+        debug_assert_eq!(value.len(), self.meta.entry_size_bytes as usize);
+
+        let at = at.max(self.last_at.unwrap_or(0));
+        let offset = u32::try_from(at.saturating_sub(self.meta.start_timestamp))
+            .map_err(|_| HistoryError::OffsetOverflow)?;
+
+        self.writer.write_all(&offset.to_be_bytes())?;
+        self.writer.write_all(value)?;
+        self.last_at = Some(at);
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Flushes, then waits until it's on disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+}
+
+/// Reads the entries in a time range, without loading the whole file
+pub struct HistoryReader {
+    file: File,
+    meta: HistoricalInstanceMetadata,
+    header_len: u64,
+    /// Complete entries, a partial one at the end is skipped
+    count: u64,
+}
+
+impl HistoryReader {
+    pub fn open(path: &Path) -> Result<Self, HistoryError> {
+        let mut file = File::open(path)?;
+        let (meta, header_len) = read_header(&mut file)?;
+        let stride = OFFSET_SIZE + meta.entry_size_bytes as u64;
+        let count = (file.metadata()?.len() - header_len) / stride;
+        Ok(Self {
+            file,
+            meta,
+            header_len,
+            count,
+        })
+    }
+
+    pub fn meta(&self) -> &HistoricalInstanceMetadata {
+        &self.meta
+    }
+
+    /// Number of complete entries when it was opened
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Entries where `since <= at < until`, oldest first
+    pub fn range(
+        &mut self,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Vec<RawEntry>, HistoryError> {
+        let (first, end) = self.bounds(since, until)?;
+        self.entries(first, end)
+    }
+
+    /// Indexes of the entries where `since <= at < until`, as `first..end`
+    pub fn bounds(
+        &mut self,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<(u64, u64), HistoryError> {
+        let first = match since {
+            Some(at) => self.lower_bound(at)?,
+            None => 0,
+        };
+        let end = match until {
+            Some(at) => self.lower_bound(at)?,
+            None => self.count,
+        };
+        Ok((first, end))
+    }
+
+    /// Entries with an index in `first..end`
+    pub fn entries(&mut self, first: u64, end: u64) -> Result<Vec<RawEntry>, HistoryError> {
+        let end = end.min(self.count);
+        if first >= end {
+            return Ok(Vec::new());
+        }
+
+        let stride = self.stride();
+        self.file
+            .seek(SeekFrom::Start(self.header_len + first * stride))?;
+        let mut body = vec![0; ((end - first) * stride) as usize];
+        self.file.read_exact(&mut body)?;
+
+        Ok(body
+            .chunks_exact(stride as usize)
+            .map(|entry| {
+                let (offset, value) = entry.split_at(OFFSET_SIZE as usize);
+                let offset = u32::from_be_bytes(offset.try_into().unwrap());
+                (self.meta.start_timestamp + offset as u64, value.to_vec())
+            })
+            .collect())
+    }
+
+    /// Index of the first entry at or after `at`
+    fn lower_bound(&mut self, at: u64) -> io::Result<u64> {
+        let Some(target) = at.checked_sub(self.meta.start_timestamp) else {
+            return Ok(0);
+        };
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if (self.offset_of(mid)? as u64) < target {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(lo)
+    }
+
+    fn offset_of(&mut self, index: u64) -> io::Result<u32> {
+        let pos = self.header_len + index * self.stride();
+        self.file.seek(SeekFrom::Start(pos))?;
+        let mut offset = [0; OFFSET_SIZE as usize];
+        self.file.read_exact(&mut offset)?;
+        Ok(u32::from_be_bytes(offset))
+    }
+
+    fn stride(&self) -> u64 {
+        OFFSET_SIZE + self.meta.entry_size_bytes as u64
+    }
+}
+
+fn encode_header(meta: &HistoricalInstanceMetadata) -> Result<Vec<u8>, HistoryError> {
+    let meta = postcard::to_stdvec(meta)?;
+    let mut header = Vec::with_capacity(6 + meta.len());
+    header.extend_from_slice(&VERSION.to_be_bytes());
+    header.extend_from_slice(&(meta.len() as u32).to_be_bytes());
+    header.extend_from_slice(&meta);
+    Ok(header)
+}
+
+/// Returns the metadata and the length of the header, leaving `file` after it
+fn read_header(file: &mut File) -> Result<(HistoricalInstanceMetadata, u64), HistoryError> {
+    let missing = |e: io::Error| match e.kind() {
+        ErrorKind::UnexpectedEof => HistoryError::MissingHeader,
+        _ => HistoryError::IO(e),
+    };
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut fixed = [0; 6];
+    file.read_exact(&mut fixed).map_err(missing)?;
+
+    let version = u16::from_be_bytes([fixed[0], fixed[1]]);
+    if version > VERSION {
+        return Err(HistoryError::NewerVersion(version));
+    }
+
+    let meta_len = u32::from_be_bytes([fixed[2], fixed[3], fixed[4], fixed[5]]) as u64;
+    let mut meta = Vec::new();
+    Read::by_ref(file).take(meta_len).read_to_end(&mut meta)?;
+    if (meta.len() as u64) < meta_len {
+        return Err(HistoryError::MissingHeader);
+    }
+
+    Ok((postcard::from_bytes(&meta)?, 6 + meta_len))
+}
+
+fn corrupt_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".corrupt");
+    PathBuf::from(name)
+}
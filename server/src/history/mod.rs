@@ -1,5 +1,9 @@
+//! Records component values over time, so they can be graphed
 //!
-//!
+//! Which (device, entity, component) instances are recorded, and for how long,
+//! is chosen by [HistoryConfig] (`history.toml` in `DATA_DIR`). Nothing is
+//! recorded by default. Old entries are dropped and rolled up by [compact].
+//! Entries can be exported to, and imported from, CSV or JSON Lines (see [export]).
 //!
 //! # File structure
 //!
@@ -7,6 +11,8 @@
 //! data/history/
 //!   {ID}.bin* (primary file)
 //!   {ID}_strings.bin* (optional, string intern table)
+//!   {ID}_hourly.bin* (optional, statistics)
+//!   {ID}_daily.bin* (optional, statistics)
 //! ```
 //!
 //! Where `{ID}` is the following joined by `_`:
@@ -23,22 +29,221 @@
 //!    - [u32] offset from `start_timestamp` in metadata
 //!    - [_] value of component
 //!
+//! All integers are BE. Entries are fixed size (see [value::entry_size]) and in
+//! time order, so they can be binary searched. A crash can leave a partial entry
+//! at the end, which is truncated the next time the file is opened.
+//!
+//! Offsets overflow about 49 days after `start_timestamp`, so compaction moves
+//! it up to the oldest kept entry and retention is capped at [compact::MAX_AGE_HOURS].
+//!
 //! ## String Intern Table Format
+//! Text and Enum components (Enums by variant name) are recorded as a [u32]
+//! InternID into their table, so repeated values only take 4 bytes.
+//!  - [u16] major version
+//!  - String*, where each string is:
+//!    - [u16] length in bytes, or [strings::TOMBSTONE] if it was garbage collected
+//!    - [_] UTF-8 (capped to [strings::MAX_STRING_LEN] bytes)
+//!
+//! The InternID is the string's index, so the table is append-only and IDs are
+//! stable. A crash can leave a partial string at the end, which is truncated.
+//! Duplicates resolve to their first ID when it's loaded.
 //!
+//! Compaction replaces strings no entry references anymore with tombstones,
+//! then swaps in the primary file before the table, so every entry always
+//! references a string.
+//!
+//! ## Statistics Format
+//! Integer and Real components also get hourly and daily statistics (see [stats]),
+//! which compaction leaves alone so they're kept indefinitely.
+//!  - [u16] major version
+//!  - Row*, one per period with values, where each row is:
+//!    - [u64] period start, milliseconds since the Unix epoch
+//!    - [f64] min, max, sum
+//!    - [u64] count
+//!    - [f64] change (see [igloo_interface::query::StatisticsRow::change])
+//!    - [f64] newest value
+//!
+//! The newest row is rewritten in place until its period ends.
 //!
 //! # Versioning
-//!  
+//! Files with a newer major version than [VERSION] are left alone and not recorded to.
+
+use crate::DATA_DIR;
+use igloo_interface::{
+    ComponentType,
+    id::{DeviceID, EntityID, MAX_ENTITY_ID_LENGTH},
+    types::agg::AggregationOp,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub mod compact;
+pub mod export;
+pub mod file;
+pub mod recorder;
+pub mod stats;
+pub mod strings;
+pub mod value;
 
 pub const VERSION: u16 = 0;
 
-struct HistoricalInstanceMetadata {
+/// Directory in `DATA_DIR`
+pub const HISTORY_DIR: &str = "history";
+pub const HISTORY_CONFIG_FILE: &str = "history.toml";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoricalInstanceMetadata {
     /// Sanity check
     /// For primitive components, this will be the size of serialized data
     /// For string|enum components, this will be the size of the InternID
-    entry_size_bytes: u16,
+    pub entry_size_bytes: u16,
+
+    /// Milliseconds since the Unix epoch, which entry offsets are relative to
+    pub start_timestamp: u64,
+    /// Policy as of the last compaction, [HistoryConfig] is what's enforced
+    pub max_age_hours: Option<u32>,
+    pub min_interval_ms: u32,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HistoryError {
+    #[error("IO error: {0}")]
+    IO(#[from] io::Error),
+    #[error("Bad metadata: {0}")]
+    Metadata(#[from] postcard::Error),
+    #[error("`{HISTORY_CONFIG_FILE}`: {0}")]
+    Config(#[from] toml::de::Error),
+    #[error("File is from a newer version ({0})")]
+    NewerVersion(u16),
+    #[error("Entries are {found} bytes, but {expected} were expected")]
+    EntrySizeMismatch { expected: u16, found: u16 },
+    #[error("File is missing its header")]
+    MissingHeader,
+    #[error("Entry is too far past the file's start timestamp")]
+    OffsetOverflow,
+    #[error("String intern table is full")]
+    StringTableFull,
+}
+
+/// Contents of [HISTORY_CONFIG_FILE]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// For rules without their own
+    pub min_interval_ms: u32,
+    /// For rules without their own, and files no rule records anymore
+    /// `None` keeps entries for [compact::MAX_AGE_HOURS]
+    pub max_age_hours: Option<u32>,
+    /// For rules without their own, and files no rule records anymore
+    pub rollups: Vec<Rollup>,
+    /// First matching rule is used, so put per entity rules before per type ones
+    pub record: Vec<RecordRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordRule {
+    /// Empty matches every device
+    #[serde(default)]
+    pub devices: Vec<DeviceID>,
+    /// Empty matches every entity
+    #[serde(default)]
+    pub entities: Vec<EntityID>,
+    pub components: Vec<ComponentType>,
+    pub min_interval_ms: Option<u32>,
+    pub max_age_hours: Option<u32>,
+    pub rollups: Option<Vec<Rollup>>,
+}
+
+/// Downsamples entries once they're older than `after_hours`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Rollup {
+    pub after_hours: u32,
+    /// Keeps one entry per interval (aligned to the Unix epoch)
+    pub interval_ms: u32,
+    /// `None`, or an op the component doesn't support, keeps the newest value
+    pub op: Option<AggregationOp>,
+}
+
+/// How an instance is recorded and kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryPolicy {
+    pub min_interval_ms: u32,
+    pub max_age_hours: Option<u32>,
+    pub rollups: Vec<Rollup>,
+}
+
+impl HistoryConfig {
+    /// Missing file records nothing
+    pub fn load(path: &Path) -> Result<Self, HistoryError> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(toml::from_str(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Policy of the first matching rule, `None` if it isn't recorded
+    pub fn policy_for(
+        &self,
+        did: &DeviceID,
+        eid: &EntityID,
+        typ: ComponentType,
+    ) -> Option<HistoryPolicy> {
+        let rule = self.record.iter().find(|rule| {
+            rule.components.contains(&typ)
+                && (rule.devices.is_empty() || rule.devices.contains(did))
+                && (rule.entities.is_empty() || rule.entities.contains(eid))
+        })?;
+        Some(HistoryPolicy {
+            min_interval_ms: rule.min_interval_ms.unwrap_or(self.min_interval_ms),
+            max_age_hours: rule.max_age_hours.or(self.max_age_hours),
+            rollups: rule.rollups.clone().unwrap_or_else(|| self.rollups.clone()),
+        })
+    }
+
+    /// For instances without a matching rule
+    pub fn default_policy(&self) -> HistoryPolicy {
+        HistoryPolicy {
+            min_interval_ms: self.min_interval_ms,
+            max_age_hours: self.max_age_hours,
+            rollups: self.rollups.clone(),
+        }
+    }
+}
+
+/// `DATA_DIR/history`
+pub fn history_dir() -> PathBuf {
+    DATA_DIR.get().unwrap().join(HISTORY_DIR)
+}
+
+/// `{ID}` in the file structure
+pub fn instance_id(did: &DeviceID, eid: &EntityID, typ: ComponentType) -> String {
+    let bytes = eid.0.as_bytes();
+    let bytes = &bytes[..bytes.len().min(MAX_ENTITY_ID_LENGTH)];
+    format!(
+        "{did}_{}_{}",
+        bs58::encode(bytes).into_string(),
+        typ.snake_name()
+    )
+}
+
+/// Instance of a primary file (`{ID}.bin`), `None` for every other file
+pub fn parse_primary_path(path: &Path) -> Option<(DeviceID, EntityID, ComponentType)> {
+    if path.extension().and_then(OsStr::to_str) != Some("bin") {
+        return None;
+    }
+    parse_instance_id(path.file_stem()?.to_str()?)
+}
 
-    ///
-    start_timestamp: u64,
-    max_age_hours: Option<u32>,
-    min_interval_ms: u32,
+/// Reverses [instance_id], the EntityID may have been capped
+pub fn parse_instance_id(id: &str) -> Option<(DeviceID, EntityID, ComponentType)> {
+    let mut parts = id.splitn(3, '_');
+    let did = parts.next()?.parse().ok()?;
+    let eid = String::from_utf8(bs58::decode(parts.next()?).into_vec().ok()?).ok()?;
+    let typ = ComponentType::from_snake_name(parts.next()?)?;
+    Some((did, EntityID(eid), typ))
 }
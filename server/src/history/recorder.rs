@@ -0,0 +1,410 @@
+//! Records the component writes chosen by [HistoryConfig]
+//!
+//! Fed by the QueryEngine's dispatch (see [crate::query::watch::dispatch]),
+//! so it sees every value reported by an Extension.
+//!
+//! Values arriving within `min_interval_ms` of the last entry are held back.
+//! The newest one is written once the interval has passed (keeping when it
+//! arrived), so the final value of a burst isn't lost.
+
+use super::{
+    HISTORY_CONFIG_FILE, HistoricalInstanceMetadata, HistoryConfig, HistoryError,
+    file::{HistoryFile, HistoryReader},
+    history_dir, instance_id,
+    stats::{StatsRow, StatsTable, read_stats, stats_path},
+    strings::{StringTable, read_strings, strings_path},
+    value,
+};
+use crate::{DATA_DIR, tree::Device};
+use igloo_interface::{
+    Component, ComponentType, SensorStateClass,
+    id::{DeviceID, EntityID, EntityIndex},
+    query::StatisticsPeriod,
+    types::IglooValue,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{
+    fs, io, mem,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Max time entries can sit in the write buffers (also flushed on shutdown)
+pub const HISTORY_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct HistoryRecorder {
+    /// `None` doesn't record anything
+    dir: Option<PathBuf>,
+    config: HistoryConfig,
+    /// In any rule, so the rest are skipped quickly
+    types: FxHashSet<ComponentType>,
+    /// `None` for instances that aren't recorded
+    instances: FxHashMap<DeviceID, FxHashMap<EntityID, FxHashMap<ComponentType, Option<Instance>>>>,
+    flushed: Instant,
+    buf: Vec<u8>,
+    /// See [super::compact]
+    pub(super) compacting: bool,
+}
+
+pub(super) struct Instance {
+    pub(super) file: HistoryFile,
+    /// For Text and Enum components
+    pub(super) strings: Option<StringTable>,
+    /// Hourly then daily, for Integer and Real components
+    stats: Vec<StatsTable>,
+    min_interval_ms: u32,
+    /// Held back by `min_interval_ms`, (arrived at, encoded value)
+    pub(super) pending: Option<(u64, Vec<u8>)>,
+}
+
+impl HistoryRecorder {
+    pub fn new(dir: Option<PathBuf>, config: HistoryConfig) -> Self {
+        let types = config
+            .record
+            .iter()
+            .flat_map(|rule| rule.components.iter().copied())
+            .collect();
+        Self {
+            dir,
+            config,
+            types,
+            instances: FxHashMap::default(),
+            flushed: Instant::now(),
+            buf: Vec::with_capacity(24),
+            compacting: false,
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(None, HistoryConfig::default())
+    }
+
+    /// Reads [HISTORY_CONFIG_FILE] from `DATA_DIR`
+    pub fn load() -> Result<Self, HistoryError> {
+        let config = HistoryConfig::load(&DATA_DIR.get().unwrap().join(HISTORY_CONFIG_FILE))?;
+        let dir = history_dir();
+        if !config.record.is_empty() {
+            fs::create_dir_all(&dir)?;
+        }
+        Ok(Self::new(Some(dir), config))
+    }
+
+    /// Failing to record is logged, but doesn't fail the write
+    pub fn on_component(&mut self, device: &Device, eindex: EntityIndex, comp: &Component) {
+        let typ = comp.get_type();
+        if !self.types.contains(&typ) {
+            return;
+        }
+        let Some(entity) = device.entities().get(eindex.0) else {
+            return;
+        };
+
+        let increasing = matches!(
+            entity.get(ComponentType::SensorStateClass),
+            Some(Component::SensorStateClass(
+                SensorStateClass::TotalIncreasing
+            ))
+        );
+
+        let mut buf = mem::take(&mut self.buf);
+        buf.clear();
+        if let Some(value) = comp.to_igloo_value()
+            && let Some(instance) = self.instance(device.id(), entity.id(), typ)
+        {
+            let now = now_ms();
+            let res = instance
+                .record(now, &value, &mut buf)
+                .and_then(|_| instance.record_stats(now, &value, increasing));
+            if let Err(e) = res {
+                eprintln!(
+                    "Failed to record history of {typ:?} on {}: {e}",
+                    device.id()
+                );
+            }
+        }
+        self.buf = buf;
+    }
+
+    /// Writes held back values that are due, and flushes every [HISTORY_FLUSH_INTERVAL]
+    pub fn on_tick(&mut self, now: Instant) {
+        if now.duration_since(self.flushed) >= HISTORY_FLUSH_INTERVAL {
+            self.flush(false);
+            self.flushed = now;
+        }
+    }
+
+    /// `force` writes held back values even if they aren't due (ex. on shutdown)
+    pub fn flush(&mut self, force: bool) {
+        let now = now_ms();
+        for instance in self.instances_mut() {
+            if let Err(e) = instance.write_pending(now, force) {
+                eprintln!("Failed to write history: {e}");
+            }
+            if let Err(e) = instance.flush() {
+                eprintln!("Failed to flush history: {e}");
+            }
+        }
+    }
+
+    /// Recorded values where `since <= at < until`, oldest first
+    /// Values held back by `min_interval_ms` aren't included yet
+    pub fn read(
+        &mut self,
+        did: &DeviceID,
+        eid: &EntityID,
+        typ: ComponentType,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Vec<(u64, IglooValue)>, HistoryError> {
+        let (Some(dir), Some(entry_size_bytes)) = (&self.dir, value::entry_size(typ)) else {
+            return Ok(Vec::new());
+        };
+        let path = dir.join(format!("{}.bin", instance_id(did, eid, typ)));
+
+        // make buffered entries readable
+        if let Some(instance) = self.open_instance(did, eid, typ) {
+            instance.flush()?;
+        }
+
+        let mut reader = match HistoryReader::open(&path) {
+            Ok(reader) => reader,
+            Err(HistoryError::IO(e)) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+        };
+        if reader.meta().entry_size_bytes != entry_size_bytes {
+            return Err(HistoryError::EntrySizeMismatch {
+                expected: entry_size_bytes,
+                found: reader.meta().entry_size_bytes,
+            });
+        }
+
+        let strings = match value::is_interned(typ) {
+            true => read_strings(&strings_path(&path))?,
+            false => Vec::new(),
+        };
+
+        Ok(reader
+            .range(since, until)?
+            .into_iter()
+            .filter_map(|(at, bytes)| Some((at, value::decode(typ, &bytes, &strings)?)))
+            .collect())
+    }
+
+    /// Rows of the `period` table starting in `since..until`, oldest first
+    pub fn read_stats(
+        &mut self,
+        did: &DeviceID,
+        eid: &EntityID,
+        typ: ComponentType,
+        period: StatisticsPeriod,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Vec<StatsRow>, HistoryError> {
+        let Some(dir) = &self.dir else {
+            return Ok(Vec::new());
+        };
+        let path = stats_path(
+            &dir.join(format!("{}.bin", instance_id(did, eid, typ))),
+            period,
+        );
+
+        if let Some(instance) = self.open_instance(did, eid, typ) {
+            instance.flush()?;
+        }
+        read_stats(&path, since, until)
+    }
+
+    /// Makes every written entry readable from the files, without writing held back values
+    pub fn flush_buffers(&mut self) {
+        for instance in self.instances_mut() {
+            if let Err(e) = instance.flush() {
+                eprintln!("Failed to flush history: {e}");
+            }
+        }
+    }
+
+    /// Flushes every file for [super::compact::run]
+    /// `None` if it isn't recording or a compaction is already running
+    pub fn begin_compaction(&mut self) -> Option<(PathBuf, HistoryConfig)> {
+        let dir = self.dir.clone()?;
+        if self.compacting {
+            return None;
+        }
+        self.flush_buffers();
+        self.compacting = true;
+        Some((dir, self.config.clone()))
+    }
+
+    /// Without opening it
+    pub(super) fn open_instance(
+        &mut self,
+        did: &DeviceID,
+        eid: &EntityID,
+        typ: ComponentType,
+    ) -> Option<&mut Instance> {
+        self.instances
+            .get_mut(did)?
+            .get_mut(eid)?
+            .get_mut(&typ)?
+            .as_mut()
+    }
+
+    fn instances_mut(&mut self) -> impl Iterator<Item = &mut Instance> {
+        self.instances
+            .values_mut()
+            .flat_map(|entities| entities.values_mut())
+            .flat_map(|comps| comps.values_mut())
+            .flatten()
+    }
+
+    /// Opens it on first use, `None` if it isn't recorded
+    fn instance(
+        &mut self,
+        did: &DeviceID,
+        eid: &EntityID,
+        typ: ComponentType,
+    ) -> Option<&mut Instance> {
+        let entities = self.instances.entry(*did).or_default();
+        if !entities.contains_key(eid) {
+            entities.insert(eid.clone(), FxHashMap::default());
+        }
+        entities
+            .get_mut(eid)
+            .unwrap()
+            .entry(typ)
+            .or_insert_with(|| Instance::open(self.dir.as_ref()?, &self.config, did, eid, typ))
+            .as_mut()
+    }
+}
+
+impl Instance {
+    fn open(
+        dir: &Path,
+        config: &HistoryConfig,
+        did: &DeviceID,
+        eid: &EntityID,
+        typ: ComponentType,
+    ) -> Option<Self> {
+        let policy = config.policy_for(did, eid, typ)?;
+        let Some(entry_size_bytes) = value::entry_size(typ) else {
+            eprintln!("History can't record {typ:?} yet, skipping it on {did} {eid}");
+            return None;
+        };
+
+        let meta = HistoricalInstanceMetadata {
+            entry_size_bytes,
+            start_timestamp: now_ms(),
+            max_age_hours: policy.max_age_hours,
+            min_interval_ms: policy.min_interval_ms,
+        };
+        let path = dir.join(format!("{}.bin", instance_id(did, eid, typ)));
+        let open = || -> Result<_, HistoryError> {
+            let strings = match value::is_interned(typ) {
+                true => Some(StringTable::open(&strings_path(&path))?),
+                false => None,
+            };
+            let stats = match value::has_stats(typ) {
+                true => [StatisticsPeriod::Hour, StatisticsPeriod::Day]
+                    .into_iter()
+                    .map(|period| StatsTable::open(&stats_path(&path, period), period))
+                    .collect::<Result<_, _>>()?,
+                false => Vec::new(),
+            };
+            Ok((HistoryFile::open(&path, meta)?, strings, stats))
+        };
+        match open() {
+            Ok((file, strings, stats)) => Some(Self {
+                file,
+                strings,
+                stats,
+                min_interval_ms: policy.min_interval_ms,
+                pending: None,
+            }),
+            Err(e) => {
+                eprintln!("Failed to open history file {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    /// `buf` is scratch space for encoding
+    fn record(
+        &mut self,
+        at: u64,
+        value: &IglooValue,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), HistoryError> {
+        let encoded = match (&mut self.strings, value::interned_str(value)) {
+            (Some(strings), Some(string)) => {
+                buf.extend_from_slice(&strings.intern(&string)?.to_be_bytes());
+                true
+            }
+            (Some(_), None) => false,
+            (None, _) => value::encode(value, buf),
+        };
+        if !encoded {
+            return Ok(());
+        }
+
+        self.write_pending(at, false)?;
+        if self.is_due(at) {
+            self.pending = None;
+            self.file.append(at, buf)
+        } else {
+            self.pending = Some((at, buf.clone()));
+            Ok(())
+        }
+    }
+
+    fn record_stats(
+        &mut self,
+        at: u64,
+        value: &IglooValue,
+        increasing: bool,
+    ) -> Result<(), HistoryError> {
+        let value = match value {
+            IglooValue::Integer(v) => *v as f64,
+            IglooValue::Real(v) => *v,
+            _ => return Ok(()),
+        };
+        for table in &mut self.stats {
+            table.push(at, value, increasing)?;
+        }
+        Ok(())
+    }
+
+    /// Strings first, so entries rarely reference a string that isn't on disk yet
+    /// (those are skipped when read)
+    pub(super) fn flush(&mut self) -> Result<(), HistoryError> {
+        if let Some(strings) = &mut self.strings {
+            strings.flush()?;
+        }
+        for table in &mut self.stats {
+            table.flush()?;
+        }
+        Ok(self.file.flush()?)
+    }
+
+    fn write_pending(&mut self, now: u64, force: bool) -> Result<(), HistoryError> {
+        if self.pending.is_some()
+            && (force || self.is_due(now))
+            && let Some((at, value)) = self.pending.take()
+        {
+            self.file.append(at, &value)?;
+        }
+        Ok(())
+    }
+
+    fn is_due(&self, now: u64) -> bool {
+        self.file
+            .last_at()
+            .is_none_or(|last| now >= last + self.min_interval_ms as u64)
+    }
+}
+
+/// Milliseconds since the Unix epoch
+pub fn now_ms() -> u64 {
+    jiff::Timestamp::now().as_millisecond() as u64
+}
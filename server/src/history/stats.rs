@@ -0,0 +1,250 @@
+//! Long-term hourly and daily statistics of Integer and Real components
+//!
+//! Updated as each value arrives (including ones the recorder holds back),
+//! and never compacted, so they outlive the instance's raw history.
+
+use super::{HistoryError, VERSION};
+use igloo_interface::query::{StatisticsPeriod, StatisticsRow};
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const HEADER_SIZE: u64 = 2;
+const ROW_SIZE: u64 = 7 * 8;
+
+/// A period of a statistics table
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsRow {
+    /// Milliseconds since the Unix epoch
+    pub start: u64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u64,
+    pub change: f64,
+    /// Newest value, so `change` carries across periods and restarts
+    pub last: f64,
+}
+
+/// Statistics of one period length
+/// The newest row is rewritten in place until its period ends
+pub struct StatsTable {
+    file: File,
+    period_ms: u64,
+    /// Index of `current` in the file
+    index: u64,
+    current: Option<StatsRow>,
+    dirty: bool,
+}
+
+impl StatsTable {
+    /// Opens `path`, creating it if it doesn't exist
+    /// A partial row left by a crash is truncated
+    pub fn open(path: &Path, period: StatisticsPeriod) -> Result<Self, HistoryError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let len = file.metadata()?.len();
+        if len == 0 {
+            file.write_all(&VERSION.to_be_bytes())?;
+        } else {
+            read_version(&mut file)?;
+        }
+
+        let rows = len.saturating_sub(HEADER_SIZE) / ROW_SIZE;
+        if len > HEADER_SIZE && HEADER_SIZE + rows * ROW_SIZE != len {
+            eprintln!(
+                "Statistics {} has a partial row, truncating it",
+                path.display()
+            );
+            file.set_len(HEADER_SIZE + rows * ROW_SIZE)?;
+        }
+
+        let current = match rows {
+            0 => None,
+            _ => {
+                file.seek(SeekFrom::Start(HEADER_SIZE + (rows - 1) * ROW_SIZE))?;
+                let mut row = [0; ROW_SIZE as usize];
+                file.read_exact(&mut row)?;
+                Some(decode_row(&row))
+            }
+        };
+
+        Ok(Self {
+            file,
+            period_ms: period_ms(period),
+            index: rows.saturating_sub(1),
+            current,
+            dirty: false,
+        })
+    }
+
+    /// `increasing` treats a drop as a counter reset (`SensorStateClass::TotalIncreasing`)
+    pub fn push(&mut self, at: u64, value: f64, increasing: bool) -> Result<(), HistoryError> {
+        let start = at / self.period_ms * self.period_ms;
+
+        // same period, or the clock went backwards
+        if let Some(row) = &mut self.current
+            && start <= row.start
+        {
+            row.min = row.min.min(value);
+            row.max = row.max.max(value);
+            row.sum += value;
+            row.count += 1;
+            row.change += change(row.last, value, increasing);
+            row.last = value;
+            self.dirty = true;
+            return Ok(());
+        }
+
+        let last = self.current.as_ref().map(|row| row.last);
+        if last.is_some() {
+            self.flush()?;
+            self.index += 1;
+        }
+        let mut row = StatsRow::new(start, value);
+        row.change = last.map_or(0., |last| change(last, value, increasing));
+        self.current = Some(row);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Writes the current row, if it changed
+    pub fn flush(&mut self) -> Result<(), HistoryError> {
+        if self.dirty
+            && let Some(current) = &self.current
+        {
+            self.file
+                .seek(SeekFrom::Start(HEADER_SIZE + self.index * ROW_SIZE))?;
+            self.file.write_all(&encode_row(current))?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl StatsRow {
+    fn new(start: u64, value: f64) -> Self {
+        Self {
+            start,
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+            change: 0.,
+            last: value,
+        }
+    }
+}
+
+impl From<StatsRow> for StatisticsRow {
+    fn from(row: StatsRow) -> Self {
+        Self {
+            start: row.start,
+            min: row.min,
+            max: row.max,
+            mean: row.sum / row.count as f64,
+            sum: row.sum,
+            count: row.count,
+            change: row.change,
+        }
+    }
+}
+
+/// `{ID}_hourly.bin` or `{ID}_daily.bin` for `{ID}.bin`
+pub fn stats_path(primary: &Path, period: StatisticsPeriod) -> PathBuf {
+    let stem = primary.file_stem().unwrap_or_default().to_string_lossy();
+    let suffix = match period {
+        StatisticsPeriod::Hour => "hourly",
+        StatisticsPeriod::Day => "daily",
+    };
+    primary.with_file_name(format!("{stem}_{suffix}.bin"))
+}
+
+/// Rows starting in `since..until`, oldest first, empty if it doesn't exist
+pub fn read_stats(
+    path: &Path,
+    since: Option<u64>,
+    until: Option<u64>,
+) -> Result<Vec<StatsRow>, HistoryError> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    read_version(&mut file)?;
+
+    let mut body = Vec::new();
+    file.read_to_end(&mut body)?;
+    Ok(body
+        .chunks_exact(ROW_SIZE as usize)
+        .map(decode_row)
+        .filter(|row| since.is_none_or(|since| row.start >= since))
+        .filter(|row| until.is_none_or(|until| row.start < until))
+        .collect())
+}
+
+fn period_ms(period: StatisticsPeriod) -> u64 {
+    match period {
+        StatisticsPeriod::Hour => 60 * 60 * 1000,
+        StatisticsPeriod::Day => 24 * 60 * 60 * 1000,
+    }
+}
+
+fn change(last: f64, value: f64, increasing: bool) -> f64 {
+    match increasing && value < last {
+        // counted up from 0 since the reset
+        true => value,
+        false => value - last,
+    }
+}
+
+fn read_version(file: &mut File) -> Result<(), HistoryError> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut version = [0; HEADER_SIZE as usize];
+    file.read_exact(&mut version).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => HistoryError::MissingHeader,
+        _ => HistoryError::IO(e),
+    })?;
+    let version = u16::from_be_bytes(version);
+    if version > VERSION {
+        return Err(HistoryError::NewerVersion(version));
+    }
+    Ok(())
+}
+
+fn encode_row(row: &StatsRow) -> [u8; ROW_SIZE as usize] {
+    let mut buf = [0; ROW_SIZE as usize];
+    let fields = [
+        row.start.to_be_bytes(),
+        row.min.to_be_bytes(),
+        row.max.to_be_bytes(),
+        row.sum.to_be_bytes(),
+        row.count.to_be_bytes(),
+        row.change.to_be_bytes(),
+        row.last.to_be_bytes(),
+    ];
+    for (chunk, field) in buf.chunks_exact_mut(8).zip(fields) {
+        chunk.copy_from_slice(&field);
+    }
+    buf
+}
+
+fn decode_row(row: &[u8]) -> StatsRow {
+    let field = |i: usize| -> [u8; 8] { row[i * 8..i * 8 + 8].try_into().unwrap() };
+    StatsRow {
+        start: u64::from_be_bytes(field(0)),
+        min: f64::from_be_bytes(field(1)),
+        max: f64::from_be_bytes(field(2)),
+        sum: f64::from_be_bytes(field(3)),
+        count: u64::from_be_bytes(field(4)),
+        change: f64::from_be_bytes(field(5)),
+        last: f64::from_be_bytes(field(6)),
+    }
+}
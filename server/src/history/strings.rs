@@ -0,0 +1,159 @@
+//! Interns the values of Text and Enum components (see the String Intern Table Format)
+
+use super::{HistoryError, VERSION};
+use rustc_hash::FxHashMap;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Entries reference strings by their index in the table
+pub type InternID = u32;
+
+/// Length of a garbage collected string
+pub const TOMBSTONE: u16 = u16::MAX;
+
+/// Longer strings are cut at a char boundary
+pub const MAX_STRING_LEN: usize = TOMBSTONE as usize - 1;
+
+pub struct StringTable {
+    writer: BufWriter<File>,
+    /// `None` for garbage collected strings
+    strings: Vec<Option<String>>,
+    /// First ID of each string
+    ids: FxHashMap<String, InternID>,
+}
+
+impl StringTable {
+    /// Opens `path`, creating it if it doesn't exist
+    /// A partial string left by a crash is truncated
+    pub fn open(path: &Path) -> Result<Self, HistoryError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            file.write_all(&VERSION.to_be_bytes())?;
+        }
+
+        let (strings, valid_len) = read_table(&mut file)?;
+        if valid_len < file.metadata()?.len() {
+            eprintln!(
+                "String table {} has a partial string, truncating it",
+                path.display()
+            );
+            file.set_len(valid_len)?;
+        }
+
+        // duplicates (ex. a string interned again after a crash) resolve to the first
+        let mut ids = FxHashMap::default();
+        for (id, string) in strings.iter().enumerate() {
+            if let Some(string) = string {
+                ids.entry(string.clone()).or_insert(id as InternID);
+            }
+        }
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            strings,
+            ids,
+        })
+    }
+
+    /// Buffered, see [Self::flush]
+    pub fn intern(&mut self, string: &str) -> Result<InternID, HistoryError> {
+        let string = truncate(string);
+        if let Some(id) = self.ids.get(string) {
+            return Ok(*id);
+        }
+
+        let id =
+            InternID::try_from(self.strings.len()).map_err(|_| HistoryError::StringTableFull)?;
+        self.writer
+            .write_all(&(string.len() as u16).to_be_bytes())?;
+        self.writer.write_all(string.as_bytes())?;
+        self.strings.push(Some(string.to_string()));
+        self.ids.insert(string.to_string(), id);
+        Ok(id)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// `{ID}_strings.bin` for `{ID}.bin`
+pub fn strings_path(primary: &Path) -> PathBuf {
+    let stem = primary.file_stem().unwrap_or_default().to_string_lossy();
+    primary.with_file_name(format!("{stem}_strings.bin"))
+}
+
+/// Every string in `path` (`None` for collected ones), empty if it doesn't exist
+pub fn read_strings(path: &Path) -> Result<Vec<Option<String>>, HistoryError> {
+    match File::open(path) {
+        Ok(mut file) => Ok(read_table(&mut file)?.0),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces `path`, keeping the position (and so the ID) of every string
+pub fn write_strings(path: &Path, strings: &[Option<String>]) -> Result<(), HistoryError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&VERSION.to_be_bytes())?;
+    for string in strings {
+        match string {
+            Some(string) => {
+                writer.write_all(&(string.len() as u16).to_be_bytes())?;
+                writer.write_all(string.as_bytes())?;
+            }
+            None => writer.write_all(&TOMBSTONE.to_be_bytes())?,
+        }
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// Returns the strings and the length up to the last complete one
+fn read_table(file: &mut File) -> Result<(Vec<Option<String>>, u64), HistoryError> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut body = Vec::new();
+    file.read_to_end(&mut body)?;
+
+    let Some((version, mut rest)) = body.split_first_chunk::<2>() else {
+        return Err(HistoryError::MissingHeader);
+    };
+    let version = u16::from_be_bytes(*version);
+    if version > VERSION {
+        return Err(HistoryError::NewerVersion(version));
+    }
+
+    let mut strings = Vec::new();
+    while let Some((len, after)) = rest.split_first_chunk::<2>() {
+        let len = u16::from_be_bytes(*len);
+        if len == TOMBSTONE {
+            strings.push(None);
+            rest = after;
+            continue;
+        }
+        let Some((string, after)) = after.split_at_checked(len as usize) else {
+            break;
+        };
+        strings.push(Some(String::from_utf8_lossy(string).into_owned()));
+        rest = after;
+    }
+
+    Ok((strings, (body.len() - rest.len()) as u64))
+}
+
+fn truncate(string: &str) -> &str {
+    let mut end = string.len().min(MAX_STRING_LEN);
+    while !string.is_char_boundary(end) {
+        end -= 1;
+    }
+    &string[..end]
+}
@@ -0,0 +1,93 @@
+//! Fixed size encoding of component values in history entries
+
+use super::strings::InternID;
+use igloo_interface::{
+    ComponentType, IglooEnumValue,
+    types::{IglooColor, IglooDate, IglooTime, IglooType, IglooValue},
+};
+use std::borrow::Cow;
+
+/// Size of an encoded value, `None` if the component can't be recorded
+pub fn entry_size(typ: ComponentType) -> Option<u16> {
+    Some(match typ.igloo_type()? {
+        IglooType::Integer | IglooType::Real => 8,
+        IglooType::Text | IglooType::Enum(_) => size_of::<InternID>() as u16,
+        IglooType::Boolean => 1,
+        IglooType::Color => 24,
+        IglooType::Date => 4,
+        IglooType::Time => 3,
+        _ => return None,
+    })
+}
+
+/// Entries are an [InternID] into the string table
+pub fn is_interned(typ: ComponentType) -> bool {
+    matches!(typ.igloo_type(), Some(IglooType::Text | IglooType::Enum(_)))
+}
+
+/// Kept in long-term statistics
+pub fn has_stats(typ: ComponentType) -> bool {
+    matches!(typ.igloo_type(), Some(IglooType::Integer | IglooType::Real))
+}
+
+/// What an interned value is stored as (Enums by variant name, so reordering is fine)
+pub fn interned_str(value: &IglooValue) -> Option<Cow<'_, str>> {
+    match value {
+        IglooValue::Text(s) => Some(Cow::Borrowed(s)),
+        IglooValue::Enum(e) => Some(Cow::Owned(e.to_string())),
+        _ => None,
+    }
+}
+
+/// Appends a value that isn't interned to `buf`, returning `false` if it can't be recorded
+pub fn encode(value: &IglooValue, buf: &mut Vec<u8>) -> bool {
+    match value {
+        IglooValue::Integer(v) => buf.extend_from_slice(&v.to_be_bytes()),
+        IglooValue::Real(v) => buf.extend_from_slice(&v.to_be_bytes()),
+        IglooValue::Boolean(v) => buf.push(*v as u8),
+        IglooValue::Color(c) => {
+            for v in [c.r, c.g, c.b] {
+                buf.extend_from_slice(&v.to_be_bytes());
+            }
+        }
+        IglooValue::Date(d) => {
+            buf.extend_from_slice(&d.year.to_be_bytes());
+            buf.extend_from_slice(&[d.month, d.day]);
+        }
+        IglooValue::Time(t) => buf.extend_from_slice(&[t.hour, t.minute, t.second]),
+        _ => return false,
+    }
+    true
+}
+
+/// `bytes` must be [entry_size] long, `strings` is the instance's string table
+pub fn decode(typ: ComponentType, bytes: &[u8], strings: &[Option<String>]) -> Option<IglooValue> {
+    let f64_at = |i: usize| f64::from_be_bytes(bytes[i..i + 8].try_into().unwrap());
+    let string = || {
+        let id = InternID::from_be_bytes(bytes.try_into().ok()?);
+        strings.get(id as usize)?.clone()
+    };
+    Some(match typ.igloo_type()? {
+        IglooType::Integer => IglooValue::Integer(i64::from_be_bytes(bytes.try_into().ok()?)),
+        IglooType::Real => IglooValue::Real(f64_at(0)),
+        IglooType::Text => IglooValue::Text(string()?),
+        IglooType::Enum(typ) => IglooValue::Enum(IglooEnumValue::from_string(&typ, string()?)?),
+        IglooType::Boolean => IglooValue::Boolean(bytes[0] != 0),
+        IglooType::Color => IglooValue::Color(IglooColor {
+            r: f64_at(0),
+            g: f64_at(8),
+            b: f64_at(16),
+        }),
+        IglooType::Date => IglooValue::Date(IglooDate {
+            year: u16::from_be_bytes([bytes[0], bytes[1]]),
+            month: bytes[2],
+            day: bytes[3],
+        }),
+        IglooType::Time => IglooValue::Time(IglooTime {
+            hour: bytes[0],
+            minute: bytes[1],
+            second: bytes[2],
+        }),
+        _ => return None,
+    })
+}
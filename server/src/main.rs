@@ -1,7 +1,14 @@
-use crate::core::IglooRequest;
-use clap::Parser;
-use std::{path::PathBuf, sync::OnceLock};
+use crate::{
+    backup::BackupConfig,
+    core::IglooRequest,
+    ext::{HeartbeatConfig, QueueLimits},
+    history::export::{self, ExportFilter, ExportFormat},
+};
+use clap::{Parser, Subcommand};
+use igloo_interface::{ComponentType, id::EntityID};
+use std::{path::PathBuf, process::ExitCode, sync::OnceLock, time::Duration};
 
+mod backup;
 mod core;
 mod ext;
 mod history;
@@ -9,9 +16,15 @@ mod query;
 mod tree;
 mod web;
 
+#[cfg(test)]
+mod test;
+
 #[derive(Parser, Debug)]
 #[command(name = "igloo")]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Hostname or IP address to bind to
     #[arg(short, long, env = "IGLOO_ADDRESS", default_value = "127.0.0.1")]
     address: String,
@@ -31,25 +44,132 @@ struct Args {
     /// Path to the `www` dir (frontend)
     #[arg(long, env = "IGLOO_WWW", default_value = "./www")]
     www_dir: String,
+
+    /// Max pending writes per Extension before new writes are rejected
+    #[arg(long, env = "IGLOO_EXT_MAX_WRITES", default_value_t = QueueLimits::default().max_writes)]
+    ext_max_writes: usize,
+
+    /// Max pending messages per Extension before it is detached
+    #[arg(long, env = "IGLOO_EXT_MAX_QUEUE", default_value_t = QueueLimits::default().max_len)]
+    ext_max_queue: usize,
+
+    /// Seconds between heartbeat pings to each Extension
+    #[arg(long, env = "IGLOO_EXT_HEARTBEAT_INTERVAL", default_value_t = HeartbeatConfig::default().interval.as_secs())]
+    ext_heartbeat_interval: u64,
+
+    /// Seconds without a heartbeat response before an Extension is detached
+    #[arg(long, env = "IGLOO_EXT_HEARTBEAT_TIMEOUT", default_value_t = HeartbeatConfig::default().timeout.as_secs())]
+    ext_heartbeat_timeout: u64,
+
+    /// Path to the dir scheduled and `igloo backup` backups are written to
+    #[arg(long, env = "IGLOO_BACKUPS", default_value = "./backups")]
+    backups_dir: String,
+
+    /// Hours between automatic backups, 0 disables them
+    #[arg(long, env = "IGLOO_BACKUP_INTERVAL_HOURS", default_value_t = 0)]
+    backup_interval_hours: u64,
+
+    /// Backups kept in the backups dir, 0 keeps all of them
+    #[arg(long, env = "IGLOO_BACKUP_KEEP", default_value_t = BackupConfig::default().keep)]
+    backup_keep: usize,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write a backup of the data dir, then exit
+    Backup {
+        /// Defaults to a timestamped file in the backups dir
+        out: Option<PathBuf>,
+    },
+    /// Replace the data dir with a backup, then exit
+    /// Igloo must be stopped. The current data dir is kept beside it.
+    Restore { archive: PathBuf },
+    /// Export or import recorded history, then exit
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum HistoryCommand {
+    /// Write recorded entries as CSV or JSON Lines
+    Export {
+        #[arg(long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// Device name or ID, can be repeated (defaults to every device)
+        #[arg(long = "device")]
+        devices: Vec<String>,
+        /// Can be repeated (defaults to every entity)
+        #[arg(long = "entity")]
+        entities: Vec<String>,
+        /// In snake_case, can be repeated (defaults to every component)
+        #[arg(long = "component", value_parser = export::parse_component)]
+        components: Vec<ComponentType>,
+        /// RFC 3339 or milliseconds since the Unix epoch
+        #[arg(long, value_parser = export::parse_time)]
+        since: Option<u64>,
+        /// RFC 3339 or milliseconds since the Unix epoch (exclusive)
+        #[arg(long, value_parser = export::parse_time)]
+        until: Option<u64>,
+        /// Defaults to stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Merge a CSV or JSON Lines export into recorded history
+    /// Igloo must be stopped. Entries past retention are skipped.
+    Import {
+        file: PathBuf,
+        /// Defaults to JSON Lines for `.jsonl` files, CSV otherwise
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+    },
 }
 
 pub static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
 pub static PACKAGES_DIR: OnceLock<PathBuf> = OnceLock::new();
 pub static WWW_DIR: OnceLock<PathBuf> = OnceLock::new();
+pub static EXT_QUEUE_LIMITS: OnceLock<QueueLimits> = OnceLock::new();
+pub static EXT_HEARTBEAT: OnceLock<HeartbeatConfig> = OnceLock::new();
+pub static BACKUP: OnceLock<BackupConfig> = OnceLock::new();
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let args = Args::parse();
 
     DATA_DIR.set(PathBuf::from(args.data_dir)).unwrap();
     PACKAGES_DIR.set(PathBuf::from(args.packages_dir)).unwrap();
     WWW_DIR.set(PathBuf::from(args.www_dir)).unwrap();
+    EXT_QUEUE_LIMITS
+        .set(QueueLimits {
+            max_writes: args.ext_max_writes,
+            max_len: args.ext_max_queue,
+        })
+        .unwrap();
+    EXT_HEARTBEAT
+        .set(HeartbeatConfig {
+            interval: Duration::from_secs(args.ext_heartbeat_interval),
+            timeout: Duration::from_secs(args.ext_heartbeat_timeout),
+        })
+        .unwrap();
+    BACKUP
+        .set(BackupConfig {
+            dir: PathBuf::from(args.backups_dir),
+            interval: (args.backup_interval_hours > 0)
+                .then(|| Duration::from_secs(args.backup_interval_hours * 60 * 60)),
+            keep: args.backup_keep,
+        })
+        .unwrap();
+
+    if let Some(command) = args.command {
+        return run_command(command);
+    }
 
     let (handle, req_tx) = match core::spawn().await {
         Ok(r) => r,
         Err(e) => {
             eprintln!("{e}");
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
@@ -61,4 +181,75 @@ async fn main() {
     println!("SHUTTING DOWN");
     req_tx.send(IglooRequest::Shutdown).unwrap();
     handle.join().unwrap();
+    ExitCode::SUCCESS
+}
+
+fn run_command(command: Command) -> ExitCode {
+    let data_dir = DATA_DIR.get().unwrap();
+    let res = match command {
+        Command::Backup { out: Some(out) } => backup::create(data_dir, &out).map(|_| out),
+        Command::Backup { out: None } => backup::run(BACKUP.get().unwrap()),
+        Command::Restore { archive } => backup::restore(&archive, data_dir).map(|previous| {
+            if let Some(previous) = previous {
+                println!("Previous data dir moved to {}", previous.display());
+            }
+            archive
+        }),
+        Command::History { command } => return run_history_command(command),
+    };
+
+    match res {
+        Ok(path) => {
+            println!("Done: {}", path.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Reports on stderr, so an export can go to stdout
+fn run_history_command(command: HistoryCommand) -> ExitCode {
+    let res = match command {
+        HistoryCommand::Export {
+            format,
+            devices,
+            entities,
+            components,
+            since,
+            until,
+            out,
+        } => {
+            let filter = ExportFilter {
+                devices,
+                entities: entities.into_iter().map(EntityID).collect(),
+                components,
+                since,
+                until,
+            };
+            export::run_export(&filter, format, out.as_deref())
+                .map(|count| format!("Exported {count} entries"))
+        }
+        HistoryCommand::Import { file, format } => {
+            export::run_import(&file, format).map(|summary| {
+                format!(
+                    "Imported {} entries into {} instances, skipped {} outside retention",
+                    summary.imported, summary.files, summary.skipped
+                )
+            })
+        }
+    };
+
+    match res {
+        Ok(msg) => {
+            eprintln!("{msg}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,180 @@
+//! Routes client calls to Extensions and their replies back
+//!
+//! Each `ClientMsg::ExtensionCall` is sent as an `IglooToExtension::Custom`
+//! with a fresh [CommandID]. It stays pending until the Extension responds with
+//! `ExtensionToIgloo::CustomReply`, the Extension detaches, or it times out.
+//! The client gets the result under its own `call_id`, unless it unregistered
+//! meanwhile.
+
+use crate::{
+    core::{ClientManager, IglooError, IglooResponse},
+    ext::QueueError,
+    query::QueryEngine,
+    tree::{DeviceTree, mutation::DetachReason},
+};
+use igloo_interface::{
+    id::ExtensionIndex,
+    ipc::{CommandID, IglooToExtension},
+    query::CommandError,
+};
+use rustc_hash::{FxBuildHasher, FxHashMap};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Longer than [super::command::COMMAND_TIMEOUT], since calls
+/// can kick off slow work (ex. pairing)
+pub const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// (client_id, call_id)
+pub type Caller = (usize, usize);
+
+pub struct PendingCalls {
+    next_id: CommandID,
+    calls: FxHashMap<CommandID, PendingCall>,
+}
+
+struct PendingCall {
+    xindex: ExtensionIndex,
+    caller: Caller,
+    deadline: Instant,
+}
+
+impl Default for PendingCalls {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            calls: HashMap::with_capacity_and_hasher(10, FxBuildHasher),
+        }
+    }
+}
+
+impl PendingCalls {
+    pub fn register(&mut self, xindex: ExtensionIndex, caller: Caller) -> CommandID {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        self.calls.insert(
+            id,
+            PendingCall {
+                xindex,
+                caller,
+                deadline: Instant::now() + CALL_TIMEOUT,
+            },
+        );
+
+        id
+    }
+
+    pub fn on_reply(
+        &mut self,
+        cm: &mut ClientManager,
+        xindex: ExtensionIndex,
+        id: CommandID,
+        result: Result<serde_json::Value, String>,
+    ) {
+        // ignore replies for calls that already timed out or belong to another extension
+        match self.calls.get(&id) {
+            Some(call) if call.xindex == xindex => {}
+            _ => return,
+        }
+
+        self.resolve(cm, id, result.map_err(CommandError::Rejected));
+    }
+
+    /// Fails all pending calls sent to this Extension
+    pub fn on_ext_detached(&mut self, cm: &mut ClientManager, xindex: ExtensionIndex) {
+        let ids: Vec<CommandID> = self
+            .calls
+            .iter()
+            .filter(|(_, call)| call.xindex == xindex)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ids {
+            self.resolve(cm, id, Err(CommandError::Detached));
+        }
+    }
+
+    /// Fails all calls past their deadline
+    pub fn expire(&mut self, cm: &mut ClientManager, now: Instant) {
+        let ids: Vec<CommandID> = self
+            .calls
+            .iter()
+            .filter(|(_, call)| call.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ids {
+            self.resolve(cm, id, Err(CommandError::TimedOut));
+        }
+    }
+
+    /// Drops the client's calls, any late reply is ignored
+    pub fn on_client_unregistered(&mut self, client_id: usize) {
+        self.calls.retain(|_, call| call.caller.0 != client_id);
+    }
+
+    /// A client that can't be reached is logged, so the caller's loop carries on
+    pub fn resolve(
+        &mut self,
+        cm: &mut ClientManager,
+        id: CommandID,
+        result: Result<serde_json::Value, CommandError>,
+    ) {
+        let Some(call) = self.calls.remove(&id) else {
+            return;
+        };
+
+        let (client_id, call_id) = call.caller;
+        let res = cm.send(
+            client_id,
+            IglooResponse::ExtensionCallResult { call_id, result },
+        );
+        if let Err(e) = res {
+            eprintln!("Failed to send result of call {call_id} to client {client_id}: {e}");
+        }
+    }
+}
+
+impl QueryEngine {
+    pub fn call_ext(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &mut DeviceTree,
+        caller: Caller,
+        xindex: ExtensionIndex,
+        name: String,
+        payload: serde_json::Value,
+    ) -> Result<(), IglooError> {
+        let ext = tree.ext(&xindex)?;
+        let id = self.calls.register(xindex, caller);
+
+        let error = match ext
+            .queue
+            .push(IglooToExtension::Custom { id, name, payload })
+        {
+            Ok(_) => return Ok(()),
+            Err(QueueError::WritesFull) => CommandError::ChannelFull,
+            Err(QueueError::Full) => {
+                self.calls.resolve(cm, id, Err(CommandError::ChannelFull));
+                return tree.detach_ext(cm, self, xindex, DetachReason::ChannelFull);
+            }
+            Err(QueueError::Closed) => CommandError::Detached,
+        };
+
+        self.calls.resolve(cm, id, Err(error));
+        Ok(())
+    }
+
+    pub fn on_custom_reply(
+        &mut self,
+        cm: &mut ClientManager,
+        xindex: ExtensionIndex,
+        id: CommandID,
+        result: Result<serde_json::Value, String>,
+    ) {
+        self.calls.on_reply(cm, xindex, id, result);
+    }
+}
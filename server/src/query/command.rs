@@ -0,0 +1,252 @@
+//! Tracks commands (writes) sent to Extensions until they are acknowledged
+//!
+//! Every write is given a [CommandID] and stays pending until the Extension
+//! responds with `ExtensionToIgloo::Ack`, the Extension detaches, or it times out.
+//!
+//! If the write came from a query with `await_ack`, the results are collected
+//! and sent back to the client once every write has resolved. A client that
+//! unregisters meanwhile is forgotten, so its results never reach a client
+//! that is given the same ID later.
+
+use crate::{
+    core::{ClientManager, IglooResponse},
+    query::QueryEngine,
+};
+use igloo_interface::{
+    id::{DeviceID, EntityID, ExtensionIndex},
+    ipc::CommandID,
+    query::{CommandError, QueryResult},
+};
+use rustc_hash::{FxBuildHasher, FxHashMap};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// (client_id, query_id)
+pub type Waiter = (usize, usize);
+
+type AckResult = (DeviceID, EntityID, Result<(), CommandError>);
+
+pub struct PendingCommands {
+    next_id: CommandID,
+    commands: FxHashMap<CommandID, PendingCommand>,
+    evals: FxHashMap<Waiter, PendingEval>,
+}
+
+struct PendingCommand {
+    xindex: ExtensionIndex,
+    device: DeviceID,
+    entity: EntityID,
+    deadline: Instant,
+    waiter: Option<Waiter>,
+}
+
+struct PendingEval {
+    remaining: usize,
+    /// no more commands will be added
+    sealed: bool,
+    results: Vec<AckResult>,
+}
+
+impl Default for PendingCommands {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            commands: HashMap::with_capacity_and_hasher(50, FxBuildHasher),
+            evals: HashMap::with_capacity_and_hasher(5, FxBuildHasher),
+        }
+    }
+}
+
+impl PendingCommands {
+    /// Start collecting results for a query, `false` if one is already pending under `waiter`
+    /// Must be followed by [Self::end_eval] after all commands are registered
+    pub fn begin_eval(&mut self, waiter: Waiter) -> bool {
+        if self.evals.contains_key(&waiter) {
+            return false;
+        }
+        self.evals.insert(
+            waiter,
+            PendingEval {
+                remaining: 0,
+                sealed: false,
+                results: Vec::new(),
+            },
+        );
+        true
+    }
+
+    /// No more commands will be registered for this query
+    /// Responds right away if everything has already resolved
+    pub fn end_eval(&mut self, cm: &mut ClientManager, waiter: Waiter) {
+        let Some(eval) = self.evals.get_mut(&waiter) else {
+            return;
+        };
+        eval.sealed = true;
+        if eval.remaining == 0 {
+            self.finish_eval(cm, waiter);
+        }
+    }
+
+    /// Forgets the client's queries, its commands still resolve but aren't reported
+    pub fn on_client_unregistered(&mut self, client_id: usize) {
+        self.evals.retain(|(id, _), _| *id != client_id);
+        for cmd in self.commands.values_mut() {
+            if cmd.waiter.is_some_and(|(id, _)| id == client_id) {
+                cmd.waiter = None;
+            }
+        }
+    }
+
+    pub fn register(
+        &mut self,
+        xindex: ExtensionIndex,
+        device: DeviceID,
+        entity: EntityID,
+        waiter: Option<Waiter>,
+    ) -> CommandID {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        if let Some(waiter) = waiter
+            && let Some(eval) = self.evals.get_mut(&waiter)
+        {
+            eval.remaining += 1;
+        }
+
+        self.commands.insert(
+            id,
+            PendingCommand {
+                xindex,
+                device,
+                entity,
+                deadline: Instant::now() + COMMAND_TIMEOUT,
+                waiter,
+            },
+        );
+
+        id
+    }
+
+    pub fn on_ack(
+        &mut self,
+        cm: &mut ClientManager,
+        xindex: ExtensionIndex,
+        id: CommandID,
+        result: Result<(), String>,
+    ) {
+        // ignore acks for commands that already timed out or belong to another extension
+        match self.commands.get(&id) {
+            Some(cmd) if cmd.xindex == xindex => {}
+            _ => return,
+        }
+
+        self.resolve(cm, id, result.map_err(CommandError::Rejected));
+    }
+
+    /// Fails all pending commands sent to this Extension
+    pub fn on_ext_detached(&mut self, cm: &mut ClientManager, xindex: ExtensionIndex) {
+        let ids: Vec<CommandID> = self
+            .commands
+            .iter()
+            .filter(|(_, cmd)| cmd.xindex == xindex)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ids {
+            self.resolve(cm, id, Err(CommandError::Detached));
+        }
+    }
+
+    /// Fails all commands past their deadline
+    pub fn expire(&mut self, cm: &mut ClientManager, now: Instant) {
+        let ids: Vec<CommandID> = self
+            .commands
+            .iter()
+            .filter(|(_, cmd)| cmd.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ids {
+            self.resolve(cm, id, Err(CommandError::TimedOut));
+        }
+    }
+
+    pub fn resolve(
+        &mut self,
+        cm: &mut ClientManager,
+        id: CommandID,
+        result: Result<(), CommandError>,
+    ) {
+        let Some(cmd) = self.commands.remove(&id) else {
+            return;
+        };
+
+        let Some(waiter) = cmd.waiter else {
+            if let Err(CommandError::Rejected(reason)) = &result {
+                eprintln!(
+                    "{} rejected write to {}/{}: {reason}",
+                    cmd.xindex, cmd.device, cmd.entity
+                );
+            }
+            return;
+        };
+
+        let Some(eval) = self.evals.get_mut(&waiter) else {
+            return;
+        };
+
+        eval.results.push((cmd.device, cmd.entity, result));
+        eval.remaining -= 1;
+
+        if eval.sealed && eval.remaining == 0 {
+            self.finish_eval(cm, waiter);
+        }
+    }
+
+    /// A client that can't be reached is logged, so the caller's loop carries on
+    fn finish_eval(&mut self, cm: &mut ClientManager, waiter: Waiter) {
+        let Some(eval) = self.evals.remove(&waiter) else {
+            return;
+        };
+
+        let (client_id, query_id) = waiter;
+        let res = cm.send(
+            client_id,
+            IglooResponse::EvalResult {
+                query_id,
+                result: Ok(QueryResult::Acks(eval.results)),
+            },
+        );
+        if let Err(e) = res {
+            eprintln!("Failed to send acks of query {query_id} to client {client_id}: {e}");
+        }
+    }
+}
+
+impl QueryEngine {
+    pub fn on_ack(
+        &mut self,
+        cm: &mut ClientManager,
+        xindex: ExtensionIndex,
+        id: CommandID,
+        result: Result<(), String>,
+    ) {
+        self.commands.on_ack(cm, xindex, id, result);
+    }
+
+    /// Before the client's slot can be given to another one
+    pub fn on_client_unregistered(&mut self, client_id: usize) {
+        self.commands.on_client_unregistered(client_id);
+        self.calls.on_client_unregistered(client_id);
+    }
+
+    pub fn on_tick(&mut self, cm: &mut ClientManager, now: Instant) {
+        self.history.on_tick(now);
+        self.commands.expire(cm, now);
+        self.calls.expire(cm, now);
+    }
+}
@@ -1,27 +1,70 @@
 use globset::{GlobBuilder, GlobMatcher};
+use igloo_interface::query::{
+    DeviceFilter, DeviceInfoFilter, EntityIDFilter, NameFilter, check::QueryError,
+};
+use regex::Regex;
 use rustc_hash::{FxBuildHasher, FxHashMap};
 use std::{
     collections::HashMap,
+    fmt::Display,
     time::{Duration, Instant},
 };
 
 const GENERATION_INTERVAL: Duration = Duration::from_secs(30);
-const MAX_GLOBS: usize = 50;
+const MAX_PATTERNS: usize = 50;
 
 // TODO rename to cache?
 pub struct QueryContext {
-    globs: FxHashMap<String, (GlobMatcher, u32)>,
-    glob_generation: u32,
+    globs: PatternCache<GlobMatcher>,
+    globs_ignore_case: PatternCache<GlobMatcher>,
+    regexes: PatternCache<Regex>,
+    generation: u32,
     last_gc_check: Instant,
     now: Instant,
 }
 
+/// Compiled patterns keyed by their source, tagged with the generation they were last used in
+struct PatternCache<T> {
+    entries: FxHashMap<String, (T, u32)>,
+}
+
+/// A compiled [NameFilter]
+/// Owned (cheap to clone) so it can be used while the [QueryContext] is borrowed
+#[derive(Debug, Clone)]
+pub enum NameMatcher {
+    Is(String),
+    /// lowercased
+    IsIgnoreCase(String),
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+/// A compiled [DeviceInfoFilter]
+#[derive(Debug, Clone)]
+pub struct InfoMatcher {
+    pub manufacturer: Option<NameMatcher>,
+    pub model: Option<NameMatcher>,
+    pub hw_version: Option<NameMatcher>,
+    pub sw_version: Option<NameMatcher>,
+    pub serial: Option<NameMatcher>,
+    pub area: Option<NameMatcher>,
+}
+
+/// The patterns of a [DeviceFilter], compiled
+#[derive(Debug, Clone, Default)]
+pub struct DeviceMatcher {
+    pub name: Option<NameMatcher>,
+    pub info: Option<Box<InfoMatcher>>,
+}
+
 impl Default for QueryContext {
     fn default() -> Self {
         let now = Instant::now();
         Self {
-            globs: HashMap::with_capacity_and_hasher(MAX_GLOBS, FxBuildHasher),
-            glob_generation: 0,
+            globs: PatternCache::default(),
+            globs_ignore_case: PatternCache::default(),
+            regexes: PatternCache::default(),
+            generation: 0,
             last_gc_check: now,
             now,
         }
@@ -33,36 +76,112 @@ impl QueryContext {
         let now = Instant::now();
 
         if now.duration_since(self.last_gc_check) >= GENERATION_INTERVAL {
-            self.glob_generation = self.glob_generation.wrapping_add(1);
+            self.generation = self.generation.wrapping_add(1);
             self.last_gc_check = now;
         }
 
-        if self.globs.len() > MAX_GLOBS {
+        if self.globs.len() + self.globs_ignore_case.len() + self.regexes.len() > MAX_PATTERNS {
             self.run_gc();
         }
     }
 
     pub fn run_gc(&mut self) {
-        let min_generation = self.glob_generation.saturating_sub(2);
-        self.globs.retain(|_, (_, g)| *g >= min_generation);
+        let min_generation = self.generation.saturating_sub(2);
+        self.globs.retain_since(min_generation);
+        self.globs_ignore_case.retain_since(min_generation);
+        self.regexes.retain_since(min_generation);
     }
 
-    pub fn glob(&mut self, pattern: &str) -> &GlobMatcher {
-        let current_gen = self.glob_generation;
+    pub fn glob(&mut self, pattern: &str) -> Result<&GlobMatcher, QueryError> {
+        self.globs
+            .get_or_compile(pattern, self.generation, |pattern| {
+                GlobBuilder::new(pattern)
+                    .build()
+                    .map(|glob| glob.compile_matcher())
+            })
+    }
 
-        if !self.globs.contains_key(pattern) {
-            let glob = GlobBuilder::new(pattern)
-                .build()
-                .unwrap() // FIXME unwrap
-                .compile_matcher();
-            self.globs.insert(pattern.to_string(), (glob, current_gen));
-        } else if let Some((_, g)) = self.globs.get_mut(pattern)
-            && *g < current_gen.saturating_sub(1)
-        {
-            *g = current_gen;
-        }
+    pub fn glob_ignore_case(&mut self, pattern: &str) -> Result<&GlobMatcher, QueryError> {
+        self.globs_ignore_case
+            .get_or_compile(pattern, self.generation, |pattern| {
+                GlobBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map(|glob| glob.compile_matcher())
+            })
+    }
+
+    pub fn regex(&mut self, pattern: &str) -> Result<&Regex, QueryError> {
+        self.regexes
+            .get_or_compile(pattern, self.generation, Regex::new)
+    }
+
+    pub fn name_matcher(
+        &mut self,
+        filter: &Option<Box<NameFilter>>,
+    ) -> Result<Option<NameMatcher>, QueryError> {
+        filter
+            .as_deref()
+            .map(|filter| self.compile_name(filter))
+            .transpose()
+    }
+
+    pub fn info_matcher(
+        &mut self,
+        filter: &Option<Box<DeviceInfoFilter>>,
+    ) -> Result<Option<Box<InfoMatcher>>, QueryError> {
+        let Some(filter) = filter else {
+            return Ok(None);
+        };
+        let mut compile = |field: &Option<NameFilter>| {
+            field
+                .as_ref()
+                .map(|filter| self.compile_name(filter))
+                .transpose()
+        };
+        Ok(Some(Box::new(InfoMatcher {
+            manufacturer: compile(&filter.manufacturer)?,
+            model: compile(&filter.model)?,
+            hw_version: compile(&filter.hw_version)?,
+            sw_version: compile(&filter.sw_version)?,
+            serial: compile(&filter.serial)?,
+            area: compile(&filter.area)?,
+        })))
+    }
+
+    pub fn device_matcher(&mut self, filter: &DeviceFilter) -> Result<DeviceMatcher, QueryError> {
+        Ok(DeviceMatcher {
+            name: self.name_matcher(&filter.name)?,
+            info: self.info_matcher(&filter.info)?,
+        })
+    }
+
+    fn compile_name(&mut self, filter: &NameFilter) -> Result<NameMatcher, QueryError> {
+        Ok(match filter {
+            NameFilter::Is(name) => NameMatcher::Is(name.clone()),
+            NameFilter::IsIgnoreCase(name) => NameMatcher::IsIgnoreCase(name.to_lowercase()),
+            NameFilter::Matches(pattern) => NameMatcher::Glob(self.glob(pattern)?.clone()),
+            NameFilter::MatchesIgnoreCase(pattern) => {
+                NameMatcher::Glob(self.glob_ignore_case(pattern)?.clone())
+            }
+            NameFilter::Regex(pattern) => NameMatcher::Regex(self.regex(pattern)?.clone()),
+        })
+    }
 
-        &self.globs.get(pattern).unwrap().0
+    /// Compiles every pattern in an entity query up front, so invalid ones
+    /// are rejected instead of silently matching nothing
+    pub fn check_patterns(
+        &mut self,
+        device_name: &Option<Box<NameFilter>>,
+        device_info: &Option<Box<DeviceInfoFilter>>,
+        entity_id: &EntityIDFilter,
+    ) -> Result<(), QueryError> {
+        if let EntityIDFilter::Matches(pattern) = entity_id {
+            self.glob(pattern)?;
+        }
+        self.name_matcher(device_name)?;
+        self.info_matcher(device_info)?;
+        Ok(())
     }
 
     pub fn on_eval_start(&mut self) {
@@ -73,3 +192,55 @@ impl QueryContext {
         &self.now
     }
 }
+
+impl<T> Default for PatternCache<T> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::with_capacity_and_hasher(MAX_PATTERNS, FxBuildHasher),
+        }
+    }
+}
+
+impl<T> PatternCache<T> {
+    fn get_or_compile<E: Display>(
+        &mut self,
+        pattern: &str,
+        current_gen: u32,
+        compile: impl FnOnce(&str) -> Result<T, E>,
+    ) -> Result<&T, QueryError> {
+        if !self.entries.contains_key(pattern) {
+            let compiled = compile(pattern)
+                .map_err(|e| QueryError::InvalidPattern(pattern.to_string(), e.to_string()))?;
+            self.entries
+                .insert(pattern.to_string(), (compiled, current_gen));
+        } else if let Some((_, g)) = self.entries.get_mut(pattern)
+            && *g < current_gen.saturating_sub(1)
+        {
+            *g = current_gen;
+        }
+
+        Ok(&self.entries.get(pattern).unwrap().0)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn retain_since(&mut self, min_generation: u32) {
+        self.entries.retain(|_, (_, g)| *g >= min_generation);
+    }
+}
+
+impl NameMatcher {
+    #[inline(always)]
+    pub fn is_match(&self, name: &str) -> bool {
+        match self {
+            NameMatcher::Is(want) => name == want,
+            NameMatcher::IsIgnoreCase(want) => {
+                name.chars().flat_map(char::to_lowercase).eq(want.chars())
+            }
+            NameMatcher::Glob(glob) => glob.is_match(name),
+            NameMatcher::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
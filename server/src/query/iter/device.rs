@@ -1,11 +1,18 @@
-use crate::tree::{Device, DeviceTree, arena::Entry};
+use crate::{
+    query::ctx::{DeviceMatcher, InfoMatcher, NameMatcher},
+    tree::{Device, DeviceTree, arena::Entry},
+};
 use igloo_interface::{
-    id::{DeviceID, ExtensionID},
-    query::{DeviceFilter, DeviceGroupFilter, IDFilter, TypeFilter},
+    id::{DeviceID, ExtensionID, GroupID},
+    query::{DeviceFilter, DeviceGroupFilter, IDFilter, LabelFilter, TypeFilter},
     types::compare::ComparisonOp,
 };
 use rustc_hash::{FxBuildHasher, FxHashSet};
-use std::{collections::HashSet, ops::ControlFlow, time::Instant};
+use std::{
+    collections::{BTreeSet, HashSet},
+    ops::ControlFlow,
+    time::Instant,
+};
 
 pub fn estimate_device_count(tree: &DeviceTree, filter: &DeviceFilter) -> usize {
     match &filter.id {
@@ -34,6 +41,13 @@ pub fn estimate_device_count(tree: &DeviceTree, filter: &DeviceFilter) -> usize
                     .map(|g| g.devices().len())
                     .min()
                     .unwrap_or(0),
+                DeviceGroupFilter::InTree(_) | DeviceGroupFilter::InAnyTree(_) => {
+                    tree_filter_groups(tree, &filter.group)
+                        .iter()
+                        .filter_map(|gid| tree.group(gid).ok())
+                        .map(|g| g.devices().len())
+                        .sum()
+                }
                 // overestimate, since some slots can be empty
                 DeviceGroupFilter::Any => tree.devices().len(),
             },
@@ -41,11 +55,13 @@ pub fn estimate_device_count(tree: &DeviceTree, filter: &DeviceFilter) -> usize
     }
 }
 
+/// `matcher` is `filter` compiled by `QueryContext::device_matcher`
 #[inline]
 pub fn for_each_device<F>(
     now: Instant,
     tree: &DeviceTree,
     filter: &DeviceFilter,
+    matcher: &DeviceMatcher,
     type_filter: Option<&TypeFilter>,
     mut f: F,
 ) -> ControlFlow<()>
@@ -60,6 +76,10 @@ where
             };
             // skip ID filter check, bc we know it passes
             if passes_entity_count(device, &filter.entity_count)
+                && passes_availability(device, &filter.available)
+                && passes_info_filter(device, matcher.info.as_deref())
+                && passes_label_filter(device.labels(), &filter.label)
+                && passes_name_filter(device.name(), matcher.name.as_ref())
                 && passes_device_last_update(&now, device, &filter.last_update)
                 && passes_group_filter(device, &filter.group, tree)
                 && passes_type_filter(device, type_filter)
@@ -76,6 +96,10 @@ where
                 };
                 // skip ID filter check, bc we know it passes
                 if !passes_entity_count(device, &filter.entity_count)
+                    || !passes_availability(device, &filter.available)
+                    || !passes_info_filter(device, matcher.info.as_deref())
+                    || !passes_label_filter(device.labels(), &filter.label)
+                    || !passes_name_filter(device.name(), matcher.name.as_ref())
                     || !passes_device_last_update(&now, device, &filter.last_update)
                     || !passes_group_filter(device, &filter.group, tree)
                     || !passes_type_filter(device, type_filter)
@@ -108,6 +132,10 @@ where
                 // skip owner filter check, bc we know it passes
                 if !passes_id_filter(device, &filter.id)
                     || !passes_entity_count(device, &filter.entity_count)
+                    || !passes_availability(device, &filter.available)
+                    || !passes_info_filter(device, matcher.info.as_deref())
+                    || !passes_label_filter(device.labels(), &filter.label)
+                    || !passes_name_filter(device.name(), matcher.name.as_ref())
                     || !passes_device_last_update(&now, device, &filter.last_update)
                     || !passes_group_filter(device, &filter.group, tree)
                     || !passes_type_filter(device, type_filter)
@@ -133,6 +161,10 @@ where
                     // skip owner filter check, bc we know it passes
                     if !passes_id_filter(device, &filter.id)
                         || !passes_entity_count(device, &filter.entity_count)
+                        || !passes_availability(device, &filter.available)
+                        || !passes_info_filter(device, matcher.info.as_deref())
+                        || !passes_label_filter(device.labels(), &filter.label)
+                        || !passes_name_filter(device.name(), matcher.name.as_ref())
                         || !passes_device_last_update(&now, device, &filter.last_update)
                         || !passes_group_filter(device, &filter.group, tree)
                         || !passes_type_filter(device, type_filter)
@@ -160,6 +192,10 @@ where
                 // skip group filter check, bc we know it passes
                 if !passes_id_filter(device, &filter.id)
                     || !passes_entity_count(device, &filter.entity_count)
+                    || !passes_availability(device, &filter.available)
+                    || !passes_info_filter(device, matcher.info.as_deref())
+                    || !passes_label_filter(device.labels(), &filter.label)
+                    || !passes_name_filter(device.name(), matcher.name.as_ref())
                     || !passes_device_last_update(&now, device, &filter.last_update)
                     || !passes_type_filter(device, type_filter)
                     || !passes_owner_filter(device, &filter.owner)
@@ -185,6 +221,10 @@ where
                 // skip group filter check, bc we know it passes
                 if !passes_id_filter(device, &filter.id)
                     || !passes_entity_count(device, &filter.entity_count)
+                    || !passes_availability(device, &filter.available)
+                    || !passes_info_filter(device, matcher.info.as_deref())
+                    || !passes_label_filter(device.labels(), &filter.label)
+                    || !passes_name_filter(device.name(), matcher.name.as_ref())
                     || !passes_device_last_update(&now, device, &filter.last_update)
                     || !passes_type_filter(device, type_filter)
                     || !passes_owner_filter(device, &filter.owner)
@@ -225,6 +265,41 @@ where
                 // skip group filter check, bc we know it passes
                 if !passes_id_filter(device, &filter.id)
                     || !passes_entity_count(device, &filter.entity_count)
+                    || !passes_availability(device, &filter.available)
+                    || !passes_info_filter(device, matcher.info.as_deref())
+                    || !passes_label_filter(device.labels(), &filter.label)
+                    || !passes_name_filter(device.name(), matcher.name.as_ref())
+                    || !passes_device_last_update(&now, device, &filter.last_update)
+                    || !passes_type_filter(device, type_filter)
+                    || !passes_owner_filter(device, &filter.owner)
+                {
+                    continue;
+                }
+                f(device)?;
+            }
+            return ControlFlow::Continue(());
+        }
+        DeviceGroupFilter::InTree(_) | DeviceGroupFilter::InAnyTree(_) => {
+            let mut seen = HashSet::with_capacity_and_hasher(32, FxBuildHasher);
+            for gid in tree_filter_groups(tree, &filter.group) {
+                let Ok(group) = tree.group(&gid) else {
+                    continue;
+                };
+                for did in group.devices() {
+                    seen.insert(*did);
+                }
+            }
+            for did in seen {
+                let Ok(device) = tree.device(&did) else {
+                    continue;
+                };
+                // skip group filter check, bc we know it passes
+                if !passes_id_filter(device, &filter.id)
+                    || !passes_entity_count(device, &filter.entity_count)
+                    || !passes_availability(device, &filter.available)
+                    || !passes_info_filter(device, matcher.info.as_deref())
+                    || !passes_label_filter(device.labels(), &filter.label)
+                    || !passes_name_filter(device.name(), matcher.name.as_ref())
                     || !passes_device_last_update(&now, device, &filter.last_update)
                     || !passes_type_filter(device, type_filter)
                     || !passes_owner_filter(device, &filter.owner)
@@ -247,6 +322,10 @@ where
         // must check all filters
         if !passes_id_filter(device, &filter.id)
             || !passes_entity_count(device, &filter.entity_count)
+            || !passes_availability(device, &filter.available)
+            || !passes_info_filter(device, matcher.info.as_deref())
+            || !passes_label_filter(device.labels(), &filter.label)
+            || !passes_name_filter(device.name(), matcher.name.as_ref())
             || !passes_device_last_update(&now, device, &filter.last_update)
             || !passes_type_filter(device, type_filter)
             || !passes_group_filter(device, &filter.group, tree)
@@ -297,6 +376,23 @@ pub fn passes_group_filter(device: &Device, filter: &DeviceGroupFilter, tree: &D
                 .map(|g| g.devices().contains(device.id()))
                 .unwrap_or(false)
         }),
+        DeviceGroupFilter::InTree(root) => {
+            device.groups().iter().any(|gid| tree.in_tree(gid, root))
+        }
+        DeviceGroupFilter::InAnyTree(roots) => roots
+            .iter()
+            .any(|root| device.groups().iter().any(|gid| tree.in_tree(gid, root))),
+    }
+}
+
+/// Every group a tree filter covers, empty for other filters
+pub fn tree_filter_groups(tree: &DeviceTree, filter: &DeviceGroupFilter) -> FxHashSet<GroupID> {
+    match filter {
+        DeviceGroupFilter::InTree(root) => tree.subtree(root).into_iter().collect(),
+        DeviceGroupFilter::InAnyTree(roots) => {
+            roots.iter().flat_map(|root| tree.subtree(root)).collect()
+        }
+        _ => FxHashSet::default(),
     }
 }
 
@@ -308,6 +404,53 @@ pub fn passes_entity_count(device: &Device, filter: &Option<(ComparisonOp, usize
     }
 }
 
+#[inline(always)]
+pub fn passes_availability(device: &Device, filter: &Option<bool>) -> bool {
+    match filter {
+        None => true,
+        Some(available) => device.available() == *available,
+    }
+}
+
+#[inline(always)]
+pub fn passes_info_filter(device: &Device, filter: Option<&InfoMatcher>) -> bool {
+    let Some(filter) = filter else {
+        return true;
+    };
+    let info = device.info();
+    let passes = |want: &Option<NameMatcher>, have: &Option<String>| match want {
+        None => true,
+        Some(want) => have.as_deref().is_some_and(|have| want.is_match(have)),
+    };
+
+    passes(&filter.manufacturer, &info.manufacturer)
+        && passes(&filter.model, &info.model)
+        && passes(&filter.hw_version, &info.hw_version)
+        && passes(&filter.sw_version, &info.sw_version)
+        && passes(&filter.serial, &info.serial)
+        && passes(&filter.area, &info.area)
+}
+
+#[inline(always)]
+pub fn passes_label_filter(labels: &BTreeSet<String>, filter: &Option<Box<LabelFilter>>) -> bool {
+    let Some(filter) = filter else {
+        return true;
+    };
+    match filter.as_ref() {
+        LabelFilter::Has(label) => labels.contains(label),
+        LabelFilter::HasAny(want) => want.iter().any(|label| labels.contains(label)),
+        LabelFilter::HasAll(want) => want.iter().all(|label| labels.contains(label)),
+    }
+}
+
+#[inline(always)]
+pub fn passes_name_filter(name: &str, filter: Option<&NameMatcher>) -> bool {
+    match filter {
+        None => true,
+        Some(matcher) => matcher.is_match(name),
+    }
+}
+
 #[inline(always)]
 pub fn passes_device_last_update(
     now: &Instant,
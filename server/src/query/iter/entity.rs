@@ -1,7 +1,7 @@
 use crate::{
     query::{
         ctx::QueryContext,
-        iter::{estimate_device_count, for_each_device},
+        iter::{estimate_device_count, for_each_device, passes_label_filter},
     },
     tree::{Device, DeviceTree, Entity},
 };
@@ -41,10 +41,16 @@ where
         .and_then(extract_required_comp_types)
         .map(|set| set.into_iter().collect());
 
+    // invalid patterns are rejected by `QueryContext::check_patterns` before evaluation
+    let Ok(matcher) = ctx.device_matcher(device_filter) else {
+        return ControlFlow::Continue(());
+    };
+
     for_each_device(
         *ctx.now(),
         tree,
         device_filter,
+        &matcher,
         type_filter.as_ref(),
         |device| {
             match &required_types {
@@ -59,7 +65,13 @@ where
                                 continue;
                             };
 
-                            if !check_entity(ctx, entity, entity_filter, type_filter.as_ref()) {
+                            if !check_entity(
+                                ctx,
+                                device,
+                                entity,
+                                entity_filter,
+                                type_filter.as_ref(),
+                            ) {
                                 continue;
                             }
 
@@ -69,7 +81,7 @@ where
                 }
                 None => {
                     for entity in device.entities() {
-                        if !check_entity(ctx, entity, entity_filter, type_filter.as_ref()) {
+                        if !check_entity(ctx, device, entity, entity_filter, type_filter.as_ref()) {
                             continue;
                         }
 
@@ -86,6 +98,7 @@ where
 #[inline(always)]
 fn check_entity(
     ctx: &mut QueryContext,
+    device: &Device,
     entity: &Entity,
     entity_filter: &EntityFilter,
     type_filter: Option<&TypeFilter>,
@@ -106,6 +119,10 @@ fn check_entity(
         return false;
     }
 
+    if !passes_label_filter(device.entity_labels(entity.id()), &entity_filter.label) {
+        return false;
+    }
+
     if !passes_entity_id_filter(ctx, entity, &entity_filter.id) {
         return false;
     }
@@ -165,10 +182,10 @@ pub fn passes_entity_id_filter(
         EntityIDFilter::Any => true,
         EntityIDFilter::Is(id) => &entity.id().0 == id,
         EntityIDFilter::OneOf(set) => set.contains(&entity.id().0),
-        EntityIDFilter::Matches(pattern) => {
-            let glob = ctx.glob(pattern);
-            glob.is_match(&entity.id().0)
-        }
+        // invalid patterns are rejected by `QueryContext::check_patterns` before evaluation
+        EntityIDFilter::Matches(pattern) => ctx
+            .glob(pattern)
+            .is_ok_and(|glob| glob.is_match(&entity.id().0)),
     }
 }
 
@@ -1,4 +1,7 @@
-use crate::tree::{DeviceTree, Group, arena::Entry};
+use crate::{
+    query::{ctx::NameMatcher, iter::passes_name_filter},
+    tree::{DeviceTree, Group, arena::Entry},
+};
 use igloo_interface::{id::GroupID, query::IDFilter};
 use std::ops::ControlFlow;
 
@@ -11,20 +14,29 @@ pub fn estimate_group_count(tree: &DeviceTree, gid: &IDFilter<GroupID>) -> usize
 }
 
 #[inline]
-pub fn for_each_group<F>(tree: &DeviceTree, gid: &IDFilter<GroupID>, mut f: F) -> ControlFlow<()>
+pub fn for_each_group<F>(
+    tree: &DeviceTree,
+    gid: &IDFilter<GroupID>,
+    name: Option<&NameMatcher>,
+    mut f: F,
+) -> ControlFlow<()>
 where
     F: FnMut(&Group) -> ControlFlow<()>,
 {
     match gid {
         IDFilter::Is(id) => {
-            if let Ok(group) = tree.group(id) {
+            if let Ok(group) = tree.group(id)
+                && passes_name_filter(group.name(), name)
+            {
                 return f(group);
             }
             ControlFlow::Continue(())
         }
         IDFilter::OneOf(ids) => {
             for id in ids {
-                if let Ok(group) = tree.group(id) {
+                if let Ok(group) = tree.group(id)
+                    && passes_name_filter(group.name(), name)
+                {
                     f(group)?;
                 }
             }
@@ -35,6 +47,9 @@ where
                 let Entry::Occupied { value: group, .. } = group else {
                     continue;
                 };
+                if !passes_name_filter(group.name(), name) {
+                    continue;
+                }
                 f(group)?;
             }
             ControlFlow::Continue(())
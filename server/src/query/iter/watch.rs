@@ -4,8 +4,10 @@
 
 use crate::{
     query::{
-        ctx::QueryContext,
-        iter::{estimate_device_count, for_each_device, passes_entity_id_filter},
+        ctx::{DeviceMatcher, QueryContext},
+        iter::{
+            estimate_device_count, for_each_device, passes_entity_id_filter, passes_label_filter,
+        },
     },
     tree::{Device, DeviceTree, Entity},
 };
@@ -17,8 +19,12 @@ pub fn estimate_entity_count(tree: &DeviceTree, query: &WatchComponentQuery) ->
         id: query.device_filter.id.clone(),
         owner: query.device_filter.owner.clone(),
         group: query.device_filter.group.clone(),
+        name: None,
         entity_count: None,
         last_update: None,
+        available: None,
+        info: None,
+        label: None,
     };
 
     estimate_device_count(tree, &device_filter) << 3
@@ -38,8 +44,12 @@ where
         id: query.device_filter.id,
         owner: query.device_filter.owner,
         group: query.device_filter.group,
+        name: None,
         entity_count: None,
         last_update: None,
+        available: None,
+        info: None,
+        label: query.device_filter.label,
     };
 
     let entity_filter = EntityFilter {
@@ -47,14 +57,22 @@ where
         type_filter: query.entity_filter.type_filter,
         value_filter: None,
         last_update: None,
+        label: query.entity_filter.label,
     };
 
     let type_filter = &entity_filter.type_filter;
 
+    // invalid patterns are rejected by `QueryContext::check_patterns` on register
+    let Ok(name) = ctx.name_matcher(&query.device_filter.name) else {
+        return ControlFlow::Continue(());
+    };
+    let matcher = DeviceMatcher { name, info: None };
+
     for_each_device(
         *ctx.now(),
         tree,
         &device_filter,
+        &matcher,
         type_filter.as_ref(),
         |device| {
             let indices = &device.comp_to_entity()[query.component as usize];
@@ -64,7 +82,9 @@ where
                     continue;
                 };
 
-                if !passes_entity_id_filter(ctx, entity, &entity_filter.id) {
+                if !passes_entity_id_filter(ctx, entity, &entity_filter.id)
+                    || !passes_label_filter(device.entity_labels(entity.id()), &entity_filter.label)
+                {
                     continue;
                 }
 
@@ -1,11 +1,18 @@
-use crate::query::{
-    ctx::QueryContext,
-    watch::{Watcher, subscriber::TreeSubscribers},
+use crate::{
+    history::{HistoryConfig, compact::Compacted, recorder::HistoryRecorder},
+    query::{
+        call::PendingCalls,
+        command::PendingCommands,
+        ctx::QueryContext,
+        watch::{Watcher, subscriber::TreeSubscribers},
+    },
 };
 use igloo_interface::query::WatchQuery;
 use rustc_hash::{FxBuildHasher, FxHashMap};
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
+pub mod call;
+pub mod command;
 mod ctx;
 mod iter;
 mod oneshot;
@@ -16,6 +23,9 @@ pub struct QueryEngine {
     pub(self) tree_subs: TreeSubscribers,
     pub(self) watchers: Vec<Option<Watcher>>,
     pub(self) query_to_watcher: FxHashMap<WatchQuery, usize>,
+    pub(self) commands: PendingCommands,
+    pub(self) calls: PendingCalls,
+    pub(self) history: HistoryRecorder,
 }
 
 impl Default for QueryEngine {
@@ -25,6 +35,37 @@ impl Default for QueryEngine {
             tree_subs: TreeSubscribers::default(),
             watchers: Vec::with_capacity(50),
             query_to_watcher: HashMap::with_capacity_and_hasher(50, FxBuildHasher),
+            commands: PendingCommands::default(),
+            calls: PendingCalls::default(),
+            history: HistoryRecorder::disabled(),
         }
     }
 }
+
+impl QueryEngine {
+    pub fn new(history: HistoryRecorder) -> Self {
+        Self {
+            history,
+            ..Default::default()
+        }
+    }
+
+    /// Writes everything the recorder is holding, for shutdown
+    pub fn flush_history(&mut self) {
+        self.history.flush(true);
+    }
+
+    /// See [HistoryRecorder::flush_buffers]
+    pub fn flush_history_buffers(&mut self) {
+        self.history.flush_buffers();
+    }
+
+    /// See [HistoryRecorder::begin_compaction]
+    pub fn begin_history_compaction(&mut self) -> Option<(PathBuf, HistoryConfig)> {
+        self.history.begin_compaction()
+    }
+
+    pub fn finish_history_compaction(&mut self, results: Vec<Compacted>) {
+        self.history.finish_compaction(results);
+    }
+}
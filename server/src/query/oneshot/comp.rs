@@ -1,16 +1,21 @@
 use crate::{
     core::{ClientManager, IglooError},
-    ext::ExtensionRequest,
+    ext::{Pushed, QueueError},
     query::{
         QueryEngine,
+        command::Waiter,
         iter::{estimate_entity_count, for_each_entity},
     },
-    tree::DeviceTree,
+    tree::{DeviceTree, Entity, mutation::DetachReason},
 };
 use igloo_interface::{
     Aggregator, Component,
+    id::ExtensionIndex,
     ipc::IglooToExtension,
-    query::{ComponentAction as A, ComponentQuery, QueryResult as R, check::QueryError},
+    query::{
+        CommandError, ComponentAction as A, ComponentQuery, JournalEvent, QueryResult as R,
+        check::QueryError,
+    },
 };
 use rustc_hash::FxBuildHasher;
 use std::{collections::HashSet, ops::ControlFlow};
@@ -20,8 +25,17 @@ impl QueryEngine {
         &mut self,
         cm: &mut ClientManager,
         tree: &mut DeviceTree,
+        waiter: Waiter,
         query: ComponentQuery,
-    ) -> Result<Result<R, QueryError>, IglooError> {
+    ) -> Result<Option<Result<R, QueryError>>, IglooError> {
+        if let Err(err) = self.ctx.check_patterns(
+            &query.device_filter.name,
+            &query.device_filter.info,
+            &query.entity_filter.id,
+        ) {
+            return Ok(Some(Err(err)));
+        }
+
         let limit = query.limit.unwrap_or(usize::MAX);
 
         let result = match query.action {
@@ -49,7 +63,10 @@ impl QueryEngine {
             A::GetValue => match query.post_op {
                 Some(op) => {
                     let Some(mut agg) = Aggregator::new(query.component, op) else {
-                        return Ok(Err(QueryError::InvalidAggregation(query.component, op)));
+                        return Ok(Some(Err(QueryError::InvalidAggregation(
+                            query.component,
+                            op,
+                        ))));
                     };
 
                     let _ = for_each_entity(
@@ -98,7 +115,7 @@ impl QueryEngine {
                     );
 
                     if let Some(e) = error {
-                        return Ok(Err(e));
+                        return Ok(Some(Err(e)));
                     }
                     R::ComponentValueWithParents(res)
                 }
@@ -133,158 +150,162 @@ impl QueryEngine {
                     );
 
                     if let Some(e) = error {
-                        return Ok(Err(e));
+                        return Ok(Some(Err(e)));
                     }
                     R::ComponentValue(res)
                 }
             },
 
-            A::Set(value) | A::Put(value) => {
-                let comp = Component::from_igloo_value(query.component, value).unwrap(); // FIXME unwrap
-                let mut exts_to_kill = HashSet::with_capacity_and_hasher(2, FxBuildHasher);
-                let mut exts_to_flush = HashSet::with_capacity_and_hasher(2, FxBuildHasher);
-                let mut msg = ExtensionRequest::Msg(IglooToExtension::WriteComponents {
-                    device: u64::MAX,
-                    entity: usize::MAX,
-                    comps: vec![comp],
+            A::Set(ref value) | A::Put(ref value) => {
+                let comp = Component::from_igloo_value(query.component, value.clone()).unwrap(); // FIXME unwrap
+                return self.write(cm, tree, waiter, &query, |_| {
+                    ControlFlow::Continue(Some(comp.clone()))
                 });
-                let mut count = 0;
-
-                let _ = for_each_entity(
-                    &mut self.ctx,
-                    tree,
-                    &query.device_filter,
-                    &query.entity_filter,
-                    |device, entity| {
-                        let Some(xindex) = device.owner_ref() else {
-                            return ControlFlow::Continue(());
-                        };
-
-                        if exts_to_kill.contains(&xindex) {
-                            return ControlFlow::Continue(());
-                        }
-
-                        let Ok(ext) = tree.ext(&xindex) else {
-                            return ControlFlow::Continue(());
-                        };
-
-                        if let ExtensionRequest::Msg(IglooToExtension::WriteComponents {
-                            device: d,
-                            entity: e,
-                            ..
-                        }) = &mut msg
-                        {
-                            *d = *device.id().inner();
-                            *e = entity.index().0;
-                        }
-
-                        match ext.channel.try_send(msg.clone()) {
-                            Ok(_) => exts_to_flush.insert(xindex),
-                            // TODO print error
-                            Err(_) => exts_to_kill.insert(xindex),
-                        };
-
-                        count += 1;
-
-                        if count >= limit {
-                            ControlFlow::Break(())
-                        } else {
-                            ControlFlow::Continue(())
-                        }
-                    },
-                );
+            }
 
-                for xindex in exts_to_flush {
-                    if let Ok(ext) = tree.ext(&xindex) {
-                        _ = ext.channel.try_send(ExtensionRequest::Flush);
+            A::Apply(ref op) => {
+                return self.write(cm, tree, waiter, &query, |entity| {
+                    let Some(cur) = entity.get(query.component) else {
+                        return ControlFlow::Continue(None);
+                    };
+                    let new_value = cur.to_igloo_value().and_then(|value| op.eval(&value));
+                    match new_value.and_then(|v| Component::from_igloo_value(query.component, v)) {
+                        Some(comp) => ControlFlow::Continue(Some(comp)),
+                        // TODO return error
+                        None => ControlFlow::Break(()),
                     }
-                }
-
-                for xindex in exts_to_kill {
-                    tree.detach_ext(cm, self, xindex, true)?;
-                }
-
-                R::Count(count)
+                });
             }
+        };
 
-            A::Apply(op) => {
-                let mut exts_to_kill = HashSet::with_capacity_and_hasher(2, FxBuildHasher);
-                let mut exts_to_flush = HashSet::with_capacity_and_hasher(2, FxBuildHasher);
-                let mut count = 0;
-
-                let _ = for_each_entity(
-                    &mut self.ctx,
-                    tree,
-                    &query.device_filter,
-                    &query.entity_filter,
-                    |device, entity| {
-                        let Some(comp_val) = entity.get(query.component) else {
-                            return ControlFlow::Continue(());
-                        };
-                        let Some(cur_value) = comp_val.to_igloo_value() else {
-                            // TODO return error
-                            return ControlFlow::Break(());
-                        };
-
-                        let Some(new_value) = op.eval(&cur_value) else {
-                            // TODO return error
-                            return ControlFlow::Break(());
-                        };
-
-                        let Some(comp) = Component::from_igloo_value(query.component, new_value)
-                        else {
-                            // TODO return error
-                            return ControlFlow::Break(());
-                        };
-
-                        let Some(xindex) = device.owner_ref() else {
-                            return ControlFlow::Continue(());
-                        };
-
-                        if exts_to_kill.contains(&xindex) {
-                            return ControlFlow::Continue(());
-                        }
-
-                        let Ok(ext) = tree.ext(&xindex) else {
-                            return ControlFlow::Continue(());
-                        };
+        Ok(Some(Ok(result)))
+    }
 
-                        let msg = ExtensionRequest::Msg(IglooToExtension::WriteComponents {
-                            device: *device.id().inner(),
-                            entity: entity.index().0,
-                            comps: vec![comp],
-                        });
+    /// Sends a write to the owning Extension of every matched entity
+    /// `make_comp` skips an entity with `Continue(None)`, and stops the write with `Break`
+    fn write<F>(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &mut DeviceTree,
+        waiter: Waiter,
+        query: &ComponentQuery,
+        mut make_comp: F,
+    ) -> Result<Option<Result<R, QueryError>>, IglooError>
+    where
+        F: FnMut(&Entity) -> ControlFlow<(), Option<Component>>,
+    {
+        let limit = query.limit.unwrap_or(usize::MAX);
+        let waiter = query.await_ack.then_some(waiter);
+        let mut exts_to_kill = HashSet::with_capacity_and_hasher(2, FxBuildHasher);
+        let mut resolved = Vec::new();
+        // journaled once the loop releases the tree
+        let mut sets = Vec::new();
+        let mut count = 0;
+
+        if let Some(waiter) = waiter
+            && !self.commands.begin_eval(waiter)
+        {
+            return Ok(Some(Err(QueryError::DuplicateQueryID(waiter.1))));
+        }
+
+        let _ = for_each_entity(
+            &mut self.ctx,
+            tree,
+            &query.device_filter,
+            &query.entity_filter,
+            |device, entity| {
+                let comp = match make_comp(entity) {
+                    ControlFlow::Continue(Some(comp)) => comp,
+                    ControlFlow::Continue(None) => return ControlFlow::Continue(()),
+                    ControlFlow::Break(()) => return ControlFlow::Break(()),
+                };
+
+                let Some(xindex) = device.owner_ref() else {
+                    return ControlFlow::Continue(());
+                };
+
+                if exts_to_kill.contains(&xindex) {
+                    return ControlFlow::Continue(());
+                }
 
-                        match ext.channel.try_send(msg.clone()) {
-                            Ok(_) => exts_to_flush.insert(xindex),
-                            // TODO print error
-                            Err(_) => exts_to_kill.insert(xindex),
-                        };
+                let Ok(ext) = tree.ext(&xindex) else {
+                    return ControlFlow::Continue(());
+                };
 
-                        count += 1;
+                let id = self
+                    .commands
+                    .register(xindex, *device.id(), entity.id().clone(), waiter);
 
-                        if count >= limit {
-                            ControlFlow::Break(())
-                        } else {
-                            ControlFlow::Continue(())
-                        }
-                    },
-                );
+                let msg = IglooToExtension::WriteComponents {
+                    id,
+                    device: *device.id().inner(),
+                    entity: entity.index().0,
+                    comps: vec![comp.clone()],
+                };
 
-                for xindex in exts_to_flush {
-                    if let Ok(ext) = tree.ext(&xindex) {
-                        _ = ext.channel.try_send(ExtensionRequest::Flush);
+                match ext.queue.push(msg) {
+                    Ok(Pushed::Queued) => {
+                        sets.push(JournalEvent::Set(*device.id(), entity.id().clone(), comp));
+                    }
+                    Ok(Pushed::Coalesced(old)) => {
+                        sets.push(JournalEvent::Set(*device.id(), entity.id().clone(), comp));
+                        resolved.push((old, CommandError::Superseded));
+                    }
+                    // too many pending writes, but Extension is still reading
+                    Err(QueueError::WritesFull) => {
+                        resolved.push((id, CommandError::ChannelFull));
+                    }
+                    Err(QueueError::Full) => {
+                        exts_to_kill.insert(xindex);
+                        resolved.push((id, CommandError::ChannelFull));
+                    }
+                    Err(QueueError::Closed) => {
+                        resolved.push((id, CommandError::Detached));
                     }
                 }
 
-                for xindex in exts_to_kill {
-                    tree.detach_ext(cm, self, xindex, true)?;
+                count += 1;
+
+                if count >= limit {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
                 }
+            },
+        );
 
-                R::Count(count)
+        for (id, error) in resolved {
+            self.commands.resolve(cm, id, Err(error));
+        }
+
+        let res = self.finish_write(cm, tree, sets, exts_to_kill);
+
+        match waiter {
+            Some(waiter) => {
+                // responded to once every write is acknowledged
+                // sealed even if `res` failed, otherwise the client never hears back
+                self.commands.end_eval(cm, waiter);
+                res.map(|_| None)
             }
-        };
+            None => res.map(|_| Some(Ok(R::Count(count)))),
+        }
+    }
 
-        Ok(Ok(result))
+    fn finish_write(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &mut DeviceTree,
+        sets: Vec<JournalEvent>,
+        exts_to_kill: HashSet<ExtensionIndex, FxBuildHasher>,
+    ) -> Result<(), IglooError> {
+        for event in sets {
+            tree.record(cm, self, event)?;
+        }
+
+        for xindex in exts_to_kill {
+            tree.detach_ext(cm, self, xindex, DetachReason::ChannelFull)?;
+        }
+
+        Ok(())
     }
 }
@@ -17,11 +17,17 @@ impl QueryEngine {
         tree: &DeviceTree,
         query: DeviceQuery,
     ) -> Result<Result<R, QueryError>, IglooError> {
+        let matcher = match self.ctx.device_matcher(&query.filter) {
+            Ok(matcher) => matcher,
+            Err(err) => return Ok(Err(err)),
+        };
+        let now = *self.ctx.now();
+
         let result = match query.action {
             A::Count => {
                 let mut count = 0usize;
                 let limit = query.limit.unwrap_or(usize::MAX);
-                let _ = for_each_device(*self.ctx.now(), tree, &query.filter, None, |_| {
+                let _ = for_each_device(now, tree, &query.filter, &matcher, None, |_| {
                     count += 1;
                     if count >= limit {
                         ControlFlow::Break(())
@@ -37,7 +43,7 @@ impl QueryEngine {
                 let estimate = estimate_device_count(tree, &query.filter).min(limit);
                 let mut ids = Vec::with_capacity(estimate);
 
-                let _ = for_each_device(*self.ctx.now(), tree, &query.filter, None, |device| {
+                let _ = for_each_device(now, tree, &query.filter, &matcher, None, |device| {
                     ids.push(*device.id());
                     if ids.len() >= limit {
                         ControlFlow::Break(())
@@ -53,7 +59,7 @@ impl QueryEngine {
                 let estimate = estimate_device_count(tree, &query.filter).min(limit);
                 let mut snapshots = Vec::with_capacity(estimate);
 
-                let _ = for_each_device(*self.ctx.now(), tree, &query.filter, None, |device| {
+                let _ = for_each_device(now, tree, &query.filter, &matcher, None, |device| {
                     snapshots.push(device.snapshot(include_comps));
                     if snapshots.len() >= limit {
                         ControlFlow::Break(())
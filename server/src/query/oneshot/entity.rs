@@ -15,6 +15,14 @@ impl QueryEngine {
         tree: &DeviceTree,
         query: EntityQuery,
     ) -> Result<Result<R, QueryError>, IglooError> {
+        if let Err(err) = self.ctx.check_patterns(
+            &query.device_filter.name,
+            &query.device_filter.info,
+            &query.entity_filter.id,
+        ) {
+            return Ok(Err(err));
+        }
+
         let result = match query.action {
             A::Count => {
                 let mut count = 0usize;
@@ -50,7 +58,7 @@ impl QueryEngine {
                     &query.device_filter,
                     &query.entity_filter,
                     |device, entity| {
-                        snapshots.push(entity.snapshot(*device.id()));
+                        snapshots.push(entity.snapshot(device));
                         if snapshots.len() >= limit {
                             ControlFlow::Break(())
                         } else {
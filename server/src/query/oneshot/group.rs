@@ -15,12 +15,18 @@ impl QueryEngine {
         tree: &DeviceTree,
         query: GroupQuery,
     ) -> Result<Result<R, QueryError>, IglooError> {
+        let name = match self.ctx.name_matcher(&query.name) {
+            Ok(name) => name,
+            Err(err) => return Ok(Err(err)),
+        };
+        let name = name.as_ref();
+
         let result = match query.action {
             A::Count => {
                 let mut count = 0usize;
                 let limit = query.limit.unwrap_or(usize::MAX);
 
-                let _ = for_each_group(tree, &query.id, |_| {
+                let _ = for_each_group(tree, &query.id, name, |_| {
                     count += 1;
                     if count >= limit {
                         ControlFlow::Break(())
@@ -36,7 +42,7 @@ impl QueryEngine {
                 let estimate = estimate_group_count(tree, &query.id).min(limit);
                 let mut ids = Vec::with_capacity(estimate);
 
-                let _ = for_each_group(tree, &query.id, |group| {
+                let _ = for_each_group(tree, &query.id, name, |group| {
                     ids.push(*group.id());
                     if ids.len() >= limit {
                         ControlFlow::Break(())
@@ -52,7 +58,7 @@ impl QueryEngine {
                 let estimate = estimate_group_count(tree, &query.id).min(limit);
                 let mut snapshots = Vec::with_capacity(estimate);
 
-                let _ = for_each_group(tree, &query.id, |group| {
+                let _ = for_each_group(tree, &query.id, name, |group| {
                     snapshots.push(group.snapshot());
                     if snapshots.len() >= limit {
                         ControlFlow::Break(())
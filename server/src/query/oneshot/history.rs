@@ -0,0 +1,131 @@
+use crate::{
+    core::IglooError,
+    query::{QueryEngine, iter::for_each_entity},
+    tree::DeviceTree,
+};
+use igloo_interface::{
+    Aggregator, Component, ComponentType,
+    query::{HistoryQuery, HistorySeries, QueryResult as R, check::QueryError},
+    types::IglooValue,
+};
+use std::ops::ControlFlow;
+
+impl QueryEngine {
+    pub fn eval_history(
+        &mut self,
+        tree: &DeviceTree,
+        query: HistoryQuery,
+    ) -> Result<Result<R, QueryError>, IglooError> {
+        if let Err(err) = self.ctx.check_patterns(
+            &query.device_filter.name,
+            &query.device_filter.info,
+            &query.entity_filter.id,
+        ) {
+            return Ok(Err(err));
+        }
+
+        let agg = match query.bucket {
+            Some(bucket) if bucket.size_ms == 0 => return Ok(Err(QueryError::EmptyBucket)),
+            Some(bucket) => match Aggregator::new(query.component, bucket.op) {
+                Some(agg) => Some((bucket.size_ms, agg)),
+                None => {
+                    return Ok(Err(QueryError::InvalidAggregation(
+                        query.component,
+                        bucket.op,
+                    )));
+                }
+            },
+            None => None,
+        };
+
+        let mut sources = Vec::new();
+        let _ = for_each_entity(
+            &mut self.ctx,
+            tree,
+            &query.device_filter,
+            &query.entity_filter,
+            |device, entity| {
+                sources.push((*device.id(), entity.id().clone()));
+                ControlFlow::Continue(())
+            },
+        );
+
+        let mut series = Vec::with_capacity(sources.len());
+        for (did, eid) in sources {
+            let points =
+                match self
+                    .history
+                    .read(&did, &eid, query.component, query.since, query.until)
+                {
+                    Ok(points) => points,
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to read history of {:?} on {did}: {e}",
+                            query.component
+                        );
+                        continue;
+                    }
+                };
+            if !points.is_empty() {
+                series.push(HistorySeries {
+                    source: Some((did, eid)),
+                    points,
+                });
+            }
+        }
+
+        if query.merge {
+            let mut points: Vec<_> = series.into_iter().flat_map(|s| s.points).collect();
+            points.sort_by_key(|(at, _)| *at);
+            series = vec![HistorySeries {
+                source: None,
+                points,
+            }];
+        }
+
+        if let Some((size_ms, agg)) = agg {
+            let origin = query.since.unwrap_or(0);
+            for s in &mut series {
+                s.points = downsample(&s.points, query.component, origin, size_ms, &agg);
+            }
+        }
+
+        Ok(Ok(R::History(series)))
+    }
+}
+
+/// One point per non-empty bucket, where buckets are `size_ms` long starting at `origin`
+/// `points` must be oldest first and not before `origin`
+fn downsample(
+    points: &[(u64, IglooValue)],
+    typ: ComponentType,
+    origin: u64,
+    size_ms: u64,
+    empty: &Aggregator,
+) -> Vec<(u64, IglooValue)> {
+    let mut out = Vec::new();
+    let mut bucket: Option<(u64, Aggregator)> = None;
+
+    for (at, value) in points {
+        let start = origin + (at - origin) / size_ms * size_ms;
+        if bucket.as_ref().is_none_or(|(s, _)| *s != start)
+            && let Some((s, agg)) = bucket.replace((start, empty.clone()))
+            && let Some(value) = agg.finish()
+        {
+            out.push((s, value));
+        }
+
+        if let Some((_, agg)) = &mut bucket
+            && let Some(comp) = Component::from_igloo_value(typ, value.clone())
+        {
+            let _ = agg.push(&comp);
+        }
+    }
+
+    if let Some((s, agg)) = bucket
+        && let Some(value) = agg.finish()
+    {
+        out.push((s, value));
+    }
+    out
+}
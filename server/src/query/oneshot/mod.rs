@@ -1,4 +1,4 @@
-use igloo_interface::query::OneShotQuery;
+use igloo_interface::query::{OneShotQuery, QueryResult};
 
 use crate::{
     core::{ClientManager, IglooError, IglooResponse},
@@ -11,6 +11,8 @@ mod device;
 mod entity;
 mod ext;
 mod group;
+mod history;
+mod statistics;
 
 impl QueryEngine {
     pub fn eval_oneshot(
@@ -32,7 +34,14 @@ impl QueryEngine {
             Group(q) => self.eval_group(tree, q)?,
             Device(q) => self.eval_device(tree, q)?,
             Entity(q) => self.eval_entity(tree, q)?,
-            Component(q) => self.eval_component(cm, tree, q)?,
+            Journal(q) => Ok(QueryResult::Journal(tree.journal().read(&q)?)),
+            History(q) => self.eval_history(tree, q)?,
+            Statistics(q) => self.eval_statistics(tree, q)?,
+            Component(q) => match self.eval_component(cm, tree, (client_id, query_id), q)? {
+                Some(result) => result,
+                // responded to once all writes are acknowledged
+                None => return Ok(()),
+            },
         };
 
         cm.send(client_id, IglooResponse::EvalResult { query_id, result })
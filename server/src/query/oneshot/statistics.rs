@@ -0,0 +1,66 @@
+use crate::{
+    core::IglooError,
+    query::{QueryEngine, iter::for_each_entity},
+    tree::DeviceTree,
+};
+use igloo_interface::query::{
+    QueryResult as R, StatisticsQuery, StatisticsSeries, check::QueryError,
+};
+use std::ops::ControlFlow;
+
+impl QueryEngine {
+    pub fn eval_statistics(
+        &mut self,
+        tree: &DeviceTree,
+        query: StatisticsQuery,
+    ) -> Result<Result<R, QueryError>, IglooError> {
+        if let Err(err) = self.ctx.check_patterns(
+            &query.device_filter.name,
+            &query.device_filter.info,
+            &query.entity_filter.id,
+        ) {
+            return Ok(Err(err));
+        }
+
+        let mut sources = Vec::new();
+        let _ = for_each_entity(
+            &mut self.ctx,
+            tree,
+            &query.device_filter,
+            &query.entity_filter,
+            |device, entity| {
+                sources.push((*device.id(), entity.id().clone()));
+                ControlFlow::Continue(())
+            },
+        );
+
+        let mut series = Vec::with_capacity(sources.len());
+        for (did, eid) in sources {
+            let rows = match self.history.read_stats(
+                &did,
+                &eid,
+                query.component,
+                query.period,
+                query.since,
+                query.until,
+            ) {
+                Ok(rows) => rows,
+                Err(e) => {
+                    eprintln!(
+                        "Failed to read statistics of {:?} on {did}: {e}",
+                        query.component
+                    );
+                    continue;
+                }
+            };
+            if !rows.is_empty() {
+                series.push(StatisticsSeries {
+                    source: (did, eid),
+                    rows: rows.into_iter().map(Into::into).collect(),
+                });
+            }
+        }
+
+        Ok(Ok(R::Statistics(series)))
+    }
+}
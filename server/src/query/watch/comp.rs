@@ -8,26 +8,38 @@
 //!
 //! # Expansion Events
 //!  - component_put that now satifies type_filter
+//!  - component_removed that now satisfies type_filter (ex. ::Without)
 //!  - device added to group in device_filter.group
+//!  - label added to a device or entity with a label filter
+//!  - device renamed to match device_filter.name
+//!  - device replaced by new hardware that matches
+//!  - group moved into the tree of an ::InTree|::InAnyTree filter
 //!
 //! Shouldn't there be way more expansion events?
 //!  - device_created, group_craeted :: we assume queries can't know IDs before they're created
-//!  - device_renamed, group_renamed :: name filters only exist for entities (which cant be renamed)
+//!  - group_renamed :: groups are only filtered by ID
 //!  - ext_attached :: it's devices don't have entities/components yet
 //!  - entity_registered :: doesn't have components yet (can't pass query.component filter)
 //!
 //! # Contraction Events
 //!  - component_put that now doesn't satify type_filter
+//!  - component_removed, if it was query.component or now doesn't satisfy type_filter
 //!  - device_deleted
 //!  - group_deleted|group_device_removed AND device now doesn't satify device_filter.group (in cases of ::InAny, it still may be valid)
+//!  - label removed from a device or entity with a label filter
+//!  - device renamed so it no longer matches device_filter.name
+//!  - device replaced (old hardware and the donor's ID are gone)
+//!  - group moved out of the tree of an ::InTree|::InAnyTree filter
 //!  - ext_detached :: we know we can recieve component updates from detached devices
 
 use crate::{
     core::{ClientManager, IglooError, IglooResponse},
     query::{
         QueryContext,
+        ctx::NameMatcher,
         iter::{
-            passes_entity_id_filter, passes_group_filter, passes_id_filter, passes_owner_filter,
+            passes_entity_id_filter, passes_group_filter, passes_id_filter, passes_label_filter,
+            passes_name_filter, passes_owner_filter, tree_filter_groups,
             watch::{estimate_entity_count, for_each_entity},
         },
         watch::{WatcherID, dispatch::TreeEventResponder, subscriber::TreeSubscribers},
@@ -36,9 +48,10 @@ use crate::{
 };
 use igloo_interface::{
     Aggregator, Component, ComponentType,
-    id::{DeviceID, EntityIndex, ExtensionIndex, GroupID},
+    id::{DeviceID, EntityID, EntityIndex, ExtensionID, ExtensionIndex, GroupID},
     query::{
-        DeviceGroupFilter, TypeFilter, WatchComponentQuery, WatchUpdate as U, check::QueryError,
+        DeviceGroupFilter, DiscoveredDevice, TypeFilter, WatchComponentQuery, WatchUpdate as U,
+        check::QueryError,
     },
     types::IglooValue,
 };
@@ -52,7 +65,11 @@ pub struct ComponentWatcher {
     pub id: WatcherID,
     pub subs: Vec<(usize, usize)>,
     pub query: WatchComponentQuery,
+    /// Compiled `query.device_filter.name`
+    name: Option<NameMatcher>,
     matched: FxHashMap<DeviceID, DeviceMatch>,
+    /// Groups covered by an ::InTree|::InAnyTree filter, which change as groups move
+    tree_groups: FxHashSet<GroupID>,
 }
 
 #[derive(Debug)]
@@ -95,6 +112,37 @@ fn unsubscribe_from_group_events(subs: &mut TreeSubscribers, gid: GroupID, watch
     }
 }
 
+/// Devices can't subscribe to group_device_removed individually for tree filters,
+/// since the groups in the tree change
+fn subscribe_to_tree_events(
+    subs: &mut TreeSubscribers,
+    gids: &FxHashSet<GroupID>,
+    watcher_id: usize,
+) {
+    for gid in gids {
+        subscribe_to_group_events(subs, *gid, watcher_id);
+        subs.group_device_removed
+            .by_gid
+            .entry(*gid)
+            .or_default()
+            .all
+            .push(watcher_id);
+    }
+}
+
+fn unsubscribe_from_tree_events(
+    subs: &mut TreeSubscribers,
+    gids: &FxHashSet<GroupID>,
+    watcher_id: usize,
+) {
+    for gid in gids {
+        unsubscribe_from_group_events(subs, *gid, watcher_id);
+        if let Some(group_sub) = subs.group_device_removed.by_gid.get_mut(gid) {
+            group_sub.all.retain(|o| *o != watcher_id);
+        }
+    }
+}
+
 impl ComponentWatcher {
     /// Subscriptions are registered here
     /// Then the subsequent `on_*` below will trigger
@@ -116,13 +164,18 @@ impl ComponentWatcher {
             return Err(QueryError::ComponentNoValue(query.component));
         }
 
+        ctx.check_patterns(&query.device_filter.name, &None, &query.entity_filter.id)?;
+        let name = ctx.name_matcher(&query.device_filter.name)?;
+
         let mut me = Self {
             id: watcher_id,
             subs: Vec::with_capacity(3),
+            name,
             matched: HashMap::with_capacity_and_hasher(
                 estimate_entity_count(tree, &query) + 5,
                 FxBuildHasher,
             ),
+            tree_groups: tree_filter_groups(tree, &query.device_filter.group),
             query,
         };
 
@@ -145,8 +198,29 @@ impl ComponentWatcher {
                     subscribe_to_group_events(subs, *gid, watcher_id);
                 }
             }
+            DeviceGroupFilter::InTree(_) | DeviceGroupFilter::InAnyTree(_) => {
+                subscribe_to_tree_events(subs, &me.tree_groups, watcher_id);
+                // a group moving anywhere can move it into or out of the tree
+                subs.group_parent_changed.all.push(watcher_id);
+            }
         }
 
+        // labels added/removed, can cause expansion or contraction
+        if me.query.device_filter.label.is_some() {
+            subs.device_labels.all.push(watcher_id);
+        }
+        if me.query.entity_filter.label.is_some() {
+            subs.entity_labels.all.push(watcher_id);
+        }
+
+        // renames can cause expansion or contraction
+        if me.name.is_some() {
+            subs.device_renamed.all.push(watcher_id);
+        }
+
+        // replacements swap out a device's entities
+        subs.device_replaced.all.push(watcher_id);
+
         // listen to component put of CTs we care about
         // listen to all types, can cause expansion (With, And, Or) OR contraction (Without, Not, And)
         let mut care = HashSet::with_capacity_and_hasher(20, FxBuildHasher);
@@ -240,9 +314,18 @@ impl ComponentWatcher {
                     unsubscribe_from_group_events(subs, *gid, self.id);
                 }
             }
+            DeviceGroupFilter::InTree(_) | DeviceGroupFilter::InAnyTree(_) => {
+                unsubscribe_from_tree_events(subs, &self.tree_groups, self.id);
+                subs.group_parent_changed.all.retain(|o| *o != self.id);
+            }
             DeviceGroupFilter::Any => {}
         }
 
+        subs.device_labels.all.retain(|o| *o != self.id);
+        subs.entity_labels.all.retain(|o| *o != self.id);
+        subs.device_renamed.all.retain(|o| *o != self.id);
+        subs.device_replaced.all.retain(|o| *o != self.id);
+
         // clean up component_put subscriptions
         let mut care = HashSet::with_capacity_and_hasher(20, FxBuildHasher);
         if let Some(filter) = &self.query.entity_filter.type_filter {
@@ -286,10 +369,17 @@ impl ComponentWatcher {
         if !passes_id_filter(device, &self.query.device_filter.id)
             || !passes_group_filter(device, &self.query.device_filter.group, tree)
             || !passes_owner_filter(device, &self.query.device_filter.owner)
+            || !passes_label_filter(device.labels(), &self.query.device_filter.label)
+            || !passes_name_filter(device.name(), self.name.as_ref())
         {
             return false;
         }
 
+        let entity_labels = device.entity_labels(entity.id());
+        if !passes_label_filter(entity_labels, &self.query.entity_filter.label) {
+            return false;
+        }
+
         if let Some(filter) = &self.query.entity_filter.type_filter
             && !entity.matches(filter)
         {
@@ -406,6 +496,53 @@ impl ComponentWatcher {
 
         Ok(())
     }
+
+    /// Re-resolves an ::InTree|::InAnyTree filter after groups moved or were deleted
+    fn resync_tree(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+    ) -> Result<(), IglooError> {
+        let gids = tree_filter_groups(tree, &self.query.device_filter.group);
+        if gids == self.tree_groups {
+            // moved within the tree, or somewhere we don't care about
+            return Ok(());
+        }
+
+        unsubscribe_from_tree_events(subs, &self.tree_groups, self.id);
+        subscribe_to_tree_events(subs, &gids, self.id);
+        let added: Vec<GroupID> = gids.difference(&self.tree_groups).copied().collect();
+        self.tree_groups = gids;
+
+        let group_filter = self.query.device_filter.group.clone();
+        self.contract_matching_devices(subs, cm, |did, _| {
+            tree.device(did)
+                .map(|device| !passes_group_filter(device, &group_filter, tree))
+                .unwrap_or(false)
+        })?;
+
+        let mut expanded = false;
+        for gid in added {
+            let Ok(group) = tree.group(&gid) else {
+                continue;
+            };
+            for did in group.devices() {
+                let Ok(device) = tree.device(did) else {
+                    continue;
+                };
+                for entity in device.entities() {
+                    expanded |= self.try_expand_entity(ctx, subs, tree, device, entity);
+                }
+            }
+        }
+
+        if expanded {
+            self.broadcast_aggregate_update(cm)?;
+        }
+        Ok(())
+    }
 }
 
 impl DeviceMatch {
@@ -464,6 +601,9 @@ impl DeviceMatch {
                         .push(watcher_id);
                 }
             }
+            DeviceGroupFilter::InTree(_) | DeviceGroupFilter::InAnyTree(_) => {
+                // the watcher is subscribed to every group in the tree
+            }
         }
 
         Self {
@@ -550,6 +690,7 @@ impl DeviceMatch {
                     }
                 }
             }
+            DeviceGroupFilter::InTree(_) | DeviceGroupFilter::InAnyTree(_) => {}
         }
     }
 }
@@ -699,6 +840,45 @@ impl TreeEventResponder for ComponentWatcher {
         Ok(())
     }
 
+    fn on_component_removed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+        entity_index: EntityIndex,
+        _comp_type: ComponentType,
+    ) -> Result<(), IglooError> {
+        let entity = &device.entities()[entity_index.0];
+
+        if !entity.has(self.query.component)
+            || self
+                .query
+                .entity_filter
+                .type_filter
+                .as_ref()
+                .is_some_and(|filter| !entity.matches(filter))
+        {
+            self.contract_entity(subs, cm, *device.id(), entity_index)?;
+        } else if self.try_expand_entity(ctx, subs, tree, device, entity) {
+            // ex. it no longer has a component excluded by ::Without
+            let comp = entity.get(self.query.component).unwrap().clone();
+            return self.on_component_set(
+                cm,
+                ctx,
+                subs,
+                tree,
+                device,
+                entity_index,
+                self.query.component,
+                &comp,
+            );
+        }
+
+        Ok(())
+    }
+
     fn on_device_deleted(
         &mut self,
         cm: &mut ClientManager,
@@ -713,11 +893,18 @@ impl TreeEventResponder for ComponentWatcher {
     fn on_group_deleted(
         &mut self,
         cm: &mut ClientManager,
-        _ctx: &mut QueryContext,
+        ctx: &mut QueryContext,
         subs: &mut TreeSubscribers,
         tree: &DeviceTree,
         _gid: &GroupID,
     ) -> Result<(), IglooError> {
+        if matches!(
+            self.query.device_filter.group,
+            DeviceGroupFilter::InTree(_) | DeviceGroupFilter::InAnyTree(_)
+        ) {
+            return self.resync_tree(cm, ctx, subs, tree);
+        }
+
         let group_filter = self.query.device_filter.group.clone();
         self.contract_matching_devices(subs, cm, |did, _| {
             tree.device(did)
@@ -726,6 +913,100 @@ impl TreeEventResponder for ComponentWatcher {
         })
     }
 
+    fn on_group_parent_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        _group: &Group,
+    ) -> Result<(), IglooError> {
+        self.resync_tree(cm, ctx, subs, tree)
+    }
+
+    fn on_device_labels_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError> {
+        if !passes_label_filter(device.labels(), &self.query.device_filter.label) {
+            if self.matched.contains_key(device.id()) {
+                self.contract_device(subs, cm, *device.id())?;
+            }
+            return Ok(());
+        }
+
+        let mut expanded = false;
+        for entity in device.entities() {
+            expanded |= self.try_expand_entity(ctx, subs, tree, device, entity);
+        }
+        if expanded {
+            self.broadcast_aggregate_update(cm)?;
+        }
+        Ok(())
+    }
+
+    fn on_device_replaced(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+        donor: &Device,
+    ) -> Result<(), IglooError> {
+        // both sets of entities are gone, rematch the new ones under the kept ID
+        let mut changed = false;
+        for did in [donor.id(), device.id()] {
+            if let Some(device_match) = self.matched.remove(did) {
+                device_match.cleanup(subs, &self.query);
+                changed = true;
+            }
+        }
+        for entity in device.entities() {
+            changed |= self.try_expand_entity(ctx, subs, tree, device, entity);
+        }
+        if changed {
+            self.broadcast_aggregate_update(cm)?;
+        }
+        Ok(())
+    }
+
+    fn on_entity_labels_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+        eid: &EntityID,
+    ) -> Result<(), IglooError> {
+        // not registered (yet)
+        let Some(entity_index) = device.entity_index(eid) else {
+            return Ok(());
+        };
+
+        if !passes_label_filter(device.entity_labels(eid), &self.query.entity_filter.label) {
+            let matched = self
+                .matched
+                .get(device.id())
+                .is_some_and(|dm| dm.entities.contains_key(entity_index));
+            if matched {
+                self.contract_entity(subs, cm, *device.id(), *entity_index)?;
+            }
+            return Ok(());
+        }
+
+        let entity = &device.entities()[entity_index.0];
+        if self.try_expand_entity(ctx, subs, tree, device, entity) {
+            self.broadcast_aggregate_update(cm)?;
+        }
+        Ok(())
+    }
+
     fn on_group_device_added(
         &mut self,
         _cm: &mut ClientManager,
@@ -785,6 +1066,46 @@ impl TreeEventResponder for ComponentWatcher {
     }
 
     fn on_device_renamed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError> {
+        if !passes_name_filter(device.name(), self.name.as_ref()) {
+            if self.matched.contains_key(device.id()) {
+                self.contract_device(subs, cm, *device.id())?;
+            }
+            return Ok(());
+        }
+
+        let mut expanded = false;
+        for entity in device.entities() {
+            expanded |= self.try_expand_entity(ctx, subs, tree, device, entity);
+        }
+        if expanded {
+            self.broadcast_aggregate_update(cm)?;
+        }
+        Ok(())
+    }
+
+    fn on_device_availability_changed(
+        &mut self,
+        _: &mut ClientManager,
+        _: &mut QueryContext,
+        _: &mut TreeSubscribers,
+        _: &DeviceTree,
+        _: &Device,
+    ) -> Result<(), IglooError> {
+        debug_assert!(
+            false,
+            "ComponentWatcher should never receive device_availability events"
+        );
+        Ok(())
+    }
+
+    fn on_device_info_changed(
         &mut self,
         _: &mut ClientManager,
         _: &mut QueryContext,
@@ -794,7 +1115,7 @@ impl TreeEventResponder for ComponentWatcher {
     ) -> Result<(), IglooError> {
         debug_assert!(
             false,
-            "ComponentWatcher should never receive device_renamed events"
+            "ComponentWatcher should never receive device_info events"
         );
         Ok(())
     }
@@ -859,4 +1180,35 @@ impl TreeEventResponder for ComponentWatcher {
         );
         Ok(())
     }
+
+    fn on_device_discovered(
+        &mut self,
+        _: &mut ClientManager,
+        _: &mut QueryContext,
+        _: &mut TreeSubscribers,
+        _: &DeviceTree,
+        _: &DiscoveredDevice,
+    ) -> Result<(), IglooError> {
+        debug_assert!(
+            false,
+            "ComponentWatcher should never receive device_discovered events"
+        );
+        Ok(())
+    }
+
+    fn on_discovered_removed(
+        &mut self,
+        _: &mut ClientManager,
+        _: &mut QueryContext,
+        _: &mut TreeSubscribers,
+        _: &DeviceTree,
+        _: &ExtensionID,
+        _: &str,
+    ) -> Result<(), IglooError> {
+        debug_assert!(
+            false,
+            "ComponentWatcher should never receive discovered_removed events"
+        );
+        Ok(())
+    }
 }
@@ -12,7 +12,8 @@ use crate::{
 };
 use igloo_interface::{
     Component, ComponentType,
-    id::{EntityIndex, GroupID},
+    id::{EntityID, EntityIndex, ExtensionID, GroupID},
+    query::{DiscoveredDevice, JournalEntry},
 };
 
 impl QueryEngine {
@@ -25,6 +26,8 @@ impl QueryEngine {
         comp_type: ComponentType,
         comp: Component,
     ) -> Result<(), IglooError> {
+        self.history.on_component(device, entity_index, &comp);
+
         let affected = self
             .tree_subs
             .component_set
@@ -57,6 +60,7 @@ impl QueryEngine {
                             &comp,
                         )?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -73,6 +77,8 @@ impl QueryEngine {
         comp_type: ComponentType,
         comp: Component,
     ) -> Result<(), IglooError> {
+        self.history.on_component(device, entity_index, &comp);
+
         let affected =
             self.tree_subs
                 .component_put
@@ -105,6 +111,53 @@ impl QueryEngine {
                             &comp,
                         )?;
                     }
+                    Watcher::Journal(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removal undoes a put, so it goes to the same subscribers
+    pub fn on_component_removed(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &DeviceTree,
+        device: &Device,
+        entity_index: EntityIndex,
+        comp_type: ComponentType,
+    ) -> Result<(), IglooError> {
+        let affected =
+            self.tree_subs
+                .component_put
+                .affected(device.id(), &entity_index, &comp_type);
+
+        for watcher_id in affected {
+            if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
+                match watcher {
+                    Watcher::Component(w) => {
+                        w.on_component_removed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                            entity_index,
+                            comp_type,
+                        )?;
+                    }
+                    Watcher::Metadata(w) => {
+                        w.on_component_removed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                            entity_index,
+                            comp_type,
+                        )?;
+                    }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -128,6 +181,7 @@ impl QueryEngine {
                     Watcher::Metadata(w) => {
                         w.on_device_created(cm, &mut self.ctx, &mut self.tree_subs, tree, device)?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -151,6 +205,7 @@ impl QueryEngine {
                     Watcher::Metadata(w) => {
                         w.on_device_deleted(cm, &mut self.ctx, &mut self.tree_subs, tree, device)?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -174,6 +229,190 @@ impl QueryEngine {
                     Watcher::Metadata(w) => {
                         w.on_device_renamed(cm, &mut self.ctx, &mut self.tree_subs, tree, device)?;
                     }
+                    Watcher::Journal(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn on_device_availability_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError> {
+        let affected = self.tree_subs.device_availability.affected(device.id());
+        for watcher_id in affected {
+            if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
+                match watcher {
+                    Watcher::Component(w) => {
+                        w.on_device_availability_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                        )?;
+                    }
+                    Watcher::Metadata(w) => {
+                        w.on_device_availability_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                        )?;
+                    }
+                    Watcher::Journal(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn on_device_info_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError> {
+        let affected = self.tree_subs.device_info.affected(device.id());
+        for watcher_id in affected {
+            if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
+                match watcher {
+                    Watcher::Component(w) => {
+                        w.on_device_info_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                        )?;
+                    }
+                    Watcher::Metadata(w) => {
+                        w.on_device_info_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                        )?;
+                    }
+                    Watcher::Journal(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `device` has taken over `donor`'s hardware, and `donor` is deleted
+    pub fn on_device_replaced(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &DeviceTree,
+        device: &Device,
+        donor: &Device,
+    ) -> Result<(), IglooError> {
+        let affected = self.tree_subs.device_replaced.affected(device.id());
+        for watcher_id in affected {
+            if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
+                match watcher {
+                    Watcher::Component(w) => {
+                        w.on_device_replaced(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                            donor,
+                        )?;
+                    }
+                    Watcher::Metadata(w) => {
+                        w.on_device_replaced(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                            donor,
+                        )?;
+                    }
+                    Watcher::Journal(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn on_device_labels_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError> {
+        let affected = self.tree_subs.device_labels.affected(device.id());
+        for watcher_id in affected {
+            if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
+                match watcher {
+                    Watcher::Component(w) => {
+                        w.on_device_labels_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                        )?;
+                    }
+                    Watcher::Metadata(w) => {
+                        w.on_device_labels_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                        )?;
+                    }
+                    Watcher::Journal(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sent for any entity ID, even if it isn't registered
+    pub fn on_entity_labels_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &DeviceTree,
+        device: &Device,
+        eid: &EntityID,
+    ) -> Result<(), IglooError> {
+        let affected = self.tree_subs.entity_labels.affected(device.id());
+        for watcher_id in affected {
+            if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
+                match watcher {
+                    Watcher::Component(w) => {
+                        w.on_entity_labels_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                            eid,
+                        )?;
+                    }
+                    Watcher::Metadata(w) => {
+                        w.on_entity_labels_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            device,
+                            eid,
+                        )?;
+                    }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -211,6 +450,7 @@ impl QueryEngine {
                             entity_index,
                         )?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -233,6 +473,7 @@ impl QueryEngine {
                     Watcher::Metadata(w) => {
                         w.on_group_created(cm, &mut self.ctx, &mut self.tree_subs, tree, group)?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -255,6 +496,7 @@ impl QueryEngine {
                     Watcher::Metadata(w) => {
                         w.on_group_deleted(cm, &mut self.ctx, &mut self.tree_subs, tree, gid)?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -277,6 +519,42 @@ impl QueryEngine {
                     Watcher::Metadata(w) => {
                         w.on_group_renamed(cm, &mut self.ctx, &mut self.tree_subs, tree, group)?;
                     }
+                    Watcher::Journal(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn on_group_parent_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &DeviceTree,
+        group: &Group,
+    ) -> Result<(), IglooError> {
+        let affected = self.tree_subs.group_parent_changed.affected(group.id());
+        for watcher_id in affected {
+            if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
+                match watcher {
+                    Watcher::Component(w) => {
+                        w.on_group_parent_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            group,
+                        )?;
+                    }
+                    Watcher::Metadata(w) => {
+                        w.on_group_parent_changed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            group,
+                        )?;
+                    }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -317,6 +595,7 @@ impl QueryEngine {
                             device,
                         )?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -357,6 +636,7 @@ impl QueryEngine {
                             device,
                         )?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -379,6 +659,7 @@ impl QueryEngine {
                     Watcher::Metadata(w) => {
                         w.on_ext_attached(cm, &mut self.ctx, &mut self.tree_subs, tree, ext)?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
@@ -391,6 +672,9 @@ impl QueryEngine {
         tree: &DeviceTree,
         ext: &Extension,
     ) -> Result<(), IglooError> {
+        self.commands.on_ext_detached(cm, *ext.index());
+        self.calls.on_ext_detached(cm, *ext.index());
+
         let affected = self.tree_subs.ext_detached.affected(ext);
         for watcher_id in affected {
             if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
@@ -401,11 +685,99 @@ impl QueryEngine {
                     Watcher::Metadata(w) => {
                         w.on_ext_detached(cm, &mut self.ctx, &mut self.tree_subs, tree, ext)?;
                     }
+                    Watcher::Journal(_) => {}
                 }
             }
         }
         Ok(())
     }
+
+    pub fn on_device_discovered(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &DeviceTree,
+        discovered: &DiscoveredDevice,
+    ) -> Result<(), IglooError> {
+        let affected = self.tree_subs.device_discovered.affected(&discovered.ext);
+        for watcher_id in affected {
+            if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
+                match watcher {
+                    Watcher::Component(w) => {
+                        w.on_device_discovered(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            discovered,
+                        )?;
+                    }
+                    Watcher::Metadata(w) => {
+                        w.on_device_discovered(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            discovered,
+                        )?;
+                    }
+                    Watcher::Journal(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn on_discovered_removed(
+        &mut self,
+        cm: &mut ClientManager,
+        tree: &DeviceTree,
+        xid: &ExtensionID,
+        external_id: &str,
+    ) -> Result<(), IglooError> {
+        let affected = self.tree_subs.discovered_removed.affected(xid);
+        for watcher_id in affected {
+            if let Some(Some(watcher)) = self.watchers.get_mut(watcher_id) {
+                match watcher {
+                    Watcher::Component(w) => {
+                        w.on_discovered_removed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            xid,
+                            external_id,
+                        )?;
+                    }
+                    Watcher::Metadata(w) => {
+                        w.on_discovered_removed(
+                            cm,
+                            &mut self.ctx,
+                            &mut self.tree_subs,
+                            tree,
+                            xid,
+                            external_id,
+                        )?;
+                    }
+                    Watcher::Journal(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Journal entries aren't tree events, so they skip [TreeEventResponder]
+    pub fn on_journal_entry(
+        &mut self,
+        cm: &mut ClientManager,
+        entry: &JournalEntry,
+    ) -> Result<(), IglooError> {
+        for &watcher_id in &self.tree_subs.journal {
+            if let Some(Some(Watcher::Journal(w))) = self.watchers.get(watcher_id) {
+                w.on_entry(cm, entry)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub trait TreeEventResponder {
@@ -433,6 +805,18 @@ pub trait TreeEventResponder {
         comp: &Component,
     ) -> Result<(), IglooError>;
 
+    #[allow(clippy::too_many_arguments)]
+    fn on_component_removed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+        entity_index: EntityIndex,
+        comp_type: ComponentType,
+    ) -> Result<(), IglooError>;
+
     fn on_device_created(
         &mut self,
         cm: &mut ClientManager,
@@ -460,6 +844,58 @@ pub trait TreeEventResponder {
         device: &Device,
     ) -> Result<(), IglooError>;
 
+    /// Owner attaching or detaching doesn't trigger this, use
+    /// [Self::on_ext_attached] and [Self::on_ext_detached] instead
+    fn on_device_availability_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError>;
+
+    /// Info, icon or notes changed
+    fn on_device_info_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError>;
+
+    fn on_device_labels_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError>;
+
+    /// `device` has taken over `donor`'s hardware, and `donor` is deleted
+    fn on_device_replaced(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+        donor: &Device,
+    ) -> Result<(), IglooError>;
+
+    /// Sent for any entity ID, even if it isn't registered
+    fn on_entity_labels_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+        eid: &EntityID,
+    ) -> Result<(), IglooError>;
+
     fn on_entity_registered(
         &mut self,
         cm: &mut ClientManager,
@@ -497,6 +933,16 @@ pub trait TreeEventResponder {
         group: &Group,
     ) -> Result<(), IglooError>;
 
+    /// Moved into another group, or to the top
+    fn on_group_parent_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        group: &Group,
+    ) -> Result<(), IglooError>;
+
     fn on_group_device_added(
         &mut self,
         cm: &mut ClientManager,
@@ -534,4 +980,24 @@ pub trait TreeEventResponder {
         tree: &DeviceTree,
         ext: &Extension,
     ) -> Result<(), IglooError>;
+
+    fn on_device_discovered(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        discovered: &DiscoveredDevice,
+    ) -> Result<(), IglooError>;
+
+    /// Accepted, ignored, or its Extension detached
+    fn on_discovered_removed(
+        &mut self,
+        cm: &mut ClientManager,
+        ctx: &mut QueryContext,
+        subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        xid: &ExtensionID,
+        external_id: &str,
+    ) -> Result<(), IglooError>;
 }
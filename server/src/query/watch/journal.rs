@@ -0,0 +1,52 @@
+//! Streams new journal entries, nothing is sent on subscribe
+//! (past entries come from [igloo_interface::query::OneShotQuery::Journal])
+
+use crate::{
+    core::{ClientManager, IglooError, IglooResponse},
+    query::watch::{WatcherID, subscriber::TreeSubscribers},
+    tree::journal::kind_matches,
+};
+use igloo_interface::query::{JournalEntry, WatchJournalQuery, WatchUpdate};
+
+pub struct JournalWatcher {
+    pub id: WatcherID,
+    pub subs: Vec<(usize, usize)>,
+    pub query: WatchJournalQuery,
+}
+
+impl JournalWatcher {
+    pub fn register(subs: &mut TreeSubscribers, id: WatcherID, query: WatchJournalQuery) -> Self {
+        subs.journal.push(id);
+        Self {
+            id,
+            subs: Vec::with_capacity(2),
+            query,
+        }
+    }
+
+    pub fn on_sub(&mut self, client_id: usize, query_id: usize) -> Result<(), IglooError> {
+        self.subs.push((client_id, query_id));
+        Ok(())
+    }
+
+    pub fn cleanup(&mut self, subs: &mut TreeSubscribers) {
+        subs.journal.retain(|&id| id != self.id);
+    }
+
+    pub fn on_entry(&self, cm: &mut ClientManager, entry: &JournalEntry) -> Result<(), IglooError> {
+        if !kind_matches(&self.query.kinds, entry) {
+            return Ok(());
+        }
+
+        for (client_id, query_id) in &self.subs {
+            cm.send(
+                *client_id,
+                IglooResponse::WatchUpdate {
+                    query_id: *query_id,
+                    value: WatchUpdate::Journal(entry.clone()),
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
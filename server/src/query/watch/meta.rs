@@ -4,23 +4,31 @@ use crate::{
         QueryContext,
         watch::{WatcherID, dispatch::TreeEventResponder, subscriber::TreeSubscribers},
     },
-    tree::{Device, DeviceTree, Extension, Group},
+    tree::{Device, DeviceTree, DiscoveryKey, Extension, Group},
 };
 use igloo_interface::{
     Component, ComponentType,
-    id::{DeviceID, EntityIndex, ExtensionID, GroupID},
-    query::{DeviceMetadata, ExtensionMetadata, GroupMetadata, MetadataUpdate as U, WatchUpdate},
+    id::{DeviceID, EntityID, EntityIndex, ExtensionID, GroupID},
+    query::{
+        DeviceMetadata, DiscoveredDevice, ExtensionMetadata, GroupMetadata, MetadataUpdate as U,
+        WatchUpdate,
+    },
 };
-use rustc_hash::{FxBuildHasher, FxHashMap};
-use std::collections::HashMap;
+use rustc_hash::{FxBuildHasher, FxHashMap, FxHashSet};
+use std::collections::{HashMap, HashSet};
 
 pub struct MetadataWatcher {
     pub id: WatcherID,
     pub subs: Vec<(usize, usize)>,
 
     devices: FxHashMap<DeviceID, DeviceMetadata>,
+    /// devices in `devices` which are available
+    available: FxHashSet<DeviceID>,
     groups: FxHashMap<GroupID, GroupMetadata>,
     exts: FxHashMap<ExtensionID, ExtensionMetadata>,
+    discovered: FxHashMap<DiscoveryKey, DiscoveredDevice>,
+    /// only entities with labels
+    entity_labels: FxHashMap<(DeviceID, EntityID), Vec<String>>,
 }
 
 impl MetadataWatcher {
@@ -28,48 +36,51 @@ impl MetadataWatcher {
         subs.device_created.all.push(id);
         subs.device_deleted.all.push(id);
         subs.device_renamed.all.push(id);
+        subs.device_availability.all.push(id);
+        subs.device_info.all.push(id);
+        subs.device_labels.all.push(id);
+        subs.device_replaced.all.push(id);
+        subs.entity_labels.all.push(id);
 
         subs.group_created.all.push(id);
         subs.group_deleted.all.push(id);
         subs.group_renamed.all.push(id);
+        subs.group_parent_changed.all.push(id);
         subs.group_device_added.all.push(id);
         subs.group_device_removed.all.push(id);
 
         subs.ext_attached.all.push(id);
         subs.ext_detached.all.push(id);
 
+        subs.device_discovered.all.push(id);
+        subs.discovered_removed.all.push(id);
+
         let mut devices = HashMap::with_capacity_and_hasher(20, FxBuildHasher);
+        let mut available = HashSet::with_capacity_and_hasher(20, FxBuildHasher);
         let mut groups = HashMap::with_capacity_and_hasher(5, FxBuildHasher);
         let mut exts = HashMap::with_capacity_and_hasher(3, FxBuildHasher);
+        let mut entity_labels = HashMap::with_capacity_and_hasher(5, FxBuildHasher);
 
         for device in tree.devices().iter() {
-            devices.insert(
-                *device.id(),
-                DeviceMetadata {
-                    name: device.name().to_string(),
-                },
-            );
+            devices.insert(*device.id(), device_metadata(device));
+            for (eid, labels) in device.all_entity_labels() {
+                entity_labels.insert(
+                    (*device.id(), eid.clone()),
+                    labels.iter().cloned().collect(),
+                );
+            }
+            if device.available() {
+                available.insert(*device.id());
+            }
         }
 
         for group in tree.groups().iter() {
-            groups.insert(
-                *group.id(),
-                GroupMetadata {
-                    name: group.name().to_string(),
-                    devices: group.devices().iter().copied().collect(),
-                },
-            );
+            groups.insert(*group.id(), group_metadata(group));
         }
 
         for ext in tree.exts().iter() {
             if let Some(ext) = ext {
-                exts.insert(
-                    ext.id().clone(),
-                    ExtensionMetadata {
-                        index: *ext.index(),
-                        devices: ext.devices().iter().copied().collect(),
-                    },
-                );
+                exts.insert(ext.id().clone(), ext_metadata(ext));
             }
         }
 
@@ -77,8 +88,11 @@ impl MetadataWatcher {
             id,
             subs: Vec::with_capacity(5),
             devices,
+            available,
             groups,
             exts,
+            discovered: tree.discovered().clone(),
+            entity_labels,
         }
     }
 
@@ -90,11 +104,17 @@ impl MetadataWatcher {
     ) -> Result<(), IglooError> {
         self.subs.push((client_id, query_id));
 
-        let mut batch =
-            Vec::with_capacity(self.devices.len() + self.groups.len() + self.exts.len());
+        let mut batch = Vec::with_capacity(
+            (self.devices.len() << 1)
+                + self.groups.len()
+                + self.exts.len()
+                + self.discovered.len()
+                + self.entity_labels.len(),
+        );
 
         for (id, metadata) in &self.devices {
             batch.push(U::Device(*id, metadata.clone()));
+            batch.push(U::DeviceAvailability(*id, self.available.contains(id)));
         }
 
         for (id, metadata) in &self.groups {
@@ -105,6 +125,14 @@ impl MetadataWatcher {
             batch.push(U::Extension(id.clone(), metadata.clone()));
         }
 
+        for discovered in self.discovered.values() {
+            batch.push(U::Discovered(discovered.clone()));
+        }
+
+        for ((did, eid), labels) in &self.entity_labels {
+            batch.push(U::EntityLabels(*did, eid.clone(), labels.clone()));
+        }
+
         cm.send(
             client_id,
             IglooResponse::WatchUpdate {
@@ -118,29 +146,84 @@ impl MetadataWatcher {
         subs.device_created.all.retain(|&id| id != self.id);
         subs.device_deleted.all.retain(|&id| id != self.id);
         subs.device_renamed.all.retain(|&id| id != self.id);
+        subs.device_availability.all.retain(|&id| id != self.id);
+        subs.device_info.all.retain(|&id| id != self.id);
+        subs.device_labels.all.retain(|&id| id != self.id);
+        subs.device_replaced.all.retain(|&id| id != self.id);
+        subs.entity_labels.all.retain(|&id| id != self.id);
 
         subs.group_created.all.retain(|&id| id != self.id);
         subs.group_deleted.all.retain(|&id| id != self.id);
         subs.group_renamed.all.retain(|&id| id != self.id);
+        subs.group_parent_changed.all.retain(|&id| id != self.id);
         subs.group_device_added.all.retain(|&id| id != self.id);
         subs.group_device_removed.all.retain(|&id| id != self.id);
 
         subs.ext_attached.all.retain(|&id| id != self.id);
         subs.ext_detached.all.retain(|&id| id != self.id);
+
+        subs.device_discovered.all.retain(|&id| id != self.id);
+        subs.discovered_removed.all.retain(|&id| id != self.id);
     }
 
     fn broadcast(&self, cm: &mut ClientManager, update: U) -> Result<(), IglooError> {
+        self.broadcast_batch(cm, vec![update])
+    }
+
+    fn broadcast_batch(&self, cm: &mut ClientManager, batch: Vec<U>) -> Result<(), IglooError> {
         for (client_id, query_id) in &self.subs {
             cm.send(
                 *client_id,
                 IglooResponse::WatchUpdate {
                     query_id: *query_id,
-                    value: WatchUpdate::Metadata(vec![update.clone()]),
+                    value: WatchUpdate::Metadata(batch.clone()),
                 },
             )?;
         }
         Ok(())
     }
+
+    /// Broadcasts if it changed
+    fn set_available(
+        &mut self,
+        cm: &mut ClientManager,
+        did: DeviceID,
+        available: bool,
+    ) -> Result<(), IglooError> {
+        let changed = match available {
+            true => self.available.insert(did),
+            false => self.available.remove(&did),
+        };
+        if !changed {
+            return Ok(());
+        }
+        self.broadcast(cm, U::DeviceAvailability(did, available))
+    }
+}
+
+fn device_metadata(device: &Device) -> DeviceMetadata {
+    DeviceMetadata {
+        name: device.name().to_string(),
+        info: device.info().clone(),
+        icon: device.icon().map(str::to_string),
+        notes: device.notes().map(str::to_string),
+        labels: device.labels().iter().cloned().collect(),
+    }
+}
+
+fn ext_metadata(ext: &Extension) -> ExtensionMetadata {
+    ExtensionMetadata {
+        index: *ext.index(),
+        devices: ext.devices().iter().copied().collect(),
+    }
+}
+
+fn group_metadata(group: &Group) -> GroupMetadata {
+    GroupMetadata {
+        name: group.name().to_string(),
+        devices: group.devices().iter().copied().collect(),
+        parent: group.parent().copied(),
+    }
 }
 
 impl TreeEventResponder for MetadataWatcher {
@@ -152,12 +235,11 @@ impl TreeEventResponder for MetadataWatcher {
         _tree: &DeviceTree,
         device: &Device,
     ) -> Result<(), IglooError> {
-        let metadata = DeviceMetadata {
-            name: device.name().to_string(),
-        };
+        let metadata = device_metadata(device);
 
         self.devices.insert(*device.id(), metadata.clone());
-        self.broadcast(cm, U::Device(*device.id(), metadata))
+        self.broadcast(cm, U::Device(*device.id(), metadata))?;
+        self.set_available(cm, *device.id(), device.available())
     }
 
     fn on_device_deleted(
@@ -169,6 +251,8 @@ impl TreeEventResponder for MetadataWatcher {
         device: &Device,
     ) -> Result<(), IglooError> {
         self.devices.remove(device.id());
+        self.available.remove(device.id());
+        self.entity_labels.retain(|(did, _), _| did != device.id());
         self.broadcast(cm, U::DeviceRemoved(*device.id()))
     }
 
@@ -180,14 +264,116 @@ impl TreeEventResponder for MetadataWatcher {
         _tree: &DeviceTree,
         device: &Device,
     ) -> Result<(), IglooError> {
-        let metadata = DeviceMetadata {
-            name: device.name().to_string(),
-        };
+        let metadata = device_metadata(device);
+
+        self.devices.insert(*device.id(), metadata.clone());
+        self.broadcast(cm, U::Device(*device.id(), metadata))
+    }
 
+    fn on_device_availability_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        _ctx: &mut QueryContext,
+        _subs: &mut TreeSubscribers,
+        _tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError> {
+        self.set_available(cm, *device.id(), device.available())
+    }
+
+    fn on_device_info_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        _ctx: &mut QueryContext,
+        _subs: &mut TreeSubscribers,
+        _tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError> {
+        let metadata = device_metadata(device);
         self.devices.insert(*device.id(), metadata.clone());
         self.broadcast(cm, U::Device(*device.id(), metadata))
     }
 
+    fn on_device_labels_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        _ctx: &mut QueryContext,
+        _subs: &mut TreeSubscribers,
+        _tree: &DeviceTree,
+        device: &Device,
+    ) -> Result<(), IglooError> {
+        let metadata = device_metadata(device);
+        self.devices.insert(*device.id(), metadata.clone());
+        self.broadcast(cm, U::Device(*device.id(), metadata))
+    }
+
+    fn on_device_replaced(
+        &mut self,
+        cm: &mut ClientManager,
+        _ctx: &mut QueryContext,
+        _subs: &mut TreeSubscribers,
+        tree: &DeviceTree,
+        device: &Device,
+        donor: &Device,
+    ) -> Result<(), IglooError> {
+        let did = *device.id();
+        let mut batch = Vec::with_capacity(4 + donor.groups().len());
+
+        self.devices.remove(donor.id());
+        self.available.remove(donor.id());
+        self.entity_labels.retain(|(did, _), _| did != donor.id());
+        batch.push(U::DeviceRemoved(*donor.id()));
+
+        let metadata = device_metadata(device);
+        self.devices.insert(did, metadata.clone());
+        batch.push(U::Device(did, metadata));
+        let changed = match device.available() {
+            true => self.available.insert(did),
+            false => self.available.remove(&did),
+        };
+        if changed {
+            batch.push(U::DeviceAvailability(did, device.available()));
+        }
+
+        // the donor's groups now hold `device` instead
+        for gid in donor.groups() {
+            let Ok(group) = tree.group(gid) else { continue };
+            let metadata = group_metadata(group);
+            self.groups.insert(*gid, metadata.clone());
+            batch.push(U::Group(*gid, metadata));
+        }
+
+        // one or two Extensions' device lists changed
+        for ext in tree.exts().iter().flatten() {
+            let metadata = ext_metadata(ext);
+            if self.exts.get(ext.id()) != Some(&metadata) {
+                self.exts.insert(ext.id().clone(), metadata.clone());
+                batch.push(U::Extension(ext.id().clone(), metadata));
+            }
+        }
+
+        self.broadcast_batch(cm, batch)
+    }
+
+    fn on_entity_labels_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        _ctx: &mut QueryContext,
+        _subs: &mut TreeSubscribers,
+        _tree: &DeviceTree,
+        device: &Device,
+        eid: &EntityID,
+    ) -> Result<(), IglooError> {
+        let labels: Vec<String> = device.entity_labels(eid).iter().cloned().collect();
+        let key = (*device.id(), eid.clone());
+        if labels.is_empty() {
+            self.entity_labels.remove(&key);
+        } else {
+            self.entity_labels.insert(key, labels.clone());
+        }
+        self.broadcast(cm, U::EntityLabels(*device.id(), eid.clone(), labels))
+    }
+
     fn on_group_created(
         &mut self,
         cm: &mut ClientManager,
@@ -196,10 +382,7 @@ impl TreeEventResponder for MetadataWatcher {
         _tree: &DeviceTree,
         group: &Group,
     ) -> Result<(), IglooError> {
-        let metadata = GroupMetadata {
-            name: group.name().to_string(),
-            devices: group.devices().iter().copied().collect(),
-        };
+        let metadata = group_metadata(group);
 
         self.groups.insert(*group.id(), metadata.clone());
         self.broadcast(cm, U::Group(*group.id(), metadata))
@@ -225,15 +408,25 @@ impl TreeEventResponder for MetadataWatcher {
         _tree: &DeviceTree,
         group: &Group,
     ) -> Result<(), IglooError> {
-        let metadata = GroupMetadata {
-            name: group.name().to_string(),
-            devices: group.devices().iter().copied().collect(),
-        };
+        let metadata = group_metadata(group);
 
         self.groups.insert(*group.id(), metadata.clone());
         self.broadcast(cm, U::Group(*group.id(), metadata))
     }
 
+    fn on_group_parent_changed(
+        &mut self,
+        cm: &mut ClientManager,
+        _ctx: &mut QueryContext,
+        _subs: &mut TreeSubscribers,
+        _tree: &DeviceTree,
+        group: &Group,
+    ) -> Result<(), IglooError> {
+        let metadata = group_metadata(group);
+        self.groups.insert(*group.id(), metadata.clone());
+        self.broadcast(cm, U::Group(*group.id(), metadata))
+    }
+
     fn on_group_device_added(
         &mut self,
         cm: &mut ClientManager,
@@ -243,10 +436,7 @@ impl TreeEventResponder for MetadataWatcher {
         group: &Group,
         _device: &Device,
     ) -> Result<(), IglooError> {
-        let metadata = GroupMetadata {
-            name: group.name().to_string(),
-            devices: group.devices().iter().copied().collect(),
-        };
+        let metadata = group_metadata(group);
 
         self.groups.insert(*group.id(), metadata.clone());
         self.broadcast(cm, U::Group(*group.id(), metadata))
@@ -261,10 +451,7 @@ impl TreeEventResponder for MetadataWatcher {
         group: &Group,
         _device: &Device,
     ) -> Result<(), IglooError> {
-        let metadata = GroupMetadata {
-            name: group.name().to_string(),
-            devices: group.devices().iter().copied().collect(),
-        };
+        let metadata = group_metadata(group);
 
         self.groups.insert(*group.id(), metadata.clone());
         self.broadcast(cm, U::Group(*group.id(), metadata))
@@ -275,7 +462,7 @@ impl TreeEventResponder for MetadataWatcher {
         cm: &mut ClientManager,
         _ctx: &mut QueryContext,
         _subs: &mut TreeSubscribers,
-        _tree: &DeviceTree,
+        tree: &DeviceTree,
         ext: &Extension,
     ) -> Result<(), IglooError> {
         let metadata = ExtensionMetadata {
@@ -284,7 +471,13 @@ impl TreeEventResponder for MetadataWatcher {
         };
 
         self.exts.insert(ext.id().clone(), metadata.clone());
-        self.broadcast(cm, U::Extension(ext.id().clone(), metadata))
+        self.broadcast(cm, U::Extension(ext.id().clone(), metadata))?;
+
+        for did in ext.devices() {
+            let available = tree.device(did).map(|d| d.available()).unwrap_or(false);
+            self.set_available(cm, *did, available)?;
+        }
+        Ok(())
     }
 
     fn on_ext_detached(
@@ -296,7 +489,45 @@ impl TreeEventResponder for MetadataWatcher {
         ext: &Extension,
     ) -> Result<(), IglooError> {
         self.exts.remove(ext.id());
-        self.broadcast(cm, U::ExtensionRemoved(ext.id().clone()))
+        self.broadcast(cm, U::ExtensionRemoved(ext.id().clone()))?;
+
+        // devices are unlinked after this
+        for did in ext.devices() {
+            self.set_available(cm, *did, false)?;
+        }
+        Ok(())
+    }
+
+    fn on_device_discovered(
+        &mut self,
+        cm: &mut ClientManager,
+        _ctx: &mut QueryContext,
+        _subs: &mut TreeSubscribers,
+        _tree: &DeviceTree,
+        discovered: &DiscoveredDevice,
+    ) -> Result<(), IglooError> {
+        self.discovered.insert(
+            (discovered.ext.clone(), discovered.external_id.clone()),
+            discovered.clone(),
+        );
+        self.broadcast(cm, U::Discovered(discovered.clone()))
+    }
+
+    fn on_discovered_removed(
+        &mut self,
+        cm: &mut ClientManager,
+        _ctx: &mut QueryContext,
+        _subs: &mut TreeSubscribers,
+        _tree: &DeviceTree,
+        xid: &ExtensionID,
+        external_id: &str,
+    ) -> Result<(), IglooError> {
+        self.discovered
+            .remove(&(xid.clone(), external_id.to_string()));
+        self.broadcast(
+            cm,
+            U::DiscoveredRemoved(xid.clone(), external_id.to_string()),
+        )
     }
 
     fn on_component_set(
@@ -335,6 +566,23 @@ impl TreeEventResponder for MetadataWatcher {
         Ok(())
     }
 
+    fn on_component_removed(
+        &mut self,
+        _: &mut ClientManager,
+        _: &mut QueryContext,
+        _: &mut TreeSubscribers,
+        _: &DeviceTree,
+        _: &Device,
+        _: EntityIndex,
+        _: ComponentType,
+    ) -> Result<(), IglooError> {
+        debug_assert!(
+            false,
+            "MetadataWatcher should never receive component_removed events"
+        );
+        Ok(())
+    }
+
     fn on_entity_registered(
         &mut self,
         _: &mut ClientManager,
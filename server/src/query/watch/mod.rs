@@ -27,13 +27,17 @@ use crate::{
     core::{ClientManager, IglooError, IglooResponse},
     query::{
         QueryEngine,
-        watch::{comp::ComponentWatcher, meta::MetadataWatcher, subscriber::TreeSubscribers},
+        watch::{
+            comp::ComponentWatcher, journal::JournalWatcher, meta::MetadataWatcher,
+            subscriber::TreeSubscribers,
+        },
     },
     tree::DeviceTree,
 };
 
 mod comp;
 pub mod dispatch;
+mod journal;
 mod meta;
 pub mod subscriber;
 
@@ -43,6 +47,7 @@ pub type WatcherList = Vec<WatcherID>;
 pub enum Watcher {
     Component(ComponentWatcher),
     Metadata(MetadataWatcher),
+    Journal(JournalWatcher),
 }
 
 impl QueryEngine {
@@ -128,6 +133,10 @@ impl QueryEngine {
                 )?;
                 Watcher::Component(w)
             }
+            WatchQuery::Journal(query) => {
+                let w = JournalWatcher::register(&mut self.tree_subs, watcher_id, query.clone());
+                Watcher::Journal(w)
+            }
         };
 
         self.query_to_watcher.insert(query, watcher_id);
@@ -146,6 +155,7 @@ impl Watcher {
         match self {
             Watcher::Component(w) => w.on_sub(cm, client_id, query_id),
             Watcher::Metadata(w) => w.on_sub(cm, client_id, query_id),
+            Watcher::Journal(w) => w.on_sub(client_id, query_id),
         }
     }
 
@@ -153,6 +163,7 @@ impl Watcher {
         match self {
             Watcher::Component(w) => w.subs.retain(|(cid, _)| *cid != client_id),
             Watcher::Metadata(w) => w.subs.retain(|(cid, _)| *cid != client_id),
+            Watcher::Journal(w) => w.subs.retain(|(cid, _)| *cid != client_id),
         }
     }
 
@@ -160,6 +171,7 @@ impl Watcher {
         match self {
             Watcher::Component(w) => w.id,
             Watcher::Metadata(w) => w.id,
+            Watcher::Journal(w) => w.id,
         }
     }
 
@@ -167,6 +179,7 @@ impl Watcher {
         match self {
             Watcher::Component(w) => &w.subs,
             Watcher::Metadata(w) => &w.subs,
+            Watcher::Journal(w) => &w.subs,
         }
     }
 
@@ -174,6 +187,7 @@ impl Watcher {
         match self {
             Watcher::Component(w) => w.cleanup(subs),
             Watcher::Metadata(w) => w.cleanup(subs),
+            Watcher::Journal(w) => w.cleanup(subs),
         }
     }
 
@@ -181,6 +195,7 @@ impl Watcher {
         match self {
             Watcher::Component(w) => WatchQuery::Component(w.query.clone()),
             Watcher::Metadata(_) => WatchQuery::Metadata,
+            Watcher::Journal(w) => WatchQuery::Journal(w.query.clone()),
         }
     }
 }
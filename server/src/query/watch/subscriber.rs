@@ -24,13 +24,23 @@ pub struct TreeSubscribers {
     pub device_created: DeviceEventSubscribers,
     pub device_renamed: DeviceEventSubscribers,
     pub device_deleted: DeviceEventSubscribers,
+    pub device_availability: DeviceEventSubscribers,
+    pub device_info: DeviceEventSubscribers,
+    pub device_labels: DeviceEventSubscribers,
+    pub device_replaced: DeviceEventSubscribers,
+    pub entity_labels: EntityEventSubscribers,
     pub group_created: GroupEventSubscribers,
     pub group_renamed: GroupEventSubscribers,
+    pub group_parent_changed: GroupEventSubscribers,
     pub group_deleted: GroupEventSubscribers,
     pub group_device_removed: GroupDeviceEventSubscribers,
     pub group_device_added: GroupDeviceEventSubscribers,
     pub ext_attached: ExtensionEventSubscribers,
     pub ext_detached: ExtensionEventSubscribers,
+    pub device_discovered: DiscoveryEventSubscribers,
+    pub discovered_removed: DiscoveryEventSubscribers,
+    /// entries are filtered by the watchers
+    pub journal: WatcherList,
 }
 
 impl TreeSubscribers {
@@ -41,14 +51,23 @@ impl TreeSubscribers {
         self.device_created.unsubscribe(watcher_id);
         self.device_renamed.unsubscribe(watcher_id);
         self.device_deleted.unsubscribe(watcher_id);
+        self.device_availability.unsubscribe(watcher_id);
+        self.device_info.unsubscribe(watcher_id);
+        self.device_labels.unsubscribe(watcher_id);
+        self.device_replaced.unsubscribe(watcher_id);
+        self.entity_labels.unsubscribe(watcher_id);
         self.entity_registered.unsubscribe(watcher_id);
         self.group_created.unsubscribe(watcher_id);
         self.group_renamed.unsubscribe(watcher_id);
+        self.group_parent_changed.unsubscribe(watcher_id);
         self.group_deleted.unsubscribe(watcher_id);
         self.group_device_removed.unsubscribe(watcher_id);
         self.group_device_added.unsubscribe(watcher_id);
         self.ext_attached.unsubscribe(watcher_id);
         self.ext_detached.unsubscribe(watcher_id);
+        self.device_discovered.unsubscribe(watcher_id);
+        self.discovered_removed.unsubscribe(watcher_id);
+        self.journal.retain(|&id| id != watcher_id);
     }
 }
 
@@ -84,6 +103,13 @@ pub struct ExtensionEventSubscribers {
     pub all: WatcherList,
 }
 
+pub struct DiscoveryEventSubscribers {
+    /// sub to devices discovered by this ext
+    pub by_xid: FxHashMap<ExtensionID, WatcherList>,
+    /// sub to all discovered devices
+    pub all: WatcherList,
+}
+
 pub struct DeviceEventSubscribers {
     /// sub to device
     pub by_did: FxHashMap<DeviceID, WatcherList>,
@@ -167,6 +193,15 @@ impl Default for ExtensionEventSubscribers {
     }
 }
 
+impl Default for DiscoveryEventSubscribers {
+    fn default() -> Self {
+        Self {
+            by_xid: HashMap::with_capacity_and_hasher(2, FxBuildHasher),
+            all: Vec::with_capacity(2),
+        }
+    }
+}
+
 impl Default for DeviceEventSubscribers {
     fn default() -> Self {
         Self {
@@ -338,6 +373,31 @@ impl ExtensionEventSubscribers {
     }
 }
 
+impl DiscoveryEventSubscribers {
+    #[allow(dead_code)]
+    pub fn unsubscribe(&mut self, watcher_id: WatcherID) {
+        self.by_xid.retain(|_, list| {
+            list.retain(|&id| id != watcher_id);
+            !list.is_empty()
+        });
+        self.all.retain(|&id| id != watcher_id);
+    }
+
+    #[inline]
+    pub fn affected(&self, xid: &ExtensionID) -> Vec<WatcherID> {
+        let capacity = self.all.len() + self.by_xid.get(xid).map_or(0, |list| list.len());
+
+        let mut result = Vec::with_capacity(capacity);
+        result.extend_from_slice(&self.all);
+
+        if let Some(list) = self.by_xid.get(xid) {
+            result.extend_from_slice(list);
+        }
+
+        result
+    }
+}
+
 impl DeviceEventSubscribers {
     #[allow(dead_code)]
     pub fn unsubscribe(&mut self, watcher_id: WatcherID) {
@@ -0,0 +1,89 @@
+use super::Igloo;
+use igloo_interface::{
+    id::{DeviceID, ExtensionID},
+    query::{
+        DeviceAction, DeviceFilter, DeviceQuery, IDFilter, MetadataUpdate, OneShotQuery,
+        QueryResult, WatchQuery, WatchUpdate,
+    },
+};
+
+fn available_count(device: u64, available: bool) -> OneShotQuery {
+    OneShotQuery::Device(DeviceQuery {
+        filter: DeviceFilter {
+            id: IDFilter::Is(DeviceID::new(device)),
+            available: Some(available),
+            ..Default::default()
+        },
+        action: DeviceAction::Count,
+        limit: None,
+    })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ext_reports_device_unreachable() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Lamp").await;
+    let did = DeviceID::new(device);
+
+    let query_id = client.sub(WatchQuery::Metadata).await;
+    let WatchUpdate::Metadata(batch) = client.watch_update(query_id).await else {
+        panic!("Expected Metadata");
+    };
+    assert!(batch.contains(&MetadataUpdate::DeviceAvailability(did, true)));
+
+    ext.set_reachable(device, false).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::DeviceAvailability(did, false)])
+    );
+    assert_eq!(
+        client.eval(available_count(device, false)).await.unwrap(),
+        QueryResult::Count(1)
+    );
+
+    // repeating it is not a change
+    ext.set_reachable(device, false).await;
+    ext.set_reachable(device, true).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::DeviceAvailability(did, true)])
+    );
+    client.expect_silence().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unresponsive_ext_is_detached() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Lamp").await;
+    let did = DeviceID::new(device);
+    assert_eq!(
+        client.eval(available_count(device, true)).await.unwrap(),
+        QueryResult::Count(1)
+    );
+
+    let query_id = client.sub(WatchQuery::Metadata).await;
+    client.watch_update(query_id).await;
+
+    ext.stop_ponging();
+    let xid = ExtensionID("mock".to_string());
+    igloo.wait_until_attached(&xid, false).await;
+
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::ExtensionRemoved(xid)])
+    );
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::DeviceAvailability(did, false)])
+    );
+    assert_eq!(
+        client.eval(available_count(device, false)).await.unwrap(),
+        QueryResult::Count(1)
+    );
+}
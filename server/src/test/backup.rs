@@ -0,0 +1,146 @@
+use super::{Igloo, TEST_BACKUP_KEEP};
+use crate::{
+    BACKUP, DATA_DIR,
+    backup::{self, BackupError},
+    core::{ClientMsg, IglooResponse},
+    tree::persist::{DEVICES_FILE, LockError},
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+async fn backup(client: &mut super::FakeClient) -> PathBuf {
+    client.send(ClientMsg::Backup).await;
+    match client.recv().await {
+        IglooResponse::BackupResult(Ok(path)) => path,
+        other => panic!("Expected BackupResult, got {other:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_backup_restore_round_trip() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    ext.create_device("Lamp").await;
+    let archive = backup(&mut client).await;
+    ext.create_device("Fan").await;
+
+    let manifest = backup::verify(&archive).unwrap();
+    assert!(
+        manifest
+            .files
+            .iter()
+            .any(|file| file.path == "data/devices.toml")
+    );
+    // rotating backups are left out
+    assert!(!manifest.files.iter().any(|file| file.path.ends_with(".1")));
+
+    drop(ext);
+    igloo
+        .restart_with(|dir| {
+            let previous = backup::restore(&archive, dir).unwrap().unwrap();
+            let devices = fs::read_to_string(dir.join(DEVICES_FILE)).unwrap();
+            assert!(devices.contains("Lamp"));
+            assert!(!devices.contains("Fan"));
+
+            let devices = fs::read_to_string(previous.join(DEVICES_FILE)).unwrap();
+            assert!(devices.contains("Fan"));
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_restore_rejects_bad_archives() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    ext.create_device("Lamp").await;
+    let archive = backup(&mut client).await;
+    let data_dir = archive.parent().unwrap().join("restored");
+
+    let mut content = fs::read(&archive).unwrap();
+    let at = backup::MAGIC.len();
+    content[at] ^= 0xFF;
+    let corrupt = write_beside(&archive, "corrupt", &content);
+    assert!(matches!(
+        backup::restore(&corrupt, &data_dir),
+        Err(BackupError::ChecksumMismatch(_))
+    ));
+
+    // same length, so only the version differs
+    let mut content = fs::read(&archive).unwrap();
+    let version = b"\"schema_version\":1";
+    let at = content
+        .windows(version.len())
+        .position(|w| w == version)
+        .unwrap();
+    content[at + version.len() - 1] = b'9';
+    let newer = write_beside(&archive, "newer", &content);
+    assert!(matches!(
+        backup::restore(&newer, &data_dir),
+        Err(BackupError::IncompatibleVersion(9))
+    ));
+
+    let garbage = write_beside(&archive, "garbage", b"not a backup at all");
+    assert!(matches!(
+        backup::restore(&garbage, &data_dir),
+        Err(BackupError::NotABackup)
+    ));
+
+    assert!(!data_dir.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_restore_refused_while_running() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    ext.create_device("Lamp").await;
+    let archive = backup(&mut client).await;
+
+    let data_dir = DATA_DIR.get().unwrap();
+    assert!(matches!(
+        backup::restore(&archive, data_dir),
+        Err(BackupError::Lock(LockError::InUse(_)))
+    ));
+    assert!(
+        fs::read_to_string(data_dir.join(DEVICES_FILE))
+            .unwrap()
+            .contains("Lamp")
+    );
+    assert!(
+        !backup::verify(&archive)
+            .unwrap()
+            .files
+            .iter()
+            .any(|f| f.path.ends_with(".lock"))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_old_backups_pruned() {
+    let igloo = Igloo::boot().await;
+    let mut client = igloo.client().await;
+
+    let mut archives = Vec::new();
+    for _ in 0..TEST_BACKUP_KEEP + 2 {
+        archives.push(backup(&mut client).await);
+    }
+
+    let dir = &BACKUP.get().unwrap().dir;
+    let mut kept: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    kept.sort();
+    assert_eq!(kept, archives[archives.len() - TEST_BACKUP_KEEP..]);
+}
+
+fn write_beside(archive: &Path, name: &str, content: &[u8]) -> PathBuf {
+    let path = archive.with_file_name(format!("{name}.igloo"));
+    fs::write(&path, content).unwrap();
+    path
+}
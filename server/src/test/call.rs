@@ -0,0 +1,122 @@
+use super::Igloo;
+use crate::core::{ClientMsg, IglooResponse};
+use igloo_interface::{
+    id::ExtensionID,
+    ipc::{ExtensionToIgloo, IglooToExtension},
+    query::CommandError,
+};
+use serde_json::json;
+
+fn call(call_id: usize, ext: &str, name: &str) -> ClientMsg {
+    ClientMsg::ExtensionCall {
+        call_id,
+        ext: ExtensionID(ext.to_string()),
+        name: name.to_string(),
+        payload: json!({ "timeout": 60 }),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_call_reply_reaches_caller() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut caller = igloo.client().await;
+    let mut other = igloo.client().await;
+
+    caller.send(call(7, "mock", "start_pairing")).await;
+
+    let IglooToExtension::Custom { id, name, payload } = ext.recv().await else {
+        panic!("Expected Custom");
+    };
+    assert_eq!(name, "start_pairing");
+    assert_eq!(payload, json!({ "timeout": 60 }));
+
+    ext.send(ExtensionToIgloo::CustomReply {
+        id,
+        result: Ok(json!("pairing")),
+    })
+    .await;
+
+    match caller.recv().await {
+        IglooResponse::ExtensionCallResult { call_id, result } => {
+            assert_eq!(call_id, 7);
+            assert_eq!(result, Ok(json!("pairing")));
+        }
+        other => panic!("Expected ExtensionCallResult, got {other:?}"),
+    }
+    other.expect_silence().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_call_fails_on_detach() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    client.send(call(0, "mock", "firmware_update")).await;
+    ext.recv().await;
+
+    client
+        .send(ClientMsg::StopExt(ExtensionID("mock".to_string())))
+        .await;
+
+    match client.recv().await {
+        IglooResponse::ExtensionCallResult { call_id, result } => {
+            assert_eq!(call_id, 0);
+            assert_eq!(result, Err(CommandError::Detached));
+        }
+        other => panic!("Expected ExtensionCallResult, got {other:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_call_forgets_unregistered_client() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let caller = igloo.client().await;
+
+    caller.send(call(0, "mock", "start_pairing")).await;
+    let IglooToExtension::Custom { id, .. } = ext.recv().await else {
+        panic!("Expected Custom");
+    };
+
+    caller.send(ClientMsg::Unregister).await;
+    let old_id = caller.id;
+    drop(caller);
+
+    // takes the freed slot, and must not receive the old client's reply
+    let mut client = igloo.client().await;
+    assert_eq!(client.id, old_id);
+
+    ext.send(ExtensionToIgloo::CustomReply {
+        id,
+        result: Ok(json!("pairing")),
+    })
+    .await;
+    client.expect_silence().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ext_custom_is_broadcast() {
+    let mut igloo = Igloo::boot().await;
+    let ext = igloo.ext("mock").await;
+    let mut a = igloo.client().await;
+    let mut b = igloo.client().await;
+
+    ext.send(ExtensionToIgloo::Custom {
+        name: "pairing_done".to_string(),
+        payload: json!({ "devices": 2 }),
+    })
+    .await;
+
+    for client in [&mut a, &mut b] {
+        match client.recv().await {
+            IglooResponse::ExtensionCustom { ext, name, payload } => {
+                assert_eq!(ext, ExtensionID("mock".to_string()));
+                assert_eq!(name, "pairing_done");
+                assert_eq!(payload, json!({ "devices": 2 }));
+            }
+            other => panic!("Expected ExtensionCustom, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use super::{FakeClient, FakeExt, Igloo, comp_query};
+use crate::core::IglooResponse;
+use igloo_interface::{
+    Component, ComponentType,
+    query::{
+        ComponentAction, OneShotQuery, QueryResult, TypeFilter, WatchComponentQuery,
+        WatchEntityFilter, WatchQuery, WatchUpdate,
+    },
+    types::{IglooValue, agg::AggregationOp},
+};
+
+/// Device with a single "light" entity with a Dimmer then a Switch
+async fn setup_light(ext: &mut FakeExt, client: &mut FakeClient) -> u64 {
+    let device = ext.create_device("Light").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(
+        device,
+        0,
+        vec![Component::Dimmer(0.5), Component::Switch(true)],
+    )
+    .await;
+    wait_for_count(client, device, ComponentType::Switch, 1).await;
+    device
+}
+
+async fn wait_for_count(client: &mut FakeClient, device: u64, typ: ComponentType, count: usize) {
+    let query = OneShotQuery::Component(comp_query(device, typ, ComponentAction::Count));
+    client
+        .eval_until(query, |res| *res == QueryResult::Count(count))
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_remove_component() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let device = setup_light(&mut ext, &mut client).await;
+
+    ext.remove(device, 0, vec![ComponentType::Dimmer]).await;
+    wait_for_count(&mut client, device, ComponentType::Dimmer, 0).await;
+
+    // the Switch moved down to fill the gap
+    let get = |typ| OneShotQuery::Component(comp_query(device, typ, ComponentAction::GetValue));
+    assert_eq!(
+        client.eval(get(ComponentType::Switch)).await.unwrap(),
+        QueryResult::ComponentValue(vec![IglooValue::Boolean(true)])
+    );
+
+    // removing something it doesn't have is ignored
+    ext.remove(device, 0, vec![ComponentType::Dimmer]).await;
+    ext.write(device, 0, vec![Component::Dimmer(0.2)]).await;
+    wait_for_count(&mut client, device, ComponentType::Dimmer, 1).await;
+    assert_eq!(
+        client.eval(get(ComponentType::Dimmer)).await.unwrap(),
+        QueryResult::ComponentValue(vec![IglooValue::Real(0.2)])
+    );
+    assert_eq!(
+        client.eval(get(ComponentType::Switch)).await.unwrap(),
+        QueryResult::ComponentValue(vec![IglooValue::Boolean(true)])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch_follows_removal() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let device = setup_light(&mut ext, &mut client).await;
+
+    let any_switch = |type_filter| {
+        WatchQuery::Component(WatchComponentQuery {
+            device_filter: Default::default(),
+            entity_filter: WatchEntityFilter {
+                type_filter: Some(type_filter),
+                ..Default::default()
+            },
+            component: ComponentType::Switch,
+            post_op: Some(AggregationOp::Any),
+        })
+    };
+    let any = |value| WatchUpdate::ComponentAggregate(IglooValue::Boolean(value));
+
+    let dimmable = client
+        .sub(any_switch(TypeFilter::With(ComponentType::Dimmer)))
+        .await;
+    assert_eq!(client.watch_update(dimmable).await, any(true));
+    let plain = client
+        .sub(any_switch(TypeFilter::Without(ComponentType::Dimmer)))
+        .await;
+    assert_eq!(client.watch_update(plain).await, any(false));
+
+    // contracts one, expands the other
+    ext.remove(device, 0, vec![ComponentType::Dimmer]).await;
+    let mut updates = Vec::new();
+    for _ in 0..2 {
+        match client.recv().await {
+            IglooResponse::WatchUpdate { query_id, value } => updates.push((query_id, value)),
+            other => panic!("Expected WatchUpdate, got {other:?}"),
+        }
+    }
+    updates.sort_by_key(|(query_id, _)| *query_id);
+    assert_eq!(updates, vec![(dimmable, any(false)), (plain, any(true))]);
+
+    // the watched component itself
+    ext.remove(device, 0, vec![ComponentType::Switch]).await;
+    assert_eq!(client.watch_update(plain).await, any(false));
+}
@@ -0,0 +1,162 @@
+use super::Igloo;
+use crate::core::ClientMsg;
+use igloo_interface::{
+    id::DeviceID,
+    ipc::ExtensionToIgloo,
+    query::{
+        DeviceAction, DeviceFilter, DeviceInfo, DeviceInfoFilter, DeviceMetadata, DeviceQuery,
+        MetadataUpdate, NameFilter, OneShotQuery, QueryResult, WatchQuery, WatchUpdate,
+        check::QueryError,
+    },
+};
+
+fn info(sw_version: &str) -> DeviceInfo {
+    DeviceInfo {
+        manufacturer: Some("Signify".to_string()),
+        model: Some("LCT015".to_string()),
+        sw_version: Some(sw_version.to_string()),
+        ..Default::default()
+    }
+}
+
+fn running(sw_version: NameFilter) -> OneShotQuery {
+    OneShotQuery::Device(DeviceQuery {
+        filter: DeviceFilter {
+            info: Some(Box::new(DeviceInfoFilter {
+                model: Some(NameFilter::Is("LCT015".to_string())),
+                sw_version: Some(sw_version),
+                ..Default::default()
+            })),
+            ..Default::default()
+        },
+        action: DeviceAction::GetID,
+        limit: None,
+    })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_filter_by_firmware() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let old = ext.create_device_with_info("Hallway", info("1.50.2")).await;
+    let new = ext.create_device("Kitchen").await;
+    ext.create_device("Plug").await;
+
+    ext.send(ExtensionToIgloo::SetDeviceInfo {
+        device: new,
+        info: info("1.104.2"),
+    })
+    .await;
+
+    let is = |version: &str| running(NameFilter::Is(version.to_string()));
+    assert_eq!(
+        client
+            .eval_until(is("1.104.2"), |res| res != &QueryResult::DeviceId(vec![]))
+            .await,
+        QueryResult::DeviceId(vec![DeviceID::new(new)])
+    );
+    assert_eq!(
+        client.eval(is("1.50.2")).await.unwrap(),
+        QueryResult::DeviceId(vec![DeviceID::new(old)])
+    );
+
+    // every 1.x before 1.100
+    let vulnerable = running(NameFilter::Regex(r"^1\.\d{1,2}\.".to_string()));
+    assert_eq!(
+        client.eval(vulnerable).await.unwrap(),
+        QueryResult::DeviceId(vec![DeviceID::new(old)])
+    );
+    let any = running(NameFilter::Matches("1.*".to_string()));
+    assert_eq!(
+        client.eval(any).await.unwrap(),
+        QueryResult::DeviceId(vec![DeviceID::new(old), DeviceID::new(new)])
+    );
+    let invalid = running(NameFilter::Regex("1.(".to_string()));
+    assert!(matches!(
+        client.eval(invalid).await,
+        Err(QueryError::InvalidPattern(..))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_filter_by_area() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let area = |area: &str| DeviceInfo {
+        area: Some(area.to_string()),
+        ..Default::default()
+    };
+    let kitchen = ext
+        .create_device_with_info("Ceiling", area("Kitchen"))
+        .await;
+    ext.create_device_with_info("Lamp", area("Bedroom")).await;
+    ext.create_device("Plug").await;
+
+    let query = OneShotQuery::Device(DeviceQuery {
+        filter: DeviceFilter {
+            info: Some(Box::new(DeviceInfoFilter {
+                area: Some(NameFilter::IsIgnoreCase("kitchen".to_string())),
+                ..Default::default()
+            })),
+            ..Default::default()
+        },
+        action: DeviceAction::GetID,
+        limit: None,
+    });
+    assert_eq!(
+        client.eval(query).await.unwrap(),
+        QueryResult::DeviceId(vec![DeviceID::new(kitchen)])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_info_is_persisted() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Hallway").await;
+    let did = DeviceID::new(device);
+
+    let query_id = client.sub(WatchQuery::Metadata).await;
+    client.watch_update(query_id).await;
+
+    ext.send(ExtensionToIgloo::SetDeviceInfo {
+        device,
+        info: info("1.50.2"),
+    })
+    .await;
+    client.watch_update(query_id).await;
+
+    client
+        .send(ClientMsg::SetDeviceNotes {
+            device_id: did,
+            notes: Some("Behind the coat rack".to_string()),
+        })
+        .await;
+    let expected = DeviceMetadata {
+        name: "Hallway".to_string(),
+        info: info("1.50.2"),
+        icon: None,
+        notes: Some("Behind the coat rack".to_string()),
+        labels: vec![],
+    };
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::Device(did, expected.clone())])
+    );
+
+    drop(ext);
+    igloo.restart().await;
+
+    let mut client = igloo.client().await;
+    let query_id = client.sub(WatchQuery::Metadata).await;
+    let WatchUpdate::Metadata(batch) = client.watch_update(query_id).await else {
+        panic!("Expected Metadata");
+    };
+    assert!(batch.contains(&MetadataUpdate::Device(did, expected)));
+}
@@ -0,0 +1,159 @@
+use super::Igloo;
+use crate::core::{ClientMsg, IglooResponse};
+use igloo_interface::{
+    id::{DeviceID, ExtensionID},
+    ipc::IglooToExtension,
+    query::{DiscoveredDevice, GroupMetadata, MetadataUpdate, WatchQuery, WatchUpdate},
+};
+
+fn bulb() -> DiscoveredDevice {
+    DiscoveredDevice {
+        ext: ExtensionID("mock".to_string()),
+        external_id: "0x1a2b".to_string(),
+        name: "Hue Bulb".to_string(),
+        model: Some("LCT015".to_string()),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_accept_discovered_device() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    client
+        .send(ClientMsg::CreateGroup {
+            name: "Kitchen".to_string(),
+        })
+        .await;
+    let IglooResponse::GroupCreated(gid) = client.recv().await else {
+        panic!("Expected GroupCreated");
+    };
+
+    let query_id = client.sub(WatchQuery::Metadata).await;
+    client.watch_update(query_id).await;
+
+    ext.discovered("0x1a2b", "Hue Bulb", Some("LCT015")).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::Discovered(bulb())])
+    );
+
+    client.send(ClientMsg::GetDiscovered).await;
+    match client.recv().await {
+        IglooResponse::Discovered(list) => assert_eq!(list, vec![bulb()]),
+        other => panic!("Expected Discovered, got {other:?}"),
+    }
+
+    client
+        .send(ClientMsg::AcceptDiscovered {
+            ext: ExtensionID("mock".to_string()),
+            external_id: "0x1a2b".to_string(),
+            name: Some("Kitchen Light".to_string()),
+            groups: vec![gid],
+        })
+        .await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::DiscoveredRemoved(
+            ExtensionID("mock".to_string()),
+            "0x1a2b".to_string()
+        )])
+    );
+
+    let IglooToExtension::Adopt { external_id, name } = ext.recv().await else {
+        panic!("Expected Adopt");
+    };
+    assert_eq!(external_id, "0x1a2b");
+    assert_eq!(name, "Kitchen Light");
+
+    let did = DeviceID::new(ext.adopt_device(&name, &external_id).await);
+
+    // device created, available, then added to the group
+    loop {
+        let WatchUpdate::Metadata(batch) = client.watch_update(query_id).await else {
+            panic!("Expected Metadata");
+        };
+        if let [MetadataUpdate::Group(id, GroupMetadata { devices, .. })] = batch.as_slice() {
+            assert_eq!(*id, gid);
+            assert_eq!(devices, &vec![did]);
+            break;
+        }
+    }
+
+    // already accepted
+    ext.discovered("0x1a2b", "Hue Bulb", Some("LCT015")).await;
+    client.expect_silence().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ignored_device_stays_hidden() {
+    let mut igloo = Igloo::boot().await;
+    let ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let query_id = client.sub(WatchQuery::Metadata).await;
+    client.watch_update(query_id).await;
+
+    ext.discovered("0x1a2b", "Hue Bulb", Some("LCT015")).await;
+    client.watch_update(query_id).await;
+
+    client
+        .send(ClientMsg::IgnoreDiscovered {
+            ext: ExtensionID("mock".to_string()),
+            external_id: "0x1a2b".to_string(),
+        })
+        .await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::DiscoveredRemoved(
+            ExtensionID("mock".to_string()),
+            "0x1a2b".to_string()
+        )])
+    );
+
+    // only the second one shows up
+    ext.discovered("0x1a2b", "Hue Bulb", Some("LCT015")).await;
+    ext.discovered("0x3c4d", "Motion Sensor", None).await;
+    let WatchUpdate::Metadata(batch) = client.watch_update(query_id).await else {
+        panic!("Expected Metadata");
+    };
+    let [MetadataUpdate::Discovered(discovered)] = batch.as_slice() else {
+        panic!("Expected Discovered, got {batch:?}");
+    };
+    assert_eq!(discovered.external_id, "0x3c4d");
+
+    client
+        .send(ClientMsg::UnignoreDiscovered {
+            ext: ExtensionID("mock".to_string()),
+            external_id: "0x1a2b".to_string(),
+        })
+        .await;
+    client.send(ClientMsg::GetDiscovered).await;
+    client.recv().await;
+
+    ext.discovered("0x1a2b", "Hue Bulb", Some("LCT015")).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::Discovered(bulb())])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_detach_clears_discovered() {
+    let mut igloo = Igloo::boot().await;
+    let ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    ext.discovered("0x1a2b", "Hue Bulb", Some("LCT015")).await;
+
+    let xid = ExtensionID("mock".to_string());
+    client.send(ClientMsg::StopExt(xid.clone())).await;
+    igloo.wait_until_attached(&xid, false).await;
+
+    client.send(ClientMsg::GetDiscovered).await;
+    match client.recv().await {
+        IglooResponse::Discovered(list) => assert!(list.is_empty()),
+        other => panic!("Expected Discovered, got {other:?}"),
+    }
+}
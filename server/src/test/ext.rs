@@ -0,0 +1,226 @@
+use super::{FakeExt, Igloo, comp_query, ext_dir, socket_id};
+use crate::{
+    core::{ClientMsg, IglooRequest},
+    ext::{ExtensionQueue, Pushed, QueueLimits, SOCKET},
+    tree::mutation::MAX_EXT_STRIKES,
+};
+use igloo_interface::{
+    Component, ComponentType,
+    id::{ExtensionID, ExtensionIndex},
+    ipc::{ExtensionError, ExtensionToIgloo, IglooToExtension},
+    query::{ComponentAction, OneShotQuery, QueryResult},
+    types::IglooValue,
+};
+use std::time::Duration;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ext_writes_are_queryable() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Lamp").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(device, 0, vec![Component::Switch(true)]).await;
+
+    let query = OneShotQuery::Component(comp_query(
+        device,
+        ComponentType::Switch,
+        ComponentAction::GetValue,
+    ));
+    let res = client
+        .eval_until(
+            query,
+            |res| matches!(res, QueryResult::ComponentValue(values) if !values.is_empty()),
+        )
+        .await;
+
+    assert_eq!(
+        res,
+        QueryResult::ComponentValue(vec![IglooValue::Boolean(true)])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ext_cannot_write_foreign_device() {
+    let mut igloo = Igloo::boot().await;
+    let mut owner = igloo.ext("owner").await;
+    let mut other = igloo.ext("other").await;
+
+    let device = owner.create_device("Lamp").await;
+    owner.register_entity(device, "light", 0).await;
+
+    other.write(device, 0, vec![Component::Switch(true)]).await;
+
+    assert_eq!(
+        other.recv().await,
+        IglooToExtension::Error(ExtensionError::NotOwner(device))
+    );
+    owner.expect_silence().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ext_detached_after_max_strikes() {
+    let mut igloo = Igloo::boot().await;
+    let mut owner = igloo.ext("owner").await;
+    let mut other = igloo.ext("other").await;
+    let xid = ExtensionID("other".to_string());
+
+    let device = owner.create_device("Lamp").await;
+    owner.register_entity(device, "light", 0).await;
+
+    for _ in 1..MAX_EXT_STRIKES {
+        other.write(device, 0, vec![Component::Switch(true)]).await;
+        assert_eq!(
+            other.recv().await,
+            IglooToExtension::Error(ExtensionError::NotOwner(device))
+        );
+    }
+    other.expect_silence().await;
+    igloo.wait_until_attached(&xid, true).await;
+
+    other.write(device, 0, vec![Component::Switch(true)]).await;
+    igloo.wait_until_attached(&xid, false).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ext_ignores_messages_from_previous_instance() {
+    let mut igloo = Igloo::boot().await;
+    let client = igloo.client().await;
+    let mut owner = igloo.ext("owner").await;
+    let old_xid = ExtensionID("old".to_string());
+    let xid = ExtensionID("new".to_string());
+
+    let device = owner.create_device("Lamp").await;
+    owner.register_entity(device, "light", 0).await;
+
+    let old = igloo.ext("old").await;
+    client.send(ClientMsg::StopExt(old_xid.clone())).await;
+    igloo.wait_until_attached(&old_xid, false).await;
+    drop(old);
+
+    // takes over the old one's index
+    let mut new = igloo.ext("new").await;
+
+    // still in flight from the old instance
+    for _ in 0..MAX_EXT_STRIKES {
+        let req = IglooRequest::Ext {
+            sender: ExtensionIndex(1),
+            generation: u64::MAX,
+            content: ExtensionToIgloo::WriteComponents {
+                device,
+                entity: 0,
+                comps: vec![Component::Switch(true)],
+            },
+        };
+        igloo.tx.send(req).unwrap();
+    }
+
+    new.expect_silence().await;
+    igloo.wait_until_attached(&xid, true).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ext_stop_start_restart() {
+    let mut igloo = Igloo::boot().await;
+    let client = igloo.client().await;
+    let xid = ExtensionID("mock".to_string());
+    let socket = ext_dir("mock").join(SOCKET);
+
+    let ext = igloo.ext("mock").await;
+    client.send(ClientMsg::StopExt(xid.clone())).await;
+    igloo.wait_until_attached(&xid, false).await;
+    drop(ext);
+
+    // right away, while the old instance may still be shutting down
+    let ext = igloo.ext("mock").await;
+
+    let old = socket_id(&socket);
+    client.send(ClientMsg::RestartExt(xid.clone())).await;
+    let mut restarted = FakeExt::connect_new(socket.clone(), old).await;
+    igloo.wait_until_attached(&xid, true).await;
+    drop(ext);
+
+    // the old instances' cleanup left the new socket alone
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(socket.exists());
+    restarted.create_device("Lamp").await;
+}
+
+#[tokio::test]
+async fn test_queue_keeps_write_order() {
+    let queue = ExtensionQueue::new(QueueLimits::default());
+    let write = |id, comps| IglooToExtension::WriteComponents {
+        id,
+        device: 0,
+        entity: 0,
+        comps,
+    };
+
+    queue.push(write(0, vec![Component::Dimmer(0.1)])).unwrap();
+    queue.push(write(1, vec![Component::Dimmer(0.2)])).unwrap();
+    queue
+        .push(write(
+            2,
+            vec![Component::Switch(true), Component::Dimmer(0.5)],
+        ))
+        .unwrap();
+    // would land ahead of 2 if coalesced into 1
+    let pushed = queue.push(write(3, vec![Component::Dimmer(0.9)])).unwrap();
+    assert_eq!(pushed, Pushed::Queued);
+    // nothing after 3 touches the dimmer
+    let pushed = queue.push(write(4, vec![Component::Dimmer(1.0)])).unwrap();
+    assert_eq!(pushed, Pushed::Coalesced(3));
+
+    let batch = queue.next_batch().await.unwrap();
+    let ids: Vec<_> = batch
+        .iter()
+        .map(|msg| match msg {
+            IglooToExtension::WriteComponents { id, .. } => *id,
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(ids, vec![1, 2, 4]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ext_reconnects() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let xid = ExtensionID("mock".to_string());
+
+    let device = ext.create_device("Lamp").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(device, 0, vec![Component::Switch(false)]).await;
+
+    ext.disconnect().await;
+    let mut ext = FakeExt::connect(ext_dir("mock").join(SOCKET)).await;
+    igloo.wait_until_attached(&xid, true).await;
+
+    // still owns its devices
+    ext.write(device, 0, vec![Component::Switch(true)]).await;
+    let get = OneShotQuery::Component(comp_query(
+        device,
+        ComponentType::Switch,
+        ComponentAction::GetValue,
+    ));
+    client
+        .eval_until(get, |res| {
+            *res == QueryResult::ComponentValue(vec![IglooValue::Boolean(true)])
+        })
+        .await;
+    ext.expect_silence().await;
+
+    // and gets writes on the new connection
+    let set = comp_query(
+        device,
+        ComponentType::Switch,
+        ComponentAction::Set(IglooValue::Boolean(false)),
+    );
+    client.send_eval(OneShotQuery::Component(set)).await;
+    assert!(matches!(
+        ext.recv().await,
+        IglooToExtension::WriteComponents { device: d, .. } if d == device
+    ));
+}
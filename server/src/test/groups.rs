@@ -0,0 +1,182 @@
+use super::{FakeClient, Igloo, comp_query};
+use crate::core::{ClientMsg, IglooResponse};
+use igloo_interface::{
+    Component, ComponentType,
+    id::{DeviceID, GroupID},
+    query::{
+        ComponentAction, DeviceAction, DeviceFilter, DeviceGroupFilter, DeviceQuery, GroupAction,
+        GroupQuery, IDFilter, OneShotQuery, QueryResult, WatchComponentQuery, WatchDeviceFilter,
+        WatchQuery, WatchUpdate,
+    },
+    types::{IglooValue, agg::AggregationOp},
+};
+
+async fn create_group(client: &mut FakeClient, name: &str) -> GroupID {
+    client
+        .send(ClientMsg::CreateGroup {
+            name: name.to_string(),
+        })
+        .await;
+    let IglooResponse::GroupCreated(gid) = client.recv().await else {
+        panic!("Expected GroupCreated");
+    };
+    gid
+}
+
+async fn set_parent(client: &FakeClient, group_id: GroupID, parent: Option<GroupID>) {
+    client
+        .send(ClientMsg::SetGroupParent { group_id, parent })
+        .await;
+}
+
+async fn in_tree(client: &mut FakeClient, root: GroupID) -> Vec<DeviceID> {
+    let query = OneShotQuery::Device(DeviceQuery {
+        filter: DeviceFilter {
+            group: DeviceGroupFilter::InTree(root),
+            ..Default::default()
+        },
+        action: DeviceAction::GetID,
+        limit: None,
+    });
+    match client.eval(query).await.unwrap() {
+        QueryResult::DeviceId(mut dids) => {
+            dids.sort_by_key(|did| did.to_string());
+            dids
+        }
+        other => panic!("Expected DeviceId, got {other:?}"),
+    }
+}
+
+fn sorted(mut dids: Vec<DeviceID>) -> Vec<DeviceID> {
+    dids.sort_by_key(|did| did.to_string());
+    dids
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_in_tree_matches_subgroups() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let lamp = DeviceID::new(ext.create_device("Lamp").await);
+    let fan = DeviceID::new(ext.create_device("Fan").await);
+    ext.create_device("Plug").await;
+
+    let upstairs = create_group(&mut client, "Upstairs").await;
+    let bedroom = create_group(&mut client, "Bedroom").await;
+    let hall = create_group(&mut client, "Hall").await;
+    set_parent(&client, bedroom, Some(upstairs)).await;
+    set_parent(&client, hall, Some(upstairs)).await;
+    client
+        .send(ClientMsg::AddDeviceToGroup(bedroom, lamp))
+        .await;
+    client.send(ClientMsg::AddDeviceToGroup(hall, fan)).await;
+
+    assert_eq!(
+        in_tree(&mut client, upstairs).await,
+        sorted(vec![lamp, fan])
+    );
+    assert_eq!(in_tree(&mut client, bedroom).await, vec![lamp]);
+
+    // upstairs contains bedroom
+    set_parent(&client, upstairs, Some(bedroom)).await;
+    let query = OneShotQuery::Group(GroupQuery {
+        id: IDFilter::Is(upstairs),
+        name: None,
+        action: GroupAction::Snapshot,
+        limit: None,
+    });
+    match client.eval(query).await.unwrap() {
+        QueryResult::GroupSnapshot(groups) => assert_eq!(groups[0].parent, None),
+        other => panic!("Expected GroupSnapshot, got {other:?}"),
+    }
+
+    set_parent(&client, hall, None).await;
+    assert_eq!(in_tree(&mut client, upstairs).await, vec![lamp]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_deleted_group_keeps_subgroups_in_tree() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let lamp = DeviceID::new(ext.create_device("Lamp").await);
+
+    let house = create_group(&mut client, "House").await;
+    let upstairs = create_group(&mut client, "Upstairs").await;
+    let bedroom = create_group(&mut client, "Bedroom").await;
+    set_parent(&client, upstairs, Some(house)).await;
+    set_parent(&client, bedroom, Some(upstairs)).await;
+    client
+        .send(ClientMsg::AddDeviceToGroup(bedroom, lamp))
+        .await;
+
+    client.send(ClientMsg::DeleteGroup(upstairs)).await;
+    assert_eq!(in_tree(&mut client, house).await, vec![lamp]);
+
+    drop(ext);
+    igloo.restart().await;
+    let mut client = igloo.client().await;
+    assert_eq!(in_tree(&mut client, house).await, vec![lamp]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch_follows_reparent() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let lamp = ext.create_device("Lamp").await;
+    let fan = ext.create_device("Fan").await;
+    for (device, on) in [(lamp, true), (fan, false)] {
+        ext.register_entity(device, "switch", 0).await;
+        ext.write(device, 0, vec![Component::Switch(on)]).await;
+        let query = comp_query(device, ComponentType::Switch, ComponentAction::Count);
+        client
+            .eval_until(OneShotQuery::Component(query), |res| {
+                *res == QueryResult::Count(1)
+            })
+            .await;
+    }
+
+    let upstairs = create_group(&mut client, "Upstairs").await;
+    let bedroom = create_group(&mut client, "Bedroom").await;
+    let hall = create_group(&mut client, "Hall").await;
+    set_parent(&client, bedroom, Some(upstairs)).await;
+    set_parent(&client, hall, Some(upstairs)).await;
+    client
+        .send(ClientMsg::AddDeviceToGroup(bedroom, DeviceID::new(lamp)))
+        .await;
+    client
+        .send(ClientMsg::AddDeviceToGroup(hall, DeviceID::new(fan)))
+        .await;
+
+    let query_id = client
+        .sub(WatchQuery::Component(WatchComponentQuery {
+            device_filter: WatchDeviceFilter {
+                group: DeviceGroupFilter::InTree(upstairs),
+                ..Default::default()
+            },
+            entity_filter: Default::default(),
+            component: ComponentType::Switch,
+            post_op: Some(AggregationOp::Any),
+        }))
+        .await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(true))
+    );
+
+    set_parent(&client, bedroom, None).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(false))
+    );
+
+    set_parent(&client, bedroom, Some(hall)).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(true))
+    );
+}
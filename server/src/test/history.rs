@@ -0,0 +1,662 @@
+use super::{FakeClient, FakeExt, Igloo, comp_query};
+use crate::{
+    DATA_DIR, backup,
+    core::{ClientMsg, IglooRequest, IglooResponse},
+    history::{
+        HISTORY_CONFIG_FILE, HISTORY_DIR, HistoricalInstanceMetadata, HistoryConfig,
+        compact::MAX_AGE_HOURS,
+        export::{
+            DeviceNames, ExportError, ExportFilter, ExportFormat, export, import, run_export,
+            run_import,
+        },
+        file::{HistoryFile, HistoryReader},
+        history_dir, instance_id,
+        recorder::now_ms,
+        strings::{StringTable, read_strings, strings_path},
+        value,
+    },
+};
+use igloo_interface::{
+    Component, ComponentType, SensorStateClass,
+    id::{DeviceID, EntityID},
+    query::{
+        ComponentAction, DeviceFilter, HistoryBucket, HistoryQuery, HistorySeries, IDFilter,
+        OneShotQuery, QueryResult, StatisticsPeriod, StatisticsQuery, StatisticsRow,
+    },
+    types::{IglooValue, agg::AggregationOp},
+};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Boots with only Reals recorded
+async fn boot(min_interval_ms: u32) -> Igloo {
+    let mut igloo = Igloo::boot().await;
+    let config =
+        format!("[[record]]\ncomponents = [\"Real\"]\nmin_interval_ms = {min_interval_ms}\n");
+    igloo
+        .restart_with(|dir| fs::write(dir.join(HISTORY_CONFIG_FILE), config).unwrap())
+        .await;
+    igloo
+}
+
+/// Device with a "power" entity
+async fn setup_meter(ext: &mut FakeExt, client: &mut FakeClient) -> u64 {
+    let device = ext.create_device("Meter").await;
+    ext.register_entity(device, "power", 0).await;
+    write_power(ext, client, device, 0.0).await;
+    device
+}
+
+/// Returns once it's in the tree
+async fn write_power(ext: &FakeExt, client: &mut FakeClient, device: u64, power: f64) {
+    ext.write(
+        device,
+        0,
+        vec![Component::Real(power), Component::Switch(true)],
+    )
+    .await;
+    let query = comp_query(device, ComponentType::Real, ComponentAction::GetValue);
+    client
+        .eval_until(OneShotQuery::Component(query), |res| {
+            *res == QueryResult::ComponentValue(vec![IglooValue::Real(power)])
+        })
+        .await;
+}
+
+fn history_path(device: u64, typ: ComponentType) -> PathBuf {
+    let id = instance_id(&DeviceID::new(device), &EntityID("power".to_string()), typ);
+    DATA_DIR
+        .get()
+        .unwrap()
+        .join(HISTORY_DIR)
+        .join(format!("{id}.bin"))
+}
+
+async fn history(client: &mut FakeClient, query: HistoryQuery) -> Vec<HistorySeries> {
+    match client.eval(OneShotQuery::History(query)).await.unwrap() {
+        QueryResult::History(series) => series,
+        res => panic!("unexpected result: {res:?}"),
+    }
+}
+
+fn real_query(device: u64) -> HistoryQuery {
+    HistoryQuery {
+        device_filter: DeviceFilter {
+            id: IDFilter::Is(DeviceID::new(device)),
+            ..Default::default()
+        },
+        entity_filter: Default::default(),
+        component: ComponentType::Real,
+        since: None,
+        until: None,
+        bucket: None,
+        merge: false,
+    }
+}
+
+fn recorded(device: u64) -> Vec<IglooValue> {
+    let mut reader = HistoryReader::open(&history_path(device, ComponentType::Real)).unwrap();
+    reader
+        .range(None, None)
+        .unwrap()
+        .iter()
+        .map(|(_, bytes)| value::decode(ComponentType::Real, bytes, &[]).unwrap())
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_recorded() {
+    let mut igloo = boot(60_000).await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = setup_meter(&mut ext, &mut client).await;
+    for power in [1.0, 2.0] {
+        write_power(&ext, &mut client, device, power).await;
+    }
+
+    // held back values are written on shutdown
+    drop(ext);
+    igloo.restart().await;
+    assert_eq!(
+        recorded(device),
+        vec![IglooValue::Real(0.0), IglooValue::Real(2.0)]
+    );
+    assert!(!history_path(device, ComponentType::Switch).exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_recovers_torn_write() {
+    let mut igloo = boot(0).await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = setup_meter(&mut ext, &mut client).await;
+    write_power(&ext, &mut client, device, 1.0).await;
+
+    drop(ext);
+    igloo
+        .restart_with(|_| {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(history_path(device, ComponentType::Real))
+                .unwrap();
+            file.write_all(&[0, 0, 1]).unwrap();
+        })
+        .await;
+
+    let ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    ext.register_entity(device, "power", 0).await;
+    write_power(&ext, &mut client, device, 2.0).await;
+
+    drop(ext);
+    igloo.restart().await;
+    let expected = [0.0, 1.0, 2.0].map(IglooValue::Real);
+    assert_eq!(recorded(device), expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_query_range() {
+    let mut igloo = boot(0).await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = setup_meter(&mut ext, &mut client).await;
+    for power in [1.0, 2.0] {
+        // distinct timestamps
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        write_power(&ext, &mut client, device, power).await;
+    }
+
+    // buffered entries are readable without waiting for a flush
+    let series = history(&mut client, real_query(device)).await;
+    assert_eq!(series.len(), 1);
+    assert_eq!(
+        series[0].source,
+        Some((DeviceID::new(device), EntityID("power".to_string())))
+    );
+    let points = &series[0].points;
+    let values: Vec<_> = points.iter().map(|(_, v)| v.clone()).collect();
+    assert_eq!(values, [0.0, 1.0, 2.0].map(IglooValue::Real));
+
+    let query = HistoryQuery {
+        since: Some(points[1].0),
+        until: Some(points[2].0),
+        ..real_query(device)
+    };
+    let series = history(&mut client, query).await;
+    assert_eq!(series[0].points, vec![points[1].clone()]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_query_downsampled() {
+    let mut igloo = boot(0).await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = setup_meter(&mut ext, &mut client).await;
+    write_power(&ext, &mut client, device, 2.0).await;
+    ext.register_entity(device, "solar", 1).await;
+    ext.write(device, 1, vec![Component::Real(4.0)]).await;
+    let mut query = comp_query(device, ComponentType::Real, ComponentAction::GetValue);
+    query.post_op = Some(AggregationOp::Sum);
+    client
+        .eval_until(OneShotQuery::Component(query), |res| {
+            *res == QueryResult::Aggregate(Some(IglooValue::Real(6.0)))
+        })
+        .await;
+
+    // average power last hour, including values written this millisecond
+    let since = now_ms() + 1 - 3_600_000;
+    let query = HistoryQuery {
+        since: Some(since),
+        bucket: Some(HistoryBucket {
+            size_ms: 3_600_000,
+            op: AggregationOp::Mean,
+        }),
+        ..real_query(device)
+    };
+    let mut series = history(&mut client, query.clone()).await;
+    series.sort_by(|a, b| a.source.cmp(&b.source));
+    let points: Vec<_> = series.iter().map(|s| s.points.clone()).collect();
+    assert_eq!(
+        points,
+        vec![
+            vec![(since, IglooValue::Real(1.0))],
+            vec![(since, IglooValue::Real(4.0))],
+        ]
+    );
+
+    let merged = history(
+        &mut client,
+        HistoryQuery {
+            merge: true,
+            ..query
+        },
+    )
+    .await;
+    assert_eq!(
+        merged,
+        vec![HistorySeries {
+            source: None,
+            points: vec![(since, IglooValue::Real(2.0))],
+        }]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_compacted() {
+    const HOUR: u64 = 60 * 60 * 1000;
+    const TEN_MINUTES: u64 = 10 * 60 * 1000;
+
+    let mut igloo = Igloo::boot().await;
+    let now = now_ms();
+    let rolled = (now - 3 * HOUR) / TEN_MINUTES * TEN_MINUTES;
+    let path = history_path(7, ComponentType::Real);
+
+    // compacted soon after boot
+    igloo
+        .restart_with(|dir| {
+            let config = "max_age_hours = 24\n\
+                [[rollups]]\nafter_hours = 1\ninterval_ms = 600000\nop = \"Mean\"\n\
+                [[record]]\ncomponents = [\"Real\"]\n";
+            fs::write(dir.join(HISTORY_CONFIG_FILE), config).unwrap();
+            fs::create_dir_all(dir.join(HISTORY_DIR)).unwrap();
+
+            let meta = HistoricalInstanceMetadata {
+                entry_size_bytes: 8,
+                start_timestamp: now - 48 * HOUR,
+                max_age_hours: None,
+                min_interval_ms: 0,
+            };
+            let mut file = HistoryFile::open(&path, meta).unwrap();
+            let entries = [
+                (now - 48 * HOUR, 9.0f64),
+                (rolled, 1.0),
+                (rolled + 60_000, 2.0),
+                (rolled + 120_000, 3.0),
+                (now - 60_000, 5.0),
+            ];
+            for (at, power) in entries {
+                file.append(at, &power.to_be_bytes()).unwrap();
+            }
+            file.sync().unwrap();
+        })
+        .await;
+
+    let expected = vec![
+        (rolled, IglooValue::Real(2.0)),
+        (now - 60_000, IglooValue::Real(5.0)),
+    ];
+    for _ in 0..50 {
+        let mut reader = HistoryReader::open(&path).unwrap();
+        let entries: Vec<_> = reader
+            .range(None, None)
+            .unwrap()
+            .into_iter()
+            .map(|(at, bytes)| (at, value::decode(ComponentType::Real, &bytes, &[]).unwrap()))
+            .collect();
+        if entries == expected {
+            assert_eq!(reader.meta().start_timestamp, rolled);
+            assert_eq!(reader.meta().max_age_hours, Some(24));
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("history was not compacted");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_daily_rollup_at_max_age() {
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    let mut igloo = Igloo::boot().await;
+    let now = now_ms();
+    // the day it falls in starts before it
+    let cutoff = now - MAX_AGE_HOURS as u64 * HOUR;
+    let path = history_path(7, ComponentType::Real);
+
+    // compacted soon after boot
+    igloo
+        .restart_with(|dir| {
+            let config = format!(
+                "max_age_hours = {MAX_AGE_HOURS}\n\
+                [[rollups]]\nafter_hours = 48\ninterval_ms = 86400000\nop = \"Mean\"\n\
+                [[record]]\ncomponents = [\"Real\"]\n"
+            );
+            fs::write(dir.join(HISTORY_CONFIG_FILE), config).unwrap();
+            fs::create_dir_all(dir.join(HISTORY_DIR)).unwrap();
+
+            let meta = HistoricalInstanceMetadata {
+                entry_size_bytes: 8,
+                start_timestamp: cutoff,
+                max_age_hours: None,
+                min_interval_ms: 0,
+            };
+            let mut file = HistoryFile::open(&path, meta).unwrap();
+            let entries = [
+                (cutoff + 60_000, 1.0f64),
+                (cutoff + 120_000, 3.0),
+                (now - 60_000, 5.0),
+            ];
+            for (at, power) in entries {
+                file.append(at, &power.to_be_bytes()).unwrap();
+            }
+            file.sync().unwrap();
+        })
+        .await;
+
+    for _ in 0..50 {
+        let mut reader = HistoryReader::open(&path).unwrap();
+        let entries: Vec<_> = reader
+            .range(None, None)
+            .unwrap()
+            .into_iter()
+            .map(|(at, bytes)| (at, value::decode(ComponentType::Real, &bytes, &[]).unwrap()))
+            .collect();
+        if reader.meta().max_age_hours == Some(MAX_AGE_HOURS) {
+            let [(rolled, mean), last] = entries.as_slice() else {
+                panic!("unexpected entries: {entries:?}");
+            };
+            assert!((cutoff..=cutoff + 60_000).contains(rolled), "{rolled}");
+            assert_eq!(*mean, IglooValue::Real(2.0));
+            assert_eq!(*last, (now - 60_000, IglooValue::Real(5.0)));
+            assert_eq!(reader.meta().start_timestamp, *rolled);
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("history was not compacted");
+}
+
+/// Restarts with only Texts recorded, after `prepare` edits `DATA_DIR`
+async fn restart_text(igloo: &mut Igloo, prepare: impl FnOnce(&std::path::Path)) {
+    igloo
+        .restart_with(|dir| {
+            let config = "max_age_hours = 24\n[[record]]\ncomponents = [\"Text\"]\n";
+            fs::write(dir.join(HISTORY_CONFIG_FILE), config).unwrap();
+            prepare(dir);
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_text_interned() {
+    let mut igloo = Igloo::boot().await;
+    restart_text(&mut igloo, |_| {}).await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Speaker").await;
+    ext.register_entity(device, "power", 0).await;
+    let titles = ["Song A", "Song B", "Song A"];
+    for title in titles {
+        ext.write(device, 0, vec![Component::Text(title.to_string())])
+            .await;
+        let query = comp_query(device, ComponentType::Text, ComponentAction::GetValue);
+        client
+            .eval_until(OneShotQuery::Component(query), |res| {
+                *res == QueryResult::ComponentValue(vec![IglooValue::Text(title.to_string())])
+            })
+            .await;
+    }
+
+    let query = HistoryQuery {
+        component: ComponentType::Text,
+        ..real_query(device)
+    };
+    let series = history(&mut client, query).await;
+    let values: Vec<_> = series[0].points.iter().map(|(_, v)| v.clone()).collect();
+    assert_eq!(values, titles.map(|t| IglooValue::Text(t.to_string())));
+
+    let strings = read_strings(&strings_path(&history_path(device, ComponentType::Text)));
+    assert_eq!(
+        strings.unwrap(),
+        vec![Some("Song A".to_string()), Some("Song B".to_string())]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_strings_collected() {
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    let mut igloo = Igloo::boot().await;
+    let now = now_ms();
+    let path = history_path(7, ComponentType::Text);
+
+    // compacted soon after boot
+    restart_text(&mut igloo, |dir| {
+        fs::create_dir_all(dir.join(HISTORY_DIR)).unwrap();
+        let meta = HistoricalInstanceMetadata {
+            entry_size_bytes: 4,
+            start_timestamp: now - 48 * HOUR,
+            max_age_hours: Some(24),
+            min_interval_ms: 0,
+        };
+        let mut file = HistoryFile::open(&path, meta).unwrap();
+        let mut strings = StringTable::open(&strings_path(&path)).unwrap();
+        for (at, title) in [(now - 48 * HOUR, "old"), (now - 60_000, "new")] {
+            let id = strings.intern(title).unwrap();
+            file.append(at, &id.to_be_bytes()).unwrap();
+        }
+        strings.flush().unwrap();
+        file.sync().unwrap();
+    })
+    .await;
+
+    for _ in 0..50 {
+        let strings = read_strings(&strings_path(&path)).unwrap();
+        if strings == vec![None, Some("new".to_string())] {
+            let mut reader = HistoryReader::open(&path).unwrap();
+            let entries = reader.range(None, None).unwrap();
+            let value = value::decode(ComponentType::Text, &entries[0].1, &strings);
+            assert_eq!(entries.len(), 1);
+            assert_eq!(value, Some(IglooValue::Text("new".to_string())));
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("strings were not collected");
+}
+
+async fn statistics(
+    client: &mut FakeClient,
+    device: u64,
+    period: StatisticsPeriod,
+) -> Vec<StatisticsRow> {
+    let history = real_query(device);
+    let query = StatisticsQuery {
+        device_filter: history.device_filter,
+        entity_filter: history.entity_filter,
+        component: ComponentType::Real,
+        period,
+        since: None,
+        until: None,
+    };
+    match client.eval(OneShotQuery::Statistics(query)).await.unwrap() {
+        QueryResult::Statistics(mut series) => series.pop().unwrap().rows,
+        res => panic!("unexpected result: {res:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_statistics_counter_reset() {
+    // statistics include held back values
+    let mut igloo = boot(60_000).await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Meter").await;
+    ext.register_entity(device, "power", 0).await;
+    let class = Component::SensorStateClass(SensorStateClass::TotalIncreasing);
+    ext.write(device, 0, vec![class.clone()]).await;
+    // the counter resets before 3.0
+    for energy in [10.0, 12.0, 3.0, 5.0] {
+        write_power(&ext, &mut client, device, energy).await;
+    }
+
+    // may cross into a new hour
+    let rows = statistics(&mut client, device, StatisticsPeriod::Hour).await;
+    assert_eq!(rows.iter().map(|row| row.count).sum::<u64>(), 4);
+    assert_eq!(rows.iter().map(|row| row.change).sum::<f64>(), 7.0);
+    if let [row] = rows.as_slice() {
+        assert_eq!((row.min, row.max, row.mean), (3.0, 12.0, 7.5));
+    }
+
+    // carries on from the newest value
+    drop(ext);
+    igloo.restart().await;
+    let ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    ext.register_entity(device, "power", 0).await;
+    ext.write(device, 0, vec![class]).await;
+    write_power(&ext, &mut client, device, 6.0).await;
+
+    let rows = statistics(&mut client, device, StatisticsPeriod::Day).await;
+    assert_eq!(rows.iter().map(|row| row.count).sum::<u64>(), 5);
+    assert_eq!(rows.iter().map(|row| row.change).sum::<f64>(), 8.0);
+}
+
+/// Like `GET /history/export`, once every recorded entry is on disk
+async fn export_csv(igloo: &Igloo) -> (DeviceNames, String) {
+    let (tx, rx) = kanal::bounded(1);
+    igloo.tx.send(IglooRequest::HistoryExport(tx)).unwrap();
+    let names = rx.to_async().recv().await.unwrap();
+
+    let filter = ExportFilter {
+        components: vec![ComponentType::Real],
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    export(&history_dir(), &names, &filter, ExportFormat::Csv, &mut out).unwrap();
+    (names, String::from_utf8(out).unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_export_import() {
+    let mut igloo = boot(0).await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = setup_meter(&mut ext, &mut client).await;
+    write_power(&ext, &mut client, device, 2.5).await;
+
+    let (names, csv) = export_csv(&igloo).await;
+    let lines: Vec<_> = csv.lines().collect();
+    let did = DeviceID::new(device);
+    assert_eq!(lines.len(), 3);
+    assert_eq!(
+        lines[0],
+        "timestamp,device,device_id,entity,component,value"
+    );
+    assert!(lines[2].ends_with(&format!(",Meter,{did},power,real,2.5")));
+
+    // by name, with one row an hour ago and one past retention
+    let hour_ago = now_ms() - 3_600_000;
+    let row = |at: u64, value: f64| {
+        format!(
+            "{{\"timestamp\":{at},\"device\":\"Meter\",\"entity\":\"power\",\
+            \"component\":\"real\",\"value\":{value}}}\n"
+        )
+    };
+    let jsonl = row(hour_ago, 9.0) + &row(0, 1.0);
+    let bad = "timestamp,device,entity,component,value\n0,Nope,power,real,1\n";
+
+    let mut results = Vec::new();
+    igloo
+        .restart_with(|dir| {
+            let config = HistoryConfig::load(&dir.join(HISTORY_CONFIG_FILE)).unwrap();
+            let inputs = [
+                (ExportFormat::Jsonl, jsonl.as_bytes()),
+                // already recorded
+                (ExportFormat::Csv, csv.as_bytes()),
+                (ExportFormat::Csv, bad.as_bytes()),
+            ];
+            for (format, input) in inputs {
+                results.push(import(
+                    &history_dir(),
+                    &names,
+                    &config,
+                    format,
+                    input,
+                    now_ms(),
+                ));
+            }
+        })
+        .await;
+
+    let summaries: Vec<_> = results[..2]
+        .iter()
+        .map(|res| res.as_ref().unwrap())
+        .collect();
+    assert_eq!((summaries[0].imported, summaries[0].skipped), (1, 1));
+    assert_eq!((summaries[1].imported, summaries[1].skipped), (0, 0));
+    assert!(matches!(
+        results[2],
+        Err(ExportError::BadRow { line: 2, .. })
+    ));
+
+    let values = [9.0, 0.0, 2.5].map(IglooValue::Real);
+    assert_eq!(recorded(device), values);
+    let (_, csv) = export_csv(&igloo).await;
+    assert!(csv.lines().nth(1).unwrap().ends_with(",real,9"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_backup_flushes_history() {
+    let mut igloo = boot(0).await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = setup_meter(&mut ext, &mut client).await;
+    write_power(&ext, &mut client, device, 2.5).await;
+    let path = history_path(device, ComponentType::Real);
+    let compacting = path.with_extension("bin.compact");
+    fs::write(&compacting, b"mid compaction").unwrap();
+
+    client.send(ClientMsg::Backup).await;
+    let IglooResponse::BackupResult(Ok(archive)) = client.recv().await else {
+        panic!("Expected BackupResult");
+    };
+    let manifest = backup::verify(&archive).unwrap();
+    let size = |path: &Path| {
+        let rel = path.strip_prefix(DATA_DIR.get().unwrap()).unwrap();
+        let rel = format!("data/{}", rel.display());
+        manifest
+            .files
+            .iter()
+            .find(|file| file.path == rel)
+            .map(|file| file.size)
+    };
+
+    assert_eq!(size(&path), Some(fs::metadata(&path).unwrap().len()));
+    assert_eq!(recorded(device).len(), 2);
+    assert_eq!(size(&compacting), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history_import_refused_while_running() {
+    let mut igloo = boot(0).await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = setup_meter(&mut ext, &mut client).await;
+    write_power(&ext, &mut client, device, 2.5).await;
+    // flushes the recorder
+    let (_, csv) = export_csv(&igloo).await;
+
+    // exports only read, so they're fine alongside Igloo
+    let path = DATA_DIR.get().unwrap().with_file_name("export.csv");
+    let filter = ExportFilter::default();
+    run_export(&filter, ExportFormat::Csv, Some(&path)).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), csv);
+
+    let before = recorded(device);
+    assert!(matches!(run_import(&path, None), Err(ExportError::Lock(_))));
+    assert_eq!(recorded(device), before);
+}
@@ -0,0 +1,231 @@
+use super::{FakeClient, Igloo, comp_query};
+use crate::{core::ClientMsg, tree::journal::JOURNAL_FILE};
+use igloo_interface::{
+    Component, ComponentType,
+    id::{DeviceID, EntityID, ExtensionID, GroupID},
+    ipc::IglooToExtension,
+    query::{
+        ComponentAction, JournalEntry, JournalEvent, JournalKind, JournalQuery, JournalSource,
+        OneShotQuery, QueryResult, WatchJournalQuery, WatchQuery, WatchUpdate,
+    },
+    types::IglooValue,
+};
+
+async fn journal(client: &mut FakeClient, query: JournalQuery) -> Vec<JournalEntry> {
+    match client.eval(OneShotQuery::Journal(query)).await.unwrap() {
+        QueryResult::Journal(entries) => entries,
+        other => panic!("Expected Journal, got {other:?}"),
+    }
+}
+
+fn of_kind(kind: JournalKind) -> JournalQuery {
+    JournalQuery {
+        kinds: vec![kind],
+        ..Default::default()
+    }
+}
+
+fn rename(device: u64, name: &str) -> ClientMsg {
+    ClientMsg::RenameDevice {
+        device_id: DeviceID::new(device),
+        new_name: name.to_string(),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mutations_journaled() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let xid = ExtensionID("mock".to_string());
+
+    let device = ext.create_device("Lamp").await;
+    let did = DeviceID::new(device);
+    client.send(rename(device, "Desk Lamp")).await;
+
+    let expected = vec![
+        (
+            JournalSource::Extension(xid.clone()),
+            JournalEvent::DeviceCreated(did, "Lamp".to_string()),
+        ),
+        (
+            JournalSource::Client(client.id),
+            JournalEvent::DeviceRenamed(did, "Desk Lamp".to_string()),
+        ),
+    ];
+    let sourced = |entries: Vec<JournalEntry>| -> Vec<_> {
+        entries
+            .into_iter()
+            .map(|entry| (entry.source, entry.event))
+            .collect()
+    };
+    assert_eq!(
+        sourced(journal(&mut client, of_kind(JournalKind::Device)).await),
+        expected
+    );
+
+    let exts = journal(&mut client, of_kind(JournalKind::Extension)).await;
+    assert_eq!(exts.len(), 1);
+    assert_eq!(exts[0].event, JournalEvent::ExtensionAttached(xid));
+
+    drop(ext);
+    igloo.restart().await;
+    let mut client = igloo.client().await;
+    assert_eq!(
+        sourced(journal(&mut client, of_kind(JournalKind::Device)).await),
+        expected
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_set_journaled() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Lamp").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(device, 0, vec![Component::Switch(false)]).await;
+    let count = OneShotQuery::Component(comp_query(
+        device,
+        ComponentType::Switch,
+        ComponentAction::Count,
+    ));
+    client
+        .eval_until(count, |res| *res == QueryResult::Count(1))
+        .await;
+
+    let set = comp_query(
+        device,
+        ComponentType::Switch,
+        ComponentAction::Set(IglooValue::Boolean(true)),
+    );
+    client.eval(OneShotQuery::Component(set)).await.unwrap();
+    assert!(matches!(
+        ext.recv().await,
+        IglooToExtension::WriteComponents { .. }
+    ));
+
+    // the Extension's own write is journaled under its own kind
+    let sets = journal(&mut client, of_kind(JournalKind::Set)).await;
+    assert_eq!(sets.len(), 1);
+    assert_eq!(sets[0].source, JournalSource::Client(client.id));
+    assert_eq!(
+        sets[0].event,
+        JournalEvent::Set(
+            DeviceID::new(device),
+            EntityID("light".to_string()),
+            Component::Switch(true)
+        )
+    );
+
+    let reported = journal(&mut client, of_kind(JournalKind::Reported)).await;
+    assert_eq!(reported.len(), 1);
+    assert_eq!(
+        reported[0].source,
+        JournalSource::Extension(ExtensionID("mock".to_string()))
+    );
+    assert_eq!(
+        reported[0].event,
+        JournalEvent::Reported(
+            DeviceID::new(device),
+            EntityID("light".to_string()),
+            Component::Switch(false)
+        )
+    );
+
+    let at = sets[0].at;
+    let before = JournalQuery {
+        until: Some(at),
+        ..of_kind(JournalKind::Set)
+    };
+    assert!(journal(&mut client, before).await.is_empty());
+    let after = JournalQuery {
+        since: Some(at + 1),
+        ..Default::default()
+    };
+    assert!(journal(&mut client, after).await.is_empty());
+    let newest = JournalQuery {
+        limit: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(journal(&mut client, newest).await, sets);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_journal_read_across_rotations() {
+    let mut igloo = Igloo::boot().await;
+    let old = |at: u64, name: &str| JournalEntry {
+        at,
+        source: JournalSource::Igloo,
+        event: JournalEvent::GroupCreated(GroupID::from_parts(at as u32, 0), name.to_string()),
+    };
+    let rotated = [old(1000, "Kitchen"), old(2000, "Hall")];
+    igloo
+        .restart_with(|dir| {
+            let lines: String = rotated
+                .iter()
+                .map(|entry| serde_json::to_string(entry).unwrap() + "\n")
+                .collect();
+            std::fs::write(dir.join(format!("{JOURNAL_FILE}.1")), lines).unwrap();
+        })
+        .await;
+
+    let mut client = igloo.client().await;
+    client
+        .send(ClientMsg::CreateGroup {
+            name: "Office".to_string(),
+        })
+        .await;
+    client.recv().await;
+
+    let groups = journal(&mut client, of_kind(JournalKind::Group)).await;
+    assert_eq!(groups.len(), 3);
+    assert_eq!(groups[..2], rotated);
+
+    let newest = JournalQuery {
+        limit: Some(2),
+        ..of_kind(JournalKind::Group)
+    };
+    assert_eq!(journal(&mut client, newest).await, groups[1..]);
+    let since = JournalQuery {
+        since: Some(1500),
+        ..of_kind(JournalKind::Group)
+    };
+    assert_eq!(journal(&mut client, since).await, groups[1..]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch_journal() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let query_id = client
+        .sub(WatchQuery::Journal(WatchJournalQuery {
+            kinds: vec![JournalKind::Device],
+        }))
+        .await;
+
+    let device = ext.create_device("Lamp").await;
+    let did = DeviceID::new(device);
+    let WatchUpdate::Journal(entry) = client.watch_update(query_id).await else {
+        panic!("Expected a journal entry");
+    };
+    assert_eq!(
+        entry.event,
+        JournalEvent::DeviceCreated(did, "Lamp".to_string())
+    );
+
+    // other kinds are filtered out
+    ext.register_entity(device, "light", 0).await;
+    client.send(rename(device, "Desk Lamp")).await;
+    let WatchUpdate::Journal(entry) = client.watch_update(query_id).await else {
+        panic!("Expected a journal entry");
+    };
+    assert_eq!(entry.source, JournalSource::Client(client.id));
+    assert_eq!(
+        entry.event,
+        JournalEvent::DeviceRenamed(did, "Desk Lamp".to_string())
+    );
+}
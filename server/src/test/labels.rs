@@ -0,0 +1,174 @@
+use super::{FakeClient, Igloo, comp_query};
+use crate::core::ClientMsg;
+use igloo_interface::{
+    Component, ComponentType,
+    id::{DeviceID, EntityID},
+    query::{
+        ComponentAction, DeviceAction, DeviceFilter, DeviceQuery, EntityAction, EntityFilter,
+        EntityQuery, LabelFilter, MetadataUpdate, OneShotQuery, QueryResult, WatchComponentQuery,
+        WatchDeviceFilter, WatchQuery, WatchUpdate,
+    },
+    types::{IglooValue, agg::AggregationOp},
+};
+
+fn labels(labels: &[&str]) -> Vec<String> {
+    labels.iter().map(|label| label.to_string()).collect()
+}
+
+async fn set_labels(client: &FakeClient, device: DeviceID, new: &[&str]) {
+    client
+        .send(ClientMsg::SetDeviceLabels {
+            device_id: device,
+            labels: labels(new),
+        })
+        .await;
+}
+
+async fn labeled(client: &mut FakeClient, filter: LabelFilter) -> Vec<DeviceID> {
+    let query = OneShotQuery::Device(DeviceQuery {
+        filter: DeviceFilter {
+            label: Some(Box::new(filter)),
+            ..Default::default()
+        },
+        action: DeviceAction::GetID,
+        limit: None,
+    });
+    match client.eval(query).await.unwrap() {
+        QueryResult::DeviceId(mut dids) => {
+            dids.sort_by_key(|did| did.to_string());
+            dids
+        }
+        other => panic!("Expected DeviceId, got {other:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_device_labels_filter_and_persist() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let camera = DeviceID::new(ext.create_device("Camera").await);
+    let sprinkler = DeviceID::new(ext.create_device("Sprinkler").await);
+    ext.create_device("Lamp").await;
+
+    set_labels(&client, camera, &["outdoor", "critical"]).await;
+    set_labels(&client, sprinkler, &[" outdoor ", ""]).await;
+
+    let mut outdoor = vec![camera, sprinkler];
+    outdoor.sort_by_key(|did| did.to_string());
+    assert_eq!(
+        labeled(&mut client, LabelFilter::Has("outdoor".to_string())).await,
+        outdoor
+    );
+    let critical_outdoor = LabelFilter::HasAll(labels(&["outdoor", "critical"]));
+    assert_eq!(
+        labeled(&mut client, critical_outdoor.clone()).await,
+        vec![camera]
+    );
+
+    drop(ext);
+    igloo.restart().await;
+    let mut client = igloo.client().await;
+    assert_eq!(labeled(&mut client, critical_outdoor).await, vec![camera]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_entity_labels_before_registration() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Nursery").await;
+    let did = DeviceID::new(device);
+
+    let query_id = client.sub(WatchQuery::Metadata).await;
+    client.watch_update(query_id).await;
+
+    client
+        .send(ClientMsg::SetEntityLabels {
+            device_id: did,
+            entity_id: EntityID("night_light".to_string()),
+            labels: labels(&["kids-room"]),
+        })
+        .await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::Metadata(vec![MetadataUpdate::EntityLabels(
+            did,
+            EntityID("night_light".to_string()),
+            labels(&["kids-room"])
+        )])
+    );
+
+    ext.register_entity(device, "fan", 0).await;
+    ext.register_entity(device, "night_light", 1).await;
+
+    let query = OneShotQuery::Entity(EntityQuery {
+        device_filter: DeviceFilter::default(),
+        entity_filter: EntityFilter {
+            label: Some(Box::new(LabelFilter::Has("kids-room".to_string()))),
+            ..Default::default()
+        },
+        action: EntityAction::Snapshot,
+        limit: None,
+    });
+    let res = client
+        .eval_until(query, |res| res != &QueryResult::EntitySnapshot(vec![]))
+        .await;
+    let QueryResult::EntitySnapshot(entities) = res else {
+        panic!("Expected EntitySnapshot, got {res:?}");
+    };
+    assert_eq!(entities.len(), 1);
+    assert_eq!(entities[0].id, EntityID("night_light".to_string()));
+    assert_eq!(entities[0].labels, labels(&["kids-room"]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch_follows_labels() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let lamp = ext.create_device("Lamp").await;
+    let fan = ext.create_device("Fan").await;
+    for (device, on) in [(lamp, true), (fan, false)] {
+        ext.register_entity(device, "switch", 0).await;
+        ext.write(device, 0, vec![Component::Switch(on)]).await;
+        let query = comp_query(device, ComponentType::Switch, ComponentAction::Count);
+        client
+            .eval_until(OneShotQuery::Component(query), |res| {
+                *res == QueryResult::Count(1)
+            })
+            .await;
+    }
+    set_labels(&client, DeviceID::new(fan), &["critical"]).await;
+
+    let query_id = client
+        .sub(WatchQuery::Component(WatchComponentQuery {
+            device_filter: WatchDeviceFilter {
+                label: Some(Box::new(LabelFilter::Has("critical".to_string()))),
+                ..Default::default()
+            },
+            entity_filter: Default::default(),
+            component: ComponentType::Switch,
+            post_op: Some(AggregationOp::Any),
+        }))
+        .await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(false))
+    );
+
+    set_labels(&client, DeviceID::new(lamp), &["critical"]).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(true))
+    );
+
+    set_labels(&client, DeviceID::new(lamp), &[]).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(false))
+    );
+}
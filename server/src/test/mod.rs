@@ -0,0 +1,535 @@
+//! End to end test harness
+//!
+//! Boots a real IglooCore against a temporary `DATA_DIR`/`PACKAGES_DIR`.
+//! Fake Extensions connect over the real Unix socket, while the spawned
+//! `./ext` is just a placeholder script that exits once the test is done.
+//!
+//! `DATA_DIR` and `PACKAGES_DIR` are process wide, so only one [Igloo]
+//! can be alive at a time. [Igloo::boot] waits for the previous one to drop.
+//!
+//! Heartbeats use [TEST_HEARTBEAT]. Fake Extensions answer pings in the
+//! background until [FakeExt::stop_ponging].
+
+use crate::{
+    BACKUP, DATA_DIR, EXT_HEARTBEAT, PACKAGES_DIR,
+    backup::BackupConfig,
+    core::{self, ClientMsg, IglooRequest, IglooResponse},
+    ext::{EXTS_DIR, HeartbeatConfig, SOCKET},
+};
+use futures_util::{SinkExt, StreamExt};
+use igloo_interface::{
+    Component, ComponentType,
+    id::{DeviceID, ExtensionID},
+    ipc::{EWriter, ExtensionToIgloo, IglooToExtension, codec::LengthDelimitedJSONCodec},
+    query::{
+        ComponentAction, ComponentQuery, DeviceFilter, DeviceInfo, EntityFilter, ExtensionAction,
+        ExtensionQuery, IDFilter, OneShotQuery, QueryResult, WatchQuery, WatchUpdate,
+        check::QueryError,
+    },
+};
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UnixStream,
+    sync::{Mutex, MutexGuard, mpsc},
+    time,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+mod availability;
+mod backup;
+mod call;
+mod components;
+mod device_info;
+mod discovery;
+mod ext;
+mod groups;
+mod history;
+mod journal;
+mod labels;
+mod names;
+mod persist;
+mod query;
+mod replace;
+mod state;
+mod watch;
+
+/// Max time to wait for an expected message
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait when asserting nothing is received
+pub const SILENCE: Duration = Duration::from_millis(100);
+/// Short enough to detect a hung Extension within [TIMEOUT]
+pub const TEST_HEARTBEAT: HeartbeatConfig = HeartbeatConfig {
+    interval: Duration::from_secs(1),
+    timeout: Duration::from_secs(2),
+};
+/// Backups kept by [crate::backup::run]
+pub const TEST_BACKUP_KEEP: usize = 2;
+
+/// Stands in for the Extension's process, exits once the test dir is removed
+const EXT_SCRIPT: &str = "#!/bin/sh\nwhile [ -e igloo.sock ]; do sleep 0.1; done\n";
+
+static LOCK: Mutex<()> = Mutex::const_new(());
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+fn root() -> &'static PathBuf {
+    ROOT.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("igloo-test-{}", std::process::id()));
+        DATA_DIR.set(root.join("data")).unwrap();
+        PACKAGES_DIR.set(root.join("packages")).unwrap();
+        EXT_HEARTBEAT.set(TEST_HEARTBEAT).unwrap();
+        BACKUP
+            .set(BackupConfig {
+                dir: root.join("backups"),
+                interval: None,
+                keep: TEST_BACKUP_KEEP,
+            })
+            .unwrap();
+        root
+    })
+}
+
+fn ext_dir(id: &str) -> PathBuf {
+    PACKAGES_DIR.get().unwrap().join(EXTS_DIR).join(id)
+}
+
+/// Component query on a single device
+pub fn comp_query(
+    device: u64,
+    component: ComponentType,
+    action: ComponentAction,
+) -> ComponentQuery {
+    ComponentQuery {
+        device_filter: DeviceFilter {
+            id: IDFilter::Is(DeviceID::new(device)),
+            ..Default::default()
+        },
+        entity_filter: EntityFilter::default(),
+        action,
+        component,
+        post_op: None,
+        include_parents: false,
+        await_ack: false,
+        limit: None,
+    }
+}
+
+pub struct Igloo {
+    handle: Option<JoinHandle<()>>,
+    tx: kanal::Sender<IglooRequest>,
+    admin: FakeClient,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Igloo {
+    pub async fn boot() -> Self {
+        let lock = LOCK.lock().await;
+
+        let root = root();
+        _ = fs::remove_dir_all(root);
+        fs::create_dir_all(DATA_DIR.get().unwrap()).unwrap();
+        fs::create_dir_all(PACKAGES_DIR.get().unwrap().join(EXTS_DIR)).unwrap();
+
+        let (handle, tx) = core::spawn().await.unwrap();
+        let admin = FakeClient::register(&tx).await;
+
+        Self {
+            handle: Some(handle),
+            tx,
+            admin,
+            _lock: lock,
+        }
+    }
+
+    /// Shuts down IglooCore and boots a new one on the same `DATA_DIR`
+    /// Installed Extensions are removed, so it boots without any
+    pub async fn restart(&mut self) {
+        self.restart_with(|_| {}).await;
+    }
+
+    /// [Igloo::restart], running `edit` on `DATA_DIR` while it is down
+    pub async fn restart_with<F: FnOnce(&Path)>(&mut self, edit: F) {
+        _ = self.tx.send(IglooRequest::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            tokio::task::block_in_place(|| handle.join()).unwrap();
+        }
+
+        let exts_dir = PACKAGES_DIR.get().unwrap().join(EXTS_DIR);
+        fs::remove_dir_all(&exts_dir).unwrap();
+        fs::create_dir_all(&exts_dir).unwrap();
+
+        edit(DATA_DIR.get().unwrap());
+
+        let (handle, tx) = core::spawn().await.unwrap();
+        self.admin = FakeClient::register(&tx).await;
+        self.handle = Some(handle);
+        self.tx = tx;
+    }
+
+    pub async fn client(&self) -> FakeClient {
+        FakeClient::register(&self.tx).await
+    }
+
+    /// Installs and starts a fake Extension, returns once it is attached
+    pub async fn ext(&mut self, id: &str) -> FakeExt {
+        let dir = ext_dir(id);
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("ext");
+        fs::write(&script, EXT_SCRIPT).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let socket = dir.join(SOCKET);
+        let old = socket_id(&socket);
+
+        let xid = ExtensionID(id.to_string());
+        self.admin.send(ClientMsg::StartExt(xid.clone())).await;
+        let ext = FakeExt::connect_new(socket, old).await;
+
+        self.wait_until_attached(&xid, true).await;
+        ext
+    }
+
+    pub async fn wait_until_attached(&mut self, xid: &ExtensionID, attached: bool) {
+        self.admin
+            .eval_until(
+                OneShotQuery::Extension(ExtensionQuery {
+                    id: IDFilter::Is(xid.clone()),
+                    action: ExtensionAction::IsAttached,
+                    limit: None,
+                }),
+                |res| match res {
+                    QueryResult::ExtensionAttached(exts) => {
+                        exts.iter().any(|(id, a)| id == xid && *a) == attached
+                    }
+                    _ => false,
+                },
+            )
+            .await;
+    }
+}
+
+impl Drop for Igloo {
+    fn drop(&mut self) {
+        _ = self.tx.send(IglooRequest::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            _ = handle.join();
+        }
+        // placeholder Extension scripts exit once this is gone
+        _ = fs::remove_dir_all(root());
+    }
+}
+
+pub struct FakeClient {
+    pub id: usize,
+    tx: kanal::AsyncSender<IglooRequest>,
+    rx: kanal::AsyncReceiver<IglooResponse>,
+    next_query_id: usize,
+}
+
+impl FakeClient {
+    async fn register(core_tx: &kanal::Sender<IglooRequest>) -> Self {
+        let (res_tx, res_rx) = kanal::bounded::<IglooResponse>(50);
+        let tx = core_tx.clone_async();
+        tx.send(IglooRequest::RegisterClient(res_tx)).await.unwrap();
+
+        let mut me = Self {
+            id: usize::MAX,
+            tx,
+            rx: res_rx.to_async(),
+            next_query_id: 0,
+        };
+
+        match me.recv().await {
+            IglooResponse::Registered { client_id } => me.id = client_id,
+            other => panic!("Expected Registered, got {other:?}"),
+        }
+
+        me
+    }
+
+    pub async fn send(&self, msg: ClientMsg) {
+        self.tx
+            .send(IglooRequest::Client {
+                client_id: self.id,
+                msg,
+            })
+            .await
+            .unwrap();
+    }
+
+    pub async fn recv(&mut self) -> IglooResponse {
+        match time::timeout(TIMEOUT, self.rx.recv()).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => panic!("Client {} channel closed: {e}", self.id),
+            Err(_) => panic!("Client {} timed out waiting for a response", self.id),
+        }
+    }
+
+    /// Asserts nothing is received for [SILENCE]
+    pub async fn expect_silence(&mut self) {
+        if let Ok(res) = time::timeout(SILENCE, self.rx.recv()).await {
+            panic!("Client {} expected silence, got {res:?}", self.id);
+        }
+    }
+
+    pub async fn eval(&mut self, query: OneShotQuery) -> Result<QueryResult, QueryError> {
+        let query_id = self.send_eval(query).await;
+        self.eval_result(query_id).await
+    }
+
+    /// Returns the query ID, see [Self::eval_result]
+    pub async fn send_eval(&mut self, query: OneShotQuery) -> usize {
+        let query_id = self.next_query_id();
+        self.send(ClientMsg::Eval { query_id, query }).await;
+        query_id
+    }
+
+    pub async fn eval_result(&mut self, query_id: usize) -> Result<QueryResult, QueryError> {
+        match self.recv().await {
+            IglooResponse::EvalResult {
+                query_id: id,
+                result,
+            } if id == query_id => result,
+            other => panic!("Expected EvalResult for query {query_id}, got {other:?}"),
+        }
+    }
+
+    /// Re-evaluates until `pred` passes
+    /// Needed after an Extension's message, since it reaches IglooCore
+    /// separately from the client's
+    pub async fn eval_until<F>(&mut self, query: OneShotQuery, mut pred: F) -> QueryResult
+    where
+        F: FnMut(&QueryResult) -> bool,
+    {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let res = self.eval(query.clone()).await;
+            match res {
+                Ok(res) if pred(&res) => return res,
+                res => assert!(
+                    Instant::now() < deadline,
+                    "Timed out waiting for query, last result: {res:?}"
+                ),
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Returns the query ID
+    pub async fn sub(&mut self, query: WatchQuery) -> usize {
+        let query_id = self.next_query_id();
+        self.send(ClientMsg::Sub { query_id, query }).await;
+        query_id
+    }
+
+    pub async fn watch_update(&mut self, query_id: usize) -> WatchUpdate {
+        match self.recv().await {
+            IglooResponse::WatchUpdate {
+                query_id: id,
+                value,
+            } if id == query_id => value,
+            other => panic!("Expected WatchUpdate for query {query_id}, got {other:?}"),
+        }
+    }
+
+    fn next_query_id(&mut self) -> usize {
+        let id = self.next_query_id;
+        self.next_query_id += 1;
+        id
+    }
+}
+
+pub struct FakeExt {
+    writer: Arc<Mutex<EWriter>>,
+    /// Everything but pings
+    rx: mpsc::UnboundedReceiver<IglooToExtension>,
+    pong: Arc<AtomicBool>,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+/// Tells instances bound to the same socket path apart, inode numbers alone get reused
+pub fn socket_id(socket: &Path) -> Option<(u64, i64, i64)> {
+    fs::metadata(socket)
+        .ok()
+        .map(|meta| (meta.ino(), meta.ctime(), meta.ctime_nsec()))
+}
+
+impl FakeExt {
+    /// [FakeExt::connect], once an instance other than `old` (see [socket_id]) bound it
+    /// A stopping instance keeps listening until it's gone
+    async fn connect_new(socket: PathBuf, old: Option<(u64, i64, i64)>) -> Self {
+        let deadline = Instant::now() + TIMEOUT;
+        while socket_id(&socket) == old {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for {socket:?}"
+            );
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        Self::connect(socket).await
+    }
+
+    async fn connect(socket: PathBuf) -> Self {
+        let deadline = Instant::now() + TIMEOUT;
+        let stream = loop {
+            if let Ok(stream) = UnixStream::connect(&socket).await {
+                break stream;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Timed out connecting to {socket:?}"
+            );
+            time::sleep(Duration::from_millis(10)).await;
+        };
+
+        let (reader, writer) = stream.into_split();
+        let mut reader = FramedRead::new(reader, LengthDelimitedJSONCodec::new());
+        let writer = Arc::new(Mutex::new(FramedWrite::new(
+            writer,
+            LengthDelimitedJSONCodec::new(),
+        )));
+        let pong = Arc::new(AtomicBool::new(true));
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let (writer_1, pong_1) = (writer.clone(), pong.clone());
+        let reader = tokio::spawn(async move {
+            while let Some(Ok(msg)) = reader.next().await {
+                match msg {
+                    IglooToExtension::Ping => {
+                        if pong_1.load(Ordering::Relaxed) {
+                            _ = writer_1.lock().await.send(ExtensionToIgloo::Pong).await;
+                        }
+                    }
+                    msg => {
+                        if tx.send(msg).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        let me = Self {
+            writer,
+            rx,
+            pong,
+            reader,
+        };
+        me.send(ExtensionToIgloo::WhatsUpIgloo).await;
+        me
+    }
+
+    pub async fn send(&self, msg: ExtensionToIgloo) {
+        self.writer.lock().await.send(msg).await.unwrap();
+    }
+
+    /// Closes the socket, like a crashed connection, while the Extension keeps running
+    pub async fn disconnect(self) {
+        self.reader.abort();
+        _ = self.reader.await;
+    }
+
+    /// Simulates a hung Extension, which still has its socket open
+    pub fn stop_ponging(&self) {
+        self.pong.store(false, Ordering::Relaxed);
+    }
+
+    pub async fn recv(&mut self) -> IglooToExtension {
+        match time::timeout(TIMEOUT, self.rx.recv()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => panic!("Igloo closed the fake Extension's socket"),
+            Err(_) => panic!("Fake Extension timed out waiting for a message"),
+        }
+    }
+
+    /// Asserts nothing is received for [SILENCE]
+    pub async fn expect_silence(&mut self) {
+        if let Ok(Some(msg)) = time::timeout(SILENCE, self.rx.recv()).await {
+            panic!("Fake Extension expected silence, got {msg:?}");
+        }
+    }
+
+    /// Creates a device, returning its ID
+    pub async fn create_device(&mut self, name: &str) -> u64 {
+        self.create(name, None, None).await
+    }
+
+    /// Creates a device with its info, returning its ID
+    pub async fn create_device_with_info(&mut self, name: &str, info: DeviceInfo) -> u64 {
+        self.create(name, None, Some(info)).await
+    }
+
+    /// Creates a device accepted from the discovery inbox, returning its ID
+    pub async fn adopt_device(&mut self, name: &str, external_id: &str) -> u64 {
+        self.create(name, Some(external_id.to_string()), None).await
+    }
+
+    async fn create(
+        &mut self,
+        name: &str,
+        external_id: Option<String>,
+        info: Option<DeviceInfo>,
+    ) -> u64 {
+        self.send(ExtensionToIgloo::CreateDevice {
+            name: name.to_string(),
+            external_id,
+            info,
+        })
+        .await;
+
+        match self.recv().await {
+            IglooToExtension::DeviceCreated { name: n, id } if n == name => id,
+            other => panic!("Expected DeviceCreated for \"{name}\", got {other:?}"),
+        }
+    }
+
+    pub async fn register_entity(&self, device: u64, entity_id: &str, entity_index: usize) {
+        self.send(ExtensionToIgloo::RegisterEntity {
+            device,
+            entity_id: entity_id.to_string(),
+            entity_index,
+        })
+        .await;
+    }
+
+    pub async fn write(&self, device: u64, entity: usize, comps: Vec<Component>) {
+        self.send(ExtensionToIgloo::WriteComponents {
+            device,
+            entity,
+            comps,
+        })
+        .await;
+    }
+
+    pub async fn remove(&self, device: u64, entity: usize, comps: Vec<ComponentType>) {
+        self.send(ExtensionToIgloo::RemoveComponents {
+            device,
+            entity,
+            comps,
+        })
+        .await;
+    }
+
+    pub async fn set_reachable(&self, device: u64, reachable: bool) {
+        self.send(ExtensionToIgloo::SetReachable { device, reachable })
+            .await;
+    }
+
+    pub async fn discovered(&self, external_id: &str, name: &str, model: Option<&str>) {
+        self.send(ExtensionToIgloo::Discovered {
+            external_id: external_id.to_string(),
+            name: name.to_string(),
+            model: model.map(str::to_string),
+        })
+        .await;
+    }
+}
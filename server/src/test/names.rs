@@ -0,0 +1,175 @@
+use super::{FakeClient, Igloo, comp_query};
+use crate::core::{ClientMsg, IglooResponse};
+use igloo_interface::{
+    Component, ComponentType,
+    id::DeviceID,
+    query::{
+        ComponentAction, DeviceAction, DeviceFilter, DeviceQuery, EntityAction, EntityFilter,
+        EntityIDFilter, EntityQuery, GroupAction, GroupQuery, IDFilter, NameFilter, OneShotQuery,
+        QueryResult, WatchComponentQuery, WatchDeviceFilter, WatchQuery, WatchUpdate,
+        check::QueryError,
+    },
+    types::{IglooValue, agg::AggregationOp},
+};
+
+fn device_query(name: NameFilter) -> OneShotQuery {
+    OneShotQuery::Device(DeviceQuery {
+        filter: DeviceFilter {
+            name: Some(Box::new(name)),
+            ..Default::default()
+        },
+        action: DeviceAction::Count,
+        limit: None,
+    })
+}
+
+async fn count(client: &mut FakeClient, name: NameFilter) -> usize {
+    match client.eval(device_query(name)).await.unwrap() {
+        QueryResult::Count(count) => count,
+        other => panic!("Expected Count, got {other:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_device_and_group_name_filters() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    ext.create_device("Kitchen Light").await;
+    ext.create_device("kitchen fan").await;
+    ext.create_device("Garage Door").await;
+
+    let name = |s: &str| s.to_string();
+    assert_eq!(
+        count(&mut client, NameFilter::Is(name("Kitchen Light"))).await,
+        1
+    );
+    assert_eq!(
+        count(&mut client, NameFilter::IsIgnoreCase(name("KITCHEN FAN"))).await,
+        1
+    );
+    assert_eq!(
+        count(&mut client, NameFilter::Matches(name("Kitchen *"))).await,
+        1
+    );
+    assert_eq!(
+        count(
+            &mut client,
+            NameFilter::MatchesIgnoreCase(name("kitchen *"))
+        )
+        .await,
+        2
+    );
+    assert_eq!(
+        count(
+            &mut client,
+            NameFilter::Regex(name("(?i)^(kitchen|garage) "))
+        )
+        .await,
+        3
+    );
+
+    for group in ["Upstairs", "Downstairs", "Garage"] {
+        client
+            .send(ClientMsg::CreateGroup {
+                name: group.to_string(),
+            })
+            .await;
+        let IglooResponse::GroupCreated(_) = client.recv().await else {
+            panic!("Expected GroupCreated");
+        };
+    }
+    let query = OneShotQuery::Group(GroupQuery {
+        id: IDFilter::Any,
+        name: Some(Box::new(NameFilter::Matches("*stairs".to_string()))),
+        action: GroupAction::Count,
+        limit: None,
+    });
+    assert_eq!(client.eval(query).await.unwrap(), QueryResult::Count(2));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_invalid_patterns_are_errors() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Lamp").await;
+    ext.register_entity(device, "light", 0).await;
+
+    let res = client
+        .eval(device_query(NameFilter::Regex("(".to_string())))
+        .await;
+    assert!(matches!(res, Err(QueryError::InvalidPattern(p, _)) if p == "("));
+
+    let query = OneShotQuery::Entity(EntityQuery {
+        device_filter: DeviceFilter::default(),
+        entity_filter: EntityFilter {
+            id: EntityIDFilter::Matches("light[".to_string()),
+            ..Default::default()
+        },
+        action: EntityAction::Count,
+        limit: None,
+    });
+    let res = client.eval(query).await;
+    assert!(matches!(res, Err(QueryError::InvalidPattern(p, _)) if p == "light["));
+
+    // still serving queries
+    assert_eq!(
+        count(&mut client, NameFilter::Is("Lamp".to_string())).await,
+        1
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch_follows_renames() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let lamp = ext.create_device("Porch Lamp").await;
+    let fan = ext.create_device("Porch Fan").await;
+    for (device, on) in [(lamp, false), (fan, true)] {
+        ext.register_entity(device, "switch", 0).await;
+        ext.write(device, 0, vec![Component::Switch(on)]).await;
+        let query = comp_query(device, ComponentType::Switch, ComponentAction::Count);
+        client
+            .eval_until(OneShotQuery::Component(query), |res| {
+                *res == QueryResult::Count(1)
+            })
+            .await;
+    }
+
+    let query_id = client
+        .sub(WatchQuery::Component(WatchComponentQuery {
+            device_filter: WatchDeviceFilter {
+                name: Some(Box::new(NameFilter::Matches("Porch *".to_string()))),
+                ..Default::default()
+            },
+            entity_filter: Default::default(),
+            component: ComponentType::Switch,
+            post_op: Some(AggregationOp::Any),
+        }))
+        .await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(true))
+    );
+
+    let rename = |device: u64, name: &str| ClientMsg::RenameDevice {
+        device_id: DeviceID::new(device),
+        new_name: name.to_string(),
+    };
+    client.send(rename(fan, "Attic Fan")).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(false))
+    );
+
+    client.send(rename(fan, "Porch Fan")).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(true))
+    );
+}
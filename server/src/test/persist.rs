@@ -0,0 +1,85 @@
+use super::Igloo;
+use crate::{
+    core::ClientMsg,
+    tree::persist::{DEVICES_FILE, MAX_BACKUPS},
+};
+use igloo_interface::{
+    id::DeviceID,
+    query::{MetadataUpdate, WatchQuery, WatchUpdate},
+};
+use std::fs;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_corrupt_file_restored_from_backup() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let did = DeviceID::new(ext.create_device("Lamp").await);
+    let query_id = client.sub(WatchQuery::Metadata).await;
+    client.watch_update(query_id).await;
+
+    for notes in ["Desk", "Nightstand"] {
+        client
+            .send(ClientMsg::SetDeviceNotes {
+                device_id: did,
+                notes: Some(notes.to_string()),
+            })
+            .await;
+        client.watch_update(query_id).await;
+    }
+
+    drop(ext);
+    igloo
+        .restart_with(|dir| fs::write(dir.join(DEVICES_FILE), "devices = [").unwrap())
+        .await;
+
+    // newest backup is from before the last save
+    let mut client = igloo.client().await;
+    let query_id = client.sub(WatchQuery::Metadata).await;
+    let WatchUpdate::Metadata(batch) = client.watch_update(query_id).await else {
+        panic!("Expected Metadata");
+    };
+    let restored = batch.iter().find_map(|update| match update {
+        MetadataUpdate::Device(id, meta) if *id == did => Some(meta),
+        _ => None,
+    });
+    assert_eq!(restored.unwrap().notes.as_deref(), Some("Desk"));
+
+    igloo
+        .restart_with(|dir| {
+            let corrupt = fs::read_to_string(dir.join("devices.toml.corrupt")).unwrap();
+            assert_eq!(corrupt, "devices = [");
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_backups_rotate() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+
+    for i in 0..MAX_BACKUPS + 2 {
+        ext.create_device(&format!("Device {i}")).await;
+    }
+
+    drop(ext);
+    igloo
+        .restart_with(|dir| {
+            for n in 1..=MAX_BACKUPS {
+                assert!(dir.join(format!("{DEVICES_FILE}.{n}")).exists());
+            }
+            assert!(
+                !dir.join(format!("{DEVICES_FILE}.{}", MAX_BACKUPS + 1))
+                    .exists()
+            );
+            assert!(!dir.join(format!("{DEVICES_FILE}.tmp")).exists());
+
+            // newest backup is one device behind
+            let newest = fs::read_to_string(dir.join(format!("{DEVICES_FILE}.1"))).unwrap();
+            let current = fs::read_to_string(dir.join(DEVICES_FILE)).unwrap();
+            assert!(current.contains(&format!("Device {}", MAX_BACKUPS + 1)));
+            assert!(!newest.contains(&format!("Device {}", MAX_BACKUPS + 1)));
+        })
+        .await;
+}
@@ -0,0 +1,209 @@
+use super::{FakeClient, FakeExt, Igloo, comp_query};
+use igloo_interface::{
+    Component, ComponentType,
+    id::{DeviceID, EntityID, ExtensionID},
+    ipc::{ExtensionToIgloo, IglooToExtension},
+    query::{CommandError, ComponentAction, OneShotQuery, QueryResult, check::QueryError},
+    types::{IglooValue, math::MathOp},
+};
+
+use crate::core::ClientMsg;
+
+/// Device with a single "light" entity with a Switch
+async fn setup_lamp(ext: &mut FakeExt, client: &mut FakeClient) -> u64 {
+    let device = ext.create_device("Lamp").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(device, 0, vec![Component::Switch(false)]).await;
+
+    let query = OneShotQuery::Component(comp_query(
+        device,
+        ComponentType::Switch,
+        ComponentAction::Count,
+    ));
+    client
+        .eval_until(query, |res| *res == QueryResult::Count(1))
+        .await;
+
+    device
+}
+
+fn set_switch(device: u64, await_ack: bool) -> OneShotQuery {
+    let mut query = comp_query(
+        device,
+        ComponentType::Switch,
+        ComponentAction::Set(IglooValue::Boolean(true)),
+    );
+    query.await_ack = await_ack;
+    OneShotQuery::Component(query)
+}
+
+/// Receives the write from [set_switch], returning its command ID
+async fn expect_set_switch(ext: &mut FakeExt, device: u64) -> u64 {
+    match ext.recv().await {
+        IglooToExtension::WriteComponents {
+            id,
+            device: d,
+            entity: 0,
+            comps,
+        } if d == device && comps == vec![Component::Switch(true)] => id,
+        other => panic!("Expected Switch write, got {other:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_set_without_ack() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let device = setup_lamp(&mut ext, &mut client).await;
+
+    let res = client.eval(set_switch(device, false)).await;
+    assert_eq!(res.unwrap(), QueryResult::Count(1));
+
+    expect_set_switch(&mut ext, device).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_set_waits_for_ack() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let device = setup_lamp(&mut ext, &mut client).await;
+
+    let query_id = client.send_eval(set_switch(device, true)).await;
+    let id = expect_set_switch(&mut ext, device).await;
+    client.expect_silence().await;
+
+    ext.send(ExtensionToIgloo::Ack {
+        id,
+        result: Err("offline".to_string()),
+    })
+    .await;
+
+    assert_eq!(
+        client.eval_result(query_id).await.unwrap(),
+        QueryResult::Acks(vec![(
+            DeviceID::new(device),
+            EntityID("light".to_string()),
+            Err(CommandError::Rejected("offline".to_string())),
+        )])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_set_fails_on_detach() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let device = setup_lamp(&mut ext, &mut client).await;
+
+    let query_id = client.send_eval(set_switch(device, true)).await;
+    expect_set_switch(&mut ext, device).await;
+
+    client
+        .send(ClientMsg::StopExt(ExtensionID("mock".to_string())))
+        .await;
+
+    assert_eq!(
+        client.eval_result(query_id).await.unwrap(),
+        QueryResult::Acks(vec![(
+            DeviceID::new(device),
+            EntityID("light".to_string()),
+            Err(CommandError::Detached),
+        )])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_apply_skips_entities_without_component() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let device = setup_lamp(&mut ext, &mut client).await;
+
+    // the "light" entity before it has no Dimmer
+    ext.register_entity(device, "dimmer", 1).await;
+    ext.write(device, 1, vec![Component::Dimmer(0.5)]).await;
+    let count = OneShotQuery::Component(comp_query(
+        device,
+        ComponentType::Dimmer,
+        ComponentAction::Count,
+    ));
+    client
+        .eval_until(count, |res| *res == QueryResult::Count(1))
+        .await;
+
+    let apply = comp_query(
+        device,
+        ComponentType::Dimmer,
+        ComponentAction::Apply(MathOp::Add(IglooValue::Real(0.25))),
+    );
+    let res = client.eval(OneShotQuery::Component(apply)).await;
+    assert_eq!(res.unwrap(), QueryResult::Count(1));
+
+    match ext.recv().await {
+        IglooToExtension::WriteComponents {
+            device: d,
+            entity: 1,
+            comps,
+            ..
+        } if d == device => assert_eq!(comps, vec![Component::Dimmer(0.75)]),
+        other => panic!("Expected Dimmer write, got {other:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_set_rejects_pending_query_id() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let device = setup_lamp(&mut ext, &mut client).await;
+
+    let query_id = client.send_eval(set_switch(device, true)).await;
+    let id = expect_set_switch(&mut ext, device).await;
+
+    client
+        .send(ClientMsg::Eval {
+            query_id,
+            query: set_switch(device, true),
+        })
+        .await;
+    match client.eval_result(query_id).await {
+        Err(QueryError::DuplicateQueryID(id)) => assert_eq!(id, query_id),
+        other => panic!("Expected DuplicateQueryID, got {other:?}"),
+    }
+    ext.expect_silence().await;
+
+    // the first query is still answered
+    ext.send(ExtensionToIgloo::Ack { id, result: Ok(()) }).await;
+    assert_eq!(
+        client.eval_result(query_id).await.unwrap(),
+        QueryResult::Acks(vec![(
+            DeviceID::new(device),
+            EntityID("light".to_string()),
+            Ok(()),
+        )])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_set_forgets_unregistered_client() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let device = setup_lamp(&mut ext, &mut client).await;
+
+    client.send_eval(set_switch(device, true)).await;
+    let id = expect_set_switch(&mut ext, device).await;
+
+    client.send(ClientMsg::Unregister).await;
+    let old_id = client.id;
+    drop(client);
+
+    // takes the freed slot, and must not receive the old client's acks
+    let mut client = igloo.client().await;
+    assert_eq!(client.id, old_id);
+
+    ext.send(ExtensionToIgloo::Ack { id, result: Ok(()) }).await;
+    client.expect_silence().await;
+}
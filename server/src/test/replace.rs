@@ -0,0 +1,191 @@
+use super::{FakeClient, Igloo, comp_query};
+use crate::core::{ClientMsg, IglooResponse};
+use igloo_interface::{
+    Component, ComponentType,
+    id::{DeviceID, ExtensionID, GroupID},
+    ipc::IglooToExtension,
+    query::{
+        ComponentAction, DeviceAction, DeviceFilter, DeviceGroupFilter, DeviceQuery, IDFilter,
+        MetadataUpdate, OneShotQuery, QueryResult, WatchComponentQuery, WatchDeviceFilter,
+        WatchQuery, WatchUpdate,
+    },
+    types::{IglooValue, agg::AggregationOp},
+};
+
+async fn create_group(client: &mut FakeClient, name: &str) -> GroupID {
+    client
+        .send(ClientMsg::CreateGroup {
+            name: name.to_string(),
+        })
+        .await;
+    let IglooResponse::GroupCreated(gid) = client.recv().await else {
+        panic!("Expected GroupCreated");
+    };
+    gid
+}
+
+async fn switch_on(client: &mut FakeClient, device: u64) -> QueryResult {
+    let query = comp_query(device, ComponentType::Switch, ComponentAction::GetValue);
+    client.eval(OneShotQuery::Component(query)).await.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replacement_keeps_id_and_metadata() {
+    let mut igloo = Igloo::boot().await;
+    let mut old_ext = igloo.ext("mock").await;
+    let mut new_ext = igloo.ext("zigbee").await;
+    let mut client = igloo.client().await;
+
+    let old = old_ext.create_device("Coffee Plug").await;
+    let did = DeviceID::new(old);
+    let kitchen = create_group(&mut client, "Kitchen").await;
+    client.send(ClientMsg::AddDeviceToGroup(kitchen, did)).await;
+    client
+        .send(ClientMsg::SetDeviceLabels {
+            device_id: did,
+            labels: vec!["critical".to_string()],
+        })
+        .await;
+
+    let new = new_ext.create_device("Smart Plug").await;
+    new_ext.register_entity(new, "switch", 0).await;
+    new_ext.write(new, 0, vec![Component::Switch(true)]).await;
+    let query = comp_query(new, ComponentType::Switch, ComponentAction::Count);
+    client
+        .eval_until(OneShotQuery::Component(query), |res| {
+            *res == QueryResult::Count(1)
+        })
+        .await;
+
+    client
+        .send(ClientMsg::ReplaceDevice {
+            device_id: did,
+            replacement: DeviceID::new(new),
+        })
+        .await;
+    for ext in [&mut old_ext, &mut new_ext] {
+        assert_eq!(
+            ext.recv().await,
+            IglooToExtension::DeviceReplaced { id: old, by: new }
+        );
+    }
+
+    // the new hardware is reachable under the old ID
+    new_ext.write(old, 0, vec![Component::Switch(false)]).await;
+    let query = comp_query(old, ComponentType::Switch, ComponentAction::GetValue);
+    let res = client
+        .eval_until(OneShotQuery::Component(query), |res| {
+            *res == QueryResult::ComponentValue(vec![IglooValue::Boolean(false)])
+        })
+        .await;
+    assert_eq!(
+        res,
+        QueryResult::ComponentValue(vec![IglooValue::Boolean(false)])
+    );
+    assert_eq!(
+        switch_on(&mut client, new).await,
+        QueryResult::ComponentValue(vec![])
+    );
+
+    let query = OneShotQuery::Device(DeviceQuery {
+        filter: DeviceFilter {
+            id: IDFilter::Is(did),
+            ..Default::default()
+        },
+        action: DeviceAction::Snapshot(false),
+        limit: None,
+    });
+    let QueryResult::DeviceSnapshot(snapshots) = client.eval(query).await.unwrap() else {
+        panic!("Expected DeviceSnapshot");
+    };
+    assert_eq!(snapshots[0].name, "Coffee Plug");
+    assert_eq!(snapshots[0].owner, ExtensionID("zigbee".to_string()));
+    assert!(snapshots[0].groups.contains(&kitchen));
+    assert_eq!(snapshots[0].labels, vec!["critical".to_string()]);
+
+    // survives a restart
+    drop((old_ext, new_ext));
+    igloo.restart().await;
+    let mut client = igloo.client().await;
+    let query = OneShotQuery::Device(DeviceQuery {
+        filter: DeviceFilter {
+            group: DeviceGroupFilter::In(kitchen),
+            ..Default::default()
+        },
+        action: DeviceAction::GetID,
+        limit: None,
+    });
+    assert_eq!(
+        client.eval(query).await.unwrap(),
+        QueryResult::DeviceId(vec![did])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replacement_updates_watchers() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let old = ext.create_device("Porch Light").await;
+    let did = DeviceID::new(old);
+    let porch = create_group(&mut client, "Porch").await;
+    client.send(ClientMsg::AddDeviceToGroup(porch, did)).await;
+    ext.register_entity(old, "switch", 0).await;
+    ext.write(old, 0, vec![Component::Switch(false)]).await;
+
+    let new = ext.create_device("New Porch Light").await;
+    ext.register_entity(new, "switch", 0).await;
+    ext.write(new, 0, vec![Component::Switch(true)]).await;
+    let query = comp_query(new, ComponentType::Switch, ComponentAction::Count);
+    client
+        .eval_until(OneShotQuery::Component(query), |res| {
+            *res == QueryResult::Count(1)
+        })
+        .await;
+
+    let comp_id = client
+        .sub(WatchQuery::Component(WatchComponentQuery {
+            device_filter: WatchDeviceFilter {
+                group: DeviceGroupFilter::In(porch),
+                ..Default::default()
+            },
+            entity_filter: Default::default(),
+            component: ComponentType::Switch,
+            post_op: Some(AggregationOp::Any),
+        }))
+        .await;
+    assert_eq!(
+        client.watch_update(comp_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(false))
+    );
+    let meta_id = client.sub(WatchQuery::Metadata).await;
+    client.watch_update(meta_id).await;
+
+    client
+        .send(ClientMsg::ReplaceDevice {
+            device_id: did,
+            replacement: DeviceID::new(new),
+        })
+        .await;
+    assert_eq!(
+        ext.recv().await,
+        IglooToExtension::DeviceReplaced { id: old, by: new }
+    );
+
+    assert_eq!(
+        client.watch_update(comp_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(true))
+    );
+    let WatchUpdate::Metadata(batch) = client.watch_update(meta_id).await else {
+        panic!("Expected Metadata");
+    };
+    assert_eq!(batch[0], MetadataUpdate::DeviceRemoved(DeviceID::new(new)));
+    assert!(matches!(&batch[1], MetadataUpdate::Device(id, meta)
+        if *id == did && meta.name == "Porch Light"));
+    let devices = batch.iter().find_map(|update| match update {
+        MetadataUpdate::Extension(_, meta) => Some(meta.devices.clone()),
+        _ => None,
+    });
+    assert_eq!(devices, Some(vec![did]));
+}
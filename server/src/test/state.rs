@@ -0,0 +1,209 @@
+use super::{FakeClient, Igloo};
+use crate::core::ClientMsg;
+use igloo_interface::{
+    Component, ComponentType,
+    id::{DeviceID, EntityID, ExtensionID},
+    query::{
+        DeviceFilter, EntityAction, EntityFilter, EntityQuery, EntitySnapshot, IDFilter,
+        OneShotQuery, QueryResult, WatchComponentQuery, WatchQuery, WatchUpdate,
+    },
+    types::{IglooValue, agg::AggregationOp},
+};
+
+fn entity_query(device: u64) -> OneShotQuery {
+    OneShotQuery::Entity(EntityQuery {
+        device_filter: DeviceFilter {
+            id: IDFilter::Is(DeviceID::new(device)),
+            ..Default::default()
+        },
+        entity_filter: EntityFilter::default(),
+        action: EntityAction::Snapshot,
+        limit: None,
+    })
+}
+
+/// Waits until the first entity matches
+async fn entities_until<F>(client: &mut FakeClient, device: u64, pred: F) -> Vec<EntitySnapshot>
+where
+    F: Fn(&EntitySnapshot) -> bool,
+{
+    let res = client
+        .eval_until(entity_query(device), |res| match res {
+            QueryResult::EntitySnapshot(entities) => entities.first().is_some_and(&pred),
+            _ => false,
+        })
+        .await;
+    match res {
+        QueryResult::EntitySnapshot(entities) => entities,
+        other => panic!("Expected EntitySnapshot, got {other:?}"),
+    }
+}
+
+/// Lamp with a "light" entity, then restarts Igloo
+async fn restart_with_lamp(igloo: &mut Igloo) -> u64 {
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Lamp").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(
+        device,
+        0,
+        vec![Component::Switch(true), Component::Dimmer(0.5)],
+    )
+    .await;
+
+    entities_until(&mut client, device, |e| e.components.len() == 2).await;
+
+    drop(ext);
+    igloo.restart().await;
+    device
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_state_restored_as_stale() {
+    let mut igloo = Igloo::boot().await;
+    let device = restart_with_lamp(&mut igloo).await;
+    let mut client = igloo.client().await;
+
+    let restored = entities_until(&mut client, device, |_| true).await;
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored[0].id, EntityID("light".to_string()));
+    assert_eq!(
+        restored[0].components,
+        vec![Component::Switch(true), Component::Dimmer(0.5)]
+    );
+    assert!(restored[0].stale);
+
+    // re-registering keeps the last known values
+    let mut ext = igloo.ext("mock").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(device, 0, vec![Component::Switch(false)]).await;
+
+    let confirmed = entities_until(&mut client, device, |e| !e.stale).await;
+    assert_eq!(
+        confirmed[0].components,
+        vec![Component::Switch(false), Component::Dimmer(0.5)]
+    );
+    ext.expect_silence().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_changed_registration_drops_state() {
+    let mut igloo = Igloo::boot().await;
+    let device = restart_with_lamp(&mut igloo).await;
+    let mut client = igloo.client().await;
+
+    let mut ext = igloo.ext("mock").await;
+    ext.register_entity(device, "fan", 0).await;
+    ext.write(device, 0, vec![Component::Switch(false)]).await;
+
+    let entities = entities_until(&mut client, device, |e| e.components.len() == 1).await;
+    assert_eq!(entities.len(), 1);
+    assert_eq!(entities[0].id, EntityID("fan".to_string()));
+    assert_eq!(entities[0].components, vec![Component::Switch(false)]);
+    // no BadEntityRegistration
+    ext.expect_silence().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_changed_registration_notifies_watchers() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+    let plug = ext.create_device("Plug").await;
+    ext.register_entity(plug, "outlet", 0).await;
+    ext.write(plug, 0, vec![Component::Switch(false)]).await;
+    let lamp = ext.create_device("Lamp").await;
+    ext.register_entity(lamp, "light", 0).await;
+    ext.write(lamp, 0, vec![Component::Switch(true)]).await;
+    entities_until(&mut client, lamp, |e| e.components.len() == 1).await;
+
+    drop(ext);
+    igloo.restart().await;
+    let mut client = igloo.client().await;
+
+    let query_id = client
+        .sub(WatchQuery::Component(WatchComponentQuery {
+            device_filter: Default::default(),
+            entity_filter: Default::default(),
+            component: ComponentType::Switch,
+            post_op: Some(AggregationOp::Any),
+        }))
+        .await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(true))
+    );
+
+    // the Lamp's restored "light" is dropped, leaving only the Plug
+    let ext = igloo.ext("mock").await;
+    ext.register_entity(lamp, "fan", 0).await;
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentAggregate(IglooValue::Boolean(false))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replaced_device_drops_detached_state() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut zigbee = igloo.ext("zigbee").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Lamp").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(device, 0, vec![Component::Switch(true)]).await;
+    entities_until(&mut client, device, |e| e.components.len() == 1).await;
+
+    let xid = ExtensionID("mock".to_string());
+    client.send(ClientMsg::StopExt(xid.clone())).await;
+    igloo.wait_until_attached(&xid, false).await;
+    drop(ext);
+
+    // no entities yet, so nothing replaces the stale ones
+    let plug = zigbee.create_device("Plug").await;
+    client
+        .send(ClientMsg::ReplaceDevice {
+            device_id: DeviceID::new(device),
+            replacement: DeviceID::new(plug),
+        })
+        .await;
+    zigbee.recv().await;
+    drop(zigbee);
+
+    igloo.restart().await;
+    let mut client = igloo.client().await;
+    assert_eq!(
+        client.eval(entity_query(device)).await.unwrap(),
+        QueryResult::EntitySnapshot(vec![])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_state_kept_after_detach() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Lamp").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(device, 0, vec![Component::Switch(true)]).await;
+    entities_until(&mut client, device, |e| e.components.len() == 1).await;
+
+    let xid = ExtensionID("mock".to_string());
+    client.send(ClientMsg::StopExt(xid.clone())).await;
+    igloo.wait_until_attached(&xid, false).await;
+    drop(ext);
+
+    // saved on shutdown, while the device has no entities
+    igloo.restart().await;
+    let mut client = igloo.client().await;
+
+    let restored = entities_until(&mut client, device, |_| true).await;
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored[0].id, EntityID("light".to_string()));
+    assert_eq!(restored[0].components, vec![Component::Switch(true)]);
+    assert!(restored[0].stale);
+}
@@ -0,0 +1,48 @@
+use super::Igloo;
+use igloo_interface::{
+    Component, ComponentType,
+    id::{DeviceID, EntityIndex},
+    query::{WatchComponentQuery, WatchQuery, WatchUpdate},
+    types::IglooValue,
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch_sees_ext_writes() {
+    let mut igloo = Igloo::boot().await;
+    let mut ext = igloo.ext("mock").await;
+    let mut client = igloo.client().await;
+
+    let device = ext.create_device("Lamp").await;
+    ext.register_entity(device, "light", 0).await;
+    ext.write(device, 0, vec![Component::Switch(false)]).await;
+
+    let query_id = client
+        .sub(WatchQuery::Component(WatchComponentQuery {
+            device_filter: Default::default(),
+            entity_filter: Default::default(),
+            component: ComponentType::Switch,
+            post_op: None,
+        }))
+        .await;
+
+    // initial value, or the write if it raced the subscription
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentValue(
+            DeviceID::new(device),
+            EntityIndex(0),
+            IglooValue::Boolean(false)
+        )
+    );
+
+    ext.write(device, 0, vec![Component::Switch(true)]).await;
+
+    assert_eq!(
+        client.watch_update(query_id).await,
+        WatchUpdate::ComponentValue(
+            DeviceID::new(device),
+            EntityIndex(0),
+            IglooValue::Boolean(true)
+        )
+    );
+}
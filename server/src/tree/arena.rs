@@ -387,6 +387,8 @@ where
         #[serde(deny_unknown_fields)]
         struct ArenaData<T> {
             generation: u32,
+            // missing when empty
+            #[serde(default = "Vec::new")]
             entry: Vec<T>,
         }
 
@@ -0,0 +1,179 @@
+//! Append-only record of every mutation to the DeviceTree
+//!
+//! One JSON [JournalEntry] per line in [JOURNAL_FILE]. Once it passes
+//! [JOURNAL_MAX_BYTES] it's moved to `<file>.1` (shifting older ones up),
+//! keeping [JOURNAL_MAX_FILES] old files.
+
+use super::DeviceTree;
+use crate::{
+    core::{ClientManager, IglooError},
+    query::QueryEngine,
+};
+use igloo_interface::query::{
+    JournalEntry, JournalEvent, JournalKind, JournalQuery, JournalSource,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+pub const JOURNAL_FILE: &str = "journal.jsonl";
+/// Size the journal is rotated at
+pub const JOURNAL_MAX_BYTES: u64 = 4 * 1024 * 1024;
+/// Rotated journals kept (`<file>.1`, `<file>.2`, ...)
+pub const JOURNAL_MAX_FILES: usize = 5;
+
+pub struct Journal {
+    /// `None` doesn't write anything (ex. simulated trees)
+    path: Option<PathBuf>,
+    file: Option<File>,
+    size: u64,
+    /// Who is causing the current mutations, set by IglooCore per request
+    source: JournalSource,
+}
+
+impl Journal {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let size = path
+            .as_ref()
+            .and_then(|path| fs::metadata(path).ok())
+            .map_or(0, |meta| meta.len());
+        Self {
+            path,
+            file: None,
+            size,
+            source: JournalSource::Igloo,
+        }
+    }
+
+    /// Failing to write is logged, but doesn't fail the mutation
+    pub fn record(&mut self, event: JournalEvent) -> JournalEntry {
+        let entry = JournalEntry {
+            at: jiff::Timestamp::now().as_millisecond() as u64,
+            source: self.source.clone(),
+            event,
+        };
+        if let Err(e) = self.append(&entry) {
+            eprintln!("Failed to write to the journal: {e}");
+        }
+        entry
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut line = serde_json::to_vec(entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > JOURNAL_MAX_BYTES {
+            self.file = None;
+            rotate(path)?;
+            self.size = 0;
+        }
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self
+                .file
+                .insert(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        file.write_all(&line)?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Matching entries, oldest first
+    /// Files are read newest first, so older ones aren't parsed once
+    /// `limit` is reached or the entries are before `since`
+    pub fn read(&self, query: &JournalQuery) -> io::Result<Vec<JournalEntry>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+
+        let limit = query.limit.unwrap_or(usize::MAX);
+        let mut entries = Vec::new();
+        'files: for n in 0..=JOURNAL_MAX_FILES {
+            if entries.len() >= limit {
+                break;
+            }
+
+            let content = match fs::read_to_string(rotated(path, n)) {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            for line in content.lines().rev() {
+                // last line may be cut off by a crash
+                let Ok(entry) = serde_json::from_str::<JournalEntry>(line) else {
+                    continue;
+                };
+                // appended in time order, so the rest are older too
+                if query.since.is_some_and(|since| entry.at < since) {
+                    break 'files;
+                }
+                if query.until.is_some_and(|until| entry.at >= until)
+                    || !kind_matches(&query.kinds, &entry)
+                {
+                    continue;
+                }
+                entries.push(entry);
+                if entries.len() >= limit {
+                    break 'files;
+                }
+            }
+        }
+
+        entries.reverse();
+        Ok(entries)
+    }
+}
+
+/// Empty `kinds` matches everything
+pub fn kind_matches(kinds: &[JournalKind], entry: &JournalEntry) -> bool {
+    kinds.is_empty() || kinds.contains(&entry.event.kind())
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+fn rotate(path: &Path) -> io::Result<()> {
+    for n in (0..JOURNAL_MAX_FILES).rev() {
+        match fs::rename(rotated(path, n), rotated(path, n + 1)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl DeviceTree {
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    pub fn set_journal_source(&mut self, source: JournalSource) {
+        self.journal.source = source;
+    }
+
+    /// Journals a mutation and sends it to journal watchers
+    pub fn record(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        event: JournalEvent,
+    ) -> Result<(), IglooError> {
+        let entry = self.journal.record(event);
+        engine.on_journal_entry(cm, &entry)
+    }
+}
@@ -1,4 +1,5 @@
 pub mod arena;
+pub mod journal;
 pub mod model;
 pub mod mutation;
 pub mod persist;
@@ -1,6 +1,9 @@
 use crate::{
-    ext::{ExtensionProcess, ExtensionRequest},
-    tree::arena::{Arena, ArenaItem},
+    ext::{ExtensionProcess, ExtensionQueue},
+    tree::{
+        arena::{Arena, ArenaItem},
+        journal::Journal,
+    },
 };
 use igloo_interface::{
     Component, ComponentType, NUM_COMPONENTS,
@@ -8,24 +11,51 @@ use igloo_interface::{
         DeviceID, DeviceIDMarker, EntityID, EntityIndex, ExtensionID, ExtensionIndex, GroupID,
         GroupIDMarker, MAX_ENTITY_ID_LENGTH,
     },
-    query::{DeviceSnapshot, EntitySnapshot, ExtensionSnapshot, GroupSnapshot, TypeFilter},
+    query::{
+        DeviceInfo, DeviceSnapshot, DiscoveredDevice, EntitySnapshot, ExtensionSnapshot,
+        GroupSnapshot, TypeFilter,
+    },
 };
 use rustc_hash::{FxBuildHasher, FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
-use std::{collections::HashMap, fs::File, sync::Arc, time::Instant};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::Instant,
+};
 
 pub const COMP_TYPE_ARR_LEN: usize = NUM_COMPONENTS + 1;
 
+static NO_LABELS: BTreeSet<String> = BTreeSet::new();
+
+/// (owner, external ID)
+pub type DiscoveryKey = (ExtensionID, String);
+
 /// Root
 /// WARN: Mutations to the device tree must only occur in `mutation.rs`
 pub struct DeviceTree {
     pub(super) groups: Arena<GroupIDMarker, Group>,
-    pub(super) groups_file: Option<File>,
+    pub(super) groups_path: Option<PathBuf>,
     pub(super) devices: Arena<DeviceIDMarker, Device>,
-    pub(super) devices_file: Option<File>,
+    pub(super) devices_path: Option<PathBuf>,
     pub(super) attached_exts: Vec<Option<Extension>>,
     pub(super) ext_ref_lut: FxHashMap<ExtensionID, ExtensionIndex>,
+    /// Reported by attached Extensions, waiting to be accepted or ignored
+    pub(super) discovered: FxHashMap<DiscoveryKey, DiscoveredDevice>,
+    /// Accepted, waiting for the Extension to create it
+    pub(super) adopting: FxHashMap<DiscoveryKey, Adoption>,
+    pub(super) ignored: FxHashSet<DiscoveryKey>,
+    pub(super) ignored_path: Option<PathBuf>,
+    pub(super) state_path: Option<PathBuf>,
+    /// Entities of devices whose owner detached, kept in the state file
+    /// until the owner registers entities again
+    pub(super) detached_entities: FxHashMap<DeviceID, SmallVec<[Entity; 16]>>,
+    /// Entities or component values changed since the state file was saved
+    pub(super) state_dirty: bool,
+    pub(super) state_saved: Instant,
+    pub(super) journal: Journal,
 }
 
 /// Connected Extension
@@ -35,9 +65,27 @@ pub struct DeviceTree {
 pub struct Extension {
     pub(super) id: ExtensionID,
     pub(super) index: ExtensionIndex,
-    pub channel: kanal::Sender<ExtensionRequest>,
+    pub queue: ExtensionQueue,
     pub(super) devices: SmallVec<[DeviceID; 50]>,
     pub process: Arc<ExtensionProcess>,
+    /// Number of rejected messages since `strikes_since`
+    pub(super) strikes: u32,
+    /// Start of the current [EXT_STRIKE_WINDOW](super::mutation::EXT_STRIKE_WINDOW)
+    pub(super) strikes_since: Instant,
+    pub(super) last_ping: Instant,
+    pub(super) last_pong: Instant,
+    /// Its restored entities that aren't registered again by then are dropped
+    pub(super) confirm_deadline: Option<Instant>,
+}
+
+/// Accepted discovery, see [ADOPT_TIMEOUT](super::mutation::ADOPT_TIMEOUT)
+#[derive(Debug)]
+pub struct Adoption {
+    /// Put back in the inbox if the Extension doesn't create it by `deadline`
+    pub(super) discovered: DiscoveredDevice,
+    /// To add it to once created
+    pub(super) groups: Vec<GroupID>,
+    pub(super) deadline: Instant,
 }
 
 /// Collection of devices (ex. "Living Room")
@@ -47,6 +95,12 @@ pub struct Group {
     pub(super) id: GroupID,
     pub(super) name: String,
     pub(super) devices: FxHashSet<DeviceID>,
+    /// Containing group (ex. "Upstairs" for "Bedroom")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) parent: Option<GroupID>,
+    /// Built from `parent` on load
+    #[serde(skip)]
+    pub(super) children: FxHashSet<GroupID>,
 }
 
 /// Physical Device, owned (registered & attached) by Extension
@@ -56,6 +110,22 @@ pub struct Device {
     pub(super) id: DeviceID,
     pub(super) name: String,
     pub(super) owner: ExtensionID,
+    /// Owner's ID for it, if it was adopted from the discovery inbox
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) external_id: Option<String>,
+    /// Set by the owner
+    pub(super) info: DeviceInfo,
+    /// Set by users
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) notes: Option<String>,
+    /// Set by users
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub(super) labels: BTreeSet<String>,
+    /// Set by users, by ID so they outlive the entity's registration
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(super) entity_labels: BTreeMap<EntityID, BTreeSet<String>>,
     #[serde(skip)]
     pub(super) owner_ref: Option<ExtensionIndex>,
     #[serde(skip)]
@@ -74,6 +144,9 @@ pub struct Device {
     pub(super) entity_index_lut: FxHashMap<EntityID, EntityIndex>,
     #[serde(skip)]
     pub(super) last_updated: Instant,
+    /// Reported by the owner, reset when it detaches
+    #[serde(skip)]
+    pub(super) reachable: bool,
 }
 
 #[derive(Deserialize)]
@@ -82,6 +155,18 @@ struct DeviceData {
     id: DeviceID,
     name: String,
     owner: ExtensionID,
+    #[serde(default)]
+    external_id: Option<String>,
+    #[serde(default)]
+    info: DeviceInfo,
+    #[serde(default)]
+    icon: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+    #[serde(default)]
+    labels: BTreeSet<String>,
+    #[serde(default)]
+    entity_labels: BTreeMap<EntityID, BTreeSet<String>>,
 }
 
 /// Tracks presence of components on a device
@@ -101,6 +186,10 @@ pub struct Entity {
     /// `0xFF` = not present
     pub(super) indices: [u8; COMP_TYPE_ARR_LEN],
     pub(super) last_updated: Instant,
+    /// Restored from the state file, cleared once the owner writes to it
+    pub(super) stale: bool,
+    /// Restored from the state file, cleared once the owner registers it again
+    pub(super) restored: bool,
 }
 
 #[derive(thiserror::Error, Debug, Clone, Serialize, Deserialize)]
@@ -155,6 +244,32 @@ impl DeviceTree {
         self.groups.get(gid).ok_or(TreeIDError::GroupDeleted(*gid))
     }
 
+    /// Whether `gid` is `root` or one of its subgroups
+    pub fn in_tree(&self, gid: &GroupID, root: &GroupID) -> bool {
+        let mut cur = Some(*gid);
+        while let Some(gid) = cur {
+            if gid == *root {
+                return true;
+            }
+            cur = self.group(&gid).ok().and_then(|group| group.parent);
+        }
+        false
+    }
+
+    /// `root` and all of its subgroups, empty if `root` doesn't exist
+    pub fn subtree(&self, root: &GroupID) -> Vec<GroupID> {
+        let mut gids = Vec::with_capacity(8);
+        let mut stack = vec![*root];
+        while let Some(gid) = stack.pop() {
+            let Ok(group) = self.group(&gid) else {
+                continue;
+            };
+            gids.push(gid);
+            stack.extend(group.children.iter().copied());
+        }
+        gids
+    }
+
     /// Gets & Validates from GroupID
     #[inline]
     pub(super) fn group_mut(&mut self, gid: &GroupID) -> Result<&mut Group, TreeIDError> {
@@ -175,8 +290,10 @@ impl DeviceTree {
     }
 
     #[inline]
-    #[allow(dead_code)]
-    pub fn ext_mut(&mut self, index: &ExtensionIndex) -> Result<&mut Extension, TreeIDError> {
+    pub(super) fn ext_mut(
+        &mut self,
+        index: &ExtensionIndex,
+    ) -> Result<&mut Extension, TreeIDError> {
         match self.attached_exts.get_mut(index.0) {
             Some(o) => match o.as_mut() {
                 Some(f) => Ok(f),
@@ -186,6 +303,10 @@ impl DeviceTree {
         }
     }
 
+    pub fn discovered(&self) -> &FxHashMap<DiscoveryKey, DiscoveredDevice> {
+        &self.discovered
+    }
+
     #[inline]
     pub fn ext_index(&self, eid: &ExtensionID) -> Result<&ExtensionIndex, TreeIDError> {
         self.ext_ref_lut
@@ -203,6 +324,14 @@ impl Presense {
         self.0[index] |= 1u32 << bit;
     }
 
+    #[inline]
+    pub fn unset(&mut self, typ: ComponentType) {
+        let type_id = typ as usize;
+        let index = type_id >> 5;
+        let bit = type_id & 31;
+        self.0[index] &= !(1u32 << bit);
+    }
+
     #[inline(always)]
     pub fn has(&self, typ: ComponentType) -> bool {
         let type_id = typ as usize;
@@ -220,6 +349,8 @@ impl Default for Entity {
             id: EntityID(String::with_capacity(20)),
             index: EntityIndex(usize::MAX),
             last_updated: Instant::now(),
+            stale: false,
+            restored: false,
         }
     }
 }
@@ -274,17 +405,39 @@ impl Entity {
         }
     }
 
+    /// Removes a component, keeping the rest in order
+    /// Returns `None` if it wasn't present
+    #[inline]
+    pub fn remove(&mut self, typ: ComponentType) -> Option<Component> {
+        let index = self.indices[typ as usize];
+        if index == 0xFF {
+            return None;
+        }
+
+        let comp = self.components.remove(index as usize);
+        self.indices[typ as usize] = 0xFF;
+        // everything after it shifted down
+        for i in self.indices.iter_mut() {
+            if *i != 0xFF && *i > index {
+                *i -= 1;
+            }
+        }
+        Some(comp)
+    }
+
     #[inline]
     pub fn last_updated(&self) -> &Instant {
         &self.last_updated
     }
 
-    pub fn snapshot(&self, parent: DeviceID) -> EntitySnapshot {
+    pub fn snapshot(&self, parent: &Device) -> EntitySnapshot {
         EntitySnapshot {
             id: self.id.clone(),
             index: self.index,
             components: self.components.to_vec(),
-            parent,
+            parent: parent.id,
+            stale: self.stale,
+            labels: parent.entity_labels(&self.id).iter().cloned().collect(),
         }
     }
 
@@ -311,6 +464,12 @@ impl Device {
             id,
             name,
             owner,
+            external_id: None,
+            info: DeviceInfo::default(),
+            icon: None,
+            notes: None,
+            labels: BTreeSet::new(),
+            entity_labels: BTreeMap::new(),
             owner_ref: None,
             groups: FxHashSet::with_capacity_and_hasher(10, FxBuildHasher),
             presense: Presense::default(),
@@ -318,11 +477,13 @@ impl Device {
             entity_index_lut: HashMap::with_capacity_and_hasher(10, FxBuildHasher),
             last_updated: Instant::now(),
             comp_to_entity: [const { SmallVec::new_const() }; COMP_TYPE_ARR_LEN],
+            reachable: true,
         }
     }
 
     pub fn reset(&mut self) {
         self.owner_ref = None;
+        self.reachable = true;
         self.presense = Presense::default();
         self.entities = SmallVec::default();
         self.entity_index_lut = HashMap::with_capacity_and_hasher(10, FxBuildHasher);
@@ -358,6 +519,12 @@ impl Device {
         self.entity_index_lut.get(eid)
     }
 
+    /// Owner is attached and hasn't reported it as unreachable
+    #[inline]
+    pub fn available(&self) -> bool {
+        self.owner_ref.is_some() && self.reachable
+    }
+
     #[inline]
     pub fn num_entities(&self) -> usize {
         self.entities.len()
@@ -379,20 +546,82 @@ impl Device {
         &self.groups
     }
 
+    #[inline]
+    pub fn labels(&self) -> &BTreeSet<String> {
+        &self.labels
+    }
+
+    /// Labels on the entity with this ID, even if it isn't registered
+    #[inline]
+    pub fn entity_labels(&self, eid: &EntityID) -> &BTreeSet<String> {
+        self.entity_labels.get(eid).unwrap_or(&NO_LABELS)
+    }
+
+    #[inline]
+    pub fn all_entity_labels(&self) -> &BTreeMap<EntityID, BTreeSet<String>> {
+        &self.entity_labels
+    }
+
     pub fn snapshot(&self, include_components: bool) -> DeviceSnapshot {
         DeviceSnapshot {
             id: self.id,
             name: self.name.clone(),
             entities: match include_components {
-                true => self.entities.iter().map(|e| e.snapshot(self.id)).collect(),
+                true => self.entities.iter().map(|e| e.snapshot(self)).collect(),
                 false => vec![],
             },
             owner: self.owner.clone(),
             owner_ref: self.owner_ref,
             groups: self.groups.clone(),
+            available: self.available(),
+            info: Box::new(self.info.clone()),
+            icon: self.icon.clone(),
+            notes: self.notes.clone(),
+            labels: self.labels.iter().cloned().collect(),
+        }
+    }
+
+    /// Rebuilds `entity_index_lut`, `presense` and `comp_to_entity` from `entities`
+    pub(super) fn rebuild_entity_luts(&mut self) {
+        self.entity_index_lut.clear();
+        self.presense = Presense::default();
+        self.comp_to_entity = [const { SmallVec::new_const() }; COMP_TYPE_ARR_LEN];
+
+        for entity in &self.entities {
+            self.entity_index_lut
+                .insert(entity.id.clone(), entity.index);
+            for comp in &entity.components {
+                let comp_type = comp.get_type();
+                self.presense.set(comp_type);
+                self.comp_to_entity[comp_type as usize].push(entity.index);
+            }
+        }
+    }
+
+    /// Drops `entity` from `comp_to_entity` and `presense` after it lost `typ`
+    pub(super) fn forget_component(&mut self, entity: EntityIndex, typ: ComponentType) {
+        let entities = &mut self.comp_to_entity[typ as usize];
+        entities.retain(|e| *e != entity);
+        if entities.is_empty() {
+            self.presense.unset(typ);
         }
     }
 
+    #[inline]
+    pub fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    #[inline]
+    pub fn icon(&self) -> Option<&str> {
+        self.icon.as_deref()
+    }
+
+    #[inline]
+    pub fn notes(&self) -> Option<&str> {
+        self.notes.as_deref()
+    }
+
     #[inline(always)]
     pub fn has(&self, r#type: ComponentType) -> bool {
         self.presense.has(r#type)
@@ -432,11 +661,23 @@ impl Group {
         &self.devices
     }
 
+    #[inline]
+    pub fn parent(&self) -> Option<&GroupID> {
+        self.parent.as_ref()
+    }
+
+    #[inline]
+    #[allow(dead_code)]
+    pub fn children(&self) -> &FxHashSet<GroupID> {
+        &self.children
+    }
+
     pub fn snapshot(&self) -> GroupSnapshot {
         GroupSnapshot {
             id: self.id,
             name: self.name.clone(),
             devices: self.devices.clone(),
+            parent: self.parent,
         }
     }
 }
@@ -480,6 +721,13 @@ impl ArenaItem<GroupIDMarker> for Group {
 
 impl From<DeviceData> for Device {
     fn from(data: DeviceData) -> Self {
-        Device::new(data.id, data.name, data.owner)
+        let mut device = Device::new(data.id, data.name, data.owner);
+        device.external_id = data.external_id;
+        device.info = data.info;
+        device.icon = data.icon;
+        device.notes = data.notes;
+        device.labels = data.labels;
+        device.entity_labels = data.entity_labels;
+        device
     }
 }
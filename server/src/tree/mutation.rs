@@ -5,26 +5,32 @@
 //!  1. Good error handling
 //!  2. ID validation (generational and bounds checking, using .group, .device, .ext)
 //!  3. Internal side-effects (ex. updating device presence)
-//!  4. External side-effects (persistence, query engine)
+//!  4. External side-effects (persistence, journal, query engine)
 
-use super::{Device, DeviceTree, Entity, Extension, Group};
+use super::{Adoption, Device, DeviceTree, DiscoveryKey, Entity, Extension, Group};
 use crate::{
     core::{ClientManager, IglooError},
-    ext::{ExtensionHandle, ExtensionRequest},
+    ext::{ExtensionHandle, ExtensionQueue, HeartbeatConfig},
     query::QueryEngine,
-    tree::{COMP_TYPE_ARR_LEN, Presense, TreeIDError, persist::TreePersistError},
+    tree::{
+        COMP_TYPE_ARR_LEN, Presense, TreeIDError,
+        persist::{RESTORED_ENTITY_TIMEOUT, TreePersistError},
+    },
 };
 use igloo_interface::{
-    Component,
+    Component, ComponentType,
     id::{
         DeviceID, EntityID, EntityIndex, ExtensionID, ExtensionIndex, GroupID, MAX_ENTITY_ID_LENGTH,
     },
+    ipc::IglooToExtension,
+    query::{DeviceInfo, DiscoveredDevice, JournalEvent},
 };
 use rustc_hash::FxBuildHasher;
 use smallvec::SmallVec;
 use std::{
-    collections::{HashMap, HashSet},
-    time::Instant,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    mem,
+    time::{Duration, Instant},
 };
 
 #[derive(thiserror::Error, Debug)]
@@ -39,6 +45,48 @@ pub enum TreeMutationError {
         "Bad entity registration. Extension expected index={2} but is index={3}. Device={0}, Entity={1}."
     )]
     BadEntityRegistration(DeviceID, EntityID, EntityIndex, EntityIndex),
+    #[error("Device {0} is not owned by {1}.")]
+    NotOwner(DeviceID, ExtensionIndex),
+    #[error("Entity {1} does not exist on Device {0}.")]
+    EntityNotFound(DeviceID, EntityIndex),
+    #[error("{0} has not discovered a device with external ID {1}.")]
+    NotDiscovered(ExtensionID, String),
+    #[error("Group {0} cannot be moved into {1}, since it contains it.")]
+    GroupCycle(GroupID, GroupID),
+    #[error("Device {0} cannot replace itself.")]
+    ReplaceWithSelf(DeviceID),
+    #[error("Device {0} cannot be a replacement while its Extension is detached.")]
+    ReplacementDetached(DeviceID),
+}
+
+/// Number of rejected messages within [EXT_STRIKE_WINDOW] before an Extension is detached
+pub const MAX_EXT_STRIKES: u32 = 10;
+/// Strikes are forgiven once this long has passed since the first one
+pub const EXT_STRIKE_WINDOW: Duration = Duration::from_secs(60);
+/// Time an Extension has to create an accepted device before it's put back in the inbox
+pub const ADOPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetachReason {
+    /// Client requested
+    Requested,
+    /// Extension's queue is full (it stopped reading its socket)
+    ChannelFull,
+    /// Extension sent too many bad messages
+    Misbehaving,
+    /// Extension stopped responding to heartbeat pings
+    Unresponsive,
+}
+
+impl DetachReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DetachReason::Requested => "requested",
+            DetachReason::ChannelFull => "channel full",
+            DetachReason::Misbehaving => "misbehaving",
+            DetachReason::Unresponsive => "unresponsive",
+        }
+    }
 }
 
 // Extension Mutations
@@ -48,7 +96,7 @@ impl DeviceTree {
         cm: &mut ClientManager,
         engine: &mut QueryEngine,
         mut handle: ExtensionHandle,
-        channel: kanal::Sender<ExtensionRequest>,
+        queue: ExtensionQueue,
     ) -> Result<ExtensionIndex, IglooError> {
         let xid = handle.id.clone();
 
@@ -77,13 +125,19 @@ impl DeviceTree {
         let xindex = ExtensionIndex(xindex);
         handle.index = xindex;
         let process = handle.spawn();
+        let now = Instant::now();
 
         self.attached_exts[xindex.0] = Some(Extension {
             id: xid.clone(),
             index: xindex,
-            channel,
+            queue,
             devices,
             process,
+            strikes: 0,
+            strikes_since: now,
+            last_ping: now,
+            last_pong: now,
+            confirm_deadline: Some(now + RESTORED_ENTITY_TIMEOUT),
         });
 
         // link devices owned by this Extension
@@ -93,9 +147,10 @@ impl DeviceTree {
             }
         }
 
-        self.ext_ref_lut.insert(xid, xindex);
+        self.ext_ref_lut.insert(xid.clone(), xindex);
 
         engine.on_ext_attached(cm, self, self.ext(&xindex)?)?;
+        self.record(cm, engine, JournalEvent::ExtensionAttached(xid))?;
 
         Ok(xindex)
     }
@@ -106,13 +161,24 @@ impl DeviceTree {
         cm: &mut ClientManager,
         engine: &mut QueryEngine,
         index: ExtensionIndex,
-        from_err: bool,
+        reason: DetachReason,
     ) -> Result<(), IglooError> {
         // make sure valid first
         self.ext(&index)?;
 
-        if from_err {
-            println!("{index}'s channel is full. This is likely a broken program.");
+        match reason {
+            DetachReason::Requested => {}
+            DetachReason::ChannelFull => {
+                println!("{index}'s queue is full. This is likely a broken program.");
+            }
+            DetachReason::Misbehaving => {
+                println!("{index} sent too many bad messages. This is likely a broken program.");
+            }
+            DetachReason::Unresponsive => {
+                println!(
+                    "{index} stopped responding to heartbeats. This is likely a hung program."
+                );
+            }
         }
 
         let ext = self.attached_exts[index.0].take().unwrap(); // FIXME unwrap
@@ -121,21 +187,94 @@ impl DeviceTree {
 
         // notify the QueryEngine early, so it can still check device filters
         engine.on_ext_detached(cm, self, &ext)?;
+        let event = JournalEvent::ExtensionDetached(ext.id.clone(), reason.as_str().to_string());
+        self.record(cm, engine, event)?;
+
+        // its discoveries can't be accepted anymore
+        self.adopting.retain(|(owner, _), _| owner != xid);
+        let keys: Vec<DiscoveryKey> = self
+            .discovered
+            .keys()
+            .filter(|(owner, _)| owner == xid)
+            .cloned()
+            .collect();
+        for key in keys {
+            self.discovered.remove(&key);
+            engine.on_discovered_removed(cm, self, &key.0, &key.1)?;
+        }
 
         // unlink devices
         let now = Instant::now();
         for device in self.devices.iter_mut() {
             if device.owner_ref == Some(index) {
+                // still its last known state
+                if !device.entities.is_empty() {
+                    let entities = mem::take(&mut device.entities);
+                    self.detached_entities.insert(device.id, entities);
+                }
                 device.reset();
                 device.last_updated = now;
             }
         }
 
         // kill it
+        ext.queue.close();
         _ = ext.process.start_kill();
 
         Ok(())
     }
+
+    /// Records a rejected message from an Extension
+    /// Returns `true` if it has reached [MAX_EXT_STRIKES] and should be detached
+    pub fn strike_ext(&mut self, index: ExtensionIndex, now: Instant) -> Result<bool, IglooError> {
+        let ext = self.ext_mut(&index)?;
+        if ext.strikes == 0 || now.duration_since(ext.strikes_since) > EXT_STRIKE_WINDOW {
+            ext.strikes = 0;
+            ext.strikes_since = now;
+        }
+        ext.strikes += 1;
+        Ok(ext.strikes >= MAX_EXT_STRIKES)
+    }
+
+    /// Pings every Extension that's due and detaches those
+    /// that haven't responded within the timeout
+    pub fn heartbeat(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        config: &HeartbeatConfig,
+        now: Instant,
+    ) -> Result<(), IglooError> {
+        let mut dead = SmallVec::<[(ExtensionIndex, DetachReason); 2]>::new();
+
+        for ext in self.attached_exts.iter_mut().flatten() {
+            if now.duration_since(ext.last_pong) > config.timeout {
+                dead.push((ext.index, DetachReason::Unresponsive));
+                continue;
+            }
+
+            if now.duration_since(ext.last_ping) < config.interval {
+                continue;
+            }
+
+            if ext.queue.push(IglooToExtension::Ping).is_err() {
+                dead.push((ext.index, DetachReason::ChannelFull));
+                continue;
+            }
+            ext.last_ping = now;
+        }
+
+        for (xindex, reason) in dead {
+            self.detach_ext(cm, engine, xindex, reason)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn ext_ponged(&mut self, index: ExtensionIndex) -> Result<(), IglooError> {
+        self.ext_mut(&index)?.last_pong = Instant::now();
+        Ok(())
+    }
 }
 
 // Device Mutations
@@ -146,14 +285,27 @@ impl DeviceTree {
         engine: &mut QueryEngine,
         name: String,
         owner: ExtensionIndex,
+        external_id: Option<String>,
+        info: DeviceInfo,
     ) -> Result<DeviceID, IglooError> {
         let ext = self.ext(&owner)?;
+        let xid = ext.id.clone();
+        let key = external_id.as_ref().map(|eid| (xid.clone(), eid.clone()));
+        let adoption = key.as_ref().and_then(|key| self.adopting.remove(key));
+        // created after its adoption timed out
+        let rediscovered = key.filter(|key| self.discovered.contains_key(key));
 
         // FIXME add device new function plz
         let device = Device {
             id: DeviceID::default(),
             name,
-            owner: ext.id.clone(),
+            owner: xid,
+            external_id,
+            info,
+            icon: None,
+            notes: None,
+            labels: BTreeSet::new(),
+            entity_labels: BTreeMap::new(),
             owner_ref: Some(owner),
             groups: HashSet::with_capacity_and_hasher(10, FxBuildHasher),
             presense: Presense::default(),
@@ -161,6 +313,7 @@ impl DeviceTree {
             entity_index_lut: HashMap::with_capacity_and_hasher(10, FxBuildHasher),
             last_updated: Instant::now(),
             comp_to_entity: [const { SmallVec::new_const() }; COMP_TYPE_ARR_LEN],
+            reachable: true,
         };
 
         let did = self.devices.insert(device);
@@ -173,6 +326,21 @@ impl DeviceTree {
         self.save_devices()?;
 
         engine.on_device_created(cm, self, self.device(&did)?)?;
+        let name = self.device(&did)?.name.clone();
+        self.record(cm, engine, JournalEvent::DeviceCreated(did, name))?;
+
+        if let Some(key) = rediscovered {
+            self.discovered.remove(&key);
+            engine.on_discovered_removed(cm, self, &key.0, &key.1)?;
+        }
+
+        // finish adopting a discovered device
+        for gid in adoption.map(|adoption| adoption.groups).unwrap_or_default() {
+            // group may have been deleted while waiting
+            if self.group(&gid).is_ok() {
+                self.add_device_to_group(cm, engine, gid, did)?;
+            }
+        }
 
         Ok(did)
     }
@@ -187,6 +355,7 @@ impl DeviceTree {
         let Some(device) = self.devices.remove(did) else {
             return Err(IglooError::DeviceTreeID(TreeIDError::DeviceDeleted(did)));
         };
+        self.detached_entities.remove(&did);
 
         // remove from ext
         if let Some(owner_ref) = device.owner_ref
@@ -206,6 +375,7 @@ impl DeviceTree {
         self.save_devices()?;
 
         engine.on_device_deleted(cm, self, &device)?;
+        self.record(cm, engine, JournalEvent::DeviceDeleted(did))?;
 
         Ok(())
     }
@@ -219,23 +389,273 @@ impl DeviceTree {
         new_name: String,
     ) -> Result<(), IglooError> {
         let device = self.device_mut(&did)?;
-        device.name = new_name;
+        device.name = new_name.clone();
         device.last_updated = Instant::now();
 
         self.save_devices()?;
 
         engine.on_device_renamed(cm, self, self.device(&did)?)?;
+        self.record(cm, engine, JournalEvent::DeviceRenamed(did, new_name))?;
+
+        Ok(())
+    }
+
+    /// Moves `did`'s ID, name, groups and user metadata onto `replacement`
+    /// (ex. a new plug paired after the old one died), so everything referencing
+    /// `did` keeps working. `replacement`'s own ID is deleted.
+    pub fn replace_device(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        did: DeviceID,
+        replacement: DeviceID,
+    ) -> Result<(), IglooError> {
+        if did == replacement {
+            return Err(IglooError::DeviceTreeMutation(
+                TreeMutationError::ReplaceWithSelf(did),
+            ));
+        }
+        self.device(&did)?;
+        // its Extension must be told about the new ID
+        let Some(new_owner) = self.device(&replacement)?.owner_ref else {
+            return Err(IglooError::DeviceTreeMutation(
+                TreeMutationError::ReplacementDetached(replacement),
+            ));
+        };
+
+        let Some(donor) = self.devices.remove(replacement) else {
+            return Err(IglooError::DeviceTreeID(TreeIDError::DeviceDeleted(
+                replacement,
+            )));
+        };
+
+        // donor's groups now hold `did` instead
+        for gid in &donor.groups {
+            if let Ok(group) = self.group_mut(gid) {
+                group.devices.remove(&replacement);
+                group.devices.insert(did);
+            }
+        }
+
+        let device = self.device_mut(&did)?;
+        let old_owner = device.owner_ref;
+        // hardware side comes from the donor, user side stays
+        device.owner = donor.owner.clone();
+        device.owner_ref = donor.owner_ref;
+        device.external_id = donor.external_id.clone();
+        device.info = donor.info.clone();
+        device.entities = donor.entities.clone();
+        device.reachable = donor.reachable;
+        device.last_updated = Instant::now();
+        device.groups.extend(donor.groups.iter().copied());
+        device.rebuild_entity_luts();
+        // neither's last known entities are theirs anymore
+        self.detached_entities.remove(&did);
+        self.detached_entities.remove(&replacement);
+
+        if let Some(old_owner) = old_owner
+            && let Some(ext) = self.attached_exts[old_owner.0].as_mut()
+        {
+            ext.devices.retain(|d| *d != did);
+        }
+        if let Some(ext) = self.attached_exts[new_owner.0].as_mut() {
+            ext.devices.retain(|d| *d != replacement);
+            ext.devices.push(did);
+        }
+
+        self.state_dirty = true;
+        if !donor.groups.is_empty() {
+            self.save_groups()?;
+        }
+        self.save_devices()?;
+
+        engine.on_device_replaced(cm, self, self.device(&did)?, &donor)?;
+        let event = JournalEvent::DeviceReplaced {
+            device: did,
+            by: replacement,
+        };
+        self.record(cm, engine, event)?;
+
+        let msg = IglooToExtension::DeviceReplaced {
+            id: *did.inner(),
+            by: *replacement.inner(),
+        };
+        let mut owners = vec![new_owner];
+        if let Some(old_owner) = old_owner
+            && old_owner != new_owner
+            && self.ext(&old_owner).is_ok()
+        {
+            owners.push(old_owner);
+        }
+        for xindex in owners {
+            if self.ext(&xindex)?.queue.push(msg.clone()).is_err() {
+                self.detach_ext(cm, engine, xindex, DetachReason::ChannelFull)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_device_reachable(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        owner: ExtensionIndex,
+        did: DeviceID,
+        reachable: bool,
+    ) -> Result<(), IglooError> {
+        let device = self.device_mut(&did)?;
+        if device.owner_ref != Some(owner) {
+            return Err(IglooError::DeviceTreeMutation(TreeMutationError::NotOwner(
+                did, owner,
+            )));
+        }
+        if device.reachable == reachable {
+            return Ok(());
+        }
+        device.reachable = reachable;
+
+        engine.on_device_availability_changed(cm, self, self.device(&did)?)?;
+        self.record(cm, engine, JournalEvent::DeviceReachable(did, reachable))?;
+
+        Ok(())
+    }
+
+    pub fn set_device_info(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        owner: ExtensionIndex,
+        did: DeviceID,
+        info: DeviceInfo,
+    ) -> Result<(), IglooError> {
+        let device = self.device_mut(&did)?;
+        if device.owner_ref != Some(owner) {
+            return Err(IglooError::DeviceTreeMutation(TreeMutationError::NotOwner(
+                did, owner,
+            )));
+        }
+        if device.info == info {
+            return Ok(());
+        }
+        device.info = info;
+
+        self.save_devices()?;
+
+        engine.on_device_info_changed(cm, self, self.device(&did)?)?;
+        self.record(cm, engine, JournalEvent::DeviceInfoChanged(did))?;
+
+        Ok(())
+    }
+
+    pub fn set_device_icon(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        did: DeviceID,
+        icon: Option<String>,
+    ) -> Result<(), IglooError> {
+        self.device_mut(&did)?.icon = icon.clone();
+
+        self.save_devices()?;
+
+        engine.on_device_info_changed(cm, self, self.device(&did)?)?;
+        self.record(cm, engine, JournalEvent::DeviceIconChanged(did, icon))?;
+
+        Ok(())
+    }
+
+    pub fn set_device_notes(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        did: DeviceID,
+        notes: Option<String>,
+    ) -> Result<(), IglooError> {
+        self.device_mut(&did)?.notes = notes;
+
+        self.save_devices()?;
+
+        engine.on_device_info_changed(cm, self, self.device(&did)?)?;
+        self.record(cm, engine, JournalEvent::DeviceNotesChanged(did))?;
+
+        Ok(())
+    }
+
+    pub fn set_device_labels(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        did: DeviceID,
+        labels: Vec<String>,
+    ) -> Result<(), IglooError> {
+        let labels = normalize_labels(labels);
+        let device = self.device_mut(&did)?;
+        if device.labels == labels {
+            return Ok(());
+        }
+        let event = JournalEvent::DeviceLabelsChanged(did, labels.iter().cloned().collect());
+        device.labels = labels;
+
+        self.save_devices()?;
+
+        engine.on_device_labels_changed(cm, self, self.device(&did)?)?;
+        self.record(cm, engine, event)?;
+
+        Ok(())
+    }
+
+    /// The entity doesn't have to be registered, so they can be set ahead of time
+    pub fn set_entity_labels(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        did: DeviceID,
+        eid: EntityID,
+        labels: Vec<String>,
+    ) -> Result<(), IglooError> {
+        if eid.0.len() > MAX_ENTITY_ID_LENGTH {
+            return Err(IglooError::DeviceTreeID(TreeIDError::EntityIDTooLong));
+        }
+
+        let labels = normalize_labels(labels);
+        let event =
+            JournalEvent::EntityLabelsChanged(did, eid.clone(), labels.iter().cloned().collect());
+        let device = self.device_mut(&did)?;
+        let changed = if labels.is_empty() {
+            device.entity_labels.remove(&eid).is_some()
+        } else {
+            device.entity_labels.insert(eid.clone(), labels.clone()) != Some(labels)
+        };
+        if !changed {
+            return Ok(());
+        }
+
+        self.save_devices()?;
+
+        engine.on_entity_labels_changed(cm, self, self.device(&did)?, &eid)?;
+        self.record(cm, engine, event)?;
 
         Ok(())
     }
 }
 
+/// Trimmed, without empty or duplicate labels
+fn normalize_labels(labels: Vec<String>) -> BTreeSet<String> {
+    labels
+        .into_iter()
+        .map(|label| label.trim().to_string())
+        .filter(|label| !label.is_empty())
+        .collect()
+}
+
 // Entity Mutations
 impl DeviceTree {
     pub fn register_entity(
         &mut self,
         cm: &mut ClientManager,
         engine: &mut QueryEngine,
+        owner: ExtensionIndex,
         did: DeviceID,
         id: EntityID,
         expected_index: EntityIndex,
@@ -245,6 +665,27 @@ impl DeviceTree {
         }
 
         let device = self.device_mut(&did)?;
+        if device.owner_ref != Some(owner) {
+            return Err(IglooError::DeviceTreeMutation(TreeMutationError::NotOwner(
+                did, owner,
+            )));
+        }
+
+        // reconcile with entities restored from the state file
+        if let Some(entity) = device.entities.get_mut(expected_index.0)
+            && entity.restored
+        {
+            // still the same, values stay stale until written
+            if entity.id == id {
+                entity.restored = false;
+                return Ok(());
+            }
+
+            // Extension's entities changed since, so the rest can't be trusted
+            self.drop_entities(cm, engine, did, expected_index.0)?;
+        }
+        let device = self.device_mut(&did)?;
+
         let index = EntityIndex(device.entities.len());
 
         if index != expected_index {
@@ -258,10 +699,95 @@ impl DeviceTree {
             index,
             ..Default::default()
         });
-        device.entity_index_lut.insert(id, index);
+        device.entity_index_lut.insert(id.clone(), index);
         device.last_updated = Instant::now();
+        self.detached_entities.remove(&did);
+        self.state_dirty = true;
 
         engine.on_entity_registered(cm, self, self.device(&did)?, index)?;
+        self.record(cm, engine, JournalEvent::EntityRegistered(did, id))?;
+
+        Ok(())
+    }
+
+    /// Drops restored entities the owner didn't register again within
+    /// [RESTORED_ENTITY_TIMEOUT] of attaching, since it no longer has them
+    pub fn expire_restored(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        now: Instant,
+    ) -> Result<(), IglooError> {
+        let mut expired = SmallVec::<[(DeviceID, usize); 4]>::new();
+
+        for ext in self.attached_exts.iter_mut().flatten() {
+            if ext.confirm_deadline.is_none_or(|deadline| now < deadline) {
+                continue;
+            }
+            ext.confirm_deadline = None;
+
+            for did in &ext.devices {
+                if let Some(device) = self.devices.get(did)
+                    && let Some(from) = device.entities.iter().position(|e| e.restored)
+                {
+                    expired.push((*did, from));
+                }
+            }
+        }
+
+        for (did, from) in expired {
+            self.drop_entities(cm, engine, did, from)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops the device's entities from index `from` on
+    /// Their components are removed first, so watchers see them go
+    fn drop_entities(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        did: DeviceID,
+        from: usize,
+    ) -> Result<(), IglooError> {
+        let device = self.device_mut(&did)?;
+        let mut removed = Vec::new();
+        for index in from..device.entities.len() {
+            let eindex = EntityIndex(index);
+            let entity = &mut device.entities[index];
+            let types: Vec<ComponentType> = entity
+                .components
+                .iter()
+                .map(|comp| comp.get_type())
+                .collect();
+            for typ in &types {
+                entity.remove(*typ);
+            }
+            let eid = entity.id.clone();
+            for typ in &types {
+                device.forget_component(eindex, *typ);
+            }
+            removed.push((eindex, eid, types));
+        }
+
+        for (eindex, _, types) in &removed {
+            for typ in types {
+                engine.on_component_removed(cm, self, self.device(&did)?, *eindex, *typ)?;
+            }
+        }
+
+        let device = self.device_mut(&did)?;
+        device.entities.truncate(from);
+        device.rebuild_entity_luts();
+        device.last_updated = Instant::now();
+        self.state_dirty = true;
+
+        for (_, eid, types) in removed {
+            if !types.is_empty() {
+                self.record(cm, engine, JournalEvent::ComponentsRemoved(did, eid, types))?;
+            }
+        }
 
         Ok(())
     }
@@ -270,20 +796,38 @@ impl DeviceTree {
         &mut self,
         cm: &mut ClientManager,
         engine: &mut QueryEngine,
+        owner: ExtensionIndex,
         did: DeviceID,
         eindex: EntityIndex,
         comps: Vec<Component>,
     ) -> Result<(), IglooError> {
         let device = self.device_mut(&did)?;
+        if device.owner_ref != Some(owner) {
+            return Err(IglooError::DeviceTreeMutation(TreeMutationError::NotOwner(
+                did, owner,
+            )));
+        }
+        if eindex.0 >= device.entities.len() {
+            return Err(IglooError::DeviceTreeMutation(
+                TreeMutationError::EntityNotFound(did, eindex),
+            ));
+        }
         device.last_updated = Instant::now();
+        self.state_dirty = true;
 
         for comp in comps {
             // FIXME super slow
             let device = self.device_mut(&did)?;
-            let entity = &mut device.entities[eindex.0];
+            let Some(entity) = device.entities.get_mut(eindex.0) else {
+                return Err(IglooError::DeviceTreeMutation(
+                    TreeMutationError::EntityNotFound(did, eindex),
+                ));
+            };
 
             let comp_type = comp.get_type();
             entity.last_updated = Instant::now();
+            entity.stale = false;
+            let event = JournalEvent::Reported(did, entity.id.clone(), comp.clone());
 
             match entity.put(comp.clone()) {
                 Some(comp_type) => {
@@ -309,7 +853,58 @@ impl DeviceTree {
                     )?;
                 }
             }
+
+            self.record(cm, engine, event)?;
+        }
+
+        Ok(())
+    }
+
+    /// Components the entity no longer has (ex. a light switching to white-only mode)
+    /// Types it doesn't have are ignored
+    pub fn remove_components(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        owner: ExtensionIndex,
+        did: DeviceID,
+        eindex: EntityIndex,
+        comp_types: Vec<ComponentType>,
+    ) -> Result<(), IglooError> {
+        let device = self.device_mut(&did)?;
+        if device.owner_ref != Some(owner) {
+            return Err(IglooError::DeviceTreeMutation(TreeMutationError::NotOwner(
+                did, owner,
+            )));
+        }
+        let Some(entity) = device.entities.get_mut(eindex.0) else {
+            return Err(IglooError::DeviceTreeMutation(
+                TreeMutationError::EntityNotFound(did, eindex),
+            ));
+        };
+
+        let mut removed = Vec::with_capacity(comp_types.len());
+        for comp_type in comp_types {
+            if entity.remove(comp_type).is_some() {
+                removed.push(comp_type);
+            }
         }
+        if removed.is_empty() {
+            return Ok(());
+        }
+
+        entity.last_updated = Instant::now();
+        let event = JournalEvent::ComponentsRemoved(did, entity.id.clone(), removed.clone());
+        for comp_type in &removed {
+            device.forget_component(eindex, *comp_type);
+        }
+        device.last_updated = Instant::now();
+        self.state_dirty = true;
+
+        for comp_type in removed {
+            engine.on_component_removed(cm, self, self.device(&did)?, eindex, comp_type)?;
+        }
+        self.record(cm, engine, event)?;
 
         Ok(())
     }
@@ -326,8 +921,10 @@ impl DeviceTree {
     ) -> Result<GroupID, IglooError> {
         let group = Group {
             id: GroupID::default(),
-            name,
+            name: name.clone(),
             devices: HashSet::with_capacity_and_hasher(10, FxBuildHasher),
+            parent: None,
+            children: HashSet::with_hasher(FxBuildHasher),
         };
 
         let gid = self.groups.insert(group);
@@ -336,6 +933,7 @@ impl DeviceTree {
         self.save_groups()?;
 
         engine.on_group_created(cm, self, self.group(&gid)?)?;
+        self.record(cm, engine, JournalEvent::GroupCreated(gid, name))?;
 
         Ok(gid)
     }
@@ -347,10 +945,24 @@ impl DeviceTree {
         engine: &mut QueryEngine,
         gid: GroupID,
     ) -> Result<(), IglooError> {
+        // subgroups move up a level, so they stay in the same tree
+        let group = self.group(&gid)?;
+        let parent = group.parent;
+        let children: Vec<GroupID> = group.children.iter().copied().collect();
+        for child in children {
+            self.set_group_parent(cm, engine, child, parent)?;
+        }
+
         let Some(group) = self.groups.remove(gid) else {
             return Err(IglooError::DeviceTreeID(TreeIDError::GroupDeleted(gid)));
         };
 
+        if let Some(parent) = group.parent
+            && let Ok(parent) = self.group_mut(&parent)
+        {
+            parent.children.remove(&gid);
+        }
+
         // remove from all devices
         for did in group.devices {
             if let Ok(device) = self.device_mut(&did) {
@@ -361,6 +973,7 @@ impl DeviceTree {
         self.save_groups()?;
 
         engine.on_group_deleted(cm, self, &gid)?;
+        self.record(cm, engine, JournalEvent::GroupDeleted(gid))?;
 
         Ok(())
     }
@@ -374,11 +987,52 @@ impl DeviceTree {
         new_name: String,
     ) -> Result<(), IglooError> {
         let group = self.group_mut(gid)?;
-        group.name = new_name;
+        group.name = new_name.clone();
 
         self.save_groups()?;
 
         engine.on_group_renamed(cm, self, self.group(gid)?)?;
+        self.record(cm, engine, JournalEvent::GroupRenamed(*gid, new_name))?;
+
+        Ok(())
+    }
+
+    /// Moves `gid` into `parent`, or to the top if `None`
+    pub fn set_group_parent(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        gid: GroupID,
+        parent: Option<GroupID>,
+    ) -> Result<(), IglooError> {
+        let old_parent = self.group(&gid)?.parent;
+        if old_parent == parent {
+            return Ok(());
+        }
+
+        if let Some(parent) = parent {
+            self.group(&parent)?;
+            if self.in_tree(&parent, &gid) {
+                return Err(IglooError::DeviceTreeMutation(
+                    TreeMutationError::GroupCycle(gid, parent),
+                ));
+            }
+        }
+
+        if let Some(old_parent) = old_parent
+            && let Ok(old_parent) = self.group_mut(&old_parent)
+        {
+            old_parent.children.remove(&gid);
+        }
+        if let Some(parent) = parent {
+            self.group_mut(&parent)?.children.insert(gid);
+        }
+        self.group_mut(&gid)?.parent = parent;
+
+        self.save_groups()?;
+
+        engine.on_group_parent_changed(cm, self, self.group(&gid)?)?;
+        self.record(cm, engine, JournalEvent::GroupParentChanged(gid, parent))?;
 
         Ok(())
     }
@@ -400,6 +1054,7 @@ impl DeviceTree {
         self.save_groups()?;
 
         engine.on_group_device_added(cm, self, self.group(&gid)?, self.device(&did)?)?;
+        self.record(cm, engine, JournalEvent::GroupDeviceAdded(gid, did))?;
 
         Ok(())
     }
@@ -421,7 +1076,175 @@ impl DeviceTree {
         self.save_groups()?;
 
         engine.on_group_device_removed(cm, self, self.group(&gid)?, self.device(&did)?)?;
+        self.record(cm, engine, JournalEvent::GroupDeviceRemoved(gid, did))?;
+
+        Ok(())
+    }
+}
+
+// Discovery Mutations
+impl DeviceTree {
+    /// Adds (or updates) a device in the discovery inbox
+    /// Ignored and already accepted devices are dropped
+    pub fn discover_device(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        owner: ExtensionIndex,
+        external_id: String,
+        name: String,
+        model: Option<String>,
+    ) -> Result<(), IglooError> {
+        let xid = self.ext(&owner)?.id.clone();
+        let key = (xid, external_id);
+
+        if self.ignored.contains(&key) || self.adopting.contains_key(&key) {
+            return Ok(());
+        }
+
+        // already adopted
+        if self
+            .devices
+            .iter()
+            .any(|device| device.owner == key.0 && device.external_id.as_ref() == Some(&key.1))
+        {
+            return Ok(());
+        }
+
+        let discovered = DiscoveredDevice {
+            ext: key.0.clone(),
+            external_id: key.1.clone(),
+            name,
+            model,
+        };
+
+        if self.discovered.get(&key) == Some(&discovered) {
+            return Ok(());
+        }
+        self.discovered.insert(key, discovered.clone());
+
+        engine.on_device_discovered(cm, self, &discovered)?;
+
+        Ok(())
+    }
+
+    /// Tells the owning Extension to create the device
+    /// Once it does, it's added to `groups`
+    pub fn accept_discovered(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        xid: ExtensionID,
+        external_id: String,
+        name: Option<String>,
+        groups: Vec<GroupID>,
+    ) -> Result<(), IglooError> {
+        let xindex = *self.ext_index(&xid)?;
+        for gid in &groups {
+            self.group(gid)?;
+        }
 
+        let key = (xid, external_id);
+        let Some(discovered) = self.discovered.remove(&key) else {
+            return Err(IglooError::DeviceTreeMutation(
+                TreeMutationError::NotDiscovered(key.0, key.1),
+            ));
+        };
+
+        let msg = IglooToExtension::Adopt {
+            external_id: key.1.clone(),
+            name: name.unwrap_or_else(|| discovered.name.clone()),
+        };
+        let adoption = Adoption {
+            discovered,
+            groups,
+            deadline: Instant::now() + ADOPT_TIMEOUT,
+        };
+        self.adopting.insert(key.clone(), adoption);
+
+        engine.on_discovered_removed(cm, self, &key.0, &key.1)?;
+        self.record(cm, engine, JournalEvent::DiscoveredAccepted(key.0, key.1))?;
+
+        if self.ext(&xindex)?.queue.push(msg).is_err() {
+            return self.detach_ext(cm, engine, xindex, DetachReason::ChannelFull);
+        }
+
+        Ok(())
+    }
+
+    /// Puts accepted devices the Extension didn't create within [ADOPT_TIMEOUT]
+    /// back in the inbox, so they can be accepted again
+    pub fn expire_adopting(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        now: Instant,
+    ) -> Result<(), IglooError> {
+        let expired: Vec<DiscoveryKey> = self
+            .adopting
+            .iter()
+            .filter(|(_, adoption)| now >= adoption.deadline)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired {
+            let adoption = self.adopting.remove(&key).unwrap();
+            eprintln!(
+                "{} didn't create {} in time, returning it to discovered",
+                key.0, key.1
+            );
+            self.discovered
+                .insert(key.clone(), adoption.discovered.clone());
+            engine.on_device_discovered(cm, self, &adoption.discovered)?;
+            self.record(
+                cm,
+                engine,
+                JournalEvent::DiscoveredAdoptTimedOut(key.0, key.1),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes it from the inbox and stops it from being discovered again
+    pub fn ignore_discovered(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        xid: ExtensionID,
+        external_id: String,
+    ) -> Result<(), IglooError> {
+        let key = (xid, external_id);
+        let was_discovered = self.discovered.remove(&key).is_some();
+
+        let newly_ignored = self.ignored.insert(key.clone());
+        if newly_ignored {
+            self.save_ignored()?;
+        }
+
+        if was_discovered {
+            engine.on_discovered_removed(cm, self, &key.0, &key.1)?;
+        }
+        if newly_ignored {
+            self.record(cm, engine, JournalEvent::DiscoveredIgnored(key.0, key.1))?;
+        }
+
+        Ok(())
+    }
+
+    /// Lets it be discovered again (once the Extension reports it)
+    pub fn unignore_discovered(
+        &mut self,
+        cm: &mut ClientManager,
+        engine: &mut QueryEngine,
+        xid: ExtensionID,
+        external_id: String,
+    ) -> Result<(), IglooError> {
+        let key = (xid, external_id);
+        if self.ignored.remove(&key) {
+            self.save_ignored()?;
+            self.record(cm, engine, JournalEvent::DiscoveredUnignored(key.0, key.1))?;
+        }
         Ok(())
     }
 }
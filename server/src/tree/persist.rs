@@ -1,17 +1,41 @@
-use super::{Device, DeviceTree, Group};
-use crate::{DATA_DIR, tree::arena::Arena};
-use igloo_interface::id::{DeviceIDMarker, GroupIDMarker};
-use rustc_hash::FxBuildHasher;
-use serde::Serialize;
+use super::{Device, DeviceTree, DiscoveryKey, Entity, Group};
+use crate::{
+    DATA_DIR,
+    tree::{
+        arena::Arena,
+        journal::{JOURNAL_FILE, Journal},
+    },
+};
+use igloo_interface::{
+    Component,
+    id::{DeviceID, DeviceIDMarker, EntityID, EntityIndex, GroupID, GroupIDMarker},
+};
+use rustc_hash::{FxBuildHasher, FxHashSet};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    io::{Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    fs::{self, File, TryLockError},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 pub const GROUPS_FILE: &str = "groups.toml";
 pub const DEVICES_FILE: &str = "devices.toml";
+/// Discovered devices the user doesn't want to see again
+pub const IGNORED_FILE: &str = "ignored.toml";
+/// Last known entities and component values, so they survive restarts
+pub const STATE_FILE: &str = "state.json";
+/// Max time changes to the state can go unsaved (also saved on shutdown)
+pub const STATE_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// Time an Extension has after attaching to register its restored entities again
+pub const RESTORED_ENTITY_TIMEOUT: Duration = Duration::from_secs(60);
+/// Previous versions kept of each TOML file (`<file>.1`, `<file>.2`, ...)
+pub const MAX_BACKUPS: usize = 3;
+/// Contents of a TOML file that doesn't exist yet
+const DEFAULT_CONTENT: &str = "generation = 0\n\n";
+/// Locked by a running Igloo, see [lock_data_dir]
+pub const LOCK_FILE: &str = "igloo.lock";
 
 #[derive(thiserror::Error, Debug)]
 pub enum TreePersistError {
@@ -23,15 +47,49 @@ pub enum TreePersistError {
     Deserialize(&'static str, toml::de::Error),
     #[error("`{}`: {}", _0, _1)]
     Serialize(&'static str, toml::ser::Error),
+    #[error("`{}`: {}", _0, _1)]
+    Json(&'static str, serde_json::Error),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum LockError {
+    #[error("{} is in use by a running Igloo, stop it first", _0.display())]
+    InUse(PathBuf),
+    #[error("Failed to lock the data dir: {0}")]
+    IO(#[from] io::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeviceState {
+    id: DeviceID,
+    /// Position is the entity index
+    entities: Vec<EntityState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EntityState {
+    id: EntityID,
+    components: Vec<Component>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct IgnoredData {
+    #[serde(default)]
+    ignored: FxHashSet<DiscoveryKey>,
 }
 
 impl DeviceTree {
     pub fn load() -> Result<Self, TreePersistError> {
-        let mut groups_file = Self::open_file(GROUPS_FILE)?;
-        let mut devices_file = Self::open_file(DEVICES_FILE)?;
+        let groups_path = Self::data_path(GROUPS_FILE)?;
+        let devices_path = Self::data_path(DEVICES_FILE)?;
+        let ignored_path = Self::data_path(IGNORED_FILE)?;
+        let state_path = Self::data_path(STATE_FILE)?;
+
+        let mut groups: Arena<GroupIDMarker, Group> = load_toml(GROUPS_FILE, &groups_path)?;
+        let devices: Arena<DeviceIDMarker, Device> = load_toml(DEVICES_FILE, &devices_path)?;
+        let ignored = load_toml::<IgnoredData>(IGNORED_FILE, &ignored_path)?.ignored;
 
-        let groups = Self::load_groups(&mut groups_file)?;
-        let devices = Self::load_devices(&mut devices_file)?;
+        Self::link_groups(&mut groups);
 
         // build device->group
         let mut devices = devices;
@@ -43,78 +101,304 @@ impl DeviceTree {
             }
         }
 
+        // the state is only a cache, so a bad one shouldn't stop Igloo from starting
+        match Self::load_state(&state_path) {
+            Ok(state) => Self::restore_state(&mut devices, state),
+            Err(e) => eprintln!("Failed to restore state, starting without it: {e}"),
+        }
+
         Ok(Self {
             groups,
-            groups_file: Some(groups_file),
+            groups_path: Some(groups_path),
             devices,
-            devices_file: Some(devices_file),
+            devices_path: Some(devices_path),
             attached_exts: Vec::with_capacity(10),
             ext_ref_lut: HashMap::with_capacity_and_hasher(10, FxBuildHasher),
+            discovered: HashMap::with_capacity_and_hasher(10, FxBuildHasher),
+            adopting: HashMap::with_capacity_and_hasher(5, FxBuildHasher),
+            ignored,
+            ignored_path: Some(ignored_path),
+            state_path: Some(state_path),
+            detached_entities: HashMap::with_capacity_and_hasher(10, FxBuildHasher),
+            state_dirty: false,
+            state_saved: Instant::now(),
+            journal: Journal::new(Some(Self::data_path(JOURNAL_FILE)?)),
         })
     }
 
-    fn open_file(filename: &str) -> Result<File, TreePersistError> {
-        let mut path = DATA_DIR.get().unwrap().clone();
-        path.push(filename);
-
+    /// Saved devices, without creating or restoring any file
+    /// For the CLI, which may run alongside Igloo
+    pub fn read_devices() -> Result<Arena<DeviceIDMarker, Device>, TreePersistError> {
+        let path = Self::data_path(DEVICES_FILE)?;
         if !fs::exists(&path)? {
-            fs::write(&path, format!("generation = 0\n\n"))?;
+            return Ok(Arena::new(0));
+        }
+        read_toml(DEVICES_FILE, &path)
+    }
+
+    /// Builds parent->children, dropping parents that are missing or form a cycle
+    fn link_groups(groups: &mut Arena<GroupIDMarker, Group>) {
+        let links: Vec<(GroupID, GroupID)> = groups
+            .iter()
+            .filter_map(|group| group.parent.map(|parent| (group.id, parent)))
+            .collect();
+
+        for (gid, parent) in links {
+            let mut cur = Some(parent);
+            let mut steps = 0;
+            while let Some(ancestor) = cur
+                && ancestor != gid
+                && steps <= groups.len()
+            {
+                cur = groups.get(&ancestor).and_then(|group| group.parent);
+                steps += 1;
+            }
+
+            match groups.get_mut(&parent) {
+                Some(parent_group) if cur.is_none() => {
+                    parent_group.children.insert(gid);
+                }
+                _ => {
+                    eprintln!("Group {gid} has an invalid parent {parent}, moving it to the top");
+                    groups.get_mut(&gid).unwrap().parent = None;
+                }
+            }
         }
+    }
 
-        let file = File::options().read(true).write(true).open(&path)?;
+    fn data_path(filename: &str) -> Result<PathBuf, TreePersistError> {
+        let path = DATA_DIR.get().unwrap().join(filename);
 
-        let meta = file.metadata()?;
-        if meta.is_dir() {
+        // follows symlinks
+        if fs::metadata(&path).is_ok_and(|meta| meta.is_dir()) {
             return Err(TreePersistError::FileIsDirectory(path));
         }
 
-        if meta.is_symlink() {
-            let sym_meta = fs::symlink_metadata(&path)?;
-            if sym_meta.is_dir() {
-                return Err(TreePersistError::FileIsDirectory(path));
-            }
+        Ok(path)
+    }
+
+    fn load_state(path: &Path) -> Result<Vec<DeviceState>, TreePersistError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        if content.is_empty() {
+            return Ok(Vec::new());
         }
+        serde_json::from_str(&content).map_err(|e| TreePersistError::Json(STATE_FILE, e))
+    }
+
+    /// Restored entities are stale until their owner confirms them
+    /// Ones it doesn't register again are dropped, see [RESTORED_ENTITY_TIMEOUT]
+    fn restore_state(devices: &mut Arena<DeviceIDMarker, Device>, state: Vec<DeviceState>) {
+        for device_state in state {
+            // deleted since
+            let Some(device) = devices.get_mut(&device_state.id) else {
+                continue;
+            };
+
+            for (index, entity_state) in device_state.entities.into_iter().enumerate() {
+                let mut entity = Entity {
+                    id: entity_state.id,
+                    index: EntityIndex(index),
+                    stale: true,
+                    restored: true,
+                    ..Default::default()
+                };
+                for comp in entity_state.components {
+                    entity.put(comp);
+                }
+                device.entities.push(entity);
+            }
 
-        Ok(file)
+            device.rebuild_entity_luts();
+        }
     }
 
-    fn load_groups(file: &mut File) -> Result<Arena<GroupIDMarker, Group>, TreePersistError> {
-        let mut content = String::with_capacity(file.metadata()?.len() as usize);
-        file.read_to_string(&mut content)?;
-        Ok(toml::from_str(&content).map_err(|e| TreePersistError::Deserialize(GROUPS_FILE, e))?)
+    /// Saves the state if it changed and hasn't been saved in [STATE_FLUSH_INTERVAL]
+    pub fn flush_state(&mut self, now: Instant) -> Result<(), TreePersistError> {
+        if self.state_dirty && now.duration_since(self.state_saved) >= STATE_FLUSH_INTERVAL {
+            self.save_state()?;
+        }
+        Ok(())
     }
 
-    fn load_devices(file: &mut File) -> Result<Arena<DeviceIDMarker, Device>, TreePersistError> {
-        let mut content = String::with_capacity(file.metadata()?.len() as usize);
-        file.read_to_string(&mut content)?;
-        Ok(toml::from_str(&content).map_err(|e| TreePersistError::Deserialize(DEVICES_FILE, e))?)
+    pub fn save_state(&mut self) -> Result<(), TreePersistError> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+
+        let state: Vec<DeviceState> = self
+            .devices
+            .iter()
+            .filter_map(|device| {
+                let entities = match device.entities.is_empty() {
+                    true => self.detached_entities.get(&device.id)?,
+                    false => &device.entities,
+                };
+                Some(DeviceState {
+                    id: device.id,
+                    entities: entities
+                        .iter()
+                        .map(|entity| EntityState {
+                            id: entity.id.clone(),
+                            components: entity.components.to_vec(),
+                        })
+                        .collect(),
+                })
+            })
+            .collect();
+
+        let content =
+            serde_json::to_vec(&state).map_err(|e| TreePersistError::Json(STATE_FILE, e))?;
+        // only a cache, so no backups
+        write_atomic(path, &content)?;
+        self.state_dirty = false;
+        self.state_saved = Instant::now();
+        Ok(())
     }
 
     pub(super) fn save_groups(&mut self) -> Result<(), TreePersistError> {
-        if let Some(file) = &mut self.groups_file {
-            write_toml(GROUPS_FILE, file, &self.groups)?;
+        if let Some(path) = &self.groups_path {
+            write_toml(GROUPS_FILE, path, &self.groups)?;
         }
         Ok(())
     }
 
     pub(super) fn save_devices(&mut self) -> Result<(), TreePersistError> {
-        if let Some(file) = &mut self.devices_file {
-            write_toml(DEVICES_FILE, file, &self.devices)?;
+        if let Some(path) = &self.devices_path {
+            write_toml(DEVICES_FILE, path, &self.devices)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn save_ignored(&mut self) -> Result<(), TreePersistError> {
+        if let Some(path) = &self.ignored_path {
+            let data = IgnoredData {
+                ignored: self.ignored.clone(),
+            };
+            write_toml(IGNORED_FILE, path, &data)?;
         }
         Ok(())
     }
 }
 
+/// Held for as long as Igloo runs, so CLI commands that rewrite the data dir
+/// (`igloo restore`, `igloo history import`) can refuse to
+/// Released when the returned file is dropped, or the process exits
+pub fn lock_data_dir(data_dir: &Path) -> Result<File, LockError> {
+    fs::create_dir_all(data_dir)?;
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(data_dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(LockError::InUse(data_dir.to_path_buf())),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Loads `path`, creating it if it doesn't exist
+/// If it fails to parse, the newest backup that does is restored and the
+/// bad file is kept as `<file>.corrupt`
+fn load_toml<T: DeserializeOwned>(
+    filename: &'static str,
+    path: &Path,
+) -> Result<T, TreePersistError> {
+    if !fs::exists(path)? {
+        write_atomic(path, DEFAULT_CONTENT.as_bytes())?;
+    }
+
+    let err = match read_toml(filename, path) {
+        Ok(data) => return Ok(data),
+        Err(e @ TreePersistError::Deserialize(..)) => e,
+        Err(e) => return Err(e),
+    };
+
+    for n in 1..=MAX_BACKUPS {
+        let backup = with_suffix(path, &format!(".{n}"));
+        // missing or also corrupt
+        let Ok(data) = read_toml(filename, &backup) else {
+            continue;
+        };
+
+        eprintln!(
+            "Failed to load {err}. Restored {filename} from {}",
+            backup.display()
+        );
+        fs::rename(path, with_suffix(path, ".corrupt"))?;
+        write_atomic(path, &fs::read(&backup)?)?;
+        return Ok(data);
+    }
+
+    Err(err)
+}
+
+fn read_toml<T: DeserializeOwned>(
+    filename: &'static str,
+    path: &Path,
+) -> Result<T, TreePersistError> {
+    let content = fs::read_to_string(path)?;
+    toml::from_str(&content).map_err(|e| TreePersistError::Deserialize(filename, e))
+}
+
+/// Keeps the current version as a backup, then replaces it
 pub fn write_toml<S: Serialize>(
     filename: &'static str,
-    file: &mut File,
+    path: &Path,
     data: &S,
 ) -> Result<(), TreePersistError> {
-    file.seek(SeekFrom::Start(0))?;
     let content =
         toml::to_string_pretty(data).map_err(|e| TreePersistError::Serialize(filename, e))?;
-    file.write_all(content.as_bytes())?;
-    file.flush()?;
-    file.set_len(content.len() as u64)?;
+    rotate_backups(path)?;
+    write_atomic(path, content.as_bytes())?;
+    Ok(())
+}
+
+/// `<file>.1` is the newest backup, `<file>.MAX_BACKUPS` the oldest
+fn rotate_backups(path: &Path) -> io::Result<()> {
+    if !fs::exists(path)? {
+        return Ok(());
+    }
+
+    for n in (1..MAX_BACKUPS).rev() {
+        let from = with_suffix(path, &format!(".{n}"));
+        if fs::exists(&from)? {
+            fs::rename(from, with_suffix(path, &format!(".{}", n + 1)))?;
+        }
+    }
+
+    // hard link, so `path` never goes missing
+    let newest = with_suffix(path, ".1");
+    if fs::exists(&newest)? {
+        fs::remove_file(&newest)?;
+    }
+    fs::hard_link(path, newest)
+}
+
+/// Writes to `<file>.tmp`, then renames it over `path`, so a crash
+/// leaves either the old or the new version, never a partial one
+fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp, path)?;
+
+    // make the rename itself durable
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
     Ok(())
 }
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
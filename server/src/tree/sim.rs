@@ -2,19 +2,23 @@
 //! NOTE: Generated by Claude
 #![allow(dead_code)]
 
-use crate::tree::arena::Arena;
+use crate::tree::{arena::Arena, journal::Journal};
 
 use super::{COMP_TYPE_ARR_LEN, Device, DeviceTree, Entity, Extension, Group, Presense};
 use igloo_interface::{
     AlarmState, ClimateMode, ColorMode, Component, CoverState, FanDirection, FanOscillation,
     FanSpeed, LockState, MediaState, SensorStateClass, Unit, ValveState,
     id::{DeviceID, EntityID, EntityIndex, ExtensionID, ExtensionIndex, GroupID},
+    query::DeviceInfo,
     types::IglooColor,
 };
 use rand::{Rng, rngs::ThreadRng};
 use rustc_hash::{FxHashMap, FxHashSet};
 use smallvec::SmallVec;
-use std::time::Instant;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Instant,
+};
 
 const ROOM_NAMES: &[&str] = &[
     "Kitchen",
@@ -115,6 +119,8 @@ pub fn make_test_tree(num_exts: usize, num_groups: usize, num_devices: usize) ->
                 .unwrap_or(&"Room")
                 .to_string(),
             devices: FxHashSet::default(),
+            parent: None,
+            children: FxHashSet::default(),
         };
 
         groups
@@ -175,6 +181,12 @@ pub fn make_test_tree(num_exts: usize, num_groups: usize, num_devices: usize) ->
             id: did,
             name,
             owner,
+            external_id: None,
+            info: DeviceInfo::default(),
+            icon: None,
+            notes: None,
+            labels: BTreeSet::new(),
+            entity_labels: BTreeMap::new(),
             owner_ref: Some(ExtensionIndex(owner_idx)),
             groups: device_groups,
             presense,
@@ -182,6 +194,7 @@ pub fn make_test_tree(num_exts: usize, num_groups: usize, num_devices: usize) ->
             entities,
             entity_index_lut,
             last_updated: Instant::now(),
+            reachable: true,
         };
 
         devices
@@ -210,8 +223,17 @@ pub fn make_test_tree(num_exts: usize, num_groups: usize, num_devices: usize) ->
         attached_exts: exts,
         ext_ref_lut,
         devices,
-        devices_file: None,
-        groups_file: None,
+        devices_path: None,
+        groups_path: None,
+        discovered: FxHashMap::default(),
+        adopting: FxHashMap::default(),
+        ignored: FxHashSet::default(),
+        ignored_path: None,
+        state_path: None,
+        detached_entities: FxHashMap::default(),
+        state_dirty: false,
+        state_saved: Instant::now(),
+        journal: Journal::new(None),
     }
 }
 fn make_entities_for_archetype(
@@ -524,6 +546,8 @@ fn make_entity(eindex: usize, eid: &str, components: Vec<Component>) -> Entity {
         components: components.into(),
         indices,
         last_updated: Instant::now(),
+        stale: false,
+        restored: false,
     }
 }
 
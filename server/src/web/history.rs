@@ -0,0 +1,151 @@
+//! `GET /history/export`, downloads recorded history (see [crate::history::export])
+//!
+//! Query parameters, all optional:
+//!  - `format` `csv` (default) or `jsonl`
+//!  - `devices` comma separated device names or IDs
+//!  - `entities` comma separated EntityIDs
+//!  - `components` comma separated ComponentTypes in snake_case
+//!  - `since`, `until` RFC 3339 or milliseconds since the Unix epoch
+
+use super::WState;
+use crate::{
+    core::IglooRequest,
+    history::{
+        export::{ExportFilter, ExportFormat, export, parse_component, parse_time},
+        history_dir,
+    },
+};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{
+        StatusCode,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    },
+    response::{IntoResponse, Response},
+};
+use igloo_interface::id::EntityID;
+use serde::Deserialize;
+use std::{
+    io::{self, ErrorKind, Write},
+    mem,
+};
+
+/// Bytes sent to the client at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+    devices: Option<String>,
+    entities: Option<String>,
+    components: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+/// Buffers an export from a blocking task into chunks for the response body
+struct ChunkWriter {
+    tx: kanal::Sender<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+}
+
+impl ExportParams {
+    fn filter(&self) -> Result<ExportFilter, String> {
+        let list = |s: &Option<String>| -> Vec<String> {
+            s.iter()
+                .flat_map(|s| s.split(','))
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        };
+        Ok(ExportFilter {
+            devices: list(&self.devices),
+            entities: list(&self.entities).into_iter().map(EntityID).collect(),
+            components: list(&self.components)
+                .iter()
+                .map(|s| parse_component(s))
+                .collect::<Result<_, _>>()?,
+            since: self.since.as_deref().map(parse_time).transpose()?,
+            until: self.until.as_deref().map(parse_time).transpose()?,
+        })
+    }
+}
+
+pub async fn export_handler(
+    State(state): State<WState>,
+    Query(params): Query<ExportParams>,
+) -> Response {
+    let filter = match params.filter() {
+        Ok(filter) => filter,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    // IglooCore flushes the recorder first, so every entry so far is exported
+    let (names_tx, names_rx) = kanal::bounded(1);
+    if let Err(e) = state
+        .req_tx
+        .send(IglooRequest::HistoryExport(names_tx))
+        .await
+    {
+        eprintln!("Failed to send history export request: {e}");
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let names = match names_rx.to_async().recv().await {
+        Ok(names) => names,
+        Err(e) => {
+            eprintln!("Failed to receive device names for history export: {e}");
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
+
+    let format = params.format;
+    let (tx, rx) = kanal::bounded(4);
+    tokio::task::spawn_blocking(move || {
+        let mut out = ChunkWriter {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+        let res = export(&history_dir(), &names, &filter, format, &mut out);
+        // ends the body with an error, so the download isn't mistaken for a complete one
+        if let Err(e) = res {
+            eprintln!("Failed to export history: {e}");
+            let _ = out.tx.send(Err(io::Error::other(e.to_string())));
+        }
+    });
+
+    let chunks = futures_util::stream::unfold(rx.to_async(), |rx| async move {
+        rx.recv().await.ok().map(|chunk| (chunk, rx))
+    });
+    let disposition = format!("attachment; filename=\"history.{}\"", format.extension());
+    (
+        [
+            (CONTENT_TYPE, format.content_type().to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response()
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    /// Fails once the client is gone
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let chunk = mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+            self.tx
+                .send(Ok(chunk))
+                .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        }
+        Ok(())
+    }
+}
@@ -5,7 +5,7 @@ use axum::{
         ws::{Message, WebSocket},
     },
     response::IntoResponse,
-    routing::any,
+    routing::{any, get},
 };
 use axum_extra::{TypedHeader, headers::Cookie};
 use futures_util::{SinkExt, StreamExt};
@@ -45,6 +45,8 @@ MVP Should:
 
 */
 
+mod history;
+
 pub const PLUGINS_DIR: &str = "plugins";
 pub const DASHBOARDS_DIR: &str = "dashboards";
 
@@ -67,6 +69,7 @@ pub async fn run(
 
     let app = Router::new()
         .route("/ws", any(ws_handler))
+        .route("/history/export", get(history::export_handler))
         .nest_service("/plugins", ServeDir::new(plugins_dir))
         .nest_service("/dashboards", ServeDir::new(dashboards_dir))
         .fallback_service(ServeDir::new(www_dir).append_index_html_on_directories(true))